@@ -303,6 +303,8 @@ global init_stack:data (init_stack.end - init_stack)
 init_stack:
 	resb STACKSIZE
 .end:
+global init_stack_end
+init_stack_end:
 
 
 ;
@@ -8,13 +8,25 @@
    ║ Author: Michael Schoetter, Univ. Duesseldorf, 6.2.2024                  ║
    ╚═════════════════════════════════════════════════════════════════════════╝
 */
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
 use spin::Mutex;
 use crate::kernel::cpu as cpu;
+use crate::kernel::timer;
+use crate::devices::console;
+use crate::devices::key;
+use crate::devices::keyboard;
+use crate::devices::pcspk;
+use crate::library::arrayvec::ArrayVec;
+use crate::library::IrqSafeMutex;
+use crate::devices::theme::{self, Style};
 
 /// Global CGA instance, used for screen output in the whole kernel.
+/// Interrupts are disabled while it is locked, because soft interrupts draw on the screen too.
 /// Usage: let mut cga = cga::CGA.lock();
 ///        cga.print_byte(b'X');
-pub static CGA: Mutex<CGA> = Mutex::new(CGA::new());
+pub static CGA: IrqSafeMutex<CGA> = IrqSafeMutex::new(CGA::new());
 
 /// All 16 CGA colors.
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -40,6 +52,9 @@ pub enum Color {
 
 pub const CGA_STD_ATTR: u8 = (Color::Black as u8) << 4 | (Color::White as u8);
 
+/// Cursor position after the banner has been drawn by `banner()`.
+pub const CGA_BANNER_CURSOR_POS: (usize, usize) = (0, 2);
+
 const CGA_BASE_ADDR: *mut u8 = 0xb8000 as *mut u8;
 const CGA_ROWS: usize = 25;
 const CGA_COLUMNS: usize = 80;
@@ -49,9 +64,105 @@ const CGA_DATA_PORT: u16 = 0x3d5;  // read/write register
 const CGA_HIGH_BYTE_CMD: u8 = 14;  // cursor high byte
 const CGA_LOW_BYTE_CMD: u8 = 15;   // cursor low byte
 
+const CGA_ATTR_PORT: u16 = 0x3c0;         // attribute controller index/data (W)
+const CGA_ATTR_READ_PORT: u16 = 0x3c1;    // attribute controller data (R)
+const CGA_INPUT_STATUS_PORT: u16 = 0x3da; // input status register 1
+const CGA_ATTR_MODE_CONTROL: u8 = 0x10;   // index of the mode control register
+const CGA_ATTR_PALETTE_ENABLE: u8 = 0x20; // keep the display enabled while selecting an index
+const CGA_ATTR_BLINK_BIT: u8 = 0x08;      // blink enable bit in the mode control register
+const CGA_ATTR_OVERSCAN: u8 = 0x11;       // index of the overscan (border) color register
+
 pub struct CGA {
     index_port: cpu::IoPort,
     data_port: cpu::IoPort,
+    /// Copy of the screen content (attribute in the high byte, character in the low byte).
+    /// Only kept up to date in buffered mode.
+    shadow: [u16; CGA_ROWS * CGA_COLUMNS],
+    /// In buffered mode, `show` skips writes to video memory if the cell is unchanged.
+    buffered: bool,
+    /// Number of cells written to video memory by `show` (see `video_writes()`).
+    video_writes: usize,
+    attr_port: cpu::IoPort,
+    attr_read_port: cpu::IoPort,
+    input_status_port: cpu::IoPort,
+    /// Bit 7 of the attribute is the blink bit (otherwise the background intensity).
+    blink_enabled: bool,
+    /// The hardware cursor follows the output (see `set_cursor_follow()`).
+    cursor_follow: bool,
+    /// Position of the next output while the cursor does not follow it.
+    output_pos: (usize, usize),
+    /// What happens when the output moves past the last row.
+    overflow_policy: OverflowPolicy,
+    /// Set when the output has reached the end of the screen with `OverflowPolicy::Stop`.
+    output_stopped: bool,
+    /// The software mouse cursor, if shown (see `set_mouse_cursor()`).
+    mouse_cursor: Option<InvertedCell>,
+    /// The hardware cursor is replaced by an inverted cell (see `set_software_cursor()`).
+    software_cursor: bool,
+    /// The cell currently inverted by the software cursor, if drawn (it is hidden while blinking).
+    soft_cursor: Option<InvertedCell>,
+    /// The selected cells, if any (see `set_selection()`).
+    selection: Option<Selection>,
+    /// Attribute of selected cells (`None` inverts their attribute, see `set_selection_attribute()`).
+    selection_attr: Option<u8>,
+    /// Each character is printed as block of 2x2 cells (see `set_magnify()`).
+    magnify: bool,
+}
+
+/// A software cursor (mouse or text) drawn by inverting the attribute of the cell at `x`,`y`.
+#[derive(Copy, Clone, Debug)]
+struct InvertedCell {
+    x: usize,
+    y: usize,
+    /// Character and attribute of the cell covered by the cursor
+    saved: (u8, u8),
+}
+
+/// A run of selected cells from `start` (index in row-major order) with their original attributes.
+/// The attributes are kept in a fixed array, as the selection is made while the CGA is locked.
+#[derive(Copy, Clone, Debug)]
+struct Selection {
+    start: usize,
+    /// Original attributes of the selected cells (the first `len` entries)
+    attribs: [u8; CGA_ROWS * CGA_COLUMNS],
+    len: usize,
+    /// Attribute of the selected cells when the selection was made (`None` = inverted)
+    highlight: Option<u8>,
+}
+
+impl Selection {
+    /// Return the original attributes of the selected cells.
+    fn attribs(&self) -> &[u8] {
+        &self.attribs[..self.len]
+    }
+}
+
+/// Cursor and output modes of the CGA, saved by `CGA::save_state()`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CgaState {
+    cursor: (usize, usize),
+    cursor_follow: bool,
+    output_pos: (usize, usize),
+    overflow_policy: OverflowPolicy,
+    output_stopped: bool,
+    blink_enabled: bool,
+    magnify: bool,
+}
+
+/// Characters and attributes of all cells of the screen, saved by `CGA::save_screen()`.
+pub struct ScreenContents {
+    cells: Vec<(u8, u8)>,
+}
+
+/// What `print_byte()` does when the output moves past the last row (see `set_overflow_policy()`).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Scroll the screen up by one row (the default)
+    Scroll,
+    /// Clear the screen and continue in the top left corner
+    WrapToTop,
+    /// Discard all further output until the cursor is moved or the screen is cleared
+    Stop,
 }
 
 impl CGA {
@@ -59,29 +170,89 @@ impl CGA {
         CGA {
             index_port: cpu::IoPort::new(CGA_INDEX_PORT),
             data_port: cpu::IoPort::new(CGA_DATA_PORT),
+            shadow: [0; CGA_ROWS * CGA_COLUMNS],
+            buffered: false,
+            video_writes: 0,
+            attr_port: cpu::IoPort::new(CGA_ATTR_PORT),
+            attr_read_port: cpu::IoPort::new(CGA_ATTR_READ_PORT),
+            input_status_port: cpu::IoPort::new(CGA_INPUT_STATUS_PORT),
+            blink_enabled: true,
+            cursor_follow: true,
+            output_pos: (0, 0),
+            overflow_policy: OverflowPolicy::Scroll,
+            output_stopped: false,
+            mouse_cursor: None,
+            software_cursor: false,
+            soft_cursor: None,
+            selection: None,
+            selection_attr: None,
+            magnify: false,
+        }
+    }
+
+    /// Enable or disable buffered mode.
+    /// When enabled, the shadow buffer is filled with the current screen content,
+    /// so that `show` can skip writing cells that already hold the same character and attribute.
+    pub fn set_buffered(&mut self, buffered: bool) {
+        if buffered && !self.buffered {
+            for i in 0..CGA_ROWS * CGA_COLUMNS {
+                // Unsafe because we are reading directly from video memory.
+                // `i` is always within the bounds of the screen.
+                unsafe {
+                    let character = CGA_BASE_ADDR.add(i * 2).read();
+                    let attrib = CGA_BASE_ADDR.add(i * 2 + 1).read();
+                    self.shadow[i] = (attrib as u16) << 8 | character as u16;
+                }
+            }
         }
+        self.buffered = buffered;
+    }
+
+    /// Check if buffered mode is enabled (see `set_buffered()`).
+    pub fn is_buffered(&self) -> bool {
+        self.buffered
+    }
+
+    /// Return the number of cells written to video memory by `show` since boot.
+    /// Cells skipped in buffered mode are not counted.
+    pub fn video_writes(&self) -> usize {
+        self.video_writes
     }
 
     /// Clear CGA screen and cursor to 0,0 position.
     pub fn clear(&mut self) {
         /* Hier muss Code eingefuegt werden */
 
+        self.clear_with(b' ', CGA_STD_ATTR);
+    }
+
+    /// Fill the whole screen with character `ch` and attribute `attrib` and move the cursor to the top left corner.
+    pub fn clear_with(&mut self, ch: u8, attrib: u8) {
         for y in 0..CGA_ROWS {
             for x in 0..CGA_COLUMNS {
-                // write each character from the current row to the previous row
-                self.show(x, y, ' ', CGA_STD_ATTR);
+                self.show(x, y, ch as char, attrib);
             }
         }
-        self.setpos(0, 0);
+        self.move_output(0, 0);
     }
 
     /// Display the `character` at the given position `x`,`y` with attribute `attrib`.
     pub fn show(&mut self, x: usize, y: usize, character: char, attrib: u8) {
-        if x > CGA_COLUMNS || y > CGA_ROWS {
+        if x >= CGA_COLUMNS || y >= CGA_ROWS {
             return;
         }
 
-        let pos = (y * CGA_COLUMNS + x) * 2;
+        let index = y * CGA_COLUMNS + x;
+        let pos = index * 2;
+
+        // In buffered mode, skip the write if the cell already holds this character and attribute.
+        if self.buffered {
+            let cell = (attrib as u16) << 8 | character as u8 as u16;
+            if self.shadow[index] == cell {
+                return;
+            }
+            self.shadow[index] = cell;
+        }
 
         // Write character and attribute to the screen buffer.
         //
@@ -92,6 +263,95 @@ impl CGA {
             CGA_BASE_ADDR.offset(pos as isize).write(character as u8);
             CGA_BASE_ADDR.offset((pos + 1) as isize).write(attrib);
         }
+        self.video_writes += 1;
+    }
+
+    /// Fill the rectangle of `w` x `h` cells at `x`,`y` with spaces using attribute `attrib`.
+    /// Parts of the rectangle outside the screen are ignored.
+    pub fn clear_region(&mut self, x: usize, y: usize, w: usize, h: usize, attrib: u8) {
+        for row in y..(y + h).min(CGA_ROWS) {
+            for col in x..(x + w).min(CGA_COLUMNS) {
+                self.show(col, row, ' ', attrib);
+            }
+        }
+    }
+
+    /// Draw `title` centered in the first row of the screen with attribute `attrib`.
+    /// Titles longer than one row are truncated. The cursor is not moved.
+    pub fn draw_banner(&mut self, title: &str, attrib: u8) {
+        for x in 0..CGA_COLUMNS {
+            self.show(x, 0, ' ', attrib);
+        }
+
+        let start = banner_column(title.len());
+        for (i, b) in title.bytes().take(CGA_COLUMNS).enumerate() {
+            self.show(start + i, 0, b as char, attrib);
+        }
+    }
+
+    /// Draw the frame of a box of `w` x `h` cells at `x`,`y` with attribute `attrib`,
+    /// using the line charset (see `set_line_charset()`). Boxes smaller than 2 x 2 are not drawn.
+    /// Parts of the box outside the screen are ignored.
+    pub fn draw_box(&mut self, x: usize, y: usize, w: usize, h: usize, attrib: u8) {
+        if w < 2 || h < 2 {
+            return;
+        }
+        let lines = line_charset().chars();
+        let (right, bottom) = (x + w - 1, y + h - 1);
+
+        for col in x + 1..right {
+            self.show(col, y, lines.horizontal as char, attrib);
+            self.show(col, bottom, lines.horizontal as char, attrib);
+        }
+        for row in y + 1..bottom {
+            self.show(x, row, lines.vertical as char, attrib);
+            self.show(right, row, lines.vertical as char, attrib);
+        }
+        self.show(x, y, lines.top_left as char, attrib);
+        self.show(right, y, lines.top_right as char, attrib);
+        self.show(x, bottom, lines.bottom_left as char, attrib);
+        self.show(right, bottom, lines.bottom_right as char, attrib);
+    }
+
+    /// Read the character and attribute of the cell at position `x`,`y` from video memory.
+    /// Returns `None` if the position is outside the screen.
+    pub fn read_cell(&self, x: usize, y: usize) -> Option<(u8, u8)> {
+        if x >= CGA_COLUMNS || y >= CGA_ROWS {
+            return None;
+        }
+
+        let pos = (y * CGA_COLUMNS + x) * 2;
+
+        // Unsafe because we are reading directly from memory using a pointer.
+        // The bounds of x and y have been checked above.
+        unsafe {
+            let character = CGA_BASE_ADDR.add(pos).read();
+            let attrib = CGA_BASE_ADDR.add(pos + 1).read();
+            Some((character, attrib))
+        }
+    }
+
+    /// Set the attribute of every cell in row `y` to `attrib`, leaving the characters untouched.
+    pub fn set_row_attr(&mut self, y: usize, attrib: u8) {
+        if y >= CGA_ROWS {
+            return;
+        }
+
+        for x in 0..CGA_COLUMNS {
+            self.write_attr(y * CGA_COLUMNS + x, attrib);
+        }
+    }
+
+    /// Set the attribute of the cell at `index` (row-major order), leaving the character untouched.
+    fn write_attr(&mut self, index: usize, attrib: u8) {
+        if self.buffered {
+            self.shadow[index] = (attrib as u16) << 8 | (self.shadow[index] & 0x00ff);
+        }
+
+        // Only the attribute (odd) byte of the cell is written.
+        unsafe {
+            CGA_BASE_ADDR.add(index * 2 + 1).write(attrib);
+        }
     }
 
     pub fn enable_cursor(&mut self) {
@@ -105,6 +365,14 @@ impl CGA {
         }
     }
 
+    /// Hide the hardware cursor (bit 5 of the cursor start register). `enable_cursor()` shows it again.
+    pub fn disable_cursor(&mut self) {
+        unsafe {
+            self.index_port.outb(0x0A);
+            self.data_port.outb(0x20);
+        }
+    }
+
     /// Return cursor position `x`,`y`
     pub fn getpos(&mut self) -> (usize, usize) {
         /* Hier muss Code eingefuegt werden */
@@ -135,6 +403,7 @@ impl CGA {
 
 
         let pos : u16 = (y * CGA_COLUMNS + x) as u16;
+        self.output_stopped = false;
 
         // set cursor position
         unsafe {
@@ -143,38 +412,172 @@ impl CGA {
             self.index_port.outb(CGA_HIGH_BYTE_CMD);
             self.data_port.outb(((pos >> 8) & 0xFF) as u8);
         }
+        self.refresh_soft_cursor();
+    }
+
+    /// Let the hardware cursor follow the output (the default) or leave it where it is.
+    /// While disabled, `print_byte()`, scrolling and clearing only update video memory and the
+    /// output position, so cells can be written (e.g. a status line) without moving the cursor
+    /// parked by the user with `setpos()`. When enabled again, the cursor jumps to the output position.
+    pub fn set_cursor_follow(&mut self, enabled: bool) {
+        if enabled == self.cursor_follow {
+            return;
+        }
+
+        if enabled {
+            self.cursor_follow = true;
+            self.setpos(self.output_pos.0, self.output_pos.1);
+        } else {
+            self.output_pos = self.getpos();
+            self.cursor_follow = false;
+        }
+    }
+
+    /// Check if the hardware cursor follows the output.
+    pub fn cursor_follow(&self) -> bool {
+        self.cursor_follow
+    }
+
+    /// Return the position of the next output, which is the hardware cursor unless it does not follow the output.
+    fn output_pos(&mut self) -> (usize, usize) {
+        if self.cursor_follow {
+            self.getpos()
+        } else {
+            self.output_pos
+        }
+    }
+
+    /// Move the output position to `x`,`y` and the hardware cursor with it if it follows the output.
+    fn move_output(&mut self, x: usize, y: usize) {
+        if self.cursor_follow {
+            self.setpos(x, y);
+        } else {
+            self.output_pos = (x.min(CGA_COLUMNS - 1), y.min(CGA_ROWS - 1));
+            self.output_stopped = false;
+        }
+    }
+
+    /// Save the cursor position and the output modes (cursor follow, overflow policy, blink mode, magnification).
+    /// The screen contents are not included (see `save_screen()`).
+    pub fn save_state(&mut self) -> CgaState {
+        CgaState {
+            cursor: self.getpos(),
+            cursor_follow: self.cursor_follow,
+            output_pos: self.output_pos,
+            overflow_policy: self.overflow_policy,
+            output_stopped: self.output_stopped,
+            blink_enabled: self.blink_enabled,
+            magnify: self.magnify,
+        }
     }
 
-    /// Print byte `b` at actual position cursor position `x`,`y`
+    /// Restore the cursor position and the output modes saved by `save_state()`.
+    pub fn restore_state(&mut self, state: &CgaState) {
+        self.setpos(state.cursor.0, state.cursor.1);
+        self.cursor_follow = state.cursor_follow;
+        self.output_pos = state.output_pos;
+        self.overflow_policy = state.overflow_policy;
+        self.output_stopped = state.output_stopped;
+        self.magnify = state.magnify;
+        if state.blink_enabled != self.blink_enabled {
+            self.set_blink_enabled(state.blink_enabled);
+        }
+    }
+
+    /// Save the characters and attributes of all cells of the screen.
+    pub fn save_screen(&self) -> ScreenContents {
+        let cells = (0..CGA_ROWS * CGA_COLUMNS)
+            .filter_map(|i| self.read_cell(i % CGA_COLUMNS, i / CGA_COLUMNS))
+            .collect();
+        ScreenContents { cells }
+    }
+
+    /// Write the screen contents saved by `save_screen()` back. The cursor is not moved.
+    /// All cells are written, even in buffered mode, as video memory may have been
+    /// overwritten meanwhile (e.g. by the graphics mode of `vga`).
+    pub fn restore_screen(&mut self, screen: &ScreenContents) {
+        let buffered = self.buffered;
+        self.buffered = false;
+        for (i, &(character, attrib)) in screen.cells.iter().enumerate() {
+            self.show(i % CGA_COLUMNS, i / CGA_COLUMNS, character as char, attrib);
+        }
+        self.set_buffered(buffered);
+    }
+
+    /// Select what happens when the output moves past the last row: after a newline
+    /// in the last row or after writing the bottom right cell (see `OverflowPolicy`).
+    pub fn set_overflow_policy(&mut self, policy: OverflowPolicy) {
+        self.overflow_policy = policy;
+        self.output_stopped = false;
+    }
+
+    /// Return the overflow policy.
+    pub fn overflow_policy(&self) -> OverflowPolicy {
+        self.overflow_policy
+    }
+
+    /// Print byte `b` at actual position cursor position `x`,`y`.
+    /// After the last column, the output continues at the start of the next row.
     pub fn print_byte(&mut self, b : u8, bg: Color, fg: Color, blink: bool) {
-        let (mut x, mut y) = self.getpos();
+        if self.output_stopped {
+            return;
+        }
+        let (mut x, mut y) = self.output_pos();
+        let size = if self.magnify { 2 } else { 1 };
 
-        if b == ('\n' as u8) {
+        if b != b'\n' {
+            let attribute = self.attribute(bg, fg, blink);
+            for dy in 0..size {
+                for dx in 0..size {
+                    self.show(x + dx, y + dy, b as char, attribute);
+                }
+            }
+            x += size;
+        }
+        if b == b'\n' || x + size > CGA_COLUMNS {
             x = 0;
-            y += 1;
-            if y >= CGA_ROWS {
-                self.scrollup();
+            match self.next_row(y, size) {
+                Some(row) => y = row,
+                None => {
+                    self.output_stopped = true;
+                    return;
+                }
             }
-        } else {
-            if x >= CGA_COLUMNS{
-                x = 0;
-                y += 1;
+        }
+        self.move_output(x, y);
+    }
 
-                if y >= CGA_ROWS{
-                    y = CGA_ROWS-1;
-                    self.scrollup();
-                }
+    /// Return the row of the line following the line of `height` rows at row `y`,
+    /// applying the overflow policy if it does not fit below. Returns `None` if the output has to stop.
+    fn next_row(&mut self, y: usize, height: usize) -> Option<usize> {
+        if y + 2 * height <= CGA_ROWS {
+            return Some(y + height);
+        }
+
+        match self.overflow_policy {
+            OverflowPolicy::Scroll if height == 1 => {
+                self.scrollup();
+                Some(CGA_ROWS - 1)
             }
-            let attribute = self.attribute(bg, fg, blink);
-            self.show(x, y, b as char, attribute);
-            x += 1;
+            OverflowPolicy::Scroll => {
+                // With magnification, the rows of the new line are not necessarily
+                // the ones blanked by scrolling (the last row is left unused).
+                self.scrollup_n(height);
+                self.clear_region(0, y, CGA_COLUMNS, height, CGA_STD_ATTR);
+                Some(y)
+            }
+            OverflowPolicy::WrapToTop => {
+                self.clear();
+                Some(0)
+            }
+            OverflowPolicy::Stop => None,
         }
-        self.setpos(x, y);
     }
 
-    /// Scroll text lines by one to the top.
+    /// Scroll text lines by one to the top. The selection moves along with the text.
     pub fn scrollup(&mut self) {
         /* Hier muss Code eingefuegt werden */
+        self.hide_soft_cursor();
         for y in 1..CGA_ROWS {
             for x in 0..CGA_COLUMNS {
                 // write each character from the current row to the previous row
@@ -187,20 +590,1509 @@ impl CGA {
                 } 
             }
         }
+        if self.buffered {
+            self.shadow.copy_within(CGA_COLUMNS.., 0);
+        }
+        self.scroll_selection(1);
         
         for x in 0..CGA_COLUMNS{
             self.show(x, CGA_ROWS-1, ' ', CGA_STD_ATTR);
         }
-        self.setpos(0, CGA_ROWS-1);
+        self.move_output(0, CGA_ROWS-1);
+        self.refresh_soft_cursor();
+        scroll_beep();
+    }
+
+    /// Scroll text lines by `lines` to the top (clamped to the screen height) in one bulk copy.
+    /// The exposed rows at the bottom are blanked and the cursor is moved to the first of them.
+    /// The selection moves along with the text.
+    pub fn scrollup_n(&mut self, lines: usize) {
+        let lines = lines.min(CGA_ROWS);
+        if lines == 0 {
+            return;
+        }
+        self.hide_soft_cursor();
+
+        let kept_cells = (CGA_ROWS - lines) * CGA_COLUMNS;
+        // Unsafe because we are copying directly within video memory.
+        // Source and destination are within the bounds of the screen (they may overlap).
+        unsafe {
+            core::ptr::copy(CGA_BASE_ADDR.add(lines * CGA_COLUMNS * 2), CGA_BASE_ADDR, kept_cells * 2);
+        }
+        if self.buffered {
+            self.shadow.copy_within(lines * CGA_COLUMNS.., 0);
+        }
+        self.scroll_selection(lines);
+
+        for y in CGA_ROWS - lines..CGA_ROWS {
+            for x in 0..CGA_COLUMNS {
+                self.show(x, y, ' ', CGA_STD_ATTR);
+            }
+        }
+        self.move_output(0, CGA_ROWS - lines);
+        self.refresh_soft_cursor();
+        scroll_beep();
+    }
+
+    /// Print each character as block of 2x2 cells (large text for presentations) or normally.
+    /// The output position still addresses cells: with magnification, `print_byte()`
+    /// advances it by two columns and two rows, which halves the text area to 40x12 characters.
+    pub fn set_magnify(&mut self, enabled: bool) {
+        self.magnify = enabled;
+    }
+
+    /// Check if characters are printed as blocks of 2x2 cells (see `set_magnify()`).
+    pub fn is_magnified(&self) -> bool {
+        self.magnify
+    }
+
+    /// Show the software mouse cursor at position `x`,`y` by inverting the attribute of the cell there.
+    /// If the cursor is already shown elsewhere, the cell it covered is restored first.
+    /// Positions outside the screen are ignored.
+    pub fn set_mouse_cursor(&mut self, x: usize, y: usize) {
+        let Some(cell) = self.read_cell(x, y) else {
+            return;
+        };
+        match self.mouse_cursor {
+            Some(cursor) if (cursor.x, cursor.y) == (x, y) => return,
+            Some(_) => self.hide_mouse_cursor(),
+            None => {}
+        }
+
+        self.show(x, y, cell.0 as char, invert_attribute(cell.1));
+        self.mouse_cursor = Some(InvertedCell { x, y, saved: cell });
+    }
+
+    /// Remove the software mouse cursor and restore the cell it covered.
+    /// If the cell has been overwritten by other output meanwhile, it is left unchanged.
+    pub fn hide_mouse_cursor(&mut self) {
+        if let Some(cursor) = self.mouse_cursor.take() {
+            self.restore_inverted(cursor);
+        }
+    }
+
+    /// Return the position of the software mouse cursor, if it is shown.
+    pub fn mouse_cursor(&self) -> Option<(usize, usize)> {
+        self.mouse_cursor.map(|cursor| (cursor.x, cursor.y))
+    }
+
+    /// Replace the hardware cursor by a software cursor, which inverts the attribute of the
+    /// cell at the cursor position, or switch back to the hardware cursor. The real attribute
+    /// of the cell is restored whenever the cursor moves away. The software cursor is always
+    /// visible, even with the hardware cursor shape lost (e.g. after graphics mode).
+    /// Blinking is done by `cga::set_software_cursor()`; the blink bit of the cell is kept.
+    pub fn set_software_cursor(&mut self, enabled: bool) {
+        if enabled == self.software_cursor {
+            return;
+        }
+        self.software_cursor = enabled;
+        if enabled {
+            self.disable_cursor();
+            self.refresh_soft_cursor();
+        } else {
+            self.hide_soft_cursor();
+            self.enable_cursor();
+        }
+    }
+
+    /// Check if the software cursor replaces the hardware cursor (see `set_software_cursor()`).
+    pub fn is_software_cursor(&self) -> bool {
+        self.software_cursor
+    }
+
+    /// Draw the software cursor at the cursor position, if it is enabled.
+    /// A cursor drawn elsewhere is removed first.
+    fn refresh_soft_cursor(&mut self) {
+        if !self.software_cursor {
+            return;
+        }
+        self.hide_soft_cursor();
+        let (x, y) = self.getpos();
+        self.soft_cursor = self.draw_inverted(x, y);
+    }
+
+    /// Remove the software cursor and restore the cell it covered (see `hide_mouse_cursor()`).
+    fn hide_soft_cursor(&mut self) {
+        if let Some(cursor) = self.soft_cursor.take() {
+            self.restore_inverted(cursor);
+        }
+    }
+
+    /// Show or hide the software cursor, used for blinking.
+    fn toggle_soft_cursor(&mut self) {
+        if self.soft_cursor.is_some() {
+            self.hide_soft_cursor();
+        } else {
+            self.refresh_soft_cursor();
+        }
+    }
+
+    /// Invert the attribute of the cell at `x`,`y` and return what is needed to restore it.
+    fn draw_inverted(&mut self, x: usize, y: usize) -> Option<InvertedCell> {
+        let cell = self.read_cell(x, y)?;
+        self.show(x, y, cell.0 as char, invert_attribute(cell.1));
+        Some(InvertedCell { x, y, saved: cell })
+    }
+
+    /// Restore a cell inverted by `draw_inverted()`, unless it has been overwritten meanwhile.
+    fn restore_inverted(&mut self, cursor: InvertedCell) {
+        let drawn = (cursor.saved.0, invert_attribute(cursor.saved.1));
+        if self.read_cell(cursor.x, cursor.y) == Some(drawn) {
+            self.show(cursor.x, cursor.y, cursor.saved.0 as char, cursor.saved.1);
+        }
+    }
+
+    /// Mark the cells from `start_x`,`start_y` to `end_x`,`end_y` (both included) as selected,
+    /// like selected text: on multiple rows, the selection runs to the end of the first row and
+    /// starts at the beginning of the last one. Start and end may be given in any order and are
+    /// clamped to the screen. The characters are left untouched; the original attributes
+    /// are saved for `clear_selection()`. A previous selection is cleared first.
+    pub fn set_selection(&mut self, start_x: usize, start_y: usize, end_x: usize, end_y: usize) {
+        self.clear_selection();
+
+        let index = |x: usize, y: usize| y.min(CGA_ROWS - 1) * CGA_COLUMNS + x.min(CGA_COLUMNS - 1);
+        let (a, b) = (index(start_x, start_y), index(end_x, end_y));
+        let (start, end) = (a.min(b), a.max(b));
+
+        let mut selection = Selection { start, attribs: [0; CGA_ROWS * CGA_COLUMNS], len: 0, highlight: self.selection_attr };
+        for i in start..=end {
+            let Some((_, attrib)) = self.read_cell(i % CGA_COLUMNS, i / CGA_COLUMNS) else {
+                break;
+            };
+            selection.attribs[selection.len] = attrib;
+            selection.len += 1;
+            self.write_attr(i, selected_attribute(self.selection_attr, attrib));
+        }
+        self.selection = Some(selection);
+    }
+
+    /// Remove the selection and restore the original attributes of the selected cells.
+    /// Cells which have been written meanwhile (their attribute is not the selected one)
+    /// are left unchanged.
+    pub fn clear_selection(&mut self) {
+        let Some(selection) = self.selection.take() else {
+            return;
+        };
+        for (i, &attrib) in selection.attribs().iter().enumerate() {
+            let index = selection.start + i;
+            let current = self.read_cell(index % CGA_COLUMNS, index / CGA_COLUMNS);
+            if current.map(|(_, attr)| attr) == Some(selected_attribute(selection.highlight, attrib)) {
+                self.write_attr(index, attrib);
+            }
+        }
+    }
+
+    /// Return the number of selected cells (0 without a selection).
+    pub fn selection_len(&self) -> usize {
+        self.selection.as_ref().map_or(0, |selection| selection.len)
+    }
+
+    /// Move the selection up by `lines` rows along with the scrolled text.
+    /// Cells scrolled off the top are dropped from it.
+    fn scroll_selection(&mut self, lines: usize) {
+        let Some(selection) = &mut self.selection else {
+            return;
+        };
+        let shift = lines * CGA_COLUMNS;
+        let dropped = shift.saturating_sub(selection.start).min(selection.len);
+        if dropped == selection.len {
+            self.selection = None;
+            return;
+        }
+        selection.attribs.copy_within(dropped..selection.len, 0);
+        selection.len -= dropped;
+        selection.start = selection.start.saturating_sub(shift);
+    }
+
+    /// Show selected cells with attribute `attrib`, or with their attribute inverted
+    /// (the default) if `None`. Takes effect with the next `set_selection()`.
+    pub fn set_selection_attribute(&mut self, attrib: Option<u8>) {
+        self.selection_attr = attrib;
     }
 
-    /// Helper function returning an attribute byte for the given parameters `bg`, `fg`, and `blink`
+    /// Helper function returning an attribute byte for the given parameters `bg`, `fg`, and `blink`.
+    /// Bit 7 of the attribute depends on the blink mode (see `set_blink_enabled()`):
+    /// with blinking enabled, it is the blink bit and only the 8 dark background colors are available.
+    /// Otherwise, it is the intensity bit of the background, so all 16 colors can be used
+    /// as background and `blink` is ignored.
     pub fn attribute(&mut self, bg: Color, fg: Color, blink: bool) -> u8 {
         /* Hier muss Code eingefuegt werden */
+        if !self.blink_enabled {
+            return (bg as u8 & 0xf) << 4 | (fg as u8 & 0xf);
+        }
+
         let blink_bit = (blink as u8) << 7;
         
         let attr = ((bg as u8 & 0x7) << 4 | (fg as u8 & 0xf) ) | blink_bit;
         
         attr
     }
-}
\ No newline at end of file
+
+    /// Select whether bit 7 of the attribute makes the character blink (the default after boot)
+    /// or selects a bright background color. Programs the mode control register of the attribute controller.
+    pub fn set_blink_enabled(&mut self, enabled: bool) {
+        unsafe {
+            // Reading the input status register resets the attribute controller to index mode
+            self.input_status_port.inb();
+            self.attr_port.outb(CGA_ATTR_MODE_CONTROL | CGA_ATTR_PALETTE_ENABLE);
+            let mut mode = self.attr_read_port.inb();
+            if enabled {
+                mode |= CGA_ATTR_BLINK_BIT;
+            } else {
+                mode &= !CGA_ATTR_BLINK_BIT;
+            }
+            self.attr_port.outb(mode);
+        }
+        self.blink_enabled = enabled;
+    }
+
+    /// Check if bit 7 of the attribute makes characters blink (see `set_blink_enabled()`).
+    pub fn is_blink_enabled(&self) -> bool {
+        self.blink_enabled
+    }
+
+    /// Set the color of the screen border (overscan).
+    /// The overscan color register (index 0x11 of the attribute controller) holds an index
+    /// into the DAC, not a text color, so the DAC index of `color` is taken from its palette
+    /// register (indices 0x00-0x0f).
+    pub fn set_border_color(&mut self, color: Color) {
+        unsafe {
+            // Each access starts with reading the input status register, which resets the
+            // index/data flip-flop of the attribute controller to index mode.
+            self.input_status_port.inb();
+            self.attr_port.outb(color as u8 | CGA_ATTR_PALETTE_ENABLE);
+            let dac_index = self.attr_read_port.inb();
+
+            self.input_status_port.inb();
+            self.attr_port.outb(CGA_ATTR_OVERSCAN | CGA_ATTR_PALETTE_ENABLE);
+            self.attr_port.outb(dac_index);
+        }
+    }
+}
+
+/// Swap the foreground and background color of `attrib`, keeping the
+/// intensity and blink bits in place, so the result is valid in both blink modes.
+pub fn invert_attribute(attrib: u8) -> u8 {
+    (attrib & 0x88) | (attrib & 0x07) << 4 | (attrib >> 4) & 0x07
+}
+
+/// Return the attribute of a selected cell with the original attribute `attrib`:
+/// `highlight`, or the inverted attribute if `None` (see `CGA::set_selection_attribute()`).
+fn selected_attribute(highlight: Option<u8>, attrib: u8) -> u8 {
+    highlight.unwrap_or_else(|| invert_attribute(attrib))
+}
+
+/// Show the software mouse cursor at position `x`,`y` (see `CGA::set_mouse_cursor()`).
+pub fn set_mouse_cursor(x: usize, y: usize) {
+    // The mouse driver moves the cursor from a soft interrupt, so interrupts must be disabled.
+    cpu::without_interrupts(|| CGA.lock().set_mouse_cursor(x, y));
+}
+
+/// Remove the software mouse cursor (see `CGA::hide_mouse_cursor()`).
+pub fn hide_mouse_cursor() {
+    cpu::without_interrupts(|| CGA.lock().hide_mouse_cursor());
+}
+
+/// Blink interval of the software cursor in milliseconds.
+const SOFT_CURSOR_BLINK_MS: u64 = 500;
+
+/// Set while a blink of the software cursor is scheduled as alarm.
+static SOFT_CURSOR_BLINK_SCHEDULED: AtomicBool = AtomicBool::new(false);
+
+/// Enable or disable the software cursor (see `CGA::set_software_cursor()`).
+/// While enabled, it blinks driven by a timer alarm.
+pub fn set_software_cursor(enabled: bool) {
+    // The cursor blinks in a soft interrupt, so interrupts must be disabled.
+    cpu::without_interrupts(|| CGA.lock().set_software_cursor(enabled));
+    if enabled && !SOFT_CURSOR_BLINK_SCHEDULED.swap(true, Ordering::Relaxed) {
+        schedule_soft_cursor_blink();
+    }
+}
+
+/// Schedule the next blink of the software cursor.
+fn schedule_soft_cursor_blink() {
+    if timer::set_alarm(SOFT_CURSOR_BLINK_MS, blink_soft_cursor).is_err() {
+        SOFT_CURSOR_BLINK_SCHEDULED.store(false, Ordering::Relaxed);
+        kprintln!("cga: no free alarm, the software cursor does not blink");
+    }
+}
+
+/// Show or hide the software cursor and schedule the next blink, as long as it is enabled.
+fn blink_soft_cursor() {
+    // The interrupted code may be using the screen. Then the cursor blinks on the next alarm.
+    if let Some(mut cga) = CGA.try_lock() {
+        if !cga.software_cursor {
+            SOFT_CURSOR_BLINK_SCHEDULED.store(false, Ordering::Relaxed);
+            return;
+        }
+        cga.toggle_soft_cursor();
+    }
+    schedule_soft_cursor_blink();
+}
+
+/// Select the cells from `start_x`,`start_y` to `end_x`,`end_y` (see `CGA::set_selection()`).
+pub fn set_selection(start_x: usize, start_y: usize, end_x: usize, end_y: usize) {
+    CGA.lock().set_selection(start_x, start_y, end_x, end_y);
+}
+
+/// Remove the selection and restore the attributes of the selected cells (see `CGA::clear_selection()`).
+pub fn clear_selection() {
+    CGA.lock().clear_selection();
+}
+
+/// Enable or disable large text (see `CGA::set_magnify()`).
+pub fn set_magnify(enabled: bool) {
+    CGA.lock().set_magnify(enabled);
+}
+
+/// Draw `title` centered in the first row of the screen using the status style of the active theme
+/// and move the cursor to `CGA_BANNER_CURSOR_POS`.
+/// Titles longer than one row are truncated.
+pub fn banner(title: &str) {
+    let attrib = theme::attribute(Style::Status);
+    let mut cga = CGA.lock();
+    cga.draw_banner(title, attrib);
+    cga.setpos(CGA_BANNER_CURSOR_POS.0, CGA_BANNER_CURSOR_POS.1);
+}
+
+/// A step of the value of `input_number()`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum NumberStep {
+    Up,
+    Down,
+}
+
+/// Return `value` incremented or decremented by one (see `input_number()`), limited to `min..=max`.
+pub fn step_number(value: usize, step: NumberStep, min: usize, max: usize) -> usize {
+    let value = match step {
+        NumberStep::Up => value.saturating_add(1),
+        NumberStep::Down => value.saturating_sub(1),
+    };
+    value.max(min).min(max)
+}
+
+/// Print `prompt` and let the user choose a number between `min` and `max`, starting with
+/// `default`. Up and Down increment and decrement the value, which is redrawn in place behind
+/// the prompt; Enter confirms it and Escape returns `default`. Both end the line.
+/// `default` is limited to the range as well.
+pub fn input_number(prompt: &str, min: usize, max: usize, default: usize) -> usize {
+    let default = default.max(min).min(max);
+    print!("{}", prompt);
+    console::flush();
+
+    let (x, y) = CGA.lock().getpos();
+    let width = max.checked_ilog10().unwrap_or(0) as usize + 1;
+    let attrib = theme::attribute(Style::Highlight);
+    let mut value = default;
+
+    let result = loop {
+        draw_number(x, y, width, value, attrib);
+
+        let mut key = keyboard::get_key_buffer().wait_for_key();
+        if !key.valid() {
+            continue;
+        }
+        match (key.get_ascii(), key.get_scancode()) {
+            (b'\r', _) => break value,
+            (_, key::SCAN_ESC) => break default,
+            (_, key::SCAN_UP) => value = step_number(value, NumberStep::Up, min, max),
+            (_, key::SCAN_DOWN) => value = step_number(value, NumberStep::Down, min, max),
+            _ => {}
+        }
+    };
+
+    draw_number(x, y, width, result, attrib);
+    CGA.lock().setpos(x + width, y);
+    println!("");
+    result
+}
+
+/// Draw `value` right-aligned in a field of `width` cells at `x`,`y`.
+fn draw_number(x: usize, y: usize, width: usize, value: usize, attrib: u8) {
+    let mut cga = CGA.lock();
+    let mut rest = value;
+    for col in (x..x + width).rev() {
+        let digit = if rest > 0 || col == x + width - 1 { b'0' + (rest % 10) as u8 } else { b' ' };
+        cga.show(col, y, digit as char, attrib);
+        rest /= 10;
+    }
+}
+
+/// Return the number of columns and rows of the screen.
+pub const fn screen_size() -> (usize, usize) {
+    (CGA_COLUMNS, CGA_ROWS)
+}
+
+/// Return the column where a title of `len` characters starts, if it is centered.
+/// Titles longer than one row start at column 0.
+pub fn banner_column(len: usize) -> usize {
+    (CGA_COLUMNS - len.min(CGA_COLUMNS)) / 2
+}
+
+/// Character sets for drawing lines and boxes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum LineCharset {
+    /// Box drawing characters of code page 437 (the CGA character set)
+    Cp437 = 0,
+    /// `+`, `-` and `|`, legible on any terminal (e.g. over serial)
+    Ascii = 1,
+}
+
+/// The characters of a line charset.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct LineChars {
+    pub horizontal: u8,
+    pub vertical: u8,
+    pub top_left: u8,
+    pub top_right: u8,
+    pub bottom_left: u8,
+    pub bottom_right: u8,
+}
+
+impl LineCharset {
+    /// Return the characters of this charset.
+    pub const fn chars(self) -> LineChars {
+        match self {
+            LineCharset::Cp437 => LineChars {
+                horizontal: 0xc4,
+                vertical: 0xb3,
+                top_left: 0xda,
+                top_right: 0xbf,
+                bottom_left: 0xc0,
+                bottom_right: 0xd9,
+            },
+            LineCharset::Ascii => LineChars {
+                horizontal: b'-',
+                vertical: b'|',
+                top_left: b'+',
+                top_right: b'+',
+                bottom_left: b'+',
+                bottom_right: b'+',
+            },
+        }
+    }
+}
+
+/// Charset used by the line drawing helpers.
+static LINE_CHARSET: AtomicU8 = AtomicU8::new(LineCharset::Cp437 as u8);
+
+/// Select the charset used by `CGA::draw_box()` and `print_hline()`.
+/// `console::set()` selects it automatically: CP437 for the CGA, ASCII for other consoles.
+pub fn set_line_charset(charset: LineCharset) {
+    LINE_CHARSET.store(charset as u8, Ordering::Relaxed);
+}
+
+/// Return the charset used by the line drawing helpers.
+pub fn line_charset() -> LineCharset {
+    match LINE_CHARSET.load(Ordering::Relaxed) {
+        0 => LineCharset::Cp437,
+        _ => LineCharset::Ascii,
+    }
+}
+
+/// Print a horizontal line of `width` characters and a newline to the current console.
+/// The line characters are written as raw bytes, because CP437 characters are not valid UTF-8.
+pub fn print_hline(width: usize) {
+    let horizontal = line_charset().chars().horizontal;
+    // Keep the order with buffered output of the print macros.
+    console::flush();
+    let out = console::current();
+    for _ in 0..width {
+        out.write_byte(horizontal);
+    }
+    out.write_byte(b'\n');
+}
+
+/// Frequency and duration of the scroll beep (see `set_scroll_beep()`).
+const SCROLL_BEEP_FREQ: usize = 200;
+const SCROLL_BEEP_MS: u64 = 1;
+
+/// If set, the speaker ticks whenever the screen scrolls.
+static SCROLL_BEEP: AtomicBool = AtomicBool::new(false);
+
+/// Number of scroll beeps requested since boot (see `scroll_beeps()`).
+static SCROLL_BEEPS: AtomicUsize = AtomicUsize::new(0);
+
+/// Enable or disable a very short, low tick of the speaker whenever the screen scrolls,
+/// as audible feedback that output has been pushed off the screen. Scrolling is never
+/// blocked: the tick is skipped if the speaker is busy (e.g. music is playing or the
+/// speaker itself is printing), see `pcspk::click()`. The timer must be plugged in.
+pub fn set_scroll_beep(enabled: bool) {
+    SCROLL_BEEP.store(enabled, Ordering::Relaxed);
+}
+
+/// Check if the speaker ticks when the screen scrolls.
+pub fn scroll_beep_enabled() -> bool {
+    SCROLL_BEEP.load(Ordering::Relaxed)
+}
+
+/// Return the number of scroll beeps requested since boot, including skipped ones.
+pub fn scroll_beeps() -> usize {
+    SCROLL_BEEPS.load(Ordering::Relaxed)
+}
+
+/// Tick the speaker after scrolling, if enabled.
+fn scroll_beep() {
+    if SCROLL_BEEP.load(Ordering::Relaxed) {
+        SCROLL_BEEPS.fetch_add(1, Ordering::Relaxed);
+        pcspk::click(SCROLL_BEEP_FREQ, SCROLL_BEEP_MS);
+    }
+}
+
+/// Default mapping of non-ASCII characters to code page 437 (the CGA character set).
+const DEFAULT_GLYPHS: [(char, u8); 48] = [
+    // German umlauts
+    ('ä', 0x84), ('ö', 0x94), ('ü', 0x81), ('Ä', 0x8e), ('Ö', 0x99), ('Ü', 0x9a), ('ß', 0xe1),
+    // box drawing
+    ('─', 0xc4), ('│', 0xb3), ('┌', 0xda), ('┐', 0xbf), ('└', 0xc0), ('┘', 0xd9),
+    ('├', 0xc3), ('┤', 0xb4), ('┬', 0xc2), ('┴', 0xc1), ('┼', 0xc5),
+    ('═', 0xcd), ('║', 0xba), ('╔', 0xc9), ('╗', 0xbb), ('╚', 0xc8), ('╝', 0xbc),
+    // shades and blocks
+    ('░', 0xb0), ('▒', 0xb1), ('▓', 0xb2), ('█', 0xdb), ('▀', 0xdf), ('▄', 0xdc), ('■', 0xfe),
+    // card suits and symbols
+    ('♥', 0x03), ('♦', 0x04), ('♣', 0x05), ('♠', 0x06), ('♪', 0x0d), ('☺', 0x01), ('→', 0x1a), ('←', 0x1b),
+    // math
+    ('±', 0xf1), ('≥', 0xf2), ('≤', 0xf3), ('÷', 0xf6), ('≈', 0xf7), ('°', 0xf8), ('√', 0xfb), ('²', 0xfd), ('π', 0xe3),
+];
+
+/// Maximum number of glyphs set by `set_codepage_glyph()`.
+const MAX_GLYPH_OVERRIDES: usize = 16;
+
+/// Glyphs set by `set_codepage_glyph()`, taking precedence over `DEFAULT_GLYPHS`.
+static GLYPH_OVERRIDES: Mutex<ArrayVec<(char, u8), MAX_GLYPH_OVERRIDES>> = Mutex::new(ArrayVec::new());
+
+/// Errors that can occur when setting a glyph of the code page table.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CodepageError {
+    /// All `MAX_GLYPH_OVERRIDES` entries are in use.
+    TableFull,
+}
+
+/// Print the character `logical` as CP437 byte `cp437` on the CGA screen (see `glyph()`).
+/// This overrides the default mapping and can also be used for ASCII characters.
+/// Setting a glyph again replaces the previous mapping.
+pub fn set_codepage_glyph(logical: char, cp437: u8) -> Result<(), CodepageError> {
+    // The print macros look up glyphs, so they must not interrupt us while the table is locked.
+    cpu::without_interrupts(|| {
+        let mut overrides = GLYPH_OVERRIDES.lock();
+        if let Some(entry) = overrides.iter_mut().find(|(c, _)| *c == logical) {
+            entry.1 = cp437;
+            return Ok(());
+        }
+        overrides.push((logical, cp437)).map_err(|_| CodepageError::TableFull)
+    })
+}
+
+/// Remove all glyphs set by `set_codepage_glyph()`, so only the default mapping is used.
+pub fn reset_codepage_glyphs() {
+    cpu::without_interrupts(|| GLYPH_OVERRIDES.lock().clear());
+}
+
+/// Return the CP437 byte the character `logical` is printed as, if it is mapped
+/// by `set_codepage_glyph()` or by default (umlauts, box drawing, shades, card suits and
+/// math symbols). ASCII characters are printed as they are unless they are overridden.
+pub fn glyph(logical: char) -> Option<u8> {
+    if let Some(&(_, cp437)) = GLYPH_OVERRIDES.lock().iter().find(|(c, _)| *c == logical) {
+        return Some(cp437);
+    }
+    DEFAULT_GLYPHS.iter().find(|(c, _)| *c == logical).map(|&(_, cp437)| cp437)
+}
+
+/// A rectangular region of the screen with its own cursor.
+/// Text written to a pane is wrapped at its right border and the pane scrolls
+/// within its rectangle, so several panes can be used side by side.
+pub struct Pane {
+    x: usize,
+    y: usize,
+    w: usize,
+    h: usize,
+    cursor_x: usize,
+    cursor_y: usize,
+    attrib: u8,
+}
+
+impl Pane {
+    /// Create a pane of `w` x `h` cells at `x`,`y`, clipped to the screen.
+    /// The pane is not cleared (see `clear()`).
+    pub fn new(x: usize, y: usize, w: usize, h: usize) -> Pane {
+        let x = x.min(CGA_COLUMNS);
+        let y = y.min(CGA_ROWS);
+        Pane {
+            x,
+            y,
+            w: w.min(CGA_COLUMNS - x),
+            h: h.min(CGA_ROWS - y),
+            cursor_x: 0,
+            cursor_y: 0,
+            attrib: CGA_STD_ATTR,
+        }
+    }
+
+    /// Set the attribute used for the following output.
+    pub fn set_attribute(&mut self, attrib: u8) {
+        self.attrib = attrib;
+    }
+
+    /// Return the cursor position relative to the top left corner of the pane.
+    pub fn getpos(&self) -> (usize, usize) {
+        (self.cursor_x, self.cursor_y)
+    }
+
+    /// Clear the pane and move its cursor to the top left corner.
+    pub fn clear(&mut self) {
+        CGA.lock().clear_region(self.x, self.y, self.w, self.h, self.attrib);
+        self.cursor_x = 0;
+        self.cursor_y = 0;
+    }
+
+    /// Print `s` at the cursor position of the pane.
+    pub fn print(&mut self, s: &str) {
+        if self.w == 0 || self.h == 0 {
+            return;
+        }
+
+        let mut cga = CGA.lock();
+        for b in s.bytes() {
+            if b == b'\n' {
+                self.newline(&mut cga);
+                continue;
+            }
+            if self.cursor_x >= self.w {
+                self.newline(&mut cga);
+            }
+            cga.show(self.x + self.cursor_x, self.y + self.cursor_y, b as char, self.attrib);
+            self.cursor_x += 1;
+        }
+    }
+
+    /// Print `s` followed by a newline.
+    pub fn println(&mut self, s: &str) {
+        self.print(s);
+        self.print("\n");
+    }
+
+    /// Move the cursor to the beginning of the next line, scrolling the pane if necessary.
+    fn newline(&mut self, cga: &mut CGA) {
+        self.cursor_x = 0;
+        if self.cursor_y + 1 < self.h {
+            self.cursor_y += 1;
+        } else {
+            self.scrollup(cga);
+        }
+    }
+
+    /// Shift the rows of the pane up by one and clear its last row.
+    fn scrollup(&mut self, cga: &mut CGA) {
+        for row in self.y..self.y + self.h - 1 {
+            for col in self.x..self.x + self.w {
+                if let Some((character, attrib)) = cga.read_cell(col, row + 1) {
+                    cga.show(col, row, character as char, attrib);
+                }
+            }
+        }
+        cga.clear_region(self.x, self.y + self.h - 1, self.w, 1, self.attrib);
+    }
+}
+
+impl fmt::Write for Pane {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.print(s);
+        Ok(())
+    }
+}
+
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Cell animations.                                                        ║
+   ╚═════════════════════════════════════════════════════════════════════════╝ */
+
+/// Maximum number of concurrently running animations.
+const MAX_ANIMATIONS: usize = 4;
+
+/// Interval between two updates of the running animations in milliseconds.
+const ANIMATION_TICK_MS: u64 = 10;
+
+/// The running animations, updated by a timer alarm.
+static ANIMATIONS: Mutex<ArrayVec<Running, MAX_ANIMATIONS>> = Mutex::new(ArrayVec::new());
+
+/// Set while an update of the animations is scheduled as alarm.
+static ANIMATIONS_SCHEDULED: AtomicBool = AtomicBool::new(false);
+
+/// One frame of an animation: `glyph` is shown with attribute `attrib` at position `x`,`y`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub x: usize,
+    pub y: usize,
+    pub glyph: u8,
+    pub attrib: u8,
+}
+
+/// A sequence of frames shown in a loop, each for `interval_ms` milliseconds.
+#[derive(Copy, Clone, Debug)]
+pub struct Animation {
+    pub frames: &'static [Frame],
+    pub interval_ms: u64,
+}
+
+/// Errors that can occur when starting an animation.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AnimationError {
+    /// The animation has no frames.
+    NoFrames,
+    /// `MAX_ANIMATIONS` animations are already running.
+    TooManyAnimations,
+}
+
+/// State of a running animation.
+struct Running {
+    anim: Animation,
+    /// Index of the frame on the screen (None before the first frame is drawn)
+    frame: Option<usize>,
+    /// Uptime in milliseconds when the next frame is due
+    next_ms: u64,
+    /// Character and attribute of the cell covered by the frame on the screen
+    saved: (u8, u8),
+    /// Number of key presses (see `keyboard::key_presses()`) when the animation was started
+    key_presses: usize,
+}
+
+impl Running {
+    /// Advance to the next frame if it is due at `now_ms` and return its index.
+    /// The first frame is due immediately; after the last frame, the first one follows again.
+    fn advance(&mut self, now_ms: u64) -> Option<usize> {
+        if now_ms < self.next_ms {
+            return None;
+        }
+        let next = match self.frame {
+            Some(frame) => (frame + 1) % self.anim.frames.len(),
+            None => 0,
+        };
+        self.frame = Some(next);
+        self.next_ms = now_ms + self.anim.interval_ms;
+        Some(next)
+    }
+
+    /// Draw the next frame if it is due, restoring the cell covered by the previous frame.
+    fn step(&mut self, cga: &mut CGA, now_ms: u64) {
+        let previous = self.frame;
+        let Some(next) = self.advance(now_ms) else {
+            return;
+        };
+
+        if let Some(previous) = previous {
+            self.restore(cga, previous);
+        }
+        let frame = self.anim.frames[next];
+        self.saved = cga.read_cell(frame.x, frame.y).unwrap_or((b' ', CGA_STD_ATTR));
+        cga.show(frame.x, frame.y, frame.glyph as char, frame.attrib);
+    }
+
+    /// Restore the cell covered by frame `index`.
+    fn restore(&self, cga: &mut CGA, index: usize) {
+        let frame = self.anim.frames[index];
+        cga.show(frame.x, frame.y, self.saved.0 as char, self.saved.1);
+    }
+
+    /// Remove the animation from the screen.
+    fn erase(&self, cga: &mut CGA) {
+        if let Some(frame) = self.frame {
+            self.restore(cga, frame);
+        }
+    }
+}
+
+/// Start `anim`. It runs concurrently to the caller (driven by timer alarms, so the timer
+/// must be plugged in) and to other animations, until a key is pressed or `stop_animations()`
+/// is called. When it stops, the cell under the last frame is restored.
+pub fn animate(anim: Animation) -> Result<(), AnimationError> {
+    if anim.frames.is_empty() {
+        return Err(AnimationError::NoFrames);
+    }
+
+    let running = Running {
+        anim,
+        frame: None,
+        next_ms: timer::uptime_ms(),
+        saved: (b' ', CGA_STD_ATTR),
+        key_presses: keyboard::key_presses(),
+    };
+
+    // The animations are updated by a soft interrupt, so interrupts must be disabled.
+    cpu::without_interrupts(|| ANIMATIONS.lock().push(running))
+        .map_err(|_| AnimationError::TooManyAnimations)?;
+
+    if !ANIMATIONS_SCHEDULED.swap(true, Ordering::Relaxed) {
+        schedule_animations();
+    }
+    Ok(())
+}
+
+/// Stop all running animations and restore the cells under their frames.
+pub fn stop_animations() {
+    cpu::without_interrupts(|| {
+        let mut animations = ANIMATIONS.lock();
+        let mut cga = CGA.lock();
+        for running in animations.iter() {
+            running.erase(&mut cga);
+        }
+        animations.clear();
+    });
+}
+
+/// Schedule the next update of the animations.
+fn schedule_animations() {
+    if timer::set_alarm(ANIMATION_TICK_MS, update_animations).is_err() {
+        ANIMATIONS_SCHEDULED.store(false, Ordering::Relaxed);
+        kprintln!("animations: no free alarm, animations are stopped");
+    }
+}
+
+/// Draw the due frames of all animations, stop the animations which have seen a key press
+/// and schedule the next update, if animations are left.
+fn update_animations() {
+    let mut animations = ANIMATIONS.lock();
+
+    // The interrupted code may be using the screen. Then the frames are drawn on the next update.
+    if let Some(mut cga) = CGA.try_lock() {
+        let now = timer::uptime_ms();
+        let presses = keyboard::key_presses();
+        for running in animations.iter_mut() {
+            if running.key_presses != presses {
+                running.erase(&mut cga);
+            } else {
+                running.step(&mut cga, now);
+            }
+        }
+        animations.retain(|running| running.key_presses == presses);
+    }
+
+    if animations.is_empty() {
+        ANIMATIONS_SCHEDULED.store(false, Ordering::Relaxed);
+    } else {
+        drop(animations);
+        schedule_animations();
+    }
+}
+
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Self tests, run by the diagnostics (see `user::diag`).                  ║
+   ╚═════════════════════════════════════════════════════════════════════════╝ */
+
+pub mod selftest {
+    use alloc::vec::Vec;
+    use core::fmt::Write;
+    use crate::devices::cga::{self, Color, NumberStep, OverflowPolicy};
+    use crate::devices::cga_print;
+    use crate::library::selftest::{restore_on_exit, Test, TestResult};
+
+    /// The self tests of this module, run in this order.
+    pub const TESTS: &[Test] = &[
+        ("Cursor follow", test_cursor_follow),
+        ("Code page", test_codepage),
+        ("Mouse cursor", test_mouse_cursor),
+        ("Software cursor", test_software_cursor),
+        ("Selection", test_selection),
+        ("Selection scroll", test_selection_scroll),
+        ("Number entry", test_number_entry),
+        ("Overflow policy", test_overflow_policy),
+        ("Magnified text", test_magnify),
+        ("Scroll beep", test_scroll_beep),
+        ("Buffered writes", test_buffered_writes),
+        ("Banner centering", test_banner_column),
+        ("Bright background", test_bright_background),
+        ("Scroll up 5 lines", test_scrollup_n),
+        ("Clear with", test_clear_with),
+        ("Row attribute", test_set_row_attr),
+        ("Read cell", test_read_cell),
+        ("Pane wrap", test_pane_wrap),
+        ("Animation frames", test_animation_frames),
+        ("Line charset", test_line_charset),
+    ];
+
+    /// Run `test` on the locked screen and restore the screen contents, the cursor
+    /// and the output modes afterwards, whatever the test returns.
+    fn on_scratch_screen(test: impl FnOnce(&mut cga::CGA) -> TestResult) -> TestResult {
+        let mut cga = cga::CGA.lock();
+        let state = cga.save_state();
+        let screen = cga.save_screen();
+        let software_cursor = cga.is_software_cursor();
+
+        let result = test(&mut cga);
+
+        cga.hide_mouse_cursor();
+        cga.clear_selection();
+        cga.set_software_cursor(false);
+        cga.restore_screen(&screen);
+        cga.restore_state(&state);
+        cga.set_software_cursor(software_cursor);
+        result
+    }
+
+    /// Print with the cursor not following the output and check that the hardware cursor stays
+    /// in place, then check that it jumps to the end of the output when following again.
+    fn test_cursor_follow() -> TestResult {
+        on_scratch_screen(|cga| {
+            let parked = cga.getpos();
+            cga.set_cursor_follow(false);
+            for b in b"..." {
+                cga.print_byte(*b, Color::Black, Color::LightGray, false);
+            }
+            if cga.getpos() != parked {
+                return Err("cursor moved while not following");
+            }
+            cga.set_cursor_follow(true);
+            if cga.getpos() != (parked.0 + 3, parked.1) {
+                return Err("cursor not restored to the output position");
+            }
+            Ok(())
+        })
+    }
+
+    /// Override the glyph of a character, print it on the CGA screen
+    /// and check that the mapped CP437 byte has been written.
+    fn test_codepage() -> TestResult {
+        const GLYPH: u8 = 0x0e; // two eighth notes
+
+        cga::set_codepage_glyph('♫', GLYPH).map_err(|_| "code page table full")?;
+        let (x, y) = cga::CGA.lock().getpos();
+        cga_print::WRITER.lock().write_str("♫").ok();
+        cga::reset_codepage_glyphs();
+
+        match cga::CGA.lock().read_cell(x, y) {
+            Some((GLYPH, _)) => Ok(()),
+            _ => Err("glyph not mapped"),
+        }
+    }
+
+    /// Move the mouse cursor between two cells and check that the
+    /// vacated cell is restored and the new one inverted.
+    fn test_mouse_cursor() -> TestResult {
+        on_scratch_screen(|cga| {
+            let (x, y) = cga.getpos();
+            let x = x.min(cga::screen_size().0 - 2);
+            let first = cga.read_cell(x, y).ok_or("cursor outside the screen")?;
+            let second = cga.read_cell(x + 1, y);
+
+            cga.set_mouse_cursor(x, y);
+            if cga.read_cell(x, y) == Some(first) {
+                return Err("cell not inverted");
+            }
+            cga.set_mouse_cursor(x + 1, y);
+            if cga.read_cell(x, y) != Some(first) {
+                return Err("vacated cell not restored");
+            }
+            if cga.read_cell(x + 1, y) == second {
+                return Err("cursor not moved");
+            }
+            cga.hide_mouse_cursor();
+            if cga.read_cell(x + 1, y) != second {
+                return Err("cursor not hidden");
+            }
+            Ok(())
+        })
+    }
+
+    /// Enable the software cursor and move it by one cell, checking that the
+    /// cell under it is inverted and restored with its real attribute on move.
+    fn test_software_cursor() -> TestResult {
+        const FIRST: (u8, u8) = (b'A', 0x1e);
+        const SECOND: (u8, u8) = (b'B', 0x2f);
+
+        on_scratch_screen(|cga| {
+            let (x, y) = cga.getpos();
+            let x = x.min(cga::screen_size().0 - 2);
+            cga.set_software_cursor(false);
+            cga.show(x, y, FIRST.0 as char, FIRST.1);
+            cga.show(x + 1, y, SECOND.0 as char, SECOND.1);
+
+            cga.setpos(x, y);
+            cga.set_software_cursor(true);
+            if cga.read_cell(x, y) != Some((FIRST.0, cga::invert_attribute(FIRST.1))) {
+                return Err("cell not inverted");
+            }
+            cga.setpos(x + 1, y);
+            if cga.read_cell(x, y) != Some(FIRST) {
+                return Err("vacated cell not restored");
+            }
+            if cga.read_cell(x + 1, y) != Some((SECOND.0, cga::invert_attribute(SECOND.1))) {
+                return Err("cursor not moved");
+            }
+            cga.set_software_cursor(false);
+            if cga.read_cell(x + 1, y) != Some(SECOND) {
+                return Err("cell not restored when disabled");
+            }
+            Ok(())
+        })
+    }
+
+    /// Select cells across a row boundary (given end first) and check that only their attributes
+    /// change, then clear the selection and check that the original attributes are restored exactly.
+    fn test_selection() -> TestResult {
+        const ROW: usize = 10;
+        const CELLS: usize = 6;
+
+        on_scratch_screen(|cga| {
+            let (columns, _) = cga::screen_size();
+            let first = ROW * columns + columns - CELLS / 2;
+            let pos = |index: usize| (index % columns, index / columns);
+            let cells = |cga: &cga::CGA| -> Vec<_> {
+                (first - 1..=first + CELLS).map(|i| cga.read_cell(pos(i).0, pos(i).1)).collect()
+            };
+
+            // The cells before and after the selection are written too, to check they stay unchanged.
+            for i in first - 1..=first + CELLS {
+                let (x, y) = pos(i);
+                cga.show(x, y, (b'a' + (i % 26) as u8) as char, (i % 0x80) as u8);
+            }
+            let before = cells(cga);
+
+            let (start, end) = (pos(first), pos(first + CELLS - 1));
+            cga.set_selection(end.0, end.1, start.0, start.1);
+            if cga.selection_len() != CELLS {
+                return Err("wrong number of cells selected");
+            }
+            let expected = before.iter().enumerate().map(|(i, cell)| match (i, cell) {
+                (1..=CELLS, Some((c, attrib))) => Some((*c, cga::invert_attribute(*attrib))),
+                _ => *cell,
+            });
+            if !cells(cga).into_iter().eq(expected) {
+                return Err("selected cells not inverted or others changed");
+            }
+            cga.clear_selection();
+            if cells(cga) != before {
+                return Err("attributes not restored");
+            }
+            Ok(())
+        })
+    }
+
+    /// Select cells across the boundary of the first two rows and scroll by one line: the cells
+    /// of the first row must be dropped from the selection and the others must move up with the
+    /// text, so that clearing the selection restores exactly their attributes. Scrolling the
+    /// whole screen must drop the selection.
+    fn test_selection_scroll() -> TestResult {
+        const ATTR: u8 = 0x1e;
+        const CELLS: usize = 10;
+
+        on_scratch_screen(|cga| {
+            let (columns, rows) = cga::screen_size();
+            for y in 0..3 {
+                for x in 0..columns {
+                    cga.show(x, y, 'x', ATTR);
+                }
+            }
+            let attribs = |cga: &cga::CGA, y: usize, xs: core::ops::Range<usize>| -> Vec<u8> {
+                xs.filter_map(|x| cga.read_cell(x, y).map(|(_, attrib)| attrib)).collect()
+            };
+
+            cga.set_selection(columns - CELLS, 0, CELLS - 1, 1);
+            cga.scrollup();
+            if cga.selection_len() != CELLS {
+                return Err("scrolled off cells not dropped");
+            }
+            if attribs(cga, 0, 0..CELLS).iter().any(|&attrib| attrib != cga::invert_attribute(ATTR)) {
+                return Err("selection did not move with the text");
+            }
+            cga.clear_selection();
+            if attribs(cga, 0, 0..columns).iter().any(|&attrib| attrib != ATTR) {
+                return Err("attributes not restored after scrolling");
+            }
+
+            cga.set_selection(0, 0, columns - 1, 1);
+            cga.scrollup_n(rows);
+            if cga.selection_len() != 0 {
+                return Err("selection kept after scrolling the whole screen");
+            }
+            Ok(())
+        })
+    }
+
+    /// Step the value of the number entry up and down, including at the limits of its range.
+    fn test_number_entry() -> TestResult {
+        const CASES: [(usize, NumberStep, usize); 6] = [
+            (5, NumberStep::Up, 6),
+            (5, NumberStep::Down, 4),
+            (10, NumberStep::Up, 10),
+            (1, NumberStep::Down, 1),
+            (0, NumberStep::Up, 1),
+            (20, NumberStep::Down, 10),
+        ];
+
+        for (value, step, expected) in CASES {
+            if cga::step_number(value, step, 1, 10) != expected {
+                return Err("wrong value");
+            }
+        }
+        if cga::step_number(0, NumberStep::Down, 0, 10) != 0 {
+            return Err("value wrapped below 0");
+        }
+        Ok(())
+    }
+
+    /// Print at the bottom right cell with each overflow policy and check the resulting
+    /// screen and cursor.
+    fn test_overflow_policy() -> TestResult {
+        let (columns, rows) = cga::screen_size();
+        let (last_x, last_y) = (columns - 1, rows - 1);
+        let print_at_last_cell = |cga: &mut cga::CGA, policy, bytes: &[u8]| {
+            cga.set_overflow_policy(policy);
+            cga.setpos(last_x, last_y);
+            for b in bytes {
+                cga.print_byte(*b, Color::Black, Color::LightGray, false);
+            }
+        };
+
+        on_scratch_screen(|cga| {
+            print_at_last_cell(cga, OverflowPolicy::Scroll, b"S");
+            if cga.getpos() != (0, last_y) || cga.read_cell(last_x, last_y - 1).map(|c| c.0) != Some(b'S') {
+                return Err("scroll: line not scrolled up");
+            }
+            print_at_last_cell(cga, OverflowPolicy::WrapToTop, b"W");
+            if cga.getpos() != (0, 0) || cga.read_cell(last_x, last_y).map(|c| c.0) != Some(b' ') {
+                return Err("wrap: screen not cleared");
+            }
+            print_at_last_cell(cga, OverflowPolicy::Stop, b"XY");
+            if cga.getpos() != (last_x, last_y) || cga.read_cell(last_x, last_y).map(|c| c.0) != Some(b'X') {
+                return Err("stop: output not stopped");
+            }
+            Ok(())
+        })
+    }
+
+    /// Print one character with magnification and check that it fills a block of 2x2 cells
+    /// and the output position advances by two columns.
+    fn test_magnify() -> TestResult {
+        const X: usize = 10;
+        const Y: usize = 10;
+
+        on_scratch_screen(|cga| {
+            cga.clear_region(X, Y, 3, 2, cga::CGA_STD_ATTR);
+            cga.set_cursor_follow(true);
+            cga.setpos(X, Y);
+            cga.set_magnify(true);
+            cga.print_byte(b'M', Color::Black, Color::LightGray, false);
+            cga.set_magnify(false);
+
+            let block = [(X, Y), (X + 1, Y), (X, Y + 1), (X + 1, Y + 1)];
+            if block.iter().any(|&(x, y)| cga.read_cell(x, y).map(|c| c.0) != Some(b'M')) {
+                return Err("block not filled");
+            }
+            if cga.read_cell(X + 2, Y).map(|c| c.0) == Some(b'M') {
+                return Err("block too wide");
+            }
+            if cga.getpos() != (X + 2, Y) {
+                return Err("cursor not advanced by two columns");
+            }
+            Ok(())
+        })
+    }
+
+    /// Scroll the screen with the scroll beep enabled and check that the beep was requested.
+    fn test_scroll_beep() -> TestResult {
+        let beeps = cga::scroll_beeps();
+        let enabled = cga::scroll_beep_enabled();
+        let _restore = restore_on_exit(|| cga::set_scroll_beep(enabled));
+        cga::set_scroll_beep(true);
+        on_scratch_screen(|cga| {
+            cga.scrollup();
+            Ok(())
+        })?;
+
+        if cga::scroll_beeps() != beeps + 1 {
+            return Err("no beep on scroll");
+        }
+        Ok(())
+    }
+
+    /// Show the same cell twice in buffered mode and check that only the first call
+    /// writes to video memory, then check that a changed attribute is written again.
+    fn test_buffered_writes() -> TestResult {
+        on_scratch_screen(|cga| {
+            let buffered = cga.is_buffered();
+            cga.set_buffered(true);
+
+            cga.show(0, 0, 'B', cga::CGA_STD_ATTR);
+            let writes = cga.video_writes();
+            cga.show(0, 0, 'B', cga::CGA_STD_ATTR);
+            let repeated = cga.video_writes();
+            let attrib = cga.attribute(Color::Black, Color::Green, false);
+            cga.show(0, 0, 'B', attrib);
+            let changed = cga.video_writes();
+            cga.set_buffered(buffered);
+
+            if repeated != writes {
+                return Err("unchanged cell written");
+            }
+            if changed != writes + 1 {
+                return Err("changed cell not written");
+            }
+            Ok(())
+        })
+    }
+
+    /// Compute the start column of banners of different lengths: short titles are centered
+    /// (rounding to the left for odd margins), titles of a full row or longer start at column 0.
+    fn test_banner_column() -> TestResult {
+        let columns = cga::screen_size().0;
+
+        if cga::banner_column(0) != columns / 2 || cga::banner_column(10) != (columns - 10) / 2 {
+            return Err("title not centered");
+        }
+        if cga::banner_column(11) != (columns - 12) / 2 {
+            return Err("odd title not centered");
+        }
+        if cga::banner_column(columns) != 0 || cga::banner_column(columns + 20) != 0 {
+            return Err("long title not truncated");
+        }
+        Ok(())
+    }
+
+    /// Build attributes with a bright background in both blink modes: with blinking enabled,
+    /// bit 7 must be the blink flag and the background dimmed, otherwise it must be the
+    /// intensity bit of the background, regardless of the blink flag.
+    fn test_bright_background() -> TestResult {
+        on_scratch_screen(|cga| {
+            cga.set_blink_enabled(true);
+            let dimmed = cga.attribute(Color::LightBlue, Color::White, false);
+            let blinking = cga.attribute(Color::LightBlue, Color::White, true);
+            cga.set_blink_enabled(false);
+            let bright = cga.attribute(Color::LightBlue, Color::White, false);
+            let bright_blink = cga.attribute(Color::LightBlue, Color::White, true);
+
+            if dimmed != 0x1f || blinking != 0x9f {
+                return Err("wrong attribute in blink mode");
+            }
+            if bright != 0x9f || bright_blink != 0x9f {
+                return Err("wrong attribute in intensity mode");
+            }
+            Ok(())
+        })
+    }
+
+    /// Fill each row with its own letter, scroll up by 5 lines and check that the rows
+    /// have moved up by 5 and the 5 rows at the bottom are blank.
+    fn test_scrollup_n() -> TestResult {
+        const LINES: usize = 5;
+
+        on_scratch_screen(|cga| {
+            let (columns, rows) = cga::screen_size();
+            let letter = |y: usize| b'A' + y as u8;
+            for y in 0..rows {
+                for x in 0..columns {
+                    cga.show(x, y, letter(y) as char, cga::CGA_STD_ATTR);
+                }
+            }
+            cga.scrollup_n(LINES);
+
+            for y in 0..rows - LINES {
+                if (0..columns).any(|x| cga.read_cell(x, y) != Some((letter(y + LINES), cga::CGA_STD_ATTR))) {
+                    return Err("content not shifted");
+                }
+            }
+            for y in rows - LINES..rows {
+                if (0..columns).any(|x| cga.read_cell(x, y) != Some((b' ', cga::CGA_STD_ATTR))) {
+                    return Err("exposed row not blank");
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Fill the screen with a character and attribute and check every cell and that the
+    /// cursor is in the top left corner.
+    fn test_clear_with() -> TestResult {
+        const FILL: u8 = b'#';
+        const ATTR: u8 = 0x1e;
+
+        on_scratch_screen(|cga| {
+            let (columns, rows) = cga::screen_size();
+            cga.set_cursor_follow(true);
+            cga.clear_with(FILL, ATTR);
+
+            for y in 0..rows {
+                if (0..columns).any(|x| cga.read_cell(x, y) != Some((FILL, ATTR))) {
+                    return Err("cell not filled");
+                }
+            }
+            if cga.getpos() != (0, 0) {
+                return Err("cursor not in the top left corner");
+            }
+            Ok(())
+        })
+    }
+
+    /// Fill each row with its own letter, set the attribute of one row and check that its
+    /// characters are unchanged, every attribute matches and the other rows are untouched.
+    /// Setting the attribute of a row below the screen must change nothing.
+    fn test_set_row_attr() -> TestResult {
+        const ROW: usize = 7;
+        const ATTR: u8 = 0x4e;
+
+        on_scratch_screen(|cga| {
+            let (columns, rows) = cga::screen_size();
+            let letter = |y: usize| b'A' + y as u8;
+            for y in 0..rows {
+                for x in 0..columns {
+                    cga.show(x, y, letter(y) as char, cga::CGA_STD_ATTR);
+                }
+            }
+            let row_is = |cga: &cga::CGA, y: usize, attr: u8| {
+                (0..columns).all(|x| cga.read_cell(x, y) == Some((letter(y), attr)))
+            };
+
+            cga.set_row_attr(ROW, ATTR);
+            if !row_is(cga, ROW, ATTR) {
+                return Err("row attribute not set");
+            }
+            if (0..rows).any(|y| y != ROW && !row_is(cga, y, cga::CGA_STD_ATTR)) {
+                return Err("other row changed");
+            }
+
+            cga.set_row_attr(rows, ATTR);
+            if (0..rows).any(|y| !row_is(cga, y, if y == ROW { ATTR } else { cga::CGA_STD_ATTR })) {
+                return Err("out-of-range row changed the screen");
+            }
+            Ok(())
+        })
+    }
+
+    /// Show characters in the corners of the screen and read them back, then read cells
+    /// just outside the screen, which must return `None`.
+    fn test_read_cell() -> TestResult {
+        const ATTR: u8 = 0x2f;
+
+        on_scratch_screen(|cga| {
+            let (columns, rows) = cga::screen_size();
+            let corners = [(0, 0, b'a'), (columns - 1, 0, b'b'), (0, rows - 1, b'c'), (columns - 1, rows - 1, b'd')];
+            for (x, y, ch) in corners {
+                cga.show(x, y, ch as char, ATTR);
+            }
+            if corners.iter().any(|&(x, y, ch)| cga.read_cell(x, y) != Some((ch, ATTR))) {
+                return Err("written cell not read back");
+            }
+
+            if cga.read_cell(columns, 0).is_some() || cga.read_cell(0, rows).is_some() {
+                return Err("cell outside the screen read");
+            }
+            Ok(())
+        })
+    }
+
+    /// Print past the width and height of a small pane on a screen filled with dots: the text
+    /// must wrap at the right border of the pane, the pane must scroll within its rows and
+    /// the cells around the pane must stay untouched.
+    fn test_pane_wrap() -> TestResult {
+        const X: usize = 10;
+        const Y: usize = 5;
+        const W: usize = 4;
+        const H: usize = 2;
+        const EXPECTED: [&[u8]; H] = [b"efgh", b"ijkl"];
+
+        // A pane locks the screen for each output, so it cannot run inside `on_scratch_screen()`.
+        let (screen, state) = {
+            let mut cga = cga::CGA.lock();
+            (cga.save_screen(), cga.save_state())
+        };
+        let _restore = restore_on_exit(|| {
+            let mut cga = cga::CGA.lock();
+            cga.restore_screen(&screen);
+            cga.restore_state(&state);
+        });
+        cga::CGA.lock().clear_with(b'.', cga::CGA_STD_ATTR);
+
+        let mut pane = cga::Pane::new(X, Y, W, H);
+        pane.clear();
+        pane.print("abcdefghijkl");
+        if pane.getpos() != (W, H - 1) {
+            return Err("wrong cursor position in the pane");
+        }
+
+        let cga = cga::CGA.lock();
+        let (columns, rows) = cga::screen_size();
+        for y in 0..rows {
+            for x in 0..columns {
+                let expected = if (X..X + W).contains(&x) && (Y..Y + H).contains(&y) {
+                    EXPECTED[y - Y][x - X]
+                } else {
+                    b'.'
+                };
+                if cga.read_cell(x, y).map(|(ch, _)| ch) != Some(expected) {
+                    return Err("text not wrapped and scrolled inside the pane");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Drive an animation of two frames with synthetic times: a frame must only advance
+    /// once its interval has elapsed, the cell of the previous frame must be restored, the
+    /// frames must loop and stopping must restore the covered cell.
+    fn test_animation_frames() -> TestResult {
+        const INTERVAL_MS: u64 = 10;
+        const ATTR: u8 = 0x0e;
+        const FRAMES: [cga::Frame; 2] = [
+            cga::Frame { x: 3, y: 2, glyph: b'|', attrib: ATTR },
+            cga::Frame { x: 4, y: 2, glyph: b'/', attrib: ATTR },
+        ];
+        // Synthetic time of each step and the expected cells under the two frames afterwards.
+        const STEPS: [(u64, [u8; 2]); 4] = [(0, *b"|b"), (5, *b"|b"), (10, *b"a/"), (20, *b"|b")];
+
+        on_scratch_screen(|cga| {
+            let cells = |cga: &cga::CGA| FRAMES.map(|frame| cga.read_cell(frame.x, frame.y).map(|(ch, _)| ch));
+            cga.show(FRAMES[0].x, FRAMES[0].y, 'a', cga::CGA_STD_ATTR);
+            cga.show(FRAMES[1].x, FRAMES[1].y, 'b', cga::CGA_STD_ATTR);
+
+            let mut running = cga::Running {
+                anim: cga::Animation { frames: &FRAMES, interval_ms: INTERVAL_MS },
+                frame: None,
+                next_ms: 0,
+                saved: (b' ', cga::CGA_STD_ATTR),
+                key_presses: 0,
+            };
+            for (now, expected) in STEPS {
+                running.step(cga, now);
+                if cells(cga) != expected.map(Some) {
+                    kprintln!("animation: wrong cells at {}ms", now);
+                    return Err("frame not advanced as expected");
+                }
+            }
+
+            running.erase(cga);
+            if cells(cga) != [Some(b'a'), Some(b'b')] {
+                return Err("covered cell not restored on stop");
+            }
+            Ok(())
+        })
+    }
+
+    /// Draw a box with each line charset and check the corners and edges read from the screen:
+    /// `+`, `-` and `|` for ASCII, the single line box characters for CP437.
+    fn test_line_charset() -> TestResult {
+        const X: usize = 2;
+        const Y: usize = 3;
+        const W: usize = 4;
+        const H: usize = 3;
+        // Expected top left, top right, bottom left, bottom right, horizontal and vertical char.
+        const CASES: [(cga::LineCharset, [u8; 6]); 2] = [
+            (cga::LineCharset::Ascii, *b"++++-|"),
+            (cga::LineCharset::Cp437, [0xda, 0xbf, 0xc0, 0xd9, 0xc4, 0xb3]),
+        ];
+        const CELLS: [(usize, usize); 6] = [(X, Y), (X + W - 1, Y), (X, Y + H - 1), (X + W - 1, Y + H - 1), (X + 1, Y), (X, Y + 1)];
+
+        let charset = cga::line_charset();
+        let _restore = restore_on_exit(|| cga::set_line_charset(charset));
+        on_scratch_screen(|cga| {
+            for (charset, expected) in CASES {
+                cga::set_line_charset(charset);
+                cga.draw_box(X, Y, W, H, cga::CGA_STD_ATTR);
+                for ((x, y), ch) in CELLS.into_iter().zip(expected) {
+                    if cga.read_cell(x, y) != Some((ch, cga::CGA_STD_ATTR)) {
+                        kprintln!("line charset: wrong char at {},{} with {:?}", x, y, charset);
+                        return Err("wrong box character");
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+}
@@ -9,16 +9,21 @@
 */
 use core::fmt;
 use core::fmt::Write;
-use spin::Mutex;
 use crate::devices::cga;
 use crate::devices::cga::Color;
+use crate::devices::console::{self, Console};
+use crate::library::IrqSafeMutex;
 
 /// The global writer that can used as an interface from other modules.
-/// It is threadsafe by using 'Mutex'.
-pub static WRITER: Mutex<Writer> = Mutex::new(Writer::new());
+/// It is threadsafe by using 'IrqSafeMutex', as soft interrupts print too.
+/// It is always locked before `cga::CGA`, never while holding it.
+pub static WRITER: IrqSafeMutex<Writer> = IrqSafeMutex::new(Writer::new());
 
 /// Writer for writing formatted strings to the CGA screen
-pub struct Writer {}
+pub struct Writer {
+    /// Number of characters printed in each foreground color since boot
+    color_stats: [usize; 16],
+}
 
 pub static mut BG_COLOR: Color = Color::Black;
 pub static mut FG_COLOR: Color = Color::White;
@@ -26,27 +31,109 @@ pub static mut FG_COLOR: Color = Color::White;
 impl Writer {
     /// Create a new Writer object.
     pub const fn new() -> Writer {
-        Writer {}
+        Writer { color_stats: [0; 16] }
+    }
+
+    /// Return the number of characters printed by this writer in each foreground color.
+    pub fn color_stats(&self) -> [usize; 16] {
+        self.color_stats
     }
 }
 
+/// State of the CGA output saved by `save_state()`: the colors of the print macros,
+/// the cursor and output modes of the CGA (see `cga::CgaState`) and the global CGA settings.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct WriterState {
+    fg: Color,
+    bg: Color,
+    cga: cga::CgaState,
+    line_charset: cga::LineCharset,
+    scroll_beep: bool,
+}
+
+/// Save the complete state of the CGA output, e.g. before showing a dialog,
+/// so it can be restored in one call by `restore_state()` afterwards.
+/// The screen contents are saved separately (see `cga::CGA::save_screen()`).
+pub fn save_state() -> WriterState {
+    WriterState {
+        fg: unsafe { FG_COLOR },
+        bg: unsafe { BG_COLOR },
+        cga: cga::CGA.lock().save_state(),
+        line_charset: cga::line_charset(),
+        scroll_beep: cga::scroll_beep_enabled(),
+    }
+}
+
+/// Restore the state of the CGA output saved by `save_state()`. To restore the screen as well,
+/// restore its contents first, so the cursor ends up where it was when the state was saved.
+pub fn restore_state(state: &WriterState) {
+    unsafe {
+        FG_COLOR = state.fg;
+        BG_COLOR = state.bg;
+    }
+    cga::CGA.lock().restore_state(&state.cga);
+    cga::set_line_charset(state.line_charset);
+    cga::set_scroll_beep(state.scroll_beep);
+}
+
+/// Return the number of characters printed in each foreground color since boot.
+/// The array is indexed by the foreground color (e.g. `Color::White as usize`).
+pub fn color_stats() -> [usize; 16] {
+    WRITER.lock().color_stats()
+}
+
 /// Implementation of the 'core::fmt::Write' trait for our Writer.
 /// Required to output formatted strings.
 /// Requires only one function 'write_str'.
 impl Write for Writer {
     fn write_str(&mut self, s: &str) -> fmt::Result {
         let mut cga = cga::CGA.lock();
-        for byte in s.bytes() {
-            match byte {
-                // printable ASCII byte or newline
-                0x20..=0x7e | b'\n' => unsafe{cga.print_byte(byte, BG_COLOR, FG_COLOR, false)},
+        for c in s.chars() {
+            self.put_char(&mut cga, c);
+        }
 
-                // not part of printable ASCII range
-                _ => unsafe{cga.print_byte(0xfe, BG_COLOR, FG_COLOR, false)},
+        Ok(())
+    }
+}
+
+impl Writer {
+    /// Print a character with the global colors, mapped to CP437 by the code page table (see `cga::glyph()`).
+    fn put_char(&mut self, cga: &mut cga::CGA, c: char) {
+        match cga::glyph(c) {
+            Some(cp437) => {
+                self.color_stats[unsafe { FG_COLOR } as usize] += 1;
+                unsafe { cga.print_byte(cp437, BG_COLOR, FG_COLOR, false) }
             }
+            None if c.is_ascii() => self.put_byte(cga, c as u8),
+            // not in the code page table
+            None => self.put_byte(cga, 0xfe),
         }
+    }
 
-        Ok(())
+    /// Print a single byte with the global colors.
+    fn put_byte(&mut self, cga: &mut cga::CGA, byte: u8) {
+        if byte != b'\n' {
+            self.color_stats[unsafe { FG_COLOR } as usize] += 1;
+        }
+        match byte {
+            // printable ASCII byte, newline or CP437 line drawing character (see `cga::LineCharset`)
+            0x20..=0x7e | b'\n' | 0xb3..=0xda => unsafe{cga.print_byte(byte, BG_COLOR, FG_COLOR, false)},
+
+            // not part of printable ASCII range
+            _ => unsafe{cga.print_byte(0xfe, BG_COLOR, FG_COLOR, false)},
+        }
+    }
+}
+
+/// Allow using the CGA writer as console (this is the default console, see `console`).
+impl Console for IrqSafeMutex<Writer> {
+    fn write_byte(&self, byte: u8) {
+        let mut writer = self.lock();
+        writer.put_byte(&mut cga::CGA.lock(), byte);
+    }
+
+    fn write_str(&self, s: &str) {
+        self.lock().write_str(s).ok();
     }
 }
 
@@ -66,7 +153,83 @@ macro_rules! println {
 }
 
 /// Helper function of print macros (must be public)
+/// Writes to the current console (see `console::set`).
 pub fn print(args: fmt::Arguments) {
-    WRITER.lock().write_fmt(args).unwrap();
+    console::print(args);
 }
 
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Self tests, run by the diagnostics (see `user::diag`).                  ║
+   ╚═════════════════════════════════════════════════════════════════════════╝ */
+
+pub mod selftest {
+    use core::fmt::Write;
+    use crate::devices::cga::{self, Color, LineCharset, OverflowPolicy};
+    use crate::devices::cga_print::{self, Writer};
+    use crate::devices::theme::{self, Style};
+    use crate::library::selftest::{Test, TestResult};
+
+    /// The self tests of this module, run in this order.
+    pub const TESTS: &[Test] = &[
+        ("Writer state", test_writer_state),
+        ("Color statistics", test_color_stats),
+    ];
+
+    /// Modify colors, cursor and output modes like a dialog would, restore the saved
+    /// writer state and the screen and check that both are as before.
+    fn test_writer_state() -> TestResult {
+        let screen = cga::CGA.lock().save_screen();
+        let saved = cga_print::save_state();
+
+        theme::apply(Style::Error);
+        cga::set_line_charset(LineCharset::Ascii);
+        cga::set_scroll_beep(true);
+        {
+            let mut cga = cga::CGA.lock();
+            cga.set_overflow_policy(OverflowPolicy::Stop);
+            cga.setpos(0, 0);
+            cga.set_cursor_follow(false);
+        }
+        print!("dialog");
+        let modified = cga_print::save_state();
+
+        cga::CGA.lock().restore_screen(&screen);
+        cga_print::restore_state(&saved);
+
+        if modified == saved {
+            return Err("state not modified");
+        }
+        if cga_print::save_state() != saved {
+            return Err("state not restored");
+        }
+        Ok(())
+    }
+
+    /// Print green and then white characters with a fresh writer and check that
+    /// they are counted in the statistics of their foreground colors.
+    fn test_color_stats() -> TestResult {
+        const GREEN: &str = "gggg";
+        const WHITE: &str = "www";
+
+        let screen = cga::CGA.lock().save_screen();
+        let saved = cga_print::save_state();
+        let mut writer = Writer::new();
+
+        unsafe { cga_print::FG_COLOR = Color::Green };
+        writer.write_str(GREEN).ok();
+        unsafe { cga_print::FG_COLOR = Color::White };
+        writer.write_str(WHITE).ok();
+        let stats = writer.color_stats();
+
+        cga::CGA.lock().restore_screen(&screen);
+        cga_print::restore_state(&saved);
+
+        if stats[Color::Green as usize] != GREEN.len() || stats[Color::White as usize] != WHITE.len() {
+            return Err("wrong count per color");
+        }
+        if stats.iter().sum::<usize>() != GREEN.len() + WHITE.len() {
+            return Err("characters counted in other colors");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,420 @@
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Module: console                                                         ║
+   ╟─────────────────────────────────────────────────────────────────────────╢
+   ║ Descr.: Output sinks for the print macros. 'print!'/'println!' write to ║
+   ║         the current console (CGA by default), 'kprint!'/'kprintln!' to  ║
+   ║         the debug console (COM1 by default). Both can be redirected to  ║
+   ║         any type implementing 'Console'.                                ║
+   ╚═════════════════════════════════════════════════════════════════════════╝
+*/
+use core::fmt;
+use core::ptr;
+use core::str;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use crate::devices::cga::{self, LineCharset};
+use crate::devices::cga_print;
+use crate::devices::serial;
+use crate::kernel::log;
+use crate::library::IrqSafeMutex;
+
+/// An output sink for the print macros.
+/// Implementations lock their device internally, so they can be shared as `&'static dyn Console`.
+pub trait Console: Sync {
+    /// Write a single byte.
+    fn write_byte(&self, byte: u8);
+
+    /// Write a string. The default implementation writes it byte by byte.
+    fn write_str(&self, s: &str) {
+        for byte in s.bytes() {
+            self.write_byte(byte);
+        }
+    }
+
+    /// Wait until all output buffered by the device has been written.
+    /// The default implementation does nothing (for unbuffered devices).
+    fn sync(&self) {}
+}
+
+/// Console used by `print!` and `println!`.
+static CONSOLE: Mutex<&'static dyn Console> = Mutex::new(&cga_print::WRITER);
+
+/// Console used by `kprint!` and `kprintln!`.
+static DEBUG_CONSOLE: Mutex<&'static dyn Console> = Mutex::new(&serial::COM1);
+
+/// Size of the line buffers; longer lines are written in several parts.
+const LINE_BUFFER_SIZE: usize = 256;
+
+/// If set, the output of the print macros is collected in line buffers (see `set_line_buffered()`).
+static LINE_BUFFERED: AtomicBool = AtomicBool::new(false);
+
+/// Line buffers of `print!` and `kprint!`. Soft interrupts print too,
+/// so interrupts are disabled while a buffer is locked.
+static PRINT_BUFFER: IrqSafeMutex<LineBuffer> = IrqSafeMutex::new(LineBuffer::new());
+static KPRINT_BUFFER: IrqSafeMutex<LineBuffer> = IrqSafeMutex::new(LineBuffer::new());
+
+/// Set at the beginning of a line of `print!` (only tracked while the rate limit is active).
+static AT_LINE_START: AtomicBool = AtomicBool::new(true);
+
+/// Set while the current line of `print!` is dropped by the rate limit (see `log::set_rate_limit()`).
+static DROPPING_LINE: AtomicBool = AtomicBool::new(false);
+
+/// Redirect `print!` and `println!` to `console`.
+/// Buffered output is written to the previous console first.
+/// The line charset is switched to CP437 for the CGA and to ASCII for any other console.
+pub fn set(console: &'static dyn Console) {
+    PRINT_BUFFER.lock().flush(current());
+    *CONSOLE.lock() = console;
+
+    let on_cga = ptr::addr_eq(console as *const dyn Console, &cga_print::WRITER as *const _);
+    cga::set_line_charset(if on_cga { LineCharset::Cp437 } else { LineCharset::Ascii });
+}
+
+/// Return the console currently used by `print!` and `println!`.
+pub fn current() -> &'static dyn Console {
+    *CONSOLE.lock()
+}
+
+/// Redirect `kprint!` and `kprintln!` to `console`.
+/// Buffered output is written to the previous console first.
+pub fn set_debug(console: &'static dyn Console) {
+    KPRINT_BUFFER.lock().flush(debug());
+    *DEBUG_CONSOLE.lock() = console;
+}
+
+/// Return the console currently used by `kprint!` and `kprintln!`.
+pub fn debug() -> &'static dyn Console {
+    *DEBUG_CONSOLE.lock()
+}
+
+/// Check if one of the locks used by the print macros is held. Printing from a soft interrupt
+/// (which runs on the stack of the interrupted code) would deadlock then, as the interrupted
+/// code cannot release the lock before the soft interrupt has returned.
+pub fn is_busy() -> bool {
+    CONSOLE.is_locked() || DEBUG_CONSOLE.is_locked() || PRINT_BUFFER.is_locked() || KPRINT_BUFFER.is_locked()
+        || cga_print::WRITER.is_locked() || cga::CGA.is_locked() || serial::COM1.is_locked()
+}
+
+/// Adapter implementing `fmt::Write` for a console, required to output formatted strings.
+struct ConsoleWriter(&'static dyn Console);
+
+impl fmt::Write for ConsoleWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.write_str(s);
+        Ok(())
+    }
+}
+
+/// Write formatted output to `console`.
+/// The console selection lock is not held while writing, so a console may print itself.
+pub fn write_fmt(console: &'static dyn Console, args: fmt::Arguments) {
+    fmt::Write::write_fmt(&mut ConsoleWriter(console), args).unwrap();
+}
+
+/// Size of the buffer of a capture console; further output is dropped.
+const CAPTURE_SIZE: usize = 512;
+
+/// A console collecting its output in memory instead of showing it,
+/// e.g. to check the output of the print macros in tests.
+pub struct Capture {
+    captured: IrqSafeMutex<Captured>,
+}
+
+/// Output collected by a capture console (see `Capture::take()`).
+#[derive(Copy, Clone)]
+pub struct Captured {
+    bytes: [u8; CAPTURE_SIZE],
+    len: usize,
+}
+
+impl Capture {
+    /// Create an empty capture console.
+    pub const fn new() -> Capture {
+        Capture { captured: IrqSafeMutex::new(Captured { bytes: [0; CAPTURE_SIZE], len: 0 }) }
+    }
+
+    /// Return the output collected so far and empty the capture.
+    pub fn take(&self) -> Captured {
+        let mut captured = self.captured.lock();
+        let output = *captured;
+        captured.len = 0;
+        output
+    }
+
+    /// Return the number of bytes collected so far.
+    pub fn len(&self) -> usize {
+        self.captured.lock().len
+    }
+}
+
+impl Captured {
+    /// Return the collected bytes.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+impl Console for Capture {
+    fn write_byte(&self, byte: u8) {
+        let mut captured = self.captured.lock();
+        if captured.len < CAPTURE_SIZE {
+            let len = captured.len;
+            captured.bytes[len] = byte;
+            captured.len += 1;
+        }
+    }
+}
+
+/// Enable or disable line buffering of the print macros.
+/// When enabled, output is collected and written to the console as a whole line on each
+/// newline (or when the buffer is full or `flush()` is called), instead of byte by byte.
+/// When disabled, buffered output is written immediately.
+pub fn set_line_buffered(enabled: bool) {
+    LINE_BUFFERED.store(enabled, Ordering::Relaxed);
+    if !enabled {
+        flush();
+    }
+}
+
+/// Check if the output of the print macros is line buffered (see `set_line_buffered()`).
+pub fn is_line_buffered() -> bool {
+    LINE_BUFFERED.load(Ordering::Relaxed)
+}
+
+/// Write the buffered output of the print macros to the consoles.
+/// Buffers which are currently locked are skipped, so this is safe to call from the panic handler.
+pub fn flush() {
+    if let Some(mut buffer) = PRINT_BUFFER.try_lock() {
+        buffer.flush(current());
+    }
+    if let Some(mut buffer) = KPRINT_BUFFER.try_lock() {
+        buffer.flush(debug());
+    }
+}
+
+/// Write all buffered output synchronously: the line buffers of the print macros are flushed
+/// and the consoles are synced (e.g. the serial transmitter is drained). Call this before
+/// halting the CPU, so no output is lost. Does nothing if there is no buffered output and,
+/// like `flush()`, skips locked buffers, so it is safe to call from the panic handler.
+pub fn flush_all() {
+    flush();
+    current().sync();
+    debug().sync();
+}
+
+/// Helper function of `print!` (see `cga_print::print()`).
+/// With a rate limit (see `log::set_rate_limit()`), lines beyond the limit are dropped.
+pub fn print(args: fmt::Arguments) {
+    let limited = log::rate_limit() != 0;
+    if LINE_BUFFERED.load(Ordering::Relaxed) {
+        let mut buffer = PRINT_BUFFER.lock();
+        let mut out = BufferedWriter { buffer: &mut buffer, console: current() };
+        if limited {
+            fmt::Write::write_fmt(&mut RateLimitedWriter(&mut out), args).unwrap();
+        } else {
+            fmt::Write::write_fmt(&mut out, args).unwrap();
+        }
+    } else if limited {
+        fmt::Write::write_fmt(&mut RateLimitedWriter(&mut ConsoleWriter(current())), args).unwrap();
+    } else {
+        write_fmt(current(), args);
+    }
+}
+
+/// Helper function of `kprint!` (see `kprint::kprint()`).
+pub fn kprint(args: fmt::Arguments) {
+    if LINE_BUFFERED.load(Ordering::Relaxed) {
+        let mut buffer = KPRINT_BUFFER.lock();
+        fmt::Write::write_fmt(&mut BufferedWriter { buffer: &mut buffer, console: debug() }, args).unwrap();
+    } else {
+        write_fmt(debug(), args);
+    }
+}
+
+/// Output collected until the end of a line.
+struct LineBuffer {
+    bytes: [u8; LINE_BUFFER_SIZE],
+    len: usize,
+}
+
+impl LineBuffer {
+    const fn new() -> LineBuffer {
+        LineBuffer { bytes: [0; LINE_BUFFER_SIZE], len: 0 }
+    }
+
+    /// Append `s`, writing the buffer to `console` at the end of each line or if it is full.
+    fn write(&mut self, console: &'static dyn Console, s: &str) {
+        for b in s.bytes() {
+            self.bytes[self.len] = b;
+            self.len += 1;
+            if b == b'\n' || self.len == LINE_BUFFER_SIZE {
+                self.flush(console);
+            }
+        }
+    }
+
+    /// Write the buffered output to `console` and empty the buffer.
+    fn flush(&mut self, console: &'static dyn Console) {
+        let bytes = &self.bytes[..self.len];
+
+        // A full buffer may end within a multi byte character, which is written byte by byte.
+        match str::from_utf8(bytes) {
+            Ok(s) => console.write_str(s),
+            Err(e) => {
+                let (valid, rest) = bytes.split_at(e.valid_up_to());
+                console.write_str(unsafe { str::from_utf8_unchecked(valid) });
+                for &b in rest {
+                    console.write_byte(b);
+                }
+            }
+        }
+        self.len = 0;
+    }
+}
+
+/// Adapter implementing `fmt::Write` for a line buffer of `console`.
+struct BufferedWriter<'a> {
+    buffer: &'a mut LineBuffer,
+    console: &'static dyn Console,
+}
+
+impl fmt::Write for BufferedWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.buffer.write(self.console, s);
+        Ok(())
+    }
+}
+
+/// Adapter passing only the lines admitted by the rate limit to `out`.
+/// The first line after dropped ones is preceded by a summary.
+struct RateLimitedWriter<'a>(&'a mut dyn fmt::Write);
+
+impl fmt::Write for RateLimitedWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for line in s.split_inclusive('\n') {
+            if AT_LINE_START.load(Ordering::Relaxed) {
+                match log::admit_line() {
+                    Some(suppressed) => {
+                        DROPPING_LINE.store(false, Ordering::Relaxed);
+                        if suppressed > 0 {
+                            writeln!(self.0, "[{} lines suppressed]", suppressed)?;
+                        }
+                    }
+                    None => DROPPING_LINE.store(true, Ordering::Relaxed),
+                }
+            }
+            if !DROPPING_LINE.load(Ordering::Relaxed) {
+                self.0.write_str(line)?;
+            }
+            AT_LINE_START.store(line.ends_with('\n'), Ordering::Relaxed);
+        }
+        Ok(())
+    }
+}
+
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Self tests, run by the diagnostics (see `user::diag`).                  ║
+   ╚═════════════════════════════════════════════════════════════════════════╝ */
+
+pub mod selftest {
+    use crate::devices::console::{self, Capture};
+    use crate::library::selftest::{restore_on_exit, Test, TestResult};
+
+    /// The self tests of this module, run in this order.
+    pub const TESTS: &[Test] = &[
+        ("Capture", test_capture),
+        ("Line buffering", test_line_buffering),
+        ("Flush all", test_flush_all),
+    ];
+
+    /// Console capturing the output of the tests.
+    static CAPTURE: Capture = Capture::new();
+
+    /// Redirect `print!` to a capture console and check the captured bytes,
+    /// including a character outside ASCII.
+    fn test_capture() -> TestResult {
+        const EXPECTED: &[u8] = "captured 42 ♫\n".as_bytes();
+
+        let previous = console::current();
+        let _restore = restore_on_exit(|| console::set(previous));
+        CAPTURE.take();
+        console::set(&CAPTURE);
+        println!("captured {} ♫", 42);
+
+        if CAPTURE.take().as_bytes() != EXPECTED {
+            return Err("wrong output captured");
+        }
+        Ok(())
+    }
+
+    /// Print the same output with and without line buffering to a capture console and check
+    /// that both are equal, including a line longer than the buffer and an unterminated line.
+    /// Buffered output must not be written before the end of its line.
+    fn test_line_buffering() -> TestResult {
+        let previous = console::current();
+        let buffered = console::is_line_buffered();
+        let _restore = restore_on_exit(|| {
+            console::set_line_buffered(buffered);
+            console::set(previous);
+        });
+        console::set(&CAPTURE);
+
+        let mut outputs = [CAPTURE.take(); 2];
+        for (i, enabled) in [false, true].into_iter().enumerate() {
+            console::set_line_buffered(enabled);
+            CAPTURE.take();
+            print!("a{}", 1);
+            if enabled != (CAPTURE.len() == 0) {
+                return Err("output not buffered until the end of the line");
+            }
+            println!("b");
+            println!("{:x<300}", "");
+            print!("partial");
+            console::flush();
+            outputs[i] = CAPTURE.take();
+        }
+
+        if outputs[0].as_bytes() != outputs[1].as_bytes() {
+            return Err("buffered output differs");
+        }
+        Ok(())
+    }
+
+    /// Console capturing the debug output of the flush test.
+    static DEBUG_CAPTURE: Capture = Capture::new();
+
+    /// Print unterminated lines with `print!` and `kprint!` while line buffering is enabled
+    /// and check that `flush_all()` writes both to their consoles, then call it again with
+    /// empty buffers, which must write nothing.
+    fn test_flush_all() -> TestResult {
+        let previous = console::current();
+        let previous_debug = console::debug();
+        let buffered = console::is_line_buffered();
+        let _restore = restore_on_exit(|| {
+            console::set_line_buffered(buffered);
+            console::set(previous);
+            console::set_debug(previous_debug);
+        });
+        console::set(&CAPTURE);
+        console::set_debug(&DEBUG_CAPTURE);
+        console::set_line_buffered(true);
+        CAPTURE.take();
+        DEBUG_CAPTURE.take();
+
+        print!("pending");
+        kprint!("debug");
+        if CAPTURE.len() != 0 || DEBUG_CAPTURE.len() != 0 {
+            return Err("output not buffered");
+        }
+        console::flush_all();
+        if CAPTURE.take().as_bytes() != b"pending" || DEBUG_CAPTURE.take().as_bytes() != b"debug" {
+            return Err("pending output not flushed");
+        }
+
+        console::flush_all();
+        if CAPTURE.len() != 0 || DEBUG_CAPTURE.len() != 0 {
+            return Err("output on flushing empty buffers");
+        }
+        Ok(())
+    }
+}
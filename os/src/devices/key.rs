@@ -19,6 +19,7 @@ const KMOD_CAPS_LOCK: u8   = 32;
 const KMOD_NUM_LOCK: u8    = 64;
 const KMOD_SCROLL_LOCK: u8 = 128;
 
+pub const SCAN_ESC: u8 = 0x01;
 pub const SCAN_F1: u8 = 0x3b;
 pub const SCAN_DEL: u8 = 0x53;
 pub const SCAN_UP: u8 = 72;
@@ -33,12 +34,23 @@ pub struct Key {
     asc: u8,  // ASCII code
     scan: u8, // scan code
     modi: u8, // modifier
+    double_tap: bool, // second press of a double tap (see `keyboard::set_double_tap_window()`)
 }
 
 impl Key {
     /// Create a new key with the given ASCII code, scancode and modifier.
     pub const fn new(asc: u8, scan: u8, modi: u8) -> Key {
-        Key { asc, scan, modi }
+        Key { asc, scan, modi, double_tap: false }
+    }
+
+    /// Return `key` marked as the second press of a double tap.
+    pub const fn double_tap(key: Key) -> Key {
+        Key { double_tap: true, ..key }
+    }
+
+    /// Check if the key is the second press of a double tap.
+    pub fn is_double_tap(&self) -> bool {
+        self.double_tap
     }
 
     /// Invalid keys are represented by a scancode of 0.
@@ -1,12 +1,24 @@
 
+use crate::kernel;
 use crate::kernel::cpu as cpu;
+use crate::devices::cga;
+use crate::devices::console;
 use crate::devices::key as key;
 use crate::devices::key::Key;
+use crate::devices::pcspk;
+use crate::devices::theme::{self, Style};
 use crate::kernel::cpu::IoPort;
+use crate::kernel::init::StartupError;
+use crate::kernel::log::{self, EventKind};
+use crate::kernel::softirq;
+use crate::kernel::timer;
 use crate::kernel::interrupts::intdispatcher::{self, int_disp, InterruptVector};
 use crate::kernel::interrupts::pic::{Irq, PIC};
+use crate::library::arrayvec::ArrayVec;
+use crate::library::IrqSafeMutex;
 
 use alloc::boxed::Box;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
 use nolock::queues::mpmc;
 use nolock::queues::mpmc::bounded::scq::{Receiver, Sender};
 
@@ -16,14 +28,17 @@ use crate::kernel::interrupts::isr::ISR;
 /// Represents the keyboard.
 pub struct Keyboard {
     code: u8,       // Keyboard byte
-    prefix: u8,     // Keyboard prefix
+    prefix: u8,     // Prefix of the key being decoded
+    decoder: DecoderState, // Prefix bytes received so far
     gather: Key,    // Last decoded key
     leds: u8,       // LED status
+    typematic: Option<u8>, // Repeat rate set by `set_repeat_rate` (None = keyboard default)
+    double_tap: bool, // Last decoded key is the second press of a double tap
     control_port: IoPort,
     data_port: IoPort
 }
 
-// Translation tables for ASCII codes
+// Translation tables for ASCII codes (German layout)
 static NORMAL_TAB: [u8;89] =
     [
         0, 0, 49, 50, 51, 52, 53, 54, 55, 56, 57, 48, 225, 39, 8, 0, 113,
@@ -52,6 +67,28 @@ static ALT_TAB: [u8; 89] =
         0, 0, 0, 0, 124, 0, 0
     ];
 
+// Translation tables for ASCII codes (US layout, without AltGr)
+static US_NORMAL_TAB: [u8; 89] =
+    [
+        0, 0, 49, 50, 51, 52, 53, 54, 55, 56, 57, 48, 45, 61, 8, 0, 113,
+        119, 101, 114, 116, 121, 117, 105, 111, 112, 91, 93, 13, 0, 97,
+        115, 100, 102, 103, 104, 106, 107, 108, 59, 39, 96, 0, 92, 122,
+        120, 99, 118, 98, 110, 109, 44, 46, 47, 0, 42, 0, 32, 0, 0, 0, 0,
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 45, 0, 0, 0, 43, 0, 0, 0, 0, 0,
+        0, 0, 92, 0, 0
+    ];
+
+static US_SHIFT_TAB: [u8; 89] =
+    [
+        0, 0, 33, 64, 35, 36, 37, 94, 38, 42, 40, 41, 95, 43, 0, 0, 81, 87,
+        69, 82, 84, 89, 85, 73, 79, 80, 123, 125, 0, 0, 65, 83, 68, 70, 71,
+        72, 74, 75, 76, 58, 34, 126, 0, 124, 90, 88, 67, 86, 66, 78, 77,
+        60, 62, 63, 0, 0, 0, 32, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 124, 0, 0
+    ];
+
+static US_ALT_TAB: [u8; 89] = [0; 89];
+
 static ASC_NUM_TAB:[u8; 13] = [ 55, 56, 57, 45, 52, 53, 54, 43, 49, 50, 51, 48, 44 ];
 
 static SCAN_NUM_TAB: [u8; 13] = [  8, 9, 10, 53, 5, 6, 7, 27, 2, 3, 4, 11, 51 ];
@@ -70,23 +107,53 @@ const PREFIX2:u8    = 0xe1;
 const KBD_CTRL_PORT:u16 = 0x64;    // Status- (R) u. Steuerregister (W)
 const KBD_DATA_PORT:u16 = 0x60;    // Ausgabe- (R) u. Eingabepuffer (W)
 
-// Bits in the keyboard status register
-const KBD_OUTB: u8 = 0x01;
-const KBD_INPB: u8 = 0x02;
-const KBD_AUXB: u8 = 0x20;
+// Bits in the keyboard status register (also used by the mouse, see `mouse`)
+pub const KBD_OUTB: u8 = 0x01;  // output buffer full
+pub const KBD_INPB: u8 = 0x02;  // input buffer full
+pub const KBD_AUXB: u8 = 0x20;  // output buffer holds a byte of the mouse (auxiliary device)
 
 // Keyboard commands
 const KBD_CMD_SET_LED: u8 = 0xed;
 const KBD_CMD_SET_SPEED: u8 = 0xf3;
 const KBD_CMD_CPU_RESET: u8 = 0xfe;
+const KBD_CMD_RESET: u8 = 0xff;
 
 // Keyboard replies
 const KBD_REPLY_ACK:u8 = 0xfa;
+const KBD_REPLY_RESEND: u8 = 0xfe;
+const KBD_REPLY_SELF_TEST_PASSED: u8 = 0xaa;
+
+// Maximum number of resends of a command byte
+const KBD_MAX_RETRIES: usize = 3;
+
+// Number of status register polls before waiting for the keyboard is given up. This is a poll
+// count, not a time: each poll is a port read taking roughly 1us on the ISA bus (about 100ms).
+const KBD_TIMEOUT_POLLS: usize = 100_000;
+
+// Number of status register polls before waiting for the self test after a reset is given up (about 1s)
+const KBD_SELF_TEST_POLLS: usize = 1_000_000;
+
+/// Errors that can occur when sending a command to the keyboard.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum KbdError {
+    /// The keyboard did not react in time.
+    Timeout,
+    /// The keyboard requested a resend more than `KBD_MAX_RETRIES` times.
+    TooManyResends,
+    /// The keyboard replied with an unexpected byte.
+    UnexpectedReply(u8),
+    /// The self test after a reset failed with the given reply.
+    SelfTestFailed(u8),
+}
+
 
 
+/// Global keyboard instance. Interrupts are disabled while it is locked,
+/// so the keyboard interrupt cannot deadlock on it.
+pub static KEYBOARD: IrqSafeMutex<Keyboard> = IrqSafeMutex::new(Keyboard::new());
 
-/// Global keyboard instance.
-pub static KEYBOARD: Mutex<Keyboard> = Mutex::new(Keyboard::new());
+/// Capacity of the key buffer.
+const KEY_QUEUE_SIZE: usize = 128;
 
 /// Global key buffer.
 /// Each key is pushed to this queue by the interrupt handler
@@ -103,17 +170,167 @@ pub fn get_key_buffer() -> &'static KeyQueue {
     })
 }
 
+/// Wait at most `ms` milliseconds for a key press, using the system timer.
+/// Returns the key if it was pressed in time, or `None` if the timeout elapsed.
+/// The CPU is halted between two checks and woken up by the next interrupt
+/// (timer or keyboard), so this function does not busy-wait.
+pub fn read_with_timeout(ms: u64) -> Option<Key> {
+    wait_for_key(ms, timer::uptime_ms, || get_key_buffer().get_last_key(), cpu::wait_for_int)
+}
+
+/// Wait at most `ms` milliseconds, measured with `now`, for a key returned by `poll` and call
+/// `wait` between two checks (see `read_with_timeout()`, the self tests use a mocked timer).
+fn wait_for_key(ms: u64, mut now: impl FnMut() -> u64, mut poll: impl FnMut() -> Option<Key>,
+                mut wait: impl FnMut()) -> Option<Key> {
+    let deadline = now() + ms;
+
+    loop {
+        if let Some(key) = poll() {
+            return Some(key);
+        }
+        if now() >= deadline {
+            return None;
+        }
+        wait();
+    }
+}
+
+/// Print `prompt` (if any) and wait for any key, at most `timeout` milliseconds (if any).
+/// Returns the key, or `None` if the timeout elapsed. Like `read_with_timeout()`, the CPU is
+/// halted between two checks, so the keyboard interrupt (and the timer for a timeout) is needed.
+pub fn press_any_key(prompt: Option<&str>, timeout: Option<u32>) -> Option<Key> {
+    if let Some(prompt) = prompt {
+        print!("{}", prompt);
+        console::flush();
+    }
+
+    match timeout {
+        Some(ms) => read_with_timeout(ms as u64),
+        None => loop {
+            if let Some(key) = get_key_buffer().get_last_key() {
+                return Some(key);
+            }
+            cpu::wait_for_int();
+        },
+    }
+}
+
+/// Wait until a key is pressed and return it. Works with and without the keyboard interrupt.
+/// Unlike `Keyboard::key_hit()`, the `KEYBOARD` lock is not held while waiting: each poll
+/// locks it only for reading one byte (with interrupts disabled, so the ISR cannot deadlock on it).
+/// Decoded keys always go through the key buffer, so no key is lost between ISR and polling.
+pub fn key_hit() -> Key {
+    loop {
+        if let Some(key) = get_key_buffer().get_last_key() {
+            return key;
+        }
+
+        cpu::without_interrupts(|| {
+            if let Some(key) = KEYBOARD.lock().key_hit_irq() {
+                deliver_key(key);
+            }
+        });
+        core::hint::spin_loop();
+    }
+}
+
+/// Reset a stuck keyboard without rebooting: the keyboard is reset, its repeat rate
+/// and LEDs are restored and all pending keys, key events and held keys are discarded.
+/// Failures (e.g. no response to the reset) are logged and returned.
+pub fn reset() -> Result<(), KbdError> {
+    let result = cpu::without_interrupts(|| {
+        let result = KEYBOARD.lock().reset();
+
+        LINE.lock().len = 0;
+        KEY_EVENTS.lock().len = 0;
+        SOFT_REPEAT.lock().held.clear();
+        result
+    });
+    while get_key_buffer().get_last_key().is_some() {}
+
+    if let Err(e) = result {
+        kprintln!("keyboard reset failed: {:?}", e);
+    }
+    result
+}
+
+/// Print the complete state of the keyboard driver to the debug console (serial by default):
+/// modifiers and locks, repeat settings, mode and the content of all buffers.
+/// The state is captured with interrupts disabled, so it is consistent.
+/// Must not be called while holding the `KEYBOARD` lock.
+pub fn dump_state() {
+    let mut keys: ArrayVec<Key, KEY_QUEUE_SIZE> = ArrayVec::new();
+    let (kb, line_len, mode, events, repeat) = cpu::without_interrupts(|| {
+        // The key buffer can only be read by taking the keys, so they are put back in order.
+        let buffer = get_key_buffer();
+        while let Some(key) = buffer.get_last_key() {
+            keys.push(key).ok();
+        }
+        for key in keys.iter() {
+            buffer.push_key(*key);
+        }
+
+        let kb = KEYBOARD.lock();
+        let line = LINE.lock();
+        let events = KEY_EVENTS.lock();
+        let repeat = SOFT_REPEAT.lock();
+        (
+            (kb.decoder, kb.code, kb.gather, kb.leds, kb.typematic),
+            line.len,
+            line.mode,
+            (events.enabled, events.len),
+            (repeat.enabled, repeat.delay_ms, repeat.interval_ms, repeat.held.len()),
+        )
+    });
+    let (decoder, code, gather, leds, typematic) = kb;
+
+    kprintln!("--- Keyboard state ---");
+    kprintln!("Last byte: {:#04x}, decoder: {:?}", code, decoder);
+    kprintln!(
+        "Modifiers: shift={} ctrl_left={} ctrl_right={} alt_left={} alt_right={}",
+        gather.get_shift(), gather.get_ctrl_left(), gather.get_ctrl_right(),
+        gather.get_alt_left(), gather.get_alt_right()
+    );
+    kprintln!(
+        "Locks: caps={} num={} scroll={}, LEDs: {:#04x}",
+        gather.get_caps_lock(), gather.get_num_lock(), gather.get_scroll_lock(), leds
+    );
+    match typematic {
+        Some(rate) => kprintln!("Typematic: {:#04x}", rate),
+        None => kprintln!("Typematic: keyboard default"),
+    }
+    kprintln!(
+        "Software repeat: enabled={} delay={}ms interval={}ms held={}",
+        repeat.0, repeat.1, repeat.2, repeat.3
+    );
+    kprintln!("Mode: {:?}, cooked line: {} keys", mode, line_len);
+    kprintln!("Key events: enabled={} pending={}", events.0, events.1);
+    kprintln!(
+        "Key buffer: {}/{} keys, {} dropped, {} keys delivered since boot",
+        keys.len(), KEY_QUEUE_SIZE, get_key_buffer().dropped(), key_presses()
+    );
+    for key in keys.iter_mut() {
+        kprintln!("  ascii={:#04x} scancode={:#04x}", key.get_ascii(), key.get_scancode());
+    }
+    kprintln!("--- End of keyboard state ---");
+}
+
 /* ╔═════════════════════════════════════════════════════════════════════════╗
    ║ Interrupt service routine implementation.                               ║
    ╚═════════════════════════════════════════════════════════════════════════╝ */
 
 /// Register the keyboard interrupt handler.
-pub fn plugin() {
+/// `INT_VECTORS` must have been initialized.
+pub fn plugin() -> Result<(), StartupError> {
     /* Hier muss Code eingefuegt werden */
+    if !intdispatcher::INT_VECTORS.lock().is_initialized() {
+        return Err(StartupError::IntVectorsNotInitialized);
+    }
+
     intdispatcher::INT_VECTORS.lock().register(InterruptVector::Keyboard, Box::new(KeyboardISR {}));
 
     PIC.lock().allow(Irq::Keyboard);
-
+    Ok(())
 }
 
 /// The keyboard interrupt service routine.
@@ -127,7 +344,7 @@ impl ISR for KeyboardISR {
         let mut kb = KEYBOARD.lock();
         if let Some(key) = kb.key_hit_irq() {
         
-            get_key_buffer().push_key(key);
+            deliver_key(key);
         }
 
         // let key = get_key_buffer().get_last_key();
@@ -137,6 +354,580 @@ impl ISR for KeyboardISR {
     }
 }
 
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Raw and cooked mode.                                                    ║
+   ╚═════════════════════════════════════════════════════════════════════════╝ */
+
+// Maximum length of a line in cooked mode (excluding the return key)
+const LINE_LENGTH: usize = 128;
+
+// ASCII codes handled in cooked mode
+const ASCII_BACKSPACE: u8 = 8;
+const ASCII_RETURN: u8 = 13;
+
+/// How keys are delivered to the key buffer.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Every key is delivered immediately (default).
+    Raw,
+    /// Keys are collected in a line buffer where backspace removes the last key.
+    /// The line is delivered (including the return key) when return is pressed.
+    /// Typed keys are not echoed.
+    Cooked,
+}
+
+/// Line buffer for cooked mode.
+static LINE: Mutex<LineBuffer> = Mutex::new(LineBuffer::new());
+
+struct LineBuffer {
+    mode: Mode,
+    keys: [Key; LINE_LENGTH],
+    len: usize,
+}
+
+impl LineBuffer {
+    const fn new() -> LineBuffer {
+        LineBuffer { mode: Mode::Raw, keys: [Key::new(0, 0, 0); LINE_LENGTH], len: 0 }
+    }
+
+    /// Handle a key according to the mode.
+    fn feed(&mut self, mut key: Key) {
+        if self.mode == Mode::Raw {
+            get_key_buffer().push_key(key);
+            return;
+        }
+
+        match key.get_ascii() {
+            ASCII_BACKSPACE => {
+                self.len = self.len.saturating_sub(1);
+            }
+            ASCII_RETURN => {
+                for &line_key in &self.keys[..self.len] {
+                    get_key_buffer().push_key(line_key);
+                }
+                get_key_buffer().push_key(key);
+                self.len = 0;
+            }
+            _ => {
+                // Keys beyond the maximum line length are dropped.
+                if self.len < LINE_LENGTH {
+                    self.keys[self.len] = key;
+                    self.len += 1;
+                }
+            }
+        }
+    }
+}
+
+/// Select raw or cooked mode. A partially typed line of cooked mode is discarded.
+pub fn set_mode(mode: Mode) {
+    cpu::without_interrupts(|| {
+        let mut line = LINE.lock();
+        line.mode = mode;
+        line.len = 0;
+    });
+}
+
+/// Return the current mode.
+pub fn get_mode() -> Mode {
+    cpu::without_interrupts(|| LINE.lock().mode)
+}
+
+/// Number of keys delivered since boot (including repeats).
+static KEY_PRESSES: AtomicUsize = AtomicUsize::new(0);
+
+/// Return the number of keys delivered since boot. Can be used to detect a key press
+/// without taking the key from the key buffer.
+pub fn key_presses() -> usize {
+    KEY_PRESSES.load(Ordering::Relaxed)
+}
+
+/// Frequency and duration of the key click (see `set_key_click()`).
+const KEY_CLICK_FREQ: usize = 1000;
+const KEY_CLICK_MS: u64 = 2;
+
+/// If set, each delivered key is acknowledged by a click of the speaker.
+static KEY_CLICK: AtomicBool = AtomicBool::new(false);
+
+/// Number of key clicks requested since boot (see `key_clicks()`).
+static KEY_CLICKS: AtomicUsize = AtomicUsize::new(0);
+
+/// Enable or disable a short click of the speaker on each delivered key (including repeats),
+/// like a mechanical keyboard. The click does not block: the speaker is turned off again by
+/// a timer alarm, so the timer must be plugged in. If the speaker is already in use
+/// (e.g. music is playing), no click is played.
+pub fn set_key_click(enabled: bool) {
+    KEY_CLICK.store(enabled, Ordering::Relaxed);
+}
+
+/// Check if each delivered key is acknowledged by a click (see `set_key_click()`).
+pub fn key_click_enabled() -> bool {
+    KEY_CLICK.load(Ordering::Relaxed)
+}
+
+/// Return the number of key clicks requested since boot, including skipped ones.
+pub fn key_clicks() -> usize {
+    KEY_CLICKS.load(Ordering::Relaxed)
+}
+
+/// Deliver a decoded key to the key buffer according to the mode. Called by the ISR.
+fn deliver_key(mut key: Key) {
+    KEY_PRESSES.fetch_add(1, Ordering::Relaxed);
+    log::record(EventKind::Key, (key.get_ascii() as u64) << 8 | key.get_scancode() as u64);
+    if KEY_CLICK.load(Ordering::Relaxed) {
+        KEY_CLICKS.fetch_add(1, Ordering::Relaxed);
+        pcspk::click(KEY_CLICK_FREQ, KEY_CLICK_MS);
+    }
+    LINE.lock().feed(key);
+}
+
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Keyboard layouts, switched with Ctrl+Alt+Space.                         ║
+   ╚═════════════════════════════════════════════════════════════════════════╝ */
+
+/// The layouts selecting the translation tables from scancodes to ASCII codes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum KeyboardLayout {
+    German = 0,
+    Us = 1,
+}
+
+impl KeyboardLayout {
+    /// All layouts in the order they are cycled through by Ctrl+Alt+Space.
+    pub const ALL: [KeyboardLayout; 2] = [KeyboardLayout::German, KeyboardLayout::Us];
+
+    /// Return the name shown by the layout indicator.
+    pub fn name(self) -> &'static str {
+        match self {
+            KeyboardLayout::German => "German",
+            KeyboardLayout::Us => "US",
+        }
+    }
+
+    /// Return the layout following this one in `ALL` (wrapping around).
+    pub fn next(self) -> KeyboardLayout {
+        KeyboardLayout::ALL[(self as usize + 1) % KeyboardLayout::ALL.len()]
+    }
+
+    /// Return the tables for keys without modifier, with Shift and with AltGr.
+    fn tables(self) -> (&'static [u8; 89], &'static [u8; 89], &'static [u8; 89]) {
+        match self {
+            KeyboardLayout::German => (&NORMAL_TAB, &SHIFT_TAB, &ALT_TAB),
+            KeyboardLayout::Us => (&US_NORMAL_TAB, &US_SHIFT_TAB, &US_ALT_TAB),
+        }
+    }
+}
+
+/// The active layout. It is kept for the whole session, also across `reset()`.
+static LAYOUT: AtomicU8 = AtomicU8::new(KeyboardLayout::German as u8);
+
+/// Duration the layout indicator is shown after switching the layout (milliseconds).
+const LAYOUT_INDICATOR_MS: u64 = 1500;
+
+/// Width of the layout indicator at the right end of the bottom row.
+const LAYOUT_INDICATOR_WIDTH: usize = 20;
+
+/// Retry interval if the screen is locked while hiding the indicator (milliseconds).
+const LAYOUT_INDICATOR_RETRY_MS: u64 = 10;
+
+/// Cells covered by the layout indicator, restored when it is hidden (empty while hidden).
+static INDICATOR_SAVED: Mutex<ArrayVec<(u8, u8), LAYOUT_INDICATOR_WIDTH>> = Mutex::new(ArrayVec::new());
+
+/// Uptime at which the layout indicator is hidden. Switching again extends the time.
+static INDICATOR_HIDE_AT: AtomicU64 = AtomicU64::new(0);
+
+/// Select the keyboard layout.
+pub fn set_layout(layout: KeyboardLayout) {
+    LAYOUT.store(layout as u8, Ordering::Relaxed);
+}
+
+/// Return the active keyboard layout.
+pub fn layout() -> KeyboardLayout {
+    match LAYOUT.load(Ordering::Relaxed) {
+        1 => KeyboardLayout::Us,
+        _ => KeyboardLayout::German,
+    }
+}
+
+/// Switch to the next layout (see `KeyboardLayout::ALL`) and return it.
+/// Called by the decoder on Ctrl+Alt+Space.
+pub fn cycle_layout() -> KeyboardLayout {
+    let next = layout().next();
+    set_layout(next);
+    next
+}
+
+/// Shut the kernel down, requested with Ctrl+Alt+End.
+fn request_shutdown() {
+    kernel::shutdown();
+}
+
+/// Show the name of the active layout at the right end of the status line (bottom row)
+/// for `LAYOUT_INDICATOR_MS`. Runs as soft interrupt, so it is skipped if the screen is locked.
+fn show_layout_indicator() {
+    let Some(mut cga) = cga::CGA.try_lock() else {
+        return;
+    };
+    let Some(mut saved) = INDICATOR_SAVED.try_lock() else {
+        return;
+    };
+
+    let (columns, rows) = cga::screen_size();
+    let x = columns - LAYOUT_INDICATOR_WIDTH;
+    if saved.is_empty() {
+        for i in 0..LAYOUT_INDICATOR_WIDTH {
+            saved.push(cga.read_cell(x + i, rows - 1).unwrap_or((b' ', cga::CGA_STD_ATTR))).ok();
+        }
+    }
+
+    let attrib = theme::attribute(Style::Status);
+    let text = " Layout: ".bytes().chain(layout().name().bytes()).chain(core::iter::repeat(b' '));
+    for (i, ch) in text.take(LAYOUT_INDICATOR_WIDTH).enumerate() {
+        cga.show(x + i, rows - 1, ch as char, attrib);
+    }
+
+    INDICATOR_HIDE_AT.store(timer::uptime_ms() + LAYOUT_INDICATOR_MS, Ordering::Relaxed);
+    timer::set_alarm(LAYOUT_INDICATOR_MS, hide_layout_indicator).ok();
+}
+
+/// Restore the cells covered by the layout indicator, unless it was shown again meanwhile.
+fn hide_layout_indicator() {
+    if timer::uptime_ms() < INDICATOR_HIDE_AT.load(Ordering::Relaxed) {
+        return;
+    }
+    let (Some(mut cga), Some(mut saved)) = (cga::CGA.try_lock(), INDICATOR_SAVED.try_lock()) else {
+        timer::set_alarm(LAYOUT_INDICATOR_RETRY_MS, hide_layout_indicator).ok();
+        return;
+    };
+
+    let (columns, rows) = cga::screen_size();
+    let x = columns - LAYOUT_INDICATOR_WIDTH;
+    for (i, &(ch, attrib)) in saved.iter().enumerate() {
+        cga.show(x + i, rows - 1, ch as char, attrib);
+    }
+    saved.clear();
+}
+
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Key events (key down and key up).                                       ║
+   ╚═════════════════════════════════════════════════════════════════════════╝ */
+
+// Maximum number of pending key events
+const MAX_KEY_EVENTS: usize = 32;
+
+/// A key was pressed or released. Modifier and lock keys do not generate events.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct KeyEvent {
+    /// Make code of the key (without the break bit)
+    pub code: u8,
+    /// Prefix byte sent before the code (0, `0xe0` or `0xe1`)
+    pub prefix: u8,
+    /// True if the key was pressed, false if it was released
+    pub pressed: bool,
+}
+
+/// State of the scancode decoder between two bytes from the keyboard.
+/// Keys that are new in the MF II keyboard (compared to the old AT keyboard) send a prefix
+/// byte before their code. A release needs no state of its own, since the break code
+/// is the make code with the break bit set.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DecoderState {
+    /// Waiting for the first byte of a key
+    Idle,
+    /// Received the prefix `0xe0` (cursor block, right Ctrl/Alt, Print Screen, ...)
+    Extended,
+    /// Received the prefix `0xe1` (only sent by the Pause key as `e1 1d 45`, released as `e1 9d c5`)
+    ExtendedPause,
+}
+
+impl DecoderState {
+    pub const fn new() -> DecoderState {
+        DecoderState::Idle
+    }
+
+    /// Return the prefix byte received in this state (0 in `Idle`).
+    pub fn prefix(&self) -> u8 {
+        match self {
+            DecoderState::Idle => 0,
+            DecoderState::Extended => PREFIX1,
+            DecoderState::ExtendedPause => PREFIX2,
+        }
+    }
+
+    /// Feed the next `byte` from the keyboard. Prefix bytes only change the state.
+    /// Any other byte completes a key, which is returned as event with the prefix received
+    /// before (a prefix is only valid for the next code), and the decoder is idle again.
+    pub fn step(&mut self, byte: u8) -> Option<KeyEvent> {
+        match byte {
+            PREFIX1 => *self = DecoderState::Extended,
+            PREFIX2 => *self = DecoderState::ExtendedPause,
+            _ => {
+                let prefix = self.prefix();
+                *self = DecoderState::Idle;
+                return Some(KeyEvent { code: byte & !BREAK_BIT, prefix, pressed: byte & BREAK_BIT == 0 });
+            }
+        }
+        None
+    }
+}
+
+/// Pending key events (only recorded after `enable_key_events(true)`).
+static KEY_EVENTS: Mutex<KeyEventQueue> = Mutex::new(KeyEventQueue::new());
+
+/// A ring buffer of key events.
+struct KeyEventQueue {
+    enabled: bool,
+    events: [Option<KeyEvent>; MAX_KEY_EVENTS],
+    head: usize,
+    len: usize,
+}
+
+impl KeyEventQueue {
+    const fn new() -> KeyEventQueue {
+        KeyEventQueue { enabled: false, events: [None; MAX_KEY_EVENTS], head: 0, len: 0 }
+    }
+
+    /// Append an event. If the queue is full, the event is discarded.
+    fn push(&mut self, event: KeyEvent) {
+        if !self.enabled || self.len == MAX_KEY_EVENTS {
+            return;
+        }
+        self.events[(self.head + self.len) % MAX_KEY_EVENTS] = Some(event);
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<KeyEvent> {
+        if self.len == 0 {
+            return None;
+        }
+        let event = self.events[self.head].take();
+        self.head = (self.head + 1) % MAX_KEY_EVENTS;
+        self.len -= 1;
+        event
+    }
+}
+
+/// Enable or disable recording of key down and key up events (disabled by default).
+/// Pending events are discarded. The decoded keys are still delivered to the key buffer.
+pub fn enable_key_events(enabled: bool) {
+    cpu::without_interrupts(|| {
+        let mut events = KEY_EVENTS.lock();
+        events.enabled = enabled;
+        events.len = 0;
+    });
+}
+
+/// Return the oldest pending key event or `None`, if there is none.
+pub fn next_event() -> Option<KeyEvent> {
+    cpu::without_interrupts(|| KEY_EVENTS.lock().pop())
+}
+
+/// Record a key event. Called while decoding keys.
+fn record_event(prefix: u8, code: u8, pressed: bool) {
+    cpu::without_interrupts(|| KEY_EVENTS.lock().push(KeyEvent { code, prefix, pressed }));
+}
+
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Key filter.                                                             ║
+   ╚═════════════════════════════════════════════════════════════════════════╝ */
+
+/// A key filter receives each key press and release as soon as its scancode bytes have been
+/// decoded into a `KeyEvent` and returns the event to be handled instead, or `None` to drop it.
+pub type KeyFilter = fn(KeyEvent) -> Option<KeyEvent>;
+
+/// The installed key filter (see `set_filter()`).
+static FILTER: Mutex<Option<KeyFilter>> = Mutex::new(None);
+
+/// Install `filter` for intercepting, remapping or dropping keys, e.g. for custom layouts.
+/// The filter runs after the scancode decoder (see `DecoderState::step()`), but before the
+/// modifiers are updated and the key is translated, so remapping a key to a modifier
+/// (e.g. CapsLock to Ctrl) works as expected. A dropped key is suppressed entirely (no key, no event, no repeat).
+/// The filter is called by the keyboard ISR, so it must be short and must not use the keyboard.
+pub fn set_filter(filter: KeyFilter) {
+    cpu::without_interrupts(|| *FILTER.lock() = Some(filter));
+}
+
+/// Remove the installed key filter.
+pub fn clear_filter() {
+    cpu::without_interrupts(|| *FILTER.lock() = None);
+}
+
+/// Pass `event` through the installed filter. Called while decoding keys.
+fn filter_event(event: KeyEvent) -> Option<KeyEvent> {
+    match *FILTER.lock() {
+        Some(filter) => filter(event),
+        None => Some(event),
+    }
+}
+
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Software key repeat.                                                    ║
+   ╚═════════════════════════════════════════════════════════════════════════╝ */
+
+// Maximum number of simultaneously held keys tracked for software repeat
+const MAX_HELD_KEYS: usize = 8;
+
+/// State of the software key repeat, shared by the keyboard and the timer ISR.
+static SOFT_REPEAT: Mutex<SoftRepeat> = Mutex::new(SoftRepeat::new());
+
+/// A key that is currently held down, identified by its make code and prefix.
+#[derive(Copy, Clone)]
+struct HeldKey {
+    prefix: u8,
+    code: u8,
+    key: Key,
+    next_repeat: u64,
+}
+
+/// Software repeat: while a key is held down, it is pushed to the key buffer again
+/// after `delay_ms` and then every `interval_ms` milliseconds, driven by the timer.
+/// Repeated make codes sent by the hardware typematic are ignored while enabled.
+struct SoftRepeat {
+    enabled: bool,
+    delay_ms: u64,
+    interval_ms: u64,
+    held: ArrayVec<HeldKey, MAX_HELD_KEYS>,
+}
+
+impl SoftRepeat {
+    const fn new() -> SoftRepeat {
+        SoftRepeat { enabled: false, delay_ms: 0, interval_ms: 0, held: ArrayVec::new() }
+    }
+
+    /// Track a pressed key. Returns false if the key is already held,
+    /// i.e. the make code is a hardware repeat which should be ignored.
+    fn press(&mut self, prefix: u8, code: u8, key: Key, now: u64) -> bool {
+        if !self.enabled {
+            return true;
+        }
+        if self.held.iter().any(|held| held.prefix == prefix && held.code == code) {
+            return false;
+        }
+
+        // If more keys are held than can be tracked, the key is just not repeated.
+        self.held.push(HeldKey { prefix, code, key, next_repeat: now + self.delay_ms }).ok();
+        true
+    }
+
+    /// Stop repeating a released key.
+    fn release(&mut self, prefix: u8, code: u8) {
+        self.held.retain(|held| held.prefix != prefix || held.code != code);
+    }
+
+    /// Push a repeat of each held key whose repeat is due.
+    fn tick(&mut self, now: u64) {
+        if !self.enabled {
+            return;
+        }
+        for held in self.held.iter_mut() {
+            if now >= held.next_repeat {
+                deliver_key(held.key);
+                held.next_repeat = now + self.interval_ms;
+            }
+        }
+    }
+}
+
+/// Enable the software key repeat, independent of the typematic rate of the keyboard.
+/// A held key is repeated after `delay_ms` and then every `interval_ms` milliseconds.
+pub fn set_software_repeat(delay_ms: u64, interval_ms: u64) {
+    cpu::without_interrupts(|| {
+        let mut repeat = SOFT_REPEAT.lock();
+        repeat.enabled = true;
+        repeat.delay_ms = delay_ms;
+        repeat.interval_ms = interval_ms.max(1);
+        repeat.held.clear();
+    });
+}
+
+/// Disable the software key repeat (the hardware typematic repeat is used again).
+pub fn disable_software_repeat() {
+    cpu::without_interrupts(|| {
+        let mut repeat = SOFT_REPEAT.lock();
+        repeat.enabled = false;
+        repeat.held.clear();
+    });
+}
+
+/// Generate the due software repeats. Called by the timer ISR on each tick.
+pub fn repeat_tick(now_ms: u64) {
+    SOFT_REPEAT.lock().tick(now_ms);
+}
+
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Double-tap detection.                                                   ║
+   ╚═════════════════════════════════════════════════════════════════════════╝ */
+
+/// Detects two presses of the same key within `window_ms` milliseconds, using the time
+/// of each press. The key must be released in between, so a held key (hardware repeat)
+/// is no double tap. Modifier keys are detected as well, e.g. double-tapping Shift.
+pub struct DoubleTapDetector {
+    window_ms: u64,
+    /// Press waiting for a second one: the event, its time and if the key has been released since
+    pending: Option<(KeyEvent, u64, bool)>,
+}
+
+impl DoubleTapDetector {
+    pub const fn new(window_ms: u64) -> DoubleTapDetector {
+        DoubleTapDetector { window_ms, pending: None }
+    }
+
+    /// Return the window for the second press in milliseconds.
+    pub fn window_ms(&self) -> u64 {
+        self.window_ms
+    }
+
+    /// Feed a key event received at `now_ms` and return true if it is the second press of
+    /// the pending key within the window (the key has been released in between).
+    pub fn feed(&mut self, event: KeyEvent, now_ms: u64) -> bool {
+        let same_key = |first: &KeyEvent| first.code == event.code && first.prefix == event.prefix;
+
+        if !event.pressed {
+            if let Some((first, _, released)) = &mut self.pending && same_key(first) {
+                *released = true;
+            }
+            return false;
+        }
+
+        match self.pending {
+            Some((first, _, false)) if same_key(&first) => false,
+            Some((first, at, true)) if same_key(&first) && now_ms - at <= self.window_ms => {
+                self.pending = None;
+                true
+            }
+            _ => {
+                self.pending = Some((event, now_ms, false));
+                false
+            }
+        }
+    }
+}
+
+/// The double-tap detector fed by the keyboard ISR (window 0 = disabled).
+static DOUBLE_TAP: Mutex<DoubleTapDetector> = Mutex::new(DoubleTapDetector::new(0));
+
+/// Enable detecting double taps of keys pressed twice within `ms` milliseconds (0 disables it,
+/// the default). Every press is still delivered as key, the second press of a double tap is
+/// marked (see `Key::is_double_tap()`). Modifier keys are delivered in this case too.
+pub fn set_double_tap_window(ms: u64) {
+    cpu::without_interrupts(|| *DOUBLE_TAP.lock() = DoubleTapDetector::new(ms));
+}
+
+/// Return the double-tap window in milliseconds (0 if disabled).
+pub fn double_tap_window() -> u64 {
+    cpu::without_interrupts(|| DOUBLE_TAP.lock().window_ms())
+}
+
+/// Feed a key event to the double-tap detector and return true if it completes a double tap.
+/// Called while decoding keys.
+fn detect_tap(event: KeyEvent) -> bool {
+    cpu::without_interrupts(|| {
+        let mut detector = DOUBLE_TAP.lock();
+        detector.window_ms() != 0 && detector.feed(event, timer::uptime_ms())
+    })
+}
+
 /* ╔═════════════════════════════════════════════════════════════════════════╗
    ║ Key buffer implementation.                                              ║
    ╚═════════════════════════════════════════════════════════════════════════╝ */
@@ -148,15 +939,19 @@ pub struct KeyQueue {
     /// Keys can be popped from the queue via the receiver.
     receiver: Receiver<Key>,
     /// Keys can be pushed to the queue via the sender.
-    sender: Sender<Key>
+    sender: Sender<Key>,
+    /// Number of keys in the queue
+    len: AtomicUsize,
+    /// Number of keys discarded because the queue was full
+    dropped: AtomicUsize,
 }
 
 impl KeyQueue {
     /// Create a new empty queue.
     /// Unfortunately, this cannot be done in a const function.
     fn new() -> KeyQueue {
-        let (receiver, sender) = mpmc::bounded::scq::queue(128);
-        KeyQueue { receiver, sender }
+        let (receiver, sender) = mpmc::bounded::scq::queue(KEY_QUEUE_SIZE);
+        KeyQueue { receiver, sender, len: AtomicUsize::new(0), dropped: AtomicUsize::new(0) }
     }
 
     /// Push a key to the queue.
@@ -169,7 +964,10 @@ impl KeyQueue {
 
         // Enqueue the key into the queue.
         // If the queue is full, we ignore the key.
-        self.sender.try_enqueue(key).ok();
+        match self.sender.try_enqueue(key) {
+            Ok(_) => self.len.fetch_add(1, Ordering::Relaxed),
+            Err(_) => self.dropped.fetch_add(1, Ordering::Relaxed),
+        };
     }
 
     /// Pop a key from the queue.
@@ -181,7 +979,10 @@ impl KeyQueue {
         }
 
         match self.receiver.try_dequeue() {
-            Ok(key) => Some(key),
+            Ok(key) => {
+                self.len.fetch_sub(1, Ordering::Relaxed);
+                Some(key)
+            }
             Err(_) => None
         }
     }
@@ -195,12 +996,21 @@ impl KeyQueue {
         }
 
         loop {
-            match self.receiver.try_dequeue() {
-                Ok(key) => return key,
-                Err(_) => {}
+            if let Some(key) = self.get_last_key() {
+                return key;
             }
         }
     }
+
+    /// Return the number of keys in the queue.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    /// Return the number of keys discarded because the queue was full.
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
 }
 
 /* ╔═════════════════════════════════════════════════════════════════════════╗
@@ -212,13 +1022,29 @@ impl Keyboard {
         Keyboard {
             code: 0,
             prefix: 0,
+            decoder: DecoderState::new(),
             gather: Key::new(0, 0, 0),
             leds: 0,
+            typematic: None,
+            double_tap: false,
             control_port: IoPort::new(KBD_CTRL_PORT),
             data_port: IoPort::new(KBD_DATA_PORT)
         }
     }
 
+    /// Decode a byte as if it had been received from the keyboard
+    /// and return the key if it is complete (also used to inject bytes in tests).
+    pub fn decode_byte(&mut self, code: u8) -> Option<Key> {
+        self.code = code;
+        if !self.key_decoded() {
+            None
+        } else if self.double_tap {
+            Some(Key::double_tap(self.gather))
+        } else {
+            Some(self.gather)
+        }
+    }
+
     /// Poll a byte from the keyboard controller.
     /// Decode and return the key if it is complete.
     fn key_hit_irq(&mut self) -> Option<Key> {
@@ -234,16 +1060,9 @@ impl Keyboard {
         if (status & KBD_AUXB) != 0 {
             return None;
         }
-        // read
+        // read and decode
         let code = unsafe { self.data_port.inb() };
-        self.code = code;
-
-        // if ready to decode
-        if self.key_decoded() {
-            Some(self.gather)
-        } else {
-            None
-        }
+        self.decode_byte(code)
     }
 
     /// Interpret the make and break codes of the keyboard.
@@ -251,17 +1070,22 @@ impl Keyboard {
     fn key_decoded(&mut self) -> bool {
         let mut done: bool = false;
 
-        // Keys that are new in the MF II keyboard (compared to the old AT keyboard)
-        // send a prefix byte first.
-        if self.code == PREFIX1 || self.code == PREFIX2 {
-            self.prefix = self.code;
+        // Prefix bytes only advance the decoder state.
+        let Some(event) = self.decoder.step(self.code) else {
             return false;
-        }
+        };
+
+        // The key filter may remap the key or drop it.
+        let Some(event) = filter_event(event) else {
+            return false;
+        };
+        let tapped = detect_tap(event);
+        self.prefix = event.prefix;
+        self.code = event.code;
 
         // Releasing a key is only of interest for the "Modifier" keys SHIFT, CTRL and ALT.
         // For the others, the break code can be ignored.
-        if (self.code & BREAK_BIT) != 0 {
-            self.code &= !BREAK_BIT; // A key's break code is the same as its make code, but with the break bit set.
+        if !event.pressed {
             match self.code {
                 42 | 54 => {
                     self.gather.set_shift (false);
@@ -275,6 +1099,9 @@ impl Keyboard {
                     else                      { self.gather.set_ctrl_left(false); }
                 }
                 _ => { // All other keys
+                    let (prefix, code) = (self.prefix, self.code);
+                    cpu::without_interrupts(|| SOFT_REPEAT.lock().release(prefix, code));
+                    record_event(prefix, code, false);
                 }
             }
 
@@ -300,13 +1127,22 @@ impl Keyboard {
                 if self.prefix == PREFIX1 { self.gather.set_ctrl_right(true); }
                 else                      { self.gather.set_ctrl_left(true);  }
             }
+            57 if self.gather.get_ctrl() && self.gather.get_alt() => {
+                // Ctrl+Alt+Space switches the layout and is not delivered as key
+                cycle_layout();
+                softirq::raise(show_layout_indicator);
+            }
+            79 if self.gather.get_ctrl() && self.gather.get_alt() => {
+                // Ctrl+Alt+End shuts the kernel down (outside of the interrupt handler)
+                softirq::raise(request_shutdown);
+            }
             58 => {
                 self.gather.set_caps_lock( !self.gather.get_caps_lock() );
-                self.set_led(LED_CAPS_LOCK, self.gather.get_caps_lock());
+                self.set_led(LED_CAPS_LOCK, self.gather.get_caps_lock()).ok();
             }
             70 => {
                 self.gather.set_scroll_lock( !self.gather.get_scroll_lock() );
-                self.set_led(LED_SCROLL_LOCK, self.gather.get_scroll_lock());
+                self.set_led(LED_SCROLL_LOCK, self.gather.get_scroll_lock()).ok();
             }
             69 => { // Numlock or Break
                 if self.gather.get_ctrl_left() { // Break Key
@@ -320,17 +1156,32 @@ impl Keyboard {
                 }
                 else { // NumLock
                     self.gather.set_num_lock( !self.gather.get_num_lock() );
-                    self.set_led(LED_NUM_LOCK, self.gather.get_num_lock());
+                    self.set_led(LED_NUM_LOCK, self.gather.get_num_lock()).ok();
                 }
             }
 
             _ => { // All other keys
                 // Read ASCII code from the appropriate table -> Key is decoded
                 self.get_ascii_code();
-                done = true;
+
+                // With software repeat, repeated make codes of a held key are dropped.
+                let (prefix, code, key) = (self.prefix, self.code, self.gather);
+                done = cpu::without_interrupts(|| {
+                    SOFT_REPEAT.lock().press(prefix, code, key, timer::uptime_ms())
+                });
+                record_event(prefix, code, true);
             }
         }
 
+        // The second press of a double tap is delivered marked as such. Modifier keys are
+        // delivered as well in this case (with ASCII code 0), e.g. for double-tapping Shift.
+        if tapped && matches!(self.code, 29 | 42 | 54 | 56) {
+            self.gather.set_ascii(0);
+            self.gather.set_scancode(self.code);
+            done = true;
+        }
+        self.double_tap = tapped && done;
+
         // A prefix is only valid for the next key. So it is now handled.
         self.prefix = 0;
         done
@@ -339,6 +1190,8 @@ impl Keyboard {
 
     /// Calculate the ASCII code from the scancode and modifier bits.
     fn get_ascii_code(&mut self) {
+        let (normal_tab, shift_tab, alt_tab) = layout().tables();
+
         // Special case Scancode 53: This code is sent by both the minus key
         // of the normal keyboard area and the division key of the numeric
         // keypad. In order to get the correct code in both cases, a conversion
@@ -364,11 +1217,11 @@ impl Keyboard {
             self.gather.set_scancode(SCAN_NUM_TAB[ (self.code - 71) as usize]);
         }
         else if self.gather.get_alt_right() {
-            self.gather.set_ascii(ALT_TAB[self.code as usize]);
+            self.gather.set_ascii(alt_tab[self.code as usize]);
             self.gather.set_scancode(self.code);
         }
         else if self.gather.get_shift() {
-            self.gather.set_ascii(SHIFT_TAB[self.code as usize]);
+            self.gather.set_ascii(shift_tab[self.code as usize]);
             self.gather.set_scancode(self.code);
         }
         else if self.gather.get_caps_lock() {
@@ -376,22 +1229,23 @@ impl Keyboard {
             if (self.code >= 16 && self.code <= 26) ||
                 (self.code >= 30 && self.code<= 40) ||
                 (self.code >= 44 && self.code <= 50) {
-                self.gather.set_ascii (SHIFT_TAB[self.code as usize]);
+                self.gather.set_ascii (shift_tab[self.code as usize]);
                 self.gather.set_scancode(self.code);
             }
             else {
-                self.gather.set_ascii(NORMAL_TAB[self.code as usize]);
+                self.gather.set_ascii(normal_tab[self.code as usize]);
                 self.gather.set_scancode(self.code);
             }
         }
         else {
-            self.gather.set_ascii(NORMAL_TAB[self.code as usize]);
+            self.gather.set_ascii(normal_tab[self.code as usize]);
             self.gather.set_scancode(self.code);
         }
     }
     
     /// Poll the keyboard controller until a key is pressed.
     /// Decode and return the key if it is complete.
+    /// The caller holds the `KEYBOARD` lock while spinning, so prefer `keyboard::key_hit()`.
     pub fn key_hit(&mut self) -> Key {
         let invalid: Key = Default::default();  // nicht explizit initialisierte Tasten sind ungueltig
         let mut status : u8;
@@ -444,7 +1298,7 @@ impl Keyboard {
     /// The delay determines how long a key must be pressed before the keyboard starts repeating it.
     /// Valid values are between 0 (minimum delay) and 3 (maximum delay).
     /// 0 = 250ms, 1 = 500ms, 2 = 750ms, 3 = 1000ms
-    pub fn set_repeat_rate(&mut self, speed: u8, delay: u8) -> i8 {
+    pub fn set_repeat_rate(&mut self, speed: u8, delay: u8) -> Result<(), KbdError> {
 
         /* Hier muss Code eingefuegt werden. */
 
@@ -467,71 +1321,19 @@ impl Keyboard {
          *                  Bits 4-3 = B; Bits 2-0 = D;                              *
          *****************************************************************************/
 
-         loop{
-            unsafe{
-                let status = self.control_port.inb();
-                if status & KBD_INPB == 0 { // ready
-                    // write repeat ratecommand to keyboard
-                    self.data_port.outb(0xF3);
-                    break;
-                }
-            }
-        }
-
-        // wait until command answer arrives
-        loop{
-            unsafe{
-                let status = self.control_port.inb();
-                if status & KBD_OUTB != 0 { 
-                    break;
-
-                }
-            }
-        }
-        
-        unsafe {
-            let answer = self.data_port.inb();
-            if answer != 0xfa { // command not accepted
-                return -1
-            }
-        }
-        
+        // Bits 0-4: speed, bits 5-6: delay
+        let command = (delay & 0x03) << 5 | (speed & 0x1f);
 
-        // set repeat rate
-        let mut command = 0x00;
-        command |= (speed << 2) & 0x1f;
-        command |= delay & 0x03;
-
-        unsafe{
-            self.data_port.outb(command);
-        }
-
-        // wait until command answer arrives
-        loop{
-            unsafe{
-                let status = self.control_port.inb();
-                if status & KBD_OUTB != 0 { 
-                    break;
-                    }
-
-            }
-        }
-        
-        unsafe {
-        let answer = self.data_port.inb();
-            if answer != 0xfa { // command not accepted
-                return -1
-            }
-        }
-
-        return 0
+        self.send_command(KBD_CMD_SET_SPEED, Some(command))?;
+        self.typematic = Some(command);
+        Ok(())
 
     }
     
     /// Enable/Disable the LEDs on the keyboard.
     /// Multiple LEDs can be set at the same time as a bit mask.
     /// 1 = Caps Lock, 2 = Num Lock, 4 = Scroll Lock
-    pub fn set_led(&mut self, led: u8, on: bool) -> i8{
+    pub fn set_led(&mut self, led: u8, on: bool) -> Result<(), KbdError> {
 
         /* Hier muss Code eingefuegt werden. */
         
@@ -545,61 +1347,650 @@ impl Keyboard {
          *      on:         0 = aus, 1 = an                                          *
          *****************************************************************************/
     
-         loop{
-            unsafe{
-                let status = self.control_port.inb();
-                if status & KBD_INPB == 0 { // ready
-                    // write repeat ratecommand to keyboard
-                    self.data_port.outb(0xED);
-                    break;
-                }
+        if on {
+            self.leds |= led;
+        } else {
+            self.leds &= !led;
+        }
+
+        self.send_command(KBD_CMD_SET_LED, Some(self.leds))
+    }
+
+    /// Reset the keyboard and wait for its self test.
+    /// Afterwards, the repeat rate and the LEDs are restored and the state of the
+    /// modifier keys is cleared (their break codes may have been lost).
+    /// Interrupts must be disabled, otherwise the ISR would read the replies.
+    fn reset(&mut self) -> Result<(), KbdError> {
+        self.prefix = 0;
+        self.decoder = DecoderState::new();
+        self.gather.set_shift(false);
+        self.gather.set_alt_left(false);
+        self.gather.set_alt_right(false);
+        self.gather.set_ctrl_left(false);
+        self.gather.set_ctrl_right(false);
+
+        let (typematic, leds) = (self.typematic, self.leds);
+        reset_keyboard(self, typematic, leds)
+    }
+
+    /// Send the command `cmd` and, if given, its `data` byte to the keyboard (see `send_command`).
+    pub fn send_command(&mut self, cmd: u8, data: Option<u8>) -> Result<(), KbdError> {
+        send_command(self, cmd, data)
+    }
+}
+
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Command protocol of the keyboard, independent of the real ports.        ║
+   ╚═════════════════════════════════════════════════════════════════════════╝ */
+
+/// Access to the registers of a keyboard controller (the real one or a mock in the self tests).
+pub trait KbdController {
+    /// Read the status register.
+    fn status(&mut self) -> u8;
+
+    /// Read a byte from the output buffer.
+    fn read_data(&mut self) -> u8;
+
+    /// Write a byte to the input buffer (sent on to the keyboard).
+    fn write_data(&mut self, b: u8);
+}
+
+impl KbdController for Keyboard {
+    fn status(&mut self) -> u8 {
+        unsafe { self.control_port.inb() }
+    }
+
+    fn read_data(&mut self) -> u8 {
+        unsafe { self.data_port.inb() }
+    }
+
+    fn write_data(&mut self, b: u8) {
+        unsafe { self.data_port.outb(b); }
+    }
+}
+
+/// Send the command `cmd` and, if given, its `data` byte to the keyboard behind `kbc`.
+/// Each byte must be acknowledged by the keyboard. If the keyboard requests
+/// a resend, the byte is sent again (up to `KBD_MAX_RETRIES` times).
+pub fn send_command<C: KbdController>(kbc: &mut C, cmd: u8, data: Option<u8>) -> Result<(), KbdError> {
+    send_byte(kbc, cmd)?;
+    if let Some(data) = data {
+        send_byte(kbc, data)?;
+    }
+    Ok(())
+}
+
+/// Reset the keyboard behind `kbc`, wait for its self test and restore the repeat rate
+/// `typematic` (if set) and the LEDs `leds`.
+fn reset_keyboard<C: KbdController>(kbc: &mut C, typematic: Option<u8>, leds: u8) -> Result<(), KbdError> {
+    send_command(kbc, KBD_CMD_RESET, None)?;
+
+    match wait_for_reply(kbc, KBD_SELF_TEST_POLLS)? {
+        KBD_REPLY_SELF_TEST_PASSED => {}
+        reply => return Err(KbdError::SelfTestFailed(reply)),
+    }
+
+    if let Some(typematic) = typematic {
+        send_command(kbc, KBD_CMD_SET_SPEED, Some(typematic))?;
+    }
+    send_command(kbc, KBD_CMD_SET_LED, Some(leds))
+}
+
+/// Send a single byte to the keyboard and wait for the acknowledgement.
+fn send_byte<C: KbdController>(kbc: &mut C, byte: u8) -> Result<(), KbdError> {
+    for _ in 0..=KBD_MAX_RETRIES {
+        wait_for_input_buffer(kbc)?;
+        kbc.write_data(byte);
+
+        match wait_for_reply(kbc, KBD_TIMEOUT_POLLS)? {
+            KBD_REPLY_ACK => return Ok(()),
+            KBD_REPLY_RESEND => continue,
+            reply => return Err(KbdError::UnexpectedReply(reply)),
+        }
+    }
+
+    Err(KbdError::TooManyResends)
+}
+
+/// Wait until the input buffer of the keyboard controller is empty,
+/// so that the next byte can be written.
+fn wait_for_input_buffer<C: KbdController>(kbc: &mut C) -> Result<(), KbdError> {
+    for _ in 0..KBD_TIMEOUT_POLLS {
+        if kbc.status() & KBD_INPB == 0 {
+            return Ok(());
+        }
+    }
+    Err(KbdError::Timeout)
+}
+
+/// Wait for a reply byte from the keyboard (polling the status register up to `polls` times)
+/// and return it. Bytes of the mouse arriving meanwhile are discarded, they are no reply.
+fn wait_for_reply<C: KbdController>(kbc: &mut C, polls: usize) -> Result<u8, KbdError> {
+    for _ in 0..polls {
+        let status = kbc.status();
+        if status & KBD_OUTB != 0 {
+            let byte = kbc.read_data();
+            if status & KBD_AUXB == 0 {
+                return Ok(byte);
             }
         }
+    }
+    Err(KbdError::Timeout)
+}
 
-        // wait until command answer arrives
-        loop{
-            unsafe{
-                let status = self.control_port.inb();
-                if status & KBD_OUTB != 0 { 
-                    break;
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Self tests, run by the diagnostics (see `user::diag`).                  ║
+   ╚═════════════════════════════════════════════════════════════════════════╝ */
 
-                }
+pub mod selftest {
+    use alloc::vec::Vec;
+    use core::cell::Cell;
+    use crate::devices::console::{self, Capture};
+    use crate::devices::key::Key;
+    use crate::devices::keyboard::{self, DecoderState, DoubleTapDetector, KbdController, KbdError, KeyEvent, Keyboard, KeyboardLayout, LineBuffer, Mode};
+    use crate::kernel::{cpu, timer};
+    use crate::library::selftest::{restore_on_exit, Test, TestResult};
+
+    /// The self tests of this module, run in this order.
+    pub const TESTS: &[Test] = &[
+        ("Scancode decoder", test_decoder),
+        ("Layout switch", test_layout_switch),
+        ("Double tap", test_double_tap),
+        ("Double tap keys", test_double_tap_keys),
+        ("Command protocol", test_send_command),
+        ("Reset", test_reset),
+        ("Read with timeout", test_read_with_timeout),
+        ("Key click", test_key_click),
+        ("Key filter", test_key_filter),
+        ("Software repeat", test_soft_repeat),
+        ("Line buffer", test_line_buffer),
+        ("Key hit", test_key_hit),
+        ("Dump state", test_dump_state),
+    ];
+
+    /// A decoder test case: name, scancode bytes and the expected events (code, prefix, pressed).
+    type DecoderCase = (&'static str, &'static [u8], &'static [(u8, u8, bool)]);
+
+    /// Drive the scancode decoder through each prefix path and compare the emitted events
+    /// with the expected ones.
+    fn test_decoder() -> TestResult {
+        const CASES: [DecoderCase; 5] = [
+            ("plain key", &[0x1e, 0x9e], &[(0x1e, 0, true), (0x1e, 0, false)]),
+            ("extended key", &[0xe0, 0x48, 0xe0, 0xc8], &[(0x48, 0xe0, true), (0x48, 0xe0, false)]),
+            ("print screen", &[0xe0, 0x2a, 0xe0, 0x37], &[(0x2a, 0xe0, true), (0x37, 0xe0, true)]),
+            ("pause", &[0xe1, 0x1d, 0x45, 0xe1, 0x9d, 0xc5],
+                &[(0x1d, 0xe1, true), (0x45, 0, true), (0x1d, 0xe1, false), (0x45, 0, false)]),
+            ("prefix only", &[0xe0], &[]),
+        ];
+
+        for (name, bytes, expected) in CASES {
+            let mut decoder = DecoderState::new();
+            let mut events: Vec<KeyEvent> = Vec::new();
+            for byte in bytes {
+                events.extend(decoder.step(*byte));
+            }
+
+            let expected = expected.iter().map(|&(code, prefix, pressed)| KeyEvent { code, prefix, pressed });
+            if !events.iter().copied().eq(expected) {
+                kprintln!("decoder: wrong events for {}: {:?}", name, events);
+                return Err("wrong events");
             }
         }
-        
-        unsafe {
-            let answer = self.data_port.inb();
-            if answer != 0xfa { // command not accepted
-                return -1
+        Ok(())
+    }
+
+    /// Feed Ctrl+Alt+Space to a separate keyboard decoder repeatedly and check that the layouts
+    /// are cycled in order, that the combo is not delivered as key and that the scancode of
+    /// 'Z' on a German keyboard is translated according to the active layout.
+    fn test_layout_switch() -> TestResult {
+        const COMBO_PRESS: [u8; 3] = [0x1d, 0x38, 0x39]; // Ctrl, Alt, Space
+        const COMBO_RELEASE: [u8; 3] = [0xb9, 0xb8, 0x9d];
+        const SCAN_Z: u8 = 0x15;
+
+        let saved = keyboard::layout();
+        let _restore = restore_on_exit(|| keyboard::set_layout(saved));
+        let mut kbd = Keyboard::new();
+        let mut expected = saved;
+        for _ in 0..KeyboardLayout::ALL.len() {
+            expected = expected.next();
+            if COMBO_PRESS.iter().chain(COMBO_RELEASE.iter()).any(|&b| kbd.decode_byte(b).is_some()) {
+                return Err("combo delivered as key");
+            }
+            if keyboard::layout() != expected {
+                return Err("layouts not cycled in order");
+            }
+            let ascii = kbd.decode_byte(SCAN_Z).map(|mut key| key.get_ascii());
+            kbd.decode_byte(SCAN_Z | 0x80);
+            let wanted = if expected == KeyboardLayout::Us { b'y' } else { b'z' };
+            if ascii != Some(wanted) {
+                return Err("key not translated with the new layout");
             }
         }
-        
 
-        // set repeat rate
-        let command = 0x00 | led & on as u8;
+        if expected != saved {
+            return Err("cycle did not return to the first layout");
+        }
+        Ok(())
+    }
 
-        unsafe{
-            self.data_port.outb(command);
+    /// Feed presses of Shift with simulated times to a double-tap detector: two presses inside
+    /// the window must be a double tap, two presses outside it not, and neither a press held
+    /// down (hardware repeat) nor a third press right after a double tap.
+    fn test_double_tap() -> TestResult {
+        const WINDOW_MS: u64 = 300;
+        const SHIFT: KeyEvent = KeyEvent { code: 0x2a, prefix: 0, pressed: true };
+        const SHIFT_UP: KeyEvent = KeyEvent { code: 0x2a, prefix: 0, pressed: false };
+
+        let mut detector = DoubleTapDetector::new(WINDOW_MS);
+        detector.feed(SHIFT, 0);
+        detector.feed(SHIFT_UP, 50);
+        if !detector.feed(SHIFT, 200) {
+            return Err("presses inside the window not detected");
+        }
+        detector.feed(SHIFT_UP, 250);
+        if detector.feed(SHIFT, 300) {
+            return Err("third press detected as double tap");
         }
 
-        // wait until command answer arrives
-        loop{
-            unsafe{
-                let status = self.control_port.inb();
-                if status & KBD_OUTB != 0 { 
-                    break;
-                    }
+        detector.feed(SHIFT_UP, 2050);
+        detector.feed(SHIFT, 2000 + WINDOW_MS + 1);
+        detector.feed(SHIFT_UP, 2500);
+        if detector.feed(SHIFT, 2000 + 3 * WINDOW_MS) {
+            return Err("presses outside the window detected");
+        }
+
+        detector.feed(SHIFT_UP, 3500);
+        detector.feed(SHIFT, 4000);
+        if detector.feed(SHIFT, 4100) {
+            return Err("held key detected as double tap");
+        }
+        Ok(())
+    }
+
+    /// Decode two presses of Shift and of 'A' with double-tap detection enabled: the first
+    /// press of 'A' must be delivered unmarked and the second ones marked as double tap,
+    /// the one of Shift with ASCII code 0 and its scancode.
+    fn test_double_tap_keys() -> TestResult {
+        const SHIFT: u8 = 0x2a;
+        const SCAN_A: u8 = 0x1e;
+
+        let saved = keyboard::double_tap_window();
+        let _restore = restore_on_exit(|| keyboard::set_double_tap_window(saved));
+        keyboard::set_double_tap_window(1000);
+        let mut kbd = Keyboard::new();
+        // Decode a byte and return the delivered key as (double tap, ASCII code, scancode).
+        let mut decode = |code: u8| {
+            kbd.decode_byte(code).map(|mut key| (key.is_double_tap(), key.get_ascii(), key.get_scancode()))
+        };
+
+        if decode(SHIFT).is_some() || decode(SHIFT | 0x80).is_some() {
+            return Err("single press of Shift delivered");
+        }
+        if decode(SHIFT) != Some((true, 0, SHIFT)) {
+            return Err("double tap of Shift not delivered");
+        }
+        decode(SHIFT | 0x80);
+
+        if decode(SCAN_A) != Some((false, b'a', SCAN_A)) {
+            return Err("single press not delivered unmarked");
+        }
+        decode(SCAN_A | 0x80);
+        if decode(SCAN_A) != Some((true, b'a', SCAN_A)) {
+            return Err("second press not marked");
+        }
+        decode(SCAN_A | 0x80);
+        Ok(())
+    }
+
+    /// A controller that answers with the given replies (byte, from the mouse) in order.
+    struct MockKbc {
+        replies: &'static [(u8, bool)],
+        next: usize,
+        written: Vec<u8>,
+    }
 
+    impl KbdController for MockKbc {
+        fn status(&mut self) -> u8 {
+            match self.replies.get(self.next) {
+                Some(&(_, true)) => keyboard::KBD_OUTB | keyboard::KBD_AUXB,
+                Some(&(_, false)) => keyboard::KBD_OUTB,
+                None => 0,
             }
         }
-        
-        unsafe {
-        let answer = self.data_port.inb();
-            if answer != 0xfa { // command not accepted
-                return -1
+
+        fn read_data(&mut self) -> u8 {
+            let (byte, _) = self.replies[self.next];
+            self.next += 1;
+            byte
+        }
+
+        fn write_data(&mut self, b: u8) {
+            self.written.push(b);
+        }
+    }
+
+    /// Send commands to a mocked keyboard controller: a byte that is acknowledged at once, a
+    /// byte whose first transfer is answered with RESEND (and a mouse byte in between), and a
+    /// byte that is never answered.
+    fn test_send_command() -> TestResult {
+        const ACK: u8 = 0xfa;
+        const RESEND: u8 = 0xfe;
+        const CMD: u8 = 0xf4;
+        type Case = (&'static str, &'static [(u8, bool)], Result<(), KbdError>, &'static [u8]);
+        const CASES: [Case; 3] = [
+            ("ack", &[(ACK, false)], Ok(()), &[CMD]),
+            ("resend", &[(RESEND, false), (0x08, true), (ACK, false)], Ok(()), &[CMD, CMD]),
+            ("timeout", &[], Err(KbdError::Timeout), &[CMD]),
+        ];
+
+        for (name, replies, expected, written) in CASES {
+            let mut kbc = MockKbc { replies, next: 0, written: Vec::new() };
+            if keyboard::send_command(&mut kbc, CMD, None) != expected {
+                kprintln!("send command: wrong result for {}", name);
+                return Err("wrong result");
+            }
+            if kbc.written != written {
+                kprintln!("send command: wrong bytes written for {}: {:?}", name, kbc.written);
+                return Err("wrong bytes written");
+            }
+        }
+        Ok(())
+    }
+
+    /// Reset a mocked keyboard: a passed self test must be followed by restoring the repeat
+    /// rate and the LEDs, each acknowledged, while a failed one must stop after the reset command.
+    fn test_reset() -> TestResult {
+        const ACK: u8 = 0xfa;
+        const PASSED: u8 = 0xaa;
+        const FAILED: u8 = 0xfc;
+        const TYPEMATIC: u8 = 0x20;
+        const LEDS: u8 = 0x02;
+        type Case = (&'static str, &'static [(u8, bool)], Option<u8>, Result<(), KbdError>, &'static [u8]);
+        const CASES: [Case; 3] = [
+            ("passed", &[(ACK, false), (PASSED, false), (ACK, false), (ACK, false), (ACK, false), (ACK, false)],
+                Some(TYPEMATIC), Ok(()), &[0xff, 0xf3, TYPEMATIC, 0xed, LEDS]),
+            ("default rate", &[(ACK, false), (PASSED, false), (ACK, false), (ACK, false)],
+                None, Ok(()), &[0xff, 0xed, LEDS]),
+            ("failed", &[(ACK, false), (FAILED, false)], Some(TYPEMATIC), Err(KbdError::SelfTestFailed(FAILED)), &[0xff]),
+        ];
+
+        for (name, replies, typematic, expected, written) in CASES {
+            let mut kbc = MockKbc { replies, next: 0, written: Vec::new() };
+            if keyboard::reset_keyboard(&mut kbc, typematic, LEDS) != expected {
+                kprintln!("reset: wrong result for {}", name);
+                return Err("wrong result");
+            }
+            if kbc.written != written || kbc.next != replies.len() {
+                kprintln!("reset: wrong bytes exchanged for {}: {:?}", name, kbc.written);
+                return Err("wrong bytes exchanged");
             }
         }
+        Ok(())
+    }
+
+    /// Wait for a key with a mocked timer, which advances by 10ms on each wait: a key injected
+    /// after 30ms must be returned right away, and without a key, the wait must end as soon as
+    /// the timeout has elapsed.
+    fn test_read_with_timeout() -> TestResult {
+        const TIMEOUT_MS: u64 = 100;
+        const STEP_MS: u64 = 10;
+        const KEY_AT_MS: u64 = 30;
+
+        let clock = Cell::new(0);
+        let now = || clock.get();
+        let wait = || clock.set(clock.get() + STEP_MS);
+
+        let injected = || (clock.get() >= KEY_AT_MS).then(|| Key::new(b'a', 0x1e, 0));
+        let key = keyboard::wait_for_key(TIMEOUT_MS, now, injected, wait);
+        if key.map(|mut key| key.get_ascii()) != Some(b'a') || clock.get() != KEY_AT_MS {
+            return Err("injected key not returned in time");
+        }
 
-        return 0
+        clock.set(0);
+        if keyboard::wait_for_key(TIMEOUT_MS, now, || None, wait).is_some() {
+            return Err("key returned without a key press");
+        }
+        if clock.get() != TIMEOUT_MS {
+            return Err("wait did not end when the timeout elapsed");
+        }
+        Ok(())
+    }
+
+    /// Deliver a key with the key click enabled and check that a click has been requested,
+    /// then check that no click is requested with the key click disabled.
+    fn test_key_click() -> TestResult {
+        let enabled = keyboard::key_click_enabled();
+        let _restore = restore_on_exit(|| keyboard::set_key_click(enabled));
+        let key = Key::new(b'a', 0x1e, 0);
+
+        let clicks = keyboard::key_clicks();
+        keyboard::set_key_click(true);
+        cpu::without_interrupts(|| keyboard::deliver_key(key));
+        let enabled_clicks = keyboard::key_clicks();
+        keyboard::set_key_click(false);
+        cpu::without_interrupts(|| keyboard::deliver_key(key));
+        let disabled_clicks = keyboard::key_clicks();
+
+        // Take the delivered keys from the key buffer again.
+        keyboard::get_key_buffer().get_last_key();
+        keyboard::get_key_buffer().get_last_key();
+
+        if enabled_clicks != clicks + 1 {
+            return Err("no click on key delivery");
+        }
+        if disabled_clicks != enabled_clicks {
+            return Err("click while disabled");
+        }
+        Ok(())
+    }
+
+    /// Install a filter remapping A to B and decode a press of A and of C: the remapped key
+    /// must be translated as B, the other one must pass unchanged.
+    fn test_key_filter() -> TestResult {
+        const SCAN_A: u8 = 0x1e;
+        const SCAN_B: u8 = 0x30;
+        const SCAN_C: u8 = 0x2e;
+
+        fn a_to_b(event: KeyEvent) -> Option<KeyEvent> {
+            match event.code {
+                SCAN_A => Some(KeyEvent { code: SCAN_B, ..event }),
+                _ => Some(event),
+            }
+        }
+
+        let saved = *keyboard::FILTER.lock();
+        let _restore = restore_on_exit(|| cpu::without_interrupts(|| *keyboard::FILTER.lock() = saved));
+        keyboard::set_filter(a_to_b);
+
+        let mut kbd = Keyboard::new();
+        for (code, wanted) in [(SCAN_A, b'b'), (SCAN_C, b'c')] {
+            let ascii = kbd.decode_byte(code).map(|mut key| key.get_ascii());
+            kbd.decode_byte(code | 0x80);
+            if ascii != Some(wanted) {
+                return Err("key not remapped by the filter");
+            }
+        }
+        Ok(())
+    }
+
+    /// Hold a key with the software repeat enabled and drive `repeat_tick()` with synthetic
+    /// times: the first repeat must come after the delay, the next ones after each interval,
+    /// and none after the key has been released.
+    fn test_soft_repeat() -> TestResult {
+        const DELAY_MS: u64 = 500;
+        const INTERVAL_MS: u64 = 100;
+        // Far beyond the uptime, so the timer ISR never finds a repeat due.
+        const START_MS: u64 = u64::MAX / 2;
+        const SCAN_A: u8 = 0x1e;
+        // Synthetic tick (ms since the press) and the number of repeats expected after it.
+        const TICKS: [(u64, usize); 6] = [(0, 0), (499, 0), (500, 1), (599, 1), (600, 2), (700, 3)];
+        const RELEASED_MS: u64 = 1000;
+
+        let saved = cpu::without_interrupts(|| {
+            let repeat = keyboard::SOFT_REPEAT.lock();
+            (repeat.enabled, repeat.delay_ms, repeat.interval_ms)
+        });
+        let mode = keyboard::get_mode();
+        let _restore = restore_on_exit(|| {
+            let (enabled, delay_ms, interval_ms) = saved;
+            if enabled {
+                keyboard::set_software_repeat(delay_ms, interval_ms);
+            } else {
+                keyboard::disable_software_repeat();
+            }
+            keyboard::set_mode(mode);
+        });
+        keyboard::set_mode(keyboard::Mode::Raw);
+        keyboard::set_software_repeat(DELAY_MS, INTERVAL_MS);
+
+        let key = Key::new(b'a', SCAN_A, 0);
+        cpu::without_interrupts(|| keyboard::SOFT_REPEAT.lock().press(0, SCAN_A, key, START_MS));
+        let presses = keyboard::key_presses();
+        let repeats = |now| {
+            cpu::without_interrupts(|| keyboard::repeat_tick(now));
+            keyboard::key_presses() - presses
+        };
+
+        let mut result = Ok(());
+        for (ms, expected) in TICKS {
+            if repeats(START_MS + ms) != expected {
+                kprintln!("soft repeat: {} repeats expected {}ms after the press", expected, ms);
+                result = Err("wrong number of repeats");
+                break;
+            }
+        }
+        cpu::without_interrupts(|| keyboard::SOFT_REPEAT.lock().release(0, SCAN_A));
+        if result.is_ok() && repeats(START_MS + RELEASED_MS) != TICKS[TICKS.len() - 1].1 {
+            result = Err("repeat after release");
+        }
+
+        // Take the repeated keys from the key buffer again.
+        for _ in presses..keyboard::key_presses() {
+            keyboard::get_key_buffer().get_last_key();
+        }
+        result
+    }
+
+    /// Feed keys to a line buffer: in raw mode, each key must be in the key buffer at once.
+    /// In cooked mode, nothing may be delivered before return, then the line edited with
+    /// backspace (including the return key). Keys typed before the test are discarded.
+    fn test_line_buffer() -> TestResult {
+        let key = |ascii: u8| Key::new(ascii, 0, 0);
+        let take = || keyboard::get_key_buffer().get_last_key().map(|mut key| key.get_ascii());
+
+        cpu::without_interrupts(|| {
+            while take().is_some() {}
+            let mut line = LineBuffer::new();
+
+            line.feed(key(b'a'));
+            if take() != Some(b'a') || take().is_some() {
+                return Err("raw key not delivered at once");
+            }
+
+            line.mode = Mode::Cooked;
+            for ascii in [b'a', b'x', keyboard::ASCII_BACKSPACE, b'b'] {
+                line.feed(key(ascii));
+            }
+            if keyboard::get_key_buffer().len() != 0 {
+                return Err("cooked keys delivered before return");
+            }
+            line.feed(key(keyboard::ASCII_RETURN));
+            for expected in [b'a', b'b', keyboard::ASCII_RETURN] {
+                if take() != Some(expected) {
+                    return Err("wrong line delivered on return");
+                }
+            }
+            if take().is_some() {
+                return Err("wrong line delivered on return");
+            }
+            Ok(())
+        })
+    }
+
+    /// Scancodes of A and B (press and release), injected by `inject_keys()`.
+    const INJECTED: [u8; 4] = [0x1e, 0x9e, 0x30, 0xb0];
+
+    /// Decode `INJECTED` like the keyboard ISR does. Called by an alarm.
+    fn inject_keys() {
+        cpu::without_interrupts(|| {
+            let mut kbd = keyboard::KEYBOARD.lock();
+            for code in INJECTED {
+                if let Some(key) = kbd.decode_byte(code) {
+                    keyboard::deliver_key(key);
+                }
+            }
+        });
+    }
+
+    /// Wait with `keyboard::key_hit()` while an alarm injects two keys through the `KEYBOARD`
+    /// lock: the wait must not deadlock and both keys must be returned in order.
+    /// Keys typed before the test are discarded.
+    fn test_key_hit() -> TestResult {
+        const ALARM_MS: u64 = 20;
+
+        let mode = keyboard::get_mode();
+        let _restore = restore_on_exit(|| keyboard::set_mode(mode));
+        keyboard::set_mode(Mode::Raw);
+        while keyboard::get_key_buffer().get_last_key().is_some() {}
+
+        timer::set_alarm(ALARM_MS, inject_keys).map_err(|_| "alarm table full")?;
+        for expected in *b"ab" {
+            if keyboard::key_hit().get_ascii() != expected {
+                return Err("wrong key returned");
+            }
+        }
+        Ok(())
+    }
+
+    /// Console capturing the debug output of the tests.
+    static CAPTURE: Capture = Capture::new();
+
+    /// Set known modifiers, locks and LEDs, dump the state to a capture console and check
+    /// that the dump shows them.
+    fn test_dump_state() -> TestResult {
+        const LEDS: u8 = 0x04;
+        const EXPECTED: [&[u8]; 2] = [
+            b"Modifiers: shift=true ctrl_left=true ctrl_right=false alt_left=false alt_right=true\n",
+            b"Locks: caps=true num=false scroll=false, LEDs: 0x04\n",
+        ];
+
+        let (gather, leds) = cpu::without_interrupts(|| {
+            let kbd = keyboard::KEYBOARD.lock();
+            (kbd.gather, kbd.leds)
+        });
+        let previous = console::debug();
+        let _restore = restore_on_exit(|| {
+            console::set_debug(previous);
+            cpu::without_interrupts(|| {
+                let mut kbd = keyboard::KEYBOARD.lock();
+                kbd.gather = gather;
+                kbd.leds = leds;
+            });
+        });
+        cpu::without_interrupts(|| {
+            let mut kbd = keyboard::KEYBOARD.lock();
+            kbd.gather.set_shift(true);
+            kbd.gather.set_ctrl_left(true);
+            kbd.gather.set_ctrl_right(false);
+            kbd.gather.set_alt_left(false);
+            kbd.gather.set_alt_right(true);
+            kbd.gather.set_caps_lock(true);
+            kbd.gather.set_num_lock(false);
+            kbd.gather.set_scroll_lock(false);
+            kbd.leds = LEDS;
+        });
+
+        CAPTURE.take();
+        console::set_debug(&CAPTURE);
+        keyboard::dump_state();
+        console::set_debug(previous);
+
+        let dump = CAPTURE.take();
+        for line in EXPECTED {
+            if !dump.as_bytes().windows(line.len()).any(|window| window == line) {
+                return Err("injected state not in the dump");
+            }
+        }
+        Ok(())
     }
 }
@@ -6,6 +6,7 @@
    ║ Author: Michael Schoetter, Univ. Duesseldorf, 7.3.2023                  ║
    ╚═════════════════════════════════════════════════════════════════════════╝
 */
+use crate::devices::console;
 use crate::devices::serial;
 use core::fmt;
 use core::fmt::Write;
@@ -55,6 +56,7 @@ macro_rules! kprintln {
 }
 
 /// Helper function of print macros (must be public)
+/// Writes to the debug console (see `console::set_debug`).
 pub fn kprint(args: fmt::Arguments) {
-    WRITER.lock().write_fmt(args).unwrap();
+    console::kprint(args);
 }
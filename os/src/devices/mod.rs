@@ -4,8 +4,13 @@ pub mod kprint;
 #[macro_use]
 pub mod cga_print;
 pub mod cga;
+pub mod console;
 pub mod keyboard;
+pub mod mouse;
 pub mod key;
 pub mod serial;
 pub mod pcspk;
+pub mod rtc;
+pub mod theme;
+pub mod vga;
 
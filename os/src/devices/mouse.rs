@@ -0,0 +1,393 @@
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Module: mouse                                                           ║
+   ╟─────────────────────────────────────────────────────────────────────────╢
+   ║ Descr.: PS/2 mouse driver. The auxiliary device of the 8042 controller  ║
+   ║         sends 3-byte movement packets on IRQ12, which are decoded into  ║
+   ║         the position and the button state of the mouse. Optionally, the ║
+   ║         position is shown as software cursor on the CGA screen.         ║
+   ╚═════════════════════════════════════════════════════════════════════════╝
+*/
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use crate::devices::cga;
+use crate::devices::keyboard::{KBD_AUXB, KBD_INPB, KBD_OUTB};
+use crate::kernel::cpu;
+use crate::kernel::cpu::IoPort;
+use crate::kernel::init::StartupError;
+use crate::kernel::softirq;
+use crate::kernel::interrupts::intdispatcher::{self, InterruptVector};
+use crate::kernel::interrupts::isr::ISR;
+use crate::kernel::interrupts::pic::{Irq, PIC};
+
+// Keyboard controller IO-ports (shared with the keyboard)
+const KBC_CTRL_PORT: u16 = 0x64;
+const KBC_DATA_PORT: u16 = 0x60;
+
+// Keyboard controller commands
+const KBC_CMD_READ_CONFIG: u8 = 0x20;
+const KBC_CMD_WRITE_CONFIG: u8 = 0x60;
+const KBC_CMD_ENABLE_AUX: u8 = 0xa8;
+const KBC_CMD_WRITE_AUX: u8 = 0xd4;
+
+// Bits in the command byte of the keyboard controller
+const CONFIG_AUX_IRQ: u8 = 0x02;
+const CONFIG_AUX_CLOCK_DISABLED: u8 = 0x20;
+
+// Mouse commands
+const MOUSE_CMD_SET_SAMPLE_RATE: u8 = 0xf3;
+const MOUSE_CMD_ENABLE_REPORTING: u8 = 0xf4;
+const MOUSE_CMD_SET_DEFAULTS: u8 = 0xf6;
+
+// Mouse replies
+const MOUSE_REPLY_ACK: u8 = 0xfa;
+
+/// Number of movement packets per second sent by the mouse.
+const SAMPLE_RATE: u8 = 40;
+
+// Number of status register polls before waiting for the controller is given up
+const KBC_TIMEOUT_POLLS: usize = 100_000;
+
+// Bits in the first byte of a movement packet
+const PACKET_LEFT: u8 = 0x01;
+const PACKET_RIGHT: u8 = 0x02;
+const PACKET_MIDDLE: u8 = 0x04;
+const PACKET_ALWAYS_ONE: u8 = 0x08;
+const PACKET_X_SIGN: u8 = 0x10;
+const PACKET_Y_SIGN: u8 = 0x20;
+const PACKET_X_OVERFLOW: u8 = 0x40;
+const PACKET_Y_OVERFLOW: u8 = 0x80;
+
+/// Movement (in mickeys) needed to move the position by one column or row.
+const MICKEYS_PER_COLUMN: i32 = 8;
+const MICKEYS_PER_ROW: i32 = 16;
+
+/// Global mouse instance.
+static MOUSE: Mutex<Mouse> = Mutex::new(Mouse::new());
+
+/// Set while the position is shown as software cursor on the CGA screen.
+static SHOW_CURSOR: AtomicBool = AtomicBool::new(false);
+
+/// Errors that can occur when initializing the mouse.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MouseError {
+    /// The keyboard controller or the mouse did not react in time.
+    Timeout,
+    /// The mouse replied with an unexpected byte.
+    UnexpectedReply(u8),
+}
+
+/// One decoded movement packet. `dy` is positive for a movement up (away from the user).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Packet {
+    pub dx: i16,
+    pub dy: i16,
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool,
+}
+
+/// Decode the 3-byte movement packet `bytes`.
+/// Returns `None` if the first byte is not a valid first packet byte or if the movement overflowed.
+pub fn decode(bytes: [u8; 3]) -> Option<Packet> {
+    let flags = bytes[0];
+    if flags & PACKET_ALWAYS_ONE == 0 || flags & (PACKET_X_OVERFLOW | PACKET_Y_OVERFLOW) != 0 {
+        return None;
+    }
+
+    // The sign bits are the 9th bit of the two's complement movement values
+    let dx = bytes[1] as i16 - (((flags & PACKET_X_SIGN) as i16) << 4);
+    let dy = bytes[2] as i16 - (((flags & PACKET_Y_SIGN) as i16) << 3);
+
+    Some(Packet {
+        dx,
+        dy,
+        left: flags & PACKET_LEFT != 0,
+        right: flags & PACKET_RIGHT != 0,
+        middle: flags & PACKET_MIDDLE != 0,
+    })
+}
+
+/// Collects the bytes sent by the mouse into movement packets.
+/// A byte without the always-one bit cannot start a packet and is dropped,
+/// so that the reader synchronizes to the packet boundaries again after a lost byte.
+pub struct PacketReader {
+    bytes: [u8; 3],
+    count: usize,
+    /// Number of bytes and packets dropped to resynchronize
+    dropped: usize,
+}
+
+impl PacketReader {
+    pub const fn new() -> PacketReader {
+        PacketReader { bytes: [0; 3], count: 0, dropped: 0 }
+    }
+
+    /// Add the next byte and return the packet if it is complete.
+    pub fn push(&mut self, byte: u8) -> Option<Packet> {
+        if self.count == 0 && byte & PACKET_ALWAYS_ONE == 0 {
+            self.dropped += 1;
+            return None;
+        }
+
+        self.bytes[self.count] = byte;
+        self.count += 1;
+        if self.count < self.bytes.len() {
+            return None;
+        }
+
+        self.count = 0;
+        let packet = decode(self.bytes);
+        if packet.is_none() {
+            self.dropped += 1;
+        }
+        packet
+    }
+
+    /// Return the number of bytes and packets dropped to resynchronize.
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+}
+
+/// Position and button state of the mouse.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct MouseState {
+    /// Position on the screen (column and row), starting in the top left corner
+    pub column: usize,
+    pub row: usize,
+    /// Movement of the last packet in mickeys (`dy` is positive for a movement up)
+    pub dx: i32,
+    pub dy: i32,
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool,
+    /// Number of packets received
+    pub packets: usize,
+}
+
+/// Represents the mouse attached to the auxiliary port of the keyboard controller.
+struct Mouse {
+    reader: PacketReader,
+    state: MouseState,
+    /// Position in mickeys, counted down from the top left corner
+    x: i32,
+    y: i32,
+    control_port: IoPort,
+    data_port: IoPort,
+}
+
+impl Mouse {
+    const fn new() -> Mouse {
+        Mouse {
+            reader: PacketReader::new(),
+            state: MouseState {
+                column: 0, row: 0, dx: 0, dy: 0,
+                left: false, right: false, middle: false, packets: 0,
+            },
+            x: 0,
+            y: 0,
+            control_port: IoPort::new(KBC_CTRL_PORT),
+            data_port: IoPort::new(KBC_DATA_PORT),
+        }
+    }
+
+    /// Enable the auxiliary device and its interrupt in the keyboard controller,
+    /// reset the mouse to its defaults, set the sample rate and enable reporting.
+    fn init(&mut self) -> Result<(), MouseError> {
+        self.send_controller(KBC_CMD_ENABLE_AUX)?;
+
+        self.send_controller(KBC_CMD_READ_CONFIG)?;
+        let config = self.read_data()?;
+        self.send_controller(KBC_CMD_WRITE_CONFIG)?;
+        self.write_data((config | CONFIG_AUX_IRQ) & !CONFIG_AUX_CLOCK_DISABLED)?;
+
+        self.send_mouse(MOUSE_CMD_SET_DEFAULTS)?;
+        self.send_mouse(MOUSE_CMD_SET_SAMPLE_RATE)?;
+        self.send_mouse(SAMPLE_RATE)?;
+        self.send_mouse(MOUSE_CMD_ENABLE_REPORTING)
+    }
+
+    /// Read a byte from the controller, if it is one sent by the mouse, and update the state
+    /// if it completes a packet. Returns true if the state has changed.
+    fn poll(&mut self) -> bool {
+        let status = unsafe { self.control_port.inb() };
+        if status & KBD_OUTB == 0 || status & KBD_AUXB == 0 {
+            return false;
+        }
+
+        let byte = unsafe { self.data_port.inb() };
+        match self.reader.push(byte) {
+            Some(packet) => {
+                self.apply(packet);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Move the position by the movement of `packet` (limited to the screen)
+    /// and take over its button state.
+    fn apply(&mut self, packet: Packet) {
+        let (columns, rows) = cga::screen_size();
+        self.x = (self.x + packet.dx as i32).clamp(0, columns as i32 * MICKEYS_PER_COLUMN - 1);
+        self.y = (self.y - packet.dy as i32).clamp(0, rows as i32 * MICKEYS_PER_ROW - 1);
+
+        self.state.column = (self.x / MICKEYS_PER_COLUMN) as usize;
+        self.state.row = (self.y / MICKEYS_PER_ROW) as usize;
+        self.state.dx = packet.dx as i32;
+        self.state.dy = packet.dy as i32;
+        self.state.left = packet.left;
+        self.state.right = packet.right;
+        self.state.middle = packet.middle;
+        self.state.packets += 1;
+    }
+
+    /// Send the command `cmd` to the keyboard controller.
+    fn send_controller(&mut self, cmd: u8) -> Result<(), MouseError> {
+        self.wait_for_input_buffer()?;
+        unsafe { self.control_port.outb(cmd); }
+        Ok(())
+    }
+
+    /// Write `byte` to the data port of the keyboard controller.
+    fn write_data(&mut self, byte: u8) -> Result<(), MouseError> {
+        self.wait_for_input_buffer()?;
+        unsafe { self.data_port.outb(byte); }
+        Ok(())
+    }
+
+    /// Send `byte` to the mouse and wait for the acknowledgement.
+    fn send_mouse(&mut self, byte: u8) -> Result<(), MouseError> {
+        self.send_controller(KBC_CMD_WRITE_AUX)?;
+        self.write_data(byte)?;
+        match self.read_data()? {
+            MOUSE_REPLY_ACK => Ok(()),
+            reply => Err(MouseError::UnexpectedReply(reply)),
+        }
+    }
+
+    /// Wait until the input buffer of the keyboard controller is empty,
+    /// so that the next byte can be written.
+    fn wait_for_input_buffer(&mut self) -> Result<(), MouseError> {
+        for _ in 0..KBC_TIMEOUT_POLLS {
+            if unsafe { self.control_port.inb() } & KBD_INPB == 0 {
+                return Ok(());
+            }
+        }
+        Err(MouseError::Timeout)
+    }
+
+    /// Wait for a byte in the output buffer of the keyboard controller and return it.
+    fn read_data(&mut self) -> Result<u8, MouseError> {
+        for _ in 0..KBC_TIMEOUT_POLLS {
+            if unsafe { self.control_port.inb() } & KBD_OUTB != 0 {
+                return Ok(unsafe { self.data_port.inb() });
+            }
+        }
+        Err(MouseError::Timeout)
+    }
+}
+
+/// Initialize the mouse and register the mouse interrupt handler.
+/// `INT_VECTORS` must have been initialized. If no mouse responds,
+/// a message is written to the serial port and the system runs without mouse.
+pub fn plugin() -> Result<(), StartupError> {
+    if !intdispatcher::INT_VECTORS.lock().is_initialized() {
+        return Err(StartupError::IntVectorsNotInitialized);
+    }
+
+    // The replies of the controller would otherwise trigger the keyboard interrupt.
+    if let Err(err) = cpu::without_interrupts(|| MOUSE.lock().init()) {
+        kprintln!("mouse: not available ({:?})", err);
+        return Ok(());
+    }
+
+    intdispatcher::INT_VECTORS.lock().register(InterruptVector::Mouse, Box::new(MouseISR {}));
+
+    // IRQ12 is an input of PIC 2, which is connected to IRQ2 of PIC 1.
+    let mut pic = PIC.lock();
+    pic.allow(Irq::Cascade);
+    pic.allow(Irq::Mouse);
+    Ok(())
+}
+
+/// Return the current position and button state of the mouse.
+pub fn state() -> MouseState {
+    // The mouse ISR also locks the mouse, so interrupts must be disabled.
+    cpu::without_interrupts(|| MOUSE.lock().state)
+}
+
+/// Show or hide the position of the mouse as software cursor on the CGA screen
+/// (see `cga::set_mouse_cursor()`). The cursor is moved with each packet.
+pub fn show_cursor(show: bool) {
+    SHOW_CURSOR.store(show, Ordering::Relaxed);
+    if show {
+        let state = state();
+        cga::set_mouse_cursor(state.column, state.row);
+    } else {
+        cga::hide_mouse_cursor();
+    }
+}
+
+/// Move the software cursor to the current position. Called as soft interrupt after a packet.
+fn update_cursor() {
+    if !SHOW_CURSOR.load(Ordering::Relaxed) {
+        return;
+    }
+    let state = state();
+
+    // The interrupted code may be using the screen. Then the cursor is moved with the next packet.
+    if let Some(mut cga) = cga::CGA.try_lock() {
+        cga.set_mouse_cursor(state.column, state.row);
+    }
+}
+
+/// The mouse interrupt service routine.
+pub struct MouseISR {}
+
+impl ISR for MouseISR {
+    fn trigger(&self) {
+        if MOUSE.lock().poll() && SHOW_CURSOR.load(Ordering::Relaxed) {
+            softirq::raise(update_cursor);
+        }
+    }
+}
+
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Self tests, run by the diagnostics (see `user::diag`).                  ║
+   ╚═════════════════════════════════════════════════════════════════════════╝ */
+
+pub mod selftest {
+    use crate::devices::mouse::{self, PacketReader};
+    use crate::library::selftest::{Test, TestResult};
+
+    /// The self tests of this module, run in this order.
+    pub const TESTS: &[Test] = &[
+        ("Packets", test_mouse_packets),
+    ];
+
+    /// Decode a known movement packet (left button, 5 right, 2 down) and feed it to a packet
+    /// reader after a stray byte, which must be dropped to find the start of the packet.
+    fn test_mouse_packets() -> TestResult {
+        const PACKET: [u8; 3] = [0x29, 0x05, 0xfe];
+
+        let expected = mouse::Packet { dx: 5, dy: -2, left: true, right: false, middle: false };
+        if mouse::decode(PACKET) != Some(expected) {
+            return Err("packet decoded wrong");
+        }
+        if mouse::decode([0x69, 0x05, 0xfe]).is_some() {
+            return Err("overflowed packet accepted");
+        }
+
+        let mut reader = PacketReader::new();
+        let mut decoded = None;
+        for byte in [0x05].iter().chain(PACKET.iter()) {
+            decoded = reader.push(*byte);
+        }
+        if decoded != Some(expected) || reader.dropped() != 1 {
+            return Err("reader not resynchronized");
+        }
+        Ok(())
+    }
+}
@@ -9,18 +9,30 @@
 */
 #![allow(dead_code)]
 
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
 use spin::Mutex;
+use crate::devices::keyboard;
 use crate::kernel::cpu;
 use crate::kernel::cpu::IoPort;
+use crate::kernel::pit::{self, ChannelConfig, PIT_BASE_FREQUENCY};
+use crate::library::fixed;
+use crate::kernel::timer;
 
 pub static SPEAKER: Mutex<Speaker> = Mutex::new(Speaker::new());
 
+/// Value of `STOP_KEY_PRESSES` while no interruptible playback is running.
+const NOT_INTERRUPTIBLE: usize = usize::MAX;
+
+/// Number of key presses when `play_interruptible()` started (see `keyboard::key_presses()`).
+static STOP_KEY_PRESSES: AtomicUsize = AtomicUsize::new(NOT_INTERRUPTIBLE);
+
 // Ports
-const PORT_CTRL: u16 = 0x43;
-const PORT_DATA0: u16 = 0x40;
-const PORT_DATA2: u16 = 0x42;
 const PORT_PPI: u16 = 0x61;
 
+// Bits of the PPI port
+const PPI_TIMER_GATE: u8 = 0x01;   // gate of PIT counter 2
+const PPI_SPEAKER_DATA: u8 = 0x02; // route counter 2 output to the speaker
+
 // Frequency of musical notes
 // (Our OS does not really support floating point, so we convert the numbers to usize)
 pub const C0: usize = 130.81 as usize;
@@ -59,126 +71,304 @@ pub const F2X: usize = 739.99 as usize;
 pub const G2: usize = 783.99 as usize;
 pub const G2X: usize = 830.61 as usize;
 pub const A2: usize = 880.00 as usize;
-pub const A2X: usize = 923.33 as usize;
+pub const A2X: usize = 932.33 as usize;
 pub const B2: usize = 987.77 as usize;
 pub const C3: usize = 1046.50 as usize;
 
+/// Frequency of C0 as fixed-point value (130.81 Hz).
+/// Used by `note_freq()` as base for computing the other notes.
+const C0_FIXED: u64 = 8572764;
+
+/// Highest note supported by `note_freq()`, counted in semitones from C0.
+/// The frequency of this note is still below 20 kHz.
+const NOTE_MAX_SEMITONE: usize = 87;
+
+/// Duration of a single note played by `chromatic_scale()` (milliseconds).
+const CHROMATIC_NOTE_DURATION: usize = 150;
+
+/// Names of the twelve notes within one octave.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(usize)]
+pub enum Note {
+    C = 0,
+    Cx = 1,
+    D = 2,
+    Dx = 3,
+    E = 4,
+    F = 5,
+    Fx = 6,
+    G = 7,
+    Gx = 8,
+    A = 9,
+    Ax = 10,
+    B = 11,
+}
+
+impl Note {
+    /// Return the number of semitones between C0 and this note in the given `octave`.
+    pub const fn semitone(self, octave: usize) -> usize {
+        octave * 12 + self as usize
+    }
+}
+
+/// Return the frequency of the note `semitone` semitones above C0.
+/// The frequency is computed as C0 * 2^(semitone/12) using fixed-point arithmetic
+/// and is accurate to 1 Hz. Returns `None` if the note is higher than `NOTE_MAX_SEMITONE`.
+pub fn note_freq(semitone: usize) -> Option<usize> {
+    if semitone > NOTE_MAX_SEMITONE {
+        return None;
+    }
+
+    Some(fixed::round(fixed::mul(C0_FIXED, fixed::pow2_twelfth(semitone))) as usize)
+}
+
+/// Pan value of the center position (see `play_panned()`), the default if no panning is wanted.
+pub const PAN_CENTER: u8 = 128;
+
+/// Length of one on/off period of a panned tone (milliseconds).
+const PAN_PERIOD_MS: usize = 10;
+
+/// Return the time in milliseconds the speaker is on during period `period` of `periods`
+/// periods of a panned tone (see `play_panned()`).
+/// The duty cycle moves linearly from `(255 - pan) / 255` in the first period
+/// to `pan / 255` in the last one, so it stays at about 50% for `PAN_CENTER`.
+pub fn pan_duty(pan: u8, period: usize, periods: usize) -> usize {
+    let (start, end) = ((255 - pan) as usize, pan as usize);
+    let last = periods.saturating_sub(1).max(1);
+    let period = period.min(last);
+
+    let level = (start * (last - period) + end * period) / last;
+    (level * PAN_PERIOD_MS + 127) / 255
+}
+
+/// Return the number of periods of a panned tone of `duration` milliseconds: the duration
+/// is rounded up to whole periods, so even a short tone is played for one period.
+pub fn pan_periods(duration: usize) -> usize {
+    duration.div_ceil(PAN_PERIOD_MS).max(1)
+}
+
+/// What `Speaker::play()` does with tones shorter than the minimum duration.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ShortTone {
+    /// Play the tone for the minimum duration
+    Extend = 0,
+    /// Skip the tone like a rest (frequency 0)
+    Rest = 1,
+}
+
+/// Minimum duration of a tone in milliseconds (0 = no minimum, see `set_min_duration()`).
+static MIN_DURATION: AtomicUsize = AtomicUsize::new(0);
+
+/// Handling of tones below the minimum duration (see `set_min_duration()`).
+static SHORT_TONE: AtomicU8 = AtomicU8::new(ShortTone::Extend as u8);
+
+/// Set the minimum duration of the tones played by `Speaker::play()` to `ms` milliseconds.
+/// Very short tones may be inaudible or just click, depending on the speaker; shorter tones
+/// are extended to the minimum or skipped like a rest, as selected by `short`.
+/// The default is 0, which plays all tones as requested.
+pub fn set_min_duration(ms: usize, short: ShortTone) {
+    MIN_DURATION.store(ms, Ordering::Relaxed);
+    SHORT_TONE.store(short as u8, Ordering::Relaxed);
+}
+
+/// Return the duration a tone of `duration` milliseconds is played for with the current
+/// minimum duration, or `None` if it is skipped like a rest (see `set_min_duration()`).
+pub fn effective_duration(duration: usize) -> Option<usize> {
+    let min = MIN_DURATION.load(Ordering::Relaxed);
+    if duration >= min {
+        return Some(duration);
+    }
+    match SHORT_TONE.load(Ordering::Relaxed) {
+        0 => Some(min),
+        _ => None,
+    }
+}
+
 pub struct Speaker {
-    pit_ctrl_port: IoPort,
-    pit_data0_port: IoPort,
-    pit_data2_port: IoPort,
     ppi_port: IoPort,
+    /// Frequency and duration of the last tone played (rests are not recorded).
+    last_tone: Option<(usize, usize)>,
+    /// Set while a click started by `start_click()` sounds; cleared when another tone starts.
+    clicking: bool,
 }
 
 impl Speaker {
     /// Create a new Speaker instance.
     pub const fn new() -> Self {
         Speaker {
-            pit_ctrl_port: IoPort::new(PORT_CTRL),
-            pit_data0_port: IoPort::new(PORT_DATA0),
-            pit_data2_port: IoPort::new(PORT_DATA2),
             ppi_port: IoPort::new(PORT_PPI),
+            last_tone: None,
+            clicking: false,
         }
     }
 
     /// Play a specific frequency for a given amount of time (milliseconds).
+    /// Does nothing if the interruptible playback has been stopped (see `play_interruptible()`).
+    /// Durations below the minimum duration are extended or skipped (see `set_min_duration()`).
     pub fn play(&mut self, frequency: usize, duration: usize) {
 
-        if frequency == 0 {
+        if frequency == 0 || stop_requested() {
             self.off();
             return;
         }
+        let Some(duration) = effective_duration(duration) else {
+            self.off();
+            return;
+        };
+
+        self.last_tone = Some((frequency, duration));
+        self.clicking = false;
     
-        let divisor = 1193182 / frequency;
-    
-        unsafe {
-            // Set PIT counter 2 to mode 3 (square wave generator)
-            self.pit_ctrl_port.outb(0b10110110); // Channel 2, Access: lobyte/hibyte, Mode 3, Binary
-    
-            // Send frequency divisor (lo-byte first, then hi-byte)
-            self.pit_data2_port.outb(divisor as u8);         // Low byte
-            self.pit_data2_port.outb((divisor >> 8) as u8);  // High byte
+        let divisor = PIT_BASE_FREQUENCY / frequency;
+
+        // Set PIT counter 2 to mode 3 (square wave generator)
+        pit::PIT.lock().set_channel2(ChannelConfig::new(3, divisor as u16));
     
-            // Turn the speaker on (enable bits 0 and 1 in PPI port)
-            let mut val = self.ppi_port.inb();
-            val |= 0x03; // Set bits 0 and 1
-            self.ppi_port.outb(val);
-        }
+        self.on();
     
         self.delay(duration);
         self.off();
 
     }
 
-    /// Turn on the speaker.
-    /// The played tone is dependent on counter 2 of the PIT.
-    pub fn on(&mut self) {
+    /// Start playing `frequency` without waiting; the tone sounds until `off()` is called.
+    /// A frequency of 0 turns the speaker off.
+    pub fn tone_on(&mut self, frequency: usize) {
+        self.clicking = false;
+        if frequency == 0 {
+            self.off();
+            return;
+        }
 
-        unsafe {
-            
-            let mut port_value = self.ppi_port.inb();
+        let divisor = PIT_BASE_FREQUENCY / frequency;
 
-            port_value |= 0b11;
+        // Set PIT counter 2 to mode 3 (square wave generator)
+        pit::PIT.lock().set_channel2(ChannelConfig::new(3, divisor as u16));
+        self.on();
+    }
 
-            self.ppi_port.outb(port_value);
+    /// Play `frequency` for `duration` milliseconds with a simulated panning,
+    /// from `pan` 0 (left) over `PAN_CENTER` to 255 (right).
+    /// There is only one speaker, so this is just a perceptual approximation:
+    /// the speaker is switched on and off in periods of `PAN_PERIOD_MS` and the duty cycle
+    /// (see `pan_duty()`) changes over time. Tones panned to the left fade out, tones panned
+    /// to the right fade in, and centered tones keep a constant, reduced volume.
+    /// The duration is rounded up to whole periods (see `pan_periods()`).
+    pub fn play_panned(&mut self, frequency: usize, duration: usize, pan: u8) {
+        if frequency == 0 || stop_requested() {
+            self.off();
+            return;
+        }
+
+        self.last_tone = Some((frequency, duration));
+        self.clicking = false;
+
+        let divisor = PIT_BASE_FREQUENCY / frequency;
+
+        // Set PIT counter 2 to mode 3 (square wave generator)
+        pit::PIT.lock().set_channel2(ChannelConfig::new(3, divisor as u16));
+
+        let periods = pan_periods(duration);
+        for period in 0..periods {
+            let on_ms = pan_duty(pan, period, periods);
+            if on_ms > 0 {
+                self.on();
+                self.delay(on_ms);
+            }
+            self.off();
+            self.delay(PAN_PERIOD_MS - on_ms);
         }
+    }
 
+    /// Return the frequency and duration of the last tone played, if any
+    /// (for `play()` with the duration after applying the minimum duration).
+    pub fn last_tone(&self) -> Option<(usize, usize)> {
+        self.last_tone
+    }
+
+    /// Play the last tone again with the same frequency and duration.
+    /// Does nothing if no tone has been played yet.
+    pub fn replay_last(&mut self) {
+        if let Some((frequency, duration)) = self.last_tone {
+            self.play(frequency, duration);
+        }
+    }
+
+    /// Turn on the speaker.
+    /// The played tone is dependent on counter 2 of the PIT.
+    pub fn on(&mut self) {
+        self.set_timer_gate(true);
+        self.set_speaker_data(true);
     }
 
     /// Turn off the speaker.
     pub fn off(&mut self) {
-        unsafe {
-            let mut val = self.ppi_port.inb();
-            val &= !0x03; // Clear bits 0 and 1
-            self.ppi_port.outb(val);
+        self.set_timer_gate(false);
+        self.set_speaker_data(false);
+    }
+
+    /// Start a short click with `frequency`, which is ended by `end_click()`.
+    /// Returns false without playing if the speaker is already on or the PIT is busy.
+    pub fn start_click(&mut self, frequency: usize) -> bool {
+        if self.is_on() || frequency == 0 {
+            return false;
         }
+        // Clicks are started by interrupt handlers, which must not wait for the PIT:
+        // the interrupted code may hold it for a whole delay (see `timer::pit_delay()`).
+        let Some(mut pit) = pit::PIT.try_lock() else {
+            return false;
+        };
+        pit.set_channel2(ChannelConfig::new(3, (PIT_BASE_FREQUENCY / frequency) as u16));
+        drop(pit);
 
+        self.on();
+        self.clicking = true;
+        true
     }
 
-    /// Return the current value of the PIT counter (16-bit).
-    /// Used by `delay()` to check if the counter has reached 0 or has been reloaded.
-    fn read_counter(&mut self) -> u16 {
-        let mut counter: u16 = 0;
-
-        
-        unsafe {
-            self.pit_ctrl_port.outb(0b0000_0000);
-            counter |= self.pit_data0_port.inb() as u16;
-            counter |= (self.pit_data0_port.inb() as u16) << 8;
+    /// End the click started by `start_click()`.
+    /// Does nothing if another tone has been started in the meantime.
+    pub fn end_click(&mut self) {
+        if self.clicking {
+            self.clicking = false;
+            self.off();
         }
+    }
 
-        counter
+    /// Check if the speaker is on (counter 2 is running and routed to the speaker).
+    pub fn is_on(&mut self) -> bool {
+        let val = unsafe { self.ppi_port.inb() };
+        val & (PPI_TIMER_GATE | PPI_SPEAKER_DATA) == PPI_TIMER_GATE | PPI_SPEAKER_DATA
+    }
+
+    /// Enable or disable the gate of counter 2 of the PIT (bit 0 of the PPI port).
+    /// With the gate enabled, counter 2 is running, even if its output is not routed to the speaker.
+    pub fn set_timer_gate(&mut self, enabled: bool) {
+        self.set_ppi_bit(PPI_TIMER_GATE, enabled);
+    }
+
+    /// Route the output of counter 2 of the PIT to the speaker (bit 1 of the PPI port).
+    pub fn set_speaker_data(&mut self, enabled: bool) {
+        self.set_ppi_bit(PPI_SPEAKER_DATA, enabled);
     }
-    
-    /// Wait for a given amount of time in milliseconds using counter 0 of the PIT.
-    /// Mode 2 (rate generator) with a reload value of 1193 (0x04a9) is used.
-    /// This means that the counter will count down from 1193 to 0 and then reload itself.
-    /// Counting from 1193 to 0 takes 1ms.
-    fn delay(&mut self, duration: usize) {
-
-        let reload_value: u16 = 1193;
-
-        for _ in 0..duration {
-            unsafe {
-                // Set channel 0 to mode 2 (rate generator), access mode: lobyte/hibyte
-                self.pit_ctrl_port.outb(0b0011_0100); // 00 (chan 0), 11 (lo/hi), 010 (mode 2), 0 (binary)
-
-                // Load reload value (lo byte first)
-                self.pit_data0_port.outb((reload_value & 0xFF) as u8);       // low byte
-                self.pit_data0_port.outb((reload_value >> 8) as u8);         // high byte
-            }
 
-            // Wait for counter to wrap around (when it reaches 0 and reloads)
-            let mut prev = self.read_counter();
-            loop {
-                let curr = self.read_counter();
-                if curr > prev {
-                    break; // PIT counter reloaded (wrapped around)
-                }
-                prev = curr;
+    /// Set or clear the given bit of the PPI port, leaving all other bits untouched.
+    fn set_ppi_bit(&mut self, bit: u8, enabled: bool) {
+        unsafe {
+            let mut val = self.ppi_port.inb();
+            if enabled {
+                val |= bit;
+            } else {
+                val &= !bit;
             }
+            self.ppi_port.outb(val);
         }
+    }
 
+    /// Wait for a given amount of time in milliseconds (see `timer::delay_ms()`).
+    pub fn delay(&mut self, duration: usize) {
+        timer::delay_ms(duration);
     }
 }
 
@@ -960,3 +1150,266 @@ pub fn aerodynamic() {
     speaker.play(880, 122);
     speaker.off();
 }
+
+/// Play a click of `frequency` for `ms` milliseconds without blocking, unless the speaker
+/// is busy (locked or already on) or the PIT is locked. The speaker is turned off again by a timer alarm,
+/// so the timer must be plugged in. Can be called from interrupt handlers.
+pub fn click(frequency: usize, ms: u64) {
+    // The speaker is locked while a tone is played, so it is never waited for here.
+    let Some(mut speaker) = SPEAKER.try_lock() else {
+        return;
+    };
+    if !speaker.start_click(frequency) {
+        return;
+    }
+    drop(speaker);
+
+    if timer::set_alarm(ms, click_off).is_err() {
+        click_off();
+    }
+}
+
+/// End the click. If the speaker is locked, this is tried again on the next tick.
+fn click_off() {
+    match SPEAKER.try_lock() {
+        Some(mut speaker) => speaker.end_click(),
+        None => {
+            timer::set_alarm(1, click_off).ok();
+        }
+    }
+}
+
+/// Run `song` (e.g. `zelda`) and stop it when a key is pressed, returning true if it was stopped.
+/// The key press is checked before each note: all following notes of the song are skipped,
+/// so the song returns after the note currently playing. The speaker is turned off afterwards.
+/// The key remains in the key buffer.
+pub fn play_interruptible(song: fn()) -> bool {
+    STOP_KEY_PRESSES.store(keyboard::key_presses(), Ordering::Relaxed);
+    song();
+    let stopped = stop_requested();
+    STOP_KEY_PRESSES.store(NOT_INTERRUPTIBLE, Ordering::Relaxed);
+
+    SPEAKER.lock().off();
+    stopped
+}
+
+/// Check if a key has been pressed during an interruptible playback.
+fn stop_requested() -> bool {
+    let start = STOP_KEY_PRESSES.load(Ordering::Relaxed);
+    start != NOT_INTERRUPTIBLE && keyboard::key_presses() != start
+}
+
+/// Play `frequency` for `duration` milliseconds panned to `pan` (see `Speaker::play_panned()`).
+pub fn play_panned(frequency: usize, duration: usize, pan: u8) {
+    SPEAKER.lock().play_panned(frequency, duration, pan);
+}
+
+/// Return the frequencies of `count` consecutive semitones, starting at `start_note`.
+/// The sequence ends early after the highest representable note (see `note_freq()`).
+pub fn chromatic_frequencies(start_note: usize, count: usize) -> impl Iterator<Item = usize> {
+    (start_note..start_note + count).map_while(note_freq)
+}
+
+/// Play `count` consecutive semitones, starting at `start_note` (see `Note::semitone()`).
+/// Playback stops early when the highest representable note has been played.
+/// Returns the number of notes actually played.
+pub fn chromatic_scale(start_note: usize, count: usize) -> usize {
+    let mut speaker = SPEAKER.lock();
+    let mut played = 0;
+
+    for frequency in chromatic_frequencies(start_note, count) {
+        speaker.play(frequency, CHROMATIC_NOTE_DURATION);
+        played += 1;
+    }
+
+    speaker.off();
+    played
+}
+
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Self tests, run by the diagnostics (see `user::diag`).                  ║
+   ╚═════════════════════════════════════════════════════════════════════════╝ */
+
+pub mod selftest {
+    use alloc::vec::Vec;
+    use crate::devices::pcspk::{self, ShortTone};
+    use crate::kernel::pit::{self, ChannelConfig, PIT_BASE_FREQUENCY};
+    use crate::kernel::timer;
+    use crate::library::selftest::{restore_on_exit, Test, TestResult};
+
+    /// The self tests of this module, run in this order.
+    pub const TESTS: &[Test] = &[
+        ("Min. tone duration", test_min_duration),
+        ("Pan duty cycle", test_pan_duty),
+        ("Chromatic scale", test_chromatic_scale),
+        ("Note frequencies", test_note_freq),
+        ("Replay last tone", test_replay_last),
+        ("Timer after delay", test_timer_after_delay),
+        ("PPI bits", test_ppi_bits),
+    ];
+
+    /// Frequency of the test tones.
+    const TONE_FREQ: usize = 440;
+
+    /// Play a tone shorter than the minimum duration and check that it has been extended,
+    /// then check that it is skipped with `ShortTone::Rest`.
+    fn test_min_duration() -> TestResult {
+        const MIN_MS: usize = 20;
+        const SHORT_MS: usize = 1;
+
+        let _restore = restore_on_exit(|| pcspk::set_min_duration(0, ShortTone::Extend));
+        pcspk::set_min_duration(MIN_MS, ShortTone::Extend);
+        let extended = {
+            let mut speaker = pcspk::SPEAKER.lock();
+            speaker.play(TONE_FREQ, SHORT_MS);
+            speaker.last_tone()
+        };
+        pcspk::set_min_duration(MIN_MS, ShortTone::Rest);
+        let skipped = pcspk::effective_duration(SHORT_MS);
+
+        if extended != Some((TONE_FREQ, MIN_MS)) {
+            return Err("short tone not extended");
+        }
+        if skipped.is_some() {
+            return Err("short tone not skipped");
+        }
+        Ok(())
+    }
+
+    /// Compute the duty cycle of panned tones over time: it must fall from a full period to
+    /// zero for a tone panned to the left, rise for one panned to the right and stay at about
+    /// half a period for a centered one. A tone shorter than a period must get one period.
+    fn test_pan_duty() -> TestResult {
+        const PERIODS: usize = 8;
+        const FULL: usize = 10; // PAN_PERIOD_MS
+
+        let duties = |pan| (0..PERIODS).map(move |period| pcspk::pan_duty(pan, period, PERIODS));
+        let left: Vec<usize> = duties(0).collect();
+        let right: Vec<usize> = duties(255).collect();
+
+        if left[0] != FULL || left[PERIODS - 1] != 0 || left.windows(2).any(|w| w[1] > w[0]) {
+            return Err("left tone does not fade out");
+        }
+        if right[0] != 0 || right[PERIODS - 1] != FULL || right.windows(2).any(|w| w[1] < w[0]) {
+            return Err("right tone does not fade in");
+        }
+        if duties(pcspk::PAN_CENTER).any(|duty| duty != FULL / 2) {
+            return Err("centered tone not constant");
+        }
+        if pcspk::pan_periods(1) != 1 || pcspk::pan_periods(FULL) != 1 || pcspk::pan_periods(FULL + 1) != 2 {
+            return Err("duration not rounded up to whole periods");
+        }
+        Ok(())
+    }
+
+    /// Compute the chromatic scale over the whole note range: the frequencies must increase
+    /// strictly from note to note, and the scale must end at the highest representable note.
+    fn test_chromatic_scale() -> TestResult {
+        let frequencies: Vec<usize> = pcspk::chromatic_frequencies(0, pcspk::NOTE_MAX_SEMITONE + 12).collect();
+
+        if frequencies.len() != pcspk::NOTE_MAX_SEMITONE + 1 {
+            return Err("scale not clamped at highest note");
+        }
+        if frequencies.windows(2).any(|pair| pair[1] <= pair[0]) {
+            return Err("frequencies not increasing");
+        }
+        Ok(())
+    }
+
+    /// Compute the frequencies of all tabulated notes with `note_freq()` and check that each
+    /// one is within 1 Hz of its constant (the constants are truncated to whole Hertz).
+    fn test_note_freq() -> TestResult {
+        const TABLE: [usize; 37] = [
+            pcspk::C0, pcspk::C0X, pcspk::D0, pcspk::D0X, pcspk::E0, pcspk::F0,
+            pcspk::F0X, pcspk::G0, pcspk::G0X, pcspk::A0, pcspk::A0X, pcspk::B0,
+            pcspk::C1, pcspk::C1X, pcspk::D1, pcspk::D1X, pcspk::E1, pcspk::F1,
+            pcspk::F1X, pcspk::G1, pcspk::G1X, pcspk::A1, pcspk::A1X, pcspk::B1,
+            pcspk::C2, pcspk::C2X, pcspk::D2, pcspk::D2X, pcspk::E2, pcspk::F2,
+            pcspk::F2X, pcspk::G2, pcspk::G2X, pcspk::A2, pcspk::A2X, pcspk::B2,
+            pcspk::C3,
+        ];
+
+        for (semitone, &expected) in TABLE.iter().enumerate() {
+            match pcspk::note_freq(semitone) {
+                Some(frequency) if frequency.abs_diff(expected) <= 1 => {}
+                Some(_) => return Err("frequency off by more than 1 Hz"),
+                None => return Err("tabulated note out of range"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Replay with a new speaker and check that nothing is played, then play a tone, program
+    /// counter 2 with another tone and check that the replay restores frequency and duration.
+    fn test_replay_last() -> TestResult {
+        const TONE_MS: usize = 10;
+        const OTHER_FREQ: usize = 2 * TONE_FREQ;
+
+        let mut speaker = pcspk::Speaker::new();
+        speaker.replay_last();
+        if speaker.last_tone().is_some() {
+            return Err("tone replayed before playing");
+        }
+
+        let mut speaker = pcspk::SPEAKER.lock();
+        speaker.play(TONE_FREQ, TONE_MS);
+        let played = speaker.last_tone();
+        pit::PIT.lock().set_channel2(ChannelConfig::new(3, (PIT_BASE_FREQUENCY / OTHER_FREQ) as u16));
+        speaker.replay_last();
+
+        if speaker.last_tone() != played || played != Some((TONE_FREQ, TONE_MS)) {
+            return Err("duration not reused");
+        }
+        if pit::PIT.lock().channel2() != Some(ChannelConfig::new(3, (PIT_BASE_FREQUENCY / TONE_FREQ) as u16)) {
+            return Err("frequency not reused");
+        }
+        Ok(())
+    }
+
+    /// Wait with the PIT as delay source, which borrows counter 0 from the system timer, and
+    /// check that its configuration is restored and the timer ticks again afterwards.
+    fn test_timer_after_delay() -> TestResult {
+        const DELAY_MS: usize = 5;
+        const TICK_POLLS: usize = 10_000_000;
+
+        let source = timer::delay_source();
+        let _restore = restore_on_exit(|| timer::set_delay_source(source));
+        timer::set_delay_source(timer::DelaySource::Pit);
+
+        let saved = pit::PIT.lock().channel0();
+        pcspk::SPEAKER.lock().delay(DELAY_MS);
+        if pit::PIT.lock().channel0() != saved {
+            return Err("counter 0 not restored");
+        }
+
+        let ticks = timer::get_ticks();
+        if !(0..TICK_POLLS).any(|_| timer::get_ticks() > ticks) {
+            return Err("timer not ticking after delay");
+        }
+        Ok(())
+    }
+
+    /// Toggle the timer gate and the speaker data bit one after another, read the PPI port
+    /// after each call and check that only the bit of the called method has changed.
+    fn test_ppi_bits() -> TestResult {
+        const BOTH: u8 = pcspk::PPI_TIMER_GATE | pcspk::PPI_SPEAKER_DATA;
+        type Setter = fn(&mut pcspk::Speaker, bool);
+        const STEPS: [(Setter, bool, u8); 4] = [
+            (pcspk::Speaker::set_timer_gate, true, pcspk::PPI_TIMER_GATE),
+            (pcspk::Speaker::set_speaker_data, true, BOTH),
+            (pcspk::Speaker::set_timer_gate, false, pcspk::PPI_SPEAKER_DATA),
+            (pcspk::Speaker::set_speaker_data, false, 0),
+        ];
+
+        let mut speaker = pcspk::SPEAKER.lock();
+        speaker.off();
+        for (set, enabled, expected) in STEPS {
+            set(&mut speaker, enabled);
+            if unsafe { speaker.ppi_port.inb() } & BOTH != expected {
+                speaker.off();
+                return Err("bit not toggled alone");
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,120 @@
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Module: rtc                                                             ║
+   ╟─────────────────────────────────────────────────────────────────────────╢
+   ║ Descr.: Reading the time from the real time clock (RTC) in the CMOS.    ║
+   ╚═════════════════════════════════════════════════════════════════════════╝
+*/
+use spin::Mutex;
+use crate::kernel::cpu;
+use crate::kernel::cpu::IoPort;
+
+/// Global RTC instance.
+pub static RTC: Mutex<Rtc> = Mutex::new(Rtc::new());
+
+// CMOS IO-ports
+const CMOS_INDEX_PORT: u16 = 0x70;
+const CMOS_DATA_PORT: u16 = 0x71;
+
+// CMOS registers
+const CMOS_REG_SECONDS: u8 = 0x00;
+const CMOS_REG_MINUTES: u8 = 0x02;
+const CMOS_REG_HOURS: u8 = 0x04;
+const CMOS_REG_STATUS_A: u8 = 0x0a;
+const CMOS_REG_STATUS_B: u8 = 0x0b;
+
+// Bits in the status registers
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 0x80;
+const STATUS_B_BINARY: u8 = 0x04;
+const STATUS_B_24_HOUR: u8 = 0x02;
+
+// Bit 7 of the index port disables the NMI
+const CMOS_NMI_DISABLE: u8 = 0x80;
+
+/// A time of day as read from the RTC.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Time {
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+/// Represents the real time clock.
+pub struct Rtc {
+    index_port: IoPort,
+    data_port: IoPort,
+}
+
+impl Rtc {
+    /// Create a new Rtc object.
+    pub const fn new() -> Rtc {
+        Rtc {
+            index_port: IoPort::new(CMOS_INDEX_PORT),
+            data_port: IoPort::new(CMOS_DATA_PORT),
+        }
+    }
+
+    /// Read the current time.
+    /// The registers are read while no update is in progress and read again until
+    /// two consecutive reads match, so the result is never a mix of old and new values.
+    pub fn read_time(&mut self) -> Time {
+        let mut time = self.read_raw();
+        loop {
+            let again = self.read_raw();
+            if again == time {
+                break;
+            }
+            time = again;
+        }
+
+        let status_b = self.read_register(CMOS_REG_STATUS_B);
+        if status_b & STATUS_B_BINARY == 0 {
+            time.second = bcd_to_binary(time.second);
+            time.minute = bcd_to_binary(time.minute);
+            // Bit 7 of the hour is the PM flag in 12 hour mode
+            time.hour = bcd_to_binary(time.hour & 0x7f) | (time.hour & 0x80);
+        }
+        if status_b & STATUS_B_24_HOUR == 0 && time.hour & 0x80 != 0 {
+            time.hour = ((time.hour & 0x7f) + 12) % 24;
+        }
+
+        time
+    }
+
+    /// Read the seconds of the current time.
+    /// Faster than `read_time()`, because only the seconds register is read.
+    pub fn read_seconds(&mut self) -> u8 {
+        while self.read_register(CMOS_REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 {}
+
+        let second = self.read_register(CMOS_REG_SECONDS);
+        if self.read_register(CMOS_REG_STATUS_B) & STATUS_B_BINARY == 0 {
+            bcd_to_binary(second)
+        } else {
+            second
+        }
+    }
+
+    /// Read the time registers without conversion, after waiting for a running update to finish.
+    fn read_raw(&mut self) -> Time {
+        while self.read_register(CMOS_REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 {}
+
+        Time {
+            hour: self.read_register(CMOS_REG_HOURS),
+            minute: self.read_register(CMOS_REG_MINUTES),
+            second: self.read_register(CMOS_REG_SECONDS),
+        }
+    }
+
+    /// Read a CMOS register.
+    /// Interrupts are disabled, because selecting and reading the register must not be interrupted.
+    fn read_register(&mut self, reg: u8) -> u8 {
+        cpu::without_interrupts(|| unsafe {
+            self.index_port.outb(CMOS_NMI_DISABLE | reg);
+            self.data_port.inb()
+        })
+    }
+}
+
+/// Convert a BCD encoded value to binary.
+fn bcd_to_binary(value: u8) -> u8 {
+    (value & 0x0f) + (value >> 4) * 10
+}
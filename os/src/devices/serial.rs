@@ -2,6 +2,7 @@ use core::fmt;
 use spin::Mutex;
 use crate::kernel::cpu;
 use crate::kernel::cpu::IoPort;
+use crate::devices::console::Console;
 
 #[allow(dead_code)]
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -13,19 +14,115 @@ pub enum ComBaseAddress {
     Com4 = 0x2e8,
 }
 
+// Offsets of the FIFO control and the line status register from the base address
+const FIFO_CONTROL_OFFSET: u16 = 2;
+const LINE_STATUS_OFFSET: u16 = 5;
+
+// FIFO control: enable the FIFOs and clear the receive and transmit FIFO
+const FCR_ENABLE_FIFO: u8 = 0x07;
+
+// Bit in the line status register set when the transmit holding register (or FIFO) is empty
+const LSR_THR_EMPTY: u8 = 0x20;
+
+// Bit in the line status register set when all output has been sent
+const LSR_TRANSMITTER_EMPTY: u8 = 0x40;
+
+/// Size of the transmit FIFO of a 16550 UART in bytes.
+pub const UART_FIFO_SIZE: usize = 16;
+
+// Number of line status polls before waiting for the transmitter is given up
+const DRAIN_TIMEOUT_POLLS: usize = 100_000;
+
 /// Struct representing a COM port
 pub struct ComPort {
     /// IO-port where output is written to
-    data_port: IoPort
+    data_port: IoPort,
+    /// IO-port of the line status register
+    line_status_port: IoPort,
+    /// IO-port of the FIFO control register
+    fifo_control_port: IoPort,
+    /// Strings are written in FIFO fills (see `set_write_combining()`)
+    write_combining: bool,
 }
 
 impl ComPort {
     /// Create a new COM port
     pub const fn new(base_addr: ComBaseAddress) -> ComPort {
         ComPort {
-            data_port: IoPort::new(base_addr as u16)
+            data_port: IoPort::new(base_addr as u16),
+            line_status_port: IoPort::new(base_addr as u16 + LINE_STATUS_OFFSET),
+            fifo_control_port: IoPort::new(base_addr as u16 + FIFO_CONTROL_OFFSET),
+            write_combining: false,
+        }
+    }
+
+    /// Enable or disable write combining: the transmit FIFO of the UART is enabled and
+    /// strings are written in blocks of up to `UART_FIFO_SIZE` bytes, each time the FIFO
+    /// is empty (see `write_fifo()`), instead of byte by byte.
+    pub fn set_write_combining(&mut self, enabled: bool) {
+        unsafe { self.fifo_control_port.outb(if enabled { FCR_ENABLE_FIFO } else { 0 }); }
+        self.write_combining = enabled;
+    }
+
+    /// Write a single byte to the COM port
+    pub fn write_byte(&mut self, b: u8) {
+        // Unsafe because we are writing to hardware.
+        // By only allowing enum-values in the constructor,
+        // we ensure that the port address is valid.
+        //
+        // Since we are using a mutable reference to the port,
+        // we can be sure that the port is not used by another thread.
+        unsafe { self.data_port.outb(b); }
+    }
+
+    /// Wait until all written bytes have been sent by the transmitter.
+    /// Gives up after `DRAIN_TIMEOUT_POLLS` polls (e.g. if there is no UART).
+    pub fn drain(&mut self) {
+        for _ in 0..DRAIN_TIMEOUT_POLLS {
+            if unsafe { self.line_status_port.inb() } & LSR_TRANSMITTER_EMPTY != 0 {
+                return;
+            }
+        }
+    }
+}
+
+/// The transmitter of a UART, as used by `write_fifo()`.
+pub trait Transmitter {
+    /// Read the line status register.
+    fn line_status(&mut self) -> u8;
+
+    /// Write a byte to the transmit holding register (or FIFO).
+    fn transmit(&mut self, b: u8);
+}
+
+impl Transmitter for ComPort {
+    fn line_status(&mut self) -> u8 {
+        unsafe { self.line_status_port.inb() }
+    }
+
+    fn transmit(&mut self, b: u8) {
+        self.write_byte(b);
+    }
+}
+
+/// Write `bytes` to `tx` in FIFO fills: wait until the transmit FIFO is empty, then write
+/// up to `UART_FIFO_SIZE` bytes at once, so the line status is polled once per fill
+/// instead of once per byte. Stops waiting after `DRAIN_TIMEOUT_POLLS` polls (e.g. if there
+/// is no UART) and writes anyway. Returns the number of fills.
+pub fn write_fifo<T: Transmitter>(tx: &mut T, bytes: &[u8]) -> usize {
+    let mut fills = 0;
+    for chunk in bytes.chunks(UART_FIFO_SIZE) {
+        for _ in 0..DRAIN_TIMEOUT_POLLS {
+            if tx.line_status() & LSR_THR_EMPTY != 0 {
+                break;
+            }
+        }
+        for &b in chunk {
+            tx.transmit(b);
         }
+        fills += 1;
     }
+    fills
 }
 
 /// Implement the `Write` trait for `ComPort`.
@@ -34,21 +131,89 @@ impl fmt::Write for ComPort {
 
     /// Write a string to the COM port
     fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.write_combining {
+            write_fifo(self, s.as_bytes());
+            return Ok(());
+        }
+
         // Iterate over each byte in the string
         for &b in s.as_bytes() {
             // Write the current byte to the COM port
-            //
-            // Unsafe because we are writing to hardware.
-            // By only allowing enum-values in the constructor,
-            // we ensure that the port address is valid.
-            //
-            // Since we are using a mutable reference to the port,
-            // we can be sure that the port is not used by another thread.
-            unsafe { self.data_port.outb(b); }
+            self.write_byte(b);
         }
         Ok(())
     }
 }
 
+/// Allow using a COM port as console (see `console::set`).
+impl Console for Mutex<ComPort> {
+    fn write_byte(&self, byte: u8) {
+        self.lock().write_byte(byte);
+    }
+
+    fn write_str(&self, s: &str) {
+        fmt::Write::write_str(&mut *self.lock(), s).ok();
+    }
+
+    /// Skipped if the port is locked, so this is safe to call from the panic handler.
+    fn sync(&self) {
+        if let Some(mut port) = self.try_lock() {
+            port.drain();
+        }
+    }
+}
+
 // Standard com-port for kernel output via kprint! and kprintln!
 pub static COM1: Mutex<ComPort> = Mutex::new(ComPort::new(ComBaseAddress::Com1));
+
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Self tests, run by the diagnostics (see `user::diag`).                  ║
+   ╚═════════════════════════════════════════════════════════════════════════╝ */
+
+pub mod selftest {
+    use crate::devices::serial::{self, Transmitter};
+    use crate::library::selftest::{Test, TestResult};
+
+    /// The self tests of this module, run in this order.
+    pub const TESTS: &[Test] = &[
+        ("FIFO", test_serial_fifo),
+    ];
+
+    /// Write 20 bytes to a mocked UART and check that they are delivered in two FIFO fills
+    /// without overrunning the FIFO.
+    fn test_serial_fifo() -> TestResult {
+        /// A UART whose FIFO is sent completely between two line status reads.
+        struct MockUart {
+            fifo_level: usize,
+            sent: usize,
+            overrun: bool,
+        }
+
+        impl Transmitter for MockUart {
+            fn line_status(&mut self) -> u8 {
+                if self.fifo_level > 0 {
+                    self.fifo_level = 0;
+                    return 0;
+                }
+                0x60 // transmit holding register and transmitter empty
+            }
+
+            fn transmit(&mut self, _b: u8) {
+                self.fifo_level += 1;
+                self.sent += 1;
+                self.overrun |= self.fifo_level > serial::UART_FIFO_SIZE;
+            }
+        }
+
+        let mut uart = MockUart { fifo_level: 0, sent: 0, overrun: false };
+        let fills = serial::write_fifo(&mut uart, &[b'x'; 20]);
+
+        if uart.overrun {
+            return Err("FIFO overrun");
+        }
+        if uart.sent != 20 || fills != 2 {
+            return Err("not written in two fills");
+        }
+        Ok(())
+    }
+}
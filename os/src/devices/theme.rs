@@ -0,0 +1,160 @@
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Module: theme                                                           ║
+   ╟─────────────────────────────────────────────────────────────────────────╢
+   ║ Descr.: Color themes for a consistent look of all demos. A theme        ║
+   ║         assigns colors to styles (header, body, highlight, error,       ║
+   ║         status line) and the active theme is used by the print macros   ║
+   ║         and the banner.                                                 ║
+   ╚═════════════════════════════════════════════════════════════════════════╝
+*/
+use spin::Mutex;
+use crate::devices::cga::{self, Color};
+use crate::devices::cga_print;
+
+/// The kinds of text colored by a theme.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Style {
+    /// Titles and headings
+    Header,
+    /// Normal text
+    Body,
+    /// Text that should stand out (e.g. results)
+    Highlight,
+    /// Error messages
+    Error,
+    /// Banner and status lines
+    Status,
+}
+
+/// A color palette.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Theme {
+    pub background: Color,
+    pub header: Color,
+    pub body: Color,
+    pub highlight: Color,
+    pub error: Color,
+    pub status_fg: Color,
+    pub status_bg: Color,
+    /// Color of the screen border (see `CGA::set_border_color()`)
+    pub border: Color,
+}
+
+/// White text on black, with a blue status line (the default).
+pub const CLASSIC: Theme = Theme {
+    background: Color::Black,
+    header: Color::LightCyan,
+    body: Color::White,
+    highlight: Color::LightGreen,
+    error: Color::LightRed,
+    status_fg: Color::White,
+    status_bg: Color::Blue,
+    border: Color::Black,
+};
+
+/// Amber monochrome monitor.
+pub const AMBER: Theme = Theme {
+    background: Color::Black,
+    header: Color::Yellow,
+    body: Color::Brown,
+    highlight: Color::Yellow,
+    error: Color::LightRed,
+    status_fg: Color::Black,
+    status_bg: Color::Brown,
+    border: Color::Brown,
+};
+
+/// Green on black.
+pub const MATRIX: Theme = Theme {
+    background: Color::Black,
+    header: Color::LightGreen,
+    body: Color::Green,
+    highlight: Color::White,
+    error: Color::LightRed,
+    status_fg: Color::Black,
+    status_bg: Color::Green,
+    border: Color::Green,
+};
+
+/// The active theme.
+static THEME: Mutex<Theme> = Mutex::new(CLASSIC);
+
+/// Activate `theme`. Takes effect immediately: the border is recolored and all following
+/// output of the print macros uses the body color of the new theme.
+pub fn set(theme: &Theme) {
+    *THEME.lock() = *theme;
+    cga::CGA.lock().set_border_color(theme.border);
+    apply(Style::Body);
+}
+
+/// Return the active theme.
+pub fn get() -> Theme {
+    *THEME.lock()
+}
+
+/// Return the foreground and background color of `style` in the active theme.
+pub fn colors(style: Style) -> (Color, Color) {
+    let theme = get();
+    match style {
+        Style::Header => (theme.header, theme.background),
+        Style::Body => (theme.body, theme.background),
+        Style::Highlight => (theme.highlight, theme.background),
+        Style::Error => (theme.error, theme.background),
+        Style::Status => (theme.status_fg, theme.status_bg),
+    }
+}
+
+/// Return the CGA attribute byte of `style` in the active theme.
+pub fn attribute(style: Style) -> u8 {
+    let (fg, bg) = colors(style);
+    cga::CGA.lock().attribute(bg, fg, false)
+}
+
+/// Use the colors of `style` for the following output of the print macros.
+pub fn apply(style: Style) {
+    let (fg, bg) = colors(style);
+    unsafe {
+        cga_print::FG_COLOR = fg;
+        cga_print::BG_COLOR = bg;
+    }
+}
+
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Self tests, run by the diagnostics (see `user::diag`).                  ║
+   ╚═════════════════════════════════════════════════════════════════════════╝ */
+
+pub mod selftest {
+    use crate::devices::cga::{self, Color};
+    use crate::devices::cga_print;
+    use crate::devices::theme::{self, Style};
+    use crate::library::selftest::{restore_on_exit, Test, TestResult};
+
+    /// The self tests of this module, run in this order.
+    pub const TESTS: &[Test] = &[
+        ("Theme switch", test_theme_switch),
+    ];
+
+    /// Switch from the classic to the amber theme and check that the attribute of a style
+    /// changes to the colors of the new theme.
+    fn test_theme_switch() -> TestResult {
+        let saved = theme::get();
+        let state = cga_print::save_state();
+        let _restore = restore_on_exit(|| {
+            theme::set(&saved);
+            cga_print::restore_state(&state);
+        });
+
+        theme::set(&theme::CLASSIC);
+        let classic = theme::attribute(Style::Header);
+        theme::set(&theme::AMBER);
+        let amber = theme::attribute(Style::Header);
+
+        if classic == amber {
+            return Err("attribute unchanged by the theme");
+        }
+        if amber != cga::CGA.lock().attribute(Color::Black, Color::Yellow, false) {
+            return Err("wrong attribute for the new theme");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,493 @@
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Module: vga                                                             ║
+   ╟─────────────────────────────────────────────────────────────────────────╢
+   ║ Descr.: Graphics mode 13h of the VGA (320x200 pixels, 256 colors).      ║
+   ║         Programs the VGA registers for mode 13h and back to the         ║
+   ║         previous text mode. The pixels are bytes of the linear          ║
+   ║         framebuffer at 0xa0000, each an index into the DAC palette.     ║
+   ║         Font, palette and CGA screen are saved when entering graphics   ║
+   ║         mode and restored when leaving it.                              ║
+   ╚═════════════════════════════════════════════════════════════════════════╝
+*/
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ptr;
+use spin::Mutex;
+use crate::devices::cga::{self, CgaState, ScreenContents};
+use crate::kernel::cpu;
+use crate::kernel::cpu::IoPort;
+
+/// Size of the screen in mode 13h (pixels).
+pub const WIDTH: usize = 320;
+pub const HEIGHT: usize = 200;
+
+/// Linear framebuffer of mode 13h, one byte per pixel, row by row.
+const FRAMEBUFFER: *mut u8 = 0xa0000 as *mut u8;
+
+// VGA IO-ports
+const MISC_WRITE_PORT: u16 = 0x3c2;
+const MISC_READ_PORT: u16 = 0x3cc;
+const SEQ_INDEX_PORT: u16 = 0x3c4;
+const SEQ_DATA_PORT: u16 = 0x3c5;
+const CRTC_INDEX_PORT: u16 = 0x3d4;
+const CRTC_DATA_PORT: u16 = 0x3d5;
+const GC_INDEX_PORT: u16 = 0x3ce;
+const GC_DATA_PORT: u16 = 0x3cf;
+const AC_PORT: u16 = 0x3c0;           // attribute controller index/data (W)
+const AC_READ_PORT: u16 = 0x3c1;      // attribute controller data (R)
+const INPUT_STATUS_PORT: u16 = 0x3da; // reading resets the attribute controller to index mode
+const DAC_READ_INDEX_PORT: u16 = 0x3c7;
+const DAC_WRITE_INDEX_PORT: u16 = 0x3c8;
+const DAC_DATA_PORT: u16 = 0x3c9;
+
+// Number of registers of the sequencer, CRT controller, graphics controller and attribute controller
+const SEQ_REGS: usize = 5;
+const CRTC_REGS: usize = 25;
+const GC_REGS: usize = 9;
+const AC_REGS: usize = 21;
+
+// Register indices and bits used for accessing the font in plane 2
+const SEQ_MAP_MASK: u8 = 0x02;
+const SEQ_MEMORY_MODE: u8 = 0x04;
+const GC_READ_MAP: u8 = 0x04;
+const GC_MODE: u8 = 0x05;
+const GC_MISC: u8 = 0x06;
+const PLANE_2: u8 = 2;
+
+// CRTC registers 0-7 are write-protected by bit 7 of register 0x11.
+// Bit 7 of register 0x03 must be set for the vertical retrace registers to be accessible.
+const CRTC_H_BLANK_END: u8 = 0x03;
+const CRTC_V_RETRACE_END: u8 = 0x11;
+const CRTC_PROTECT_BIT: u8 = 0x80;
+
+/// Keeps the display enabled while selecting an attribute controller index.
+const AC_PALETTE_ENABLE: u8 = 0x20;
+
+/// Size of plane 2, which holds the eight font blocks of the text mode.
+/// Mode 13h uses all planes for pixels, so the font is overwritten by graphics.
+const FONT_PLANE_SIZE: usize = 0x10000;
+
+/// Number of DAC palette entries and bytes (6-bit red, green and blue per entry).
+const PALETTE_ENTRIES: usize = 256;
+const PALETTE_SIZE: usize = PALETTE_ENTRIES * 3;
+
+/// Contents of the VGA registers defining a video mode.
+#[derive(Copy, Clone)]
+struct Registers {
+    misc: u8,
+    seq: [u8; SEQ_REGS],
+    crtc: [u8; CRTC_REGS],
+    gc: [u8; GC_REGS],
+    ac: [u8; AC_REGS],
+}
+
+/// Register values of mode 13h: 320x200 pixels, 256 colors, chain-4 addressing
+/// of the linear framebuffer at 0xa0000.
+const MODE_13H: Registers = Registers {
+    misc: 0x63,
+    seq: [0x03, 0x01, 0x0f, 0x00, 0x0e],
+    crtc: [
+        0x5f, 0x4f, 0x50, 0x82, 0x54, 0x80, 0xbf, 0x1f,
+        0x00, 0x41, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x9c, 0x0e, 0x8f, 0x28, 0x40, 0x96, 0xb9, 0xa3,
+        0xff,
+    ],
+    gc: [0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x05, 0x0f, 0xff],
+    ac: [
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07,
+        0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f,
+        0x41, 0x00, 0x0f, 0x00, 0x00,
+    ],
+};
+
+/// Errors of switching between text mode and graphics mode.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum VgaError {
+    /// `enter_mode13h()` was called in graphics mode
+    AlreadyInGraphicsMode,
+    /// `leave_mode13h()` was called in text mode
+    NotInGraphicsMode,
+}
+
+/// Text mode state saved by `Vga::enter_mode13h()`.
+struct SavedText {
+    registers: Registers,
+    font: Vec<u8>,
+    palette: Vec<u8>,
+    cga: CgaState,
+    screen: ScreenContents,
+}
+
+pub static VGA: Mutex<Vga> = Mutex::new(Vga::new());
+
+pub struct Vga {
+    misc_write_port: IoPort,
+    misc_read_port: IoPort,
+    seq_index_port: IoPort,
+    seq_data_port: IoPort,
+    crtc_index_port: IoPort,
+    crtc_data_port: IoPort,
+    gc_index_port: IoPort,
+    gc_data_port: IoPort,
+    ac_port: IoPort,
+    ac_read_port: IoPort,
+    input_status_port: IoPort,
+    dac_read_index_port: IoPort,
+    dac_write_index_port: IoPort,
+    dac_data_port: IoPort,
+    /// The text mode to return to, set while in graphics mode.
+    saved: Option<SavedText>,
+}
+
+/// Return the offset of pixel `x`,`y` in the framebuffer, or `None` if it is outside the screen.
+pub fn pixel_offset(x: usize, y: usize) -> Option<usize> {
+    if x >= WIDTH || y >= HEIGHT {
+        return None;
+    }
+    Some(y * WIDTH + x)
+}
+
+impl Vga {
+    pub const fn new() -> Self {
+        Vga {
+            misc_write_port: IoPort::new(MISC_WRITE_PORT),
+            misc_read_port: IoPort::new(MISC_READ_PORT),
+            seq_index_port: IoPort::new(SEQ_INDEX_PORT),
+            seq_data_port: IoPort::new(SEQ_DATA_PORT),
+            crtc_index_port: IoPort::new(CRTC_INDEX_PORT),
+            crtc_data_port: IoPort::new(CRTC_DATA_PORT),
+            gc_index_port: IoPort::new(GC_INDEX_PORT),
+            gc_data_port: IoPort::new(GC_DATA_PORT),
+            ac_port: IoPort::new(AC_PORT),
+            ac_read_port: IoPort::new(AC_READ_PORT),
+            input_status_port: IoPort::new(INPUT_STATUS_PORT),
+            dac_read_index_port: IoPort::new(DAC_READ_INDEX_PORT),
+            dac_write_index_port: IoPort::new(DAC_WRITE_INDEX_PORT),
+            dac_data_port: IoPort::new(DAC_DATA_PORT),
+            saved: None,
+        }
+    }
+
+    /// Check if mode 13h is active.
+    pub fn is_graphics(&self) -> bool {
+        self.saved.is_some()
+    }
+
+    /// Switch from text mode to mode 13h and clear the screen to color 0.
+    /// The registers, the font and the palette of the text mode as well as the state
+    /// and the contents of the CGA screen are saved for `leave_mode13h()`.
+    /// Output to the CGA is invisible until then.
+    pub fn enter_mode13h(&mut self) -> Result<(), VgaError> {
+        if self.is_graphics() {
+            return Err(VgaError::AlreadyInGraphicsMode);
+        }
+
+        let (cga, screen) = {
+            let mut cga = cga::CGA.lock();
+            (cga.save_state(), cga.save_screen())
+        };
+        let registers = self.read_registers();
+        let mut font = vec![0; FONT_PLANE_SIZE];
+        self.with_font_plane(|plane| unsafe {
+            ptr::copy_nonoverlapping(plane, font.as_mut_ptr(), FONT_PLANE_SIZE);
+        });
+        let palette = self.read_palette();
+
+        self.write_registers(&MODE_13H);
+        self.saved = Some(SavedText { registers, font, palette, cga, screen });
+        self.clear(0);
+        Ok(())
+    }
+
+    /// Switch back to the text mode saved by `enter_mode13h()` and restore
+    /// its font, its palette and the state and contents of the CGA screen.
+    pub fn leave_mode13h(&mut self) -> Result<(), VgaError> {
+        let saved = self.saved.take().ok_or(VgaError::NotInGraphicsMode)?;
+
+        self.write_registers(&saved.registers);
+        self.with_font_plane(|plane| unsafe {
+            ptr::copy_nonoverlapping(saved.font.as_ptr(), plane, FONT_PLANE_SIZE);
+        });
+        self.write_palette(&saved.palette);
+
+        let mut cga = cga::CGA.lock();
+        cga.restore_screen(&saved.screen);
+        cga.restore_state(&saved.cga);
+        Ok(())
+    }
+
+    /// Set pixel `x`,`y` to palette index `color`.
+    /// Pixels outside the screen and calls in text mode are ignored.
+    pub fn put_pixel(&mut self, x: usize, y: usize, color: u8) {
+        if !self.is_graphics() {
+            return;
+        }
+        if let Some(offset) = pixel_offset(x, y) {
+            // Unsafe because we are writing directly to video memory.
+            // `offset` is within the framebuffer, as checked by `pixel_offset()`.
+            unsafe { FRAMEBUFFER.add(offset).write_volatile(color); }
+        }
+    }
+
+    /// Fill the whole screen with palette index `color`. Ignored in text mode.
+    pub fn clear(&mut self, color: u8) {
+        if !self.is_graphics() {
+            return;
+        }
+        // Unsafe because we are writing directly to video memory (exactly one screen).
+        unsafe { ptr::write_bytes(FRAMEBUFFER, color, WIDTH * HEIGHT); }
+    }
+
+    /// Fill the rectangle of `w` x `h` pixels at `x`,`y` with palette index `color`.
+    /// Parts of the rectangle outside the screen are ignored.
+    pub fn draw_rect(&mut self, x: usize, y: usize, w: usize, h: usize, color: u8) {
+        if !self.is_graphics() || x >= WIDTH {
+            return;
+        }
+        let w = w.min(WIDTH - x);
+        for row in y..(y + h).min(HEIGHT) {
+            let offset = row * WIDTH + x;
+            // Unsafe because we are writing directly to video memory.
+            // The row is clipped to the screen above.
+            unsafe { ptr::write_bytes(FRAMEBUFFER.add(offset), color, w); }
+        }
+    }
+
+    /// Set palette entry `index` to the color `red`,`green`,`blue` (6 bits each, 0-63).
+    /// In text mode, the palette is restored by `leave_mode13h()`.
+    pub fn set_palette(&mut self, index: u8, red: u8, green: u8, blue: u8) {
+        unsafe {
+            self.dac_write_index_port.outb(index);
+            self.dac_data_port.outb(red & 0x3f);
+            self.dac_data_port.outb(green & 0x3f);
+            self.dac_data_port.outb(blue & 0x3f);
+        }
+    }
+
+    /// Read all entries of the DAC palette.
+    fn read_palette(&mut self) -> Vec<u8> {
+        let mut palette = vec![0; PALETTE_SIZE];
+        unsafe {
+            // The index auto-increments after the blue component of each entry
+            self.dac_read_index_port.outb(0);
+            for component in palette.iter_mut() {
+                *component = self.dac_data_port.inb();
+            }
+        }
+        palette
+    }
+
+    /// Write all entries of the DAC palette read by `read_palette()`.
+    fn write_palette(&mut self, palette: &[u8]) {
+        unsafe {
+            self.dac_write_index_port.outb(0);
+            for &component in palette {
+                self.dac_data_port.outb(component);
+            }
+        }
+    }
+
+    /// Call `f` with the address of plane 2, which holds the font in text mode, mapped
+    /// linearly at 0xa0000 for reading and writing. Restores the memory mapping afterwards.
+    fn with_font_plane(&mut self, f: impl FnOnce(*mut u8)) {
+        let map_mask = self.read_seq(SEQ_MAP_MASK);
+        let memory_mode = self.read_seq(SEQ_MEMORY_MODE);
+        let read_map = self.read_gc(GC_READ_MAP);
+        let mode = self.read_gc(GC_MODE);
+        let misc = self.read_gc(GC_MISC);
+
+        // Write to plane 2 only, sequential addressing (no odd/even, no chain-4),
+        // read from plane 2 with read mode 0, 64 KiB mapped at 0xa0000.
+        self.write_seq(SEQ_MAP_MASK, 1 << PLANE_2);
+        self.write_seq(SEQ_MEMORY_MODE, 0x06);
+        self.write_gc(GC_READ_MAP, PLANE_2);
+        self.write_gc(GC_MODE, 0x00);
+        self.write_gc(GC_MISC, 0x04);
+
+        // The plane is accessed with plain memory accesses, which must not be moved
+        // across the register writes switching the memory mapping
+        cpu::barrier();
+        f(FRAMEBUFFER);
+        cpu::barrier();
+
+        self.write_seq(SEQ_MAP_MASK, map_mask);
+        self.write_seq(SEQ_MEMORY_MODE, memory_mode);
+        self.write_gc(GC_READ_MAP, read_map);
+        self.write_gc(GC_MODE, mode);
+        self.write_gc(GC_MISC, misc);
+    }
+
+    /// Read the registers of the current video mode.
+    fn read_registers(&mut self) -> Registers {
+        let mut regs = Registers {
+            misc: unsafe { self.misc_read_port.inb() },
+            seq: [0; SEQ_REGS],
+            crtc: [0; CRTC_REGS],
+            gc: [0; GC_REGS],
+            ac: [0; AC_REGS],
+        };
+        for (i, value) in regs.seq.iter_mut().enumerate() {
+            *value = self.read_seq(i as u8);
+        }
+        for (i, value) in regs.crtc.iter_mut().enumerate() {
+            *value = self.read_crtc(i as u8);
+        }
+        for (i, value) in regs.gc.iter_mut().enumerate() {
+            *value = self.read_gc(i as u8);
+        }
+        for (i, value) in regs.ac.iter_mut().enumerate() {
+            *value = self.read_ac(i as u8);
+        }
+        self.enable_display();
+        regs
+    }
+
+    /// Program a video mode. The order follows the usual mode set sequence:
+    /// miscellaneous output, sequencer, CRT controller, graphics controller and attribute controller.
+    fn write_registers(&mut self, regs: &Registers) {
+        // The misc. output register selects the dot clock, which needs time to settle
+        unsafe { self.misc_write_port.outb(regs.misc); }
+        cpu::io_wait();
+        for (i, &value) in regs.seq.iter().enumerate() {
+            self.write_seq(i as u8, value);
+        }
+
+        // Unlock CRTC registers 0-7 and keep them unlocked with the new values
+        let h_blank_end = self.read_crtc(CRTC_H_BLANK_END);
+        self.write_crtc(CRTC_H_BLANK_END, h_blank_end | CRTC_PROTECT_BIT);
+        let v_retrace_end = self.read_crtc(CRTC_V_RETRACE_END);
+        self.write_crtc(CRTC_V_RETRACE_END, v_retrace_end & !CRTC_PROTECT_BIT);
+        for (i, &value) in regs.crtc.iter().enumerate() {
+            let value = match i as u8 {
+                CRTC_H_BLANK_END => value | CRTC_PROTECT_BIT,
+                CRTC_V_RETRACE_END => value & !CRTC_PROTECT_BIT,
+                _ => value,
+            };
+            self.write_crtc(i as u8, value);
+        }
+
+        for (i, &value) in regs.gc.iter().enumerate() {
+            self.write_gc(i as u8, value);
+        }
+        for (i, &value) in regs.ac.iter().enumerate() {
+            self.write_ac(i as u8, value);
+        }
+        self.enable_display();
+    }
+
+    fn read_seq(&mut self, index: u8) -> u8 {
+        unsafe {
+            self.seq_index_port.outb(index);
+            self.seq_data_port.inb()
+        }
+    }
+
+    fn write_seq(&mut self, index: u8, value: u8) {
+        unsafe {
+            self.seq_index_port.outb(index);
+            self.seq_data_port.outb(value);
+        }
+    }
+
+    fn read_crtc(&mut self, index: u8) -> u8 {
+        unsafe {
+            self.crtc_index_port.outb(index);
+            self.crtc_data_port.inb()
+        }
+    }
+
+    fn write_crtc(&mut self, index: u8, value: u8) {
+        unsafe {
+            self.crtc_index_port.outb(index);
+            self.crtc_data_port.outb(value);
+        }
+    }
+
+    fn read_gc(&mut self, index: u8) -> u8 {
+        unsafe {
+            self.gc_index_port.outb(index);
+            self.gc_data_port.inb()
+        }
+    }
+
+    fn write_gc(&mut self, index: u8, value: u8) {
+        unsafe {
+            self.gc_index_port.outb(index);
+            self.gc_data_port.outb(value);
+        }
+    }
+
+    /// Read attribute controller register `index`. The display is blanked
+    /// until `enable_display()` is called, as the palette enable bit is cleared.
+    fn read_ac(&mut self, index: u8) -> u8 {
+        unsafe {
+            // Reading the input status register resets the attribute controller to index mode
+            self.input_status_port.inb();
+            self.ac_port.outb(index);
+            self.ac_read_port.inb()
+        }
+    }
+
+    /// Write attribute controller register `index` (see `read_ac()`).
+    fn write_ac(&mut self, index: u8, value: u8) {
+        unsafe {
+            self.input_status_port.inb();
+            self.ac_port.outb(index);
+            // Index and data go to the same port, toggled by a flip-flop in the controller
+            cpu::io_wait();
+            self.ac_port.outb(value);
+        }
+    }
+
+    /// Set the palette enable bit of the attribute controller, which turns the display back on.
+    fn enable_display(&mut self) {
+        unsafe {
+            self.input_status_port.inb();
+            self.ac_port.outb(AC_PALETTE_ENABLE);
+        }
+    }
+}
+
+/// Switch to mode 13h (see `Vga::enter_mode13h()`).
+pub fn enter_mode13h() -> Result<(), VgaError> {
+    // Output of interrupt handlers between saving the screen and switching the mode would be lost.
+    cpu::without_interrupts(|| VGA.lock().enter_mode13h())
+}
+
+/// Switch back to text mode (see `Vga::leave_mode13h()`).
+pub fn leave_mode13h() -> Result<(), VgaError> {
+    cpu::without_interrupts(|| VGA.lock().leave_mode13h())
+}
+
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Self tests, run by the diagnostics (see `user::diag`).                  ║
+   ╚═════════════════════════════════════════════════════════════════════════╝ */
+
+pub mod selftest {
+    use crate::devices::vga;
+    use crate::library::selftest::{Test, TestResult};
+
+    /// The self tests of this module, run in this order.
+    pub const TESTS: &[Test] = &[
+        ("Pixel address", test_vga_pixel_offset),
+    ];
+
+    /// Check the framebuffer offsets of mode 13h at the corners and outside the screen.
+    fn test_vga_pixel_offset() -> TestResult {
+        let corners = [
+            ((0, 0), 0),
+            ((vga::WIDTH - 1, 0), vga::WIDTH - 1),
+            ((0, 1), vga::WIDTH),
+            ((vga::WIDTH - 1, vga::HEIGHT - 1), vga::WIDTH * vga::HEIGHT - 1),
+        ];
+        for ((x, y), offset) in corners {
+            if vga::pixel_offset(x, y) != Some(offset) {
+                return Err("wrong pixel offset");
+            }
+        }
+        if vga::pixel_offset(vga::WIDTH, 0).is_some() || vga::pixel_offset(0, vga::HEIGHT).is_some() {
+            return Err("pixel outside the screen accepted");
+        }
+        Ok(())
+    }
+}
@@ -7,7 +7,8 @@
    ║         Memory-Layout                                                   ║
    ║            0x0        real mode & bios stuff       	                 ║
    ║            0x100000   our OS image, including global variables          ║ 
-   ║            0x500000   Start address of our heap                         ║ 
+   ║            above      heap in the largest usable region (see memory)    ║ 
+   ║                       emergency pool at the end of the region           ║
    ║                                                                         ║ 
    ║         Remarks                                                         ║
    ║            - Requires a PC with at least 8 MB RAM                       ║
@@ -17,46 +18,1007 @@
    ║         https://os.phil-opp.com/allocator-designs/                      ║
    ╚═════════════════════════════════════════════════════════════════════════╝
 */
-use alloc::alloc::Layout;
-use crate::kernel::allocator::bump::BumpAllocator;
-use crate::kernel::allocator::list::LinkedListAllocator;
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::fmt;
+use core::{mem, ptr};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use crate::devices::console::{self, Console};
+use crate::devices::{cga_print, serial};
+use crate::kernel::cpu;
+use crate::kernel::init::StartupError;
+use crate::kernel::log::{self, EventKind};
+use crate::kernel::memory;
+use crate::kernel::softirq;
+use crate::kernel::timer;
+use crate::library::arrayvec::ArrayVec;
+use crate::library::chart;
+use crate::library::IrqSafeMutex;
+use crate::kernel::allocator::boundary_tag::BoundaryTagAllocator;
+use crate::kernel::allocator::bump::{BumpAllocator, Checkpoint, RollbackError};
+use crate::kernel::allocator::list::{LinkedListAllocator, ListNode};
 
+pub mod boundary_tag;
 pub mod bump;
 pub mod list;
 
-const HEAP_START: usize = 0x500000;
-const HEAP_SIZE: usize = 1024 * 1024; // 1 MiB heap size
+/// Alignment of the start address of the heap.
+const HEAP_ALIGN: usize = 4096;
+
+/// Size of a page, the unit touched by `warmup()`.
+pub const PAGE_SIZE: usize = 4096;
+
+/// Bounds of the heap, set by `init()`.
+static HEAP_START: AtomicUsize = AtomicUsize::new(0);
+static HEAP_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+/// The available allocator backends.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Backend {
+    Bump = 0,
+    List = 1,
+    BoundaryTag = 2,
+}
+
+// Define the backend used by the kernel allocator.
+// It can be changed at runtime before `init()` is called (see `use_boundary_tag()`).
+// static BACKEND: AtomicU8 = AtomicU8::new(Backend::Bump as u8);
+static BACKEND: AtomicU8 = AtomicU8::new(Backend::List as u8);
+
+/// Set by `init()`. Afterwards, the backend must not be changed anymore.
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+// The heap bounds are set by `init()`.
+static BUMP_ALLOCATOR: Locked<BumpAllocator> = Locked::new(BumpAllocator::new(0, 0));
+static LIST_ALLOCATOR: Locked<LinkedListAllocator> = Locked::new(LinkedListAllocator::new(0, 0));
+static BOUNDARY_TAG_ALLOCATOR: Locked<BoundaryTagAllocator> = Locked::new(BoundaryTagAllocator::new(0, 0));
+
+/// Allocator of the emergency pool at the end of the heap (see `set_emergency_pool()`).
+static EMERGENCY_ALLOCATOR: Locked<LinkedListAllocator> = Locked::new(LinkedListAllocator::new(0, 0));
 
 // Define the allocator (which implements the 'GlobalAlloc' trait)
 #[global_allocator]
-// static ALLOCATOR: Locked<BumpAllocator> = Locked::new(BumpAllocator::new(HEAP_START, HEAP_SIZE));
-static ALLOCATOR: Locked<LinkedListAllocator> = Locked::new(LinkedListAllocator::new(HEAP_START, HEAP_SIZE));
+static ALLOCATOR: KernelAllocator = KernelAllocator {};
+
+/// Return the backend currently used by the kernel allocator.
+pub fn backend() -> Backend {
+    match BACKEND.load(Ordering::Relaxed) {
+        0 => Backend::Bump,
+        1 => Backend::List,
+        _ => Backend::BoundaryTag,
+    }
+}
+
+/// Use the boundary tag allocator as backend.
+/// Must be called before `init()`, since the heap cannot be handed over
+/// from one backend to another.
+pub fn use_boundary_tag() {
+    assert!(!INITIALIZED.load(Ordering::Relaxed), "allocator backend must be selected before init()");
+    BACKEND.store(Backend::BoundaryTag as u8, Ordering::Relaxed);
+}
+
+/// Minimum alignment of every allocation (see `set_min_align()`).
+static MIN_ALIGN: AtomicUsize = AtomicUsize::new(1);
+
+/// Errors that can occur when setting the minimum alignment.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AlignError {
+    /// The alignment is not a power of two.
+    NotPowerOfTwo,
+    /// The alignment is smaller than the alignment of a free list node.
+    TooSmall,
+    /// The heap has already been initialized.
+    AlreadyInitialized,
+}
+
+/// Align every allocation to at least `align` bytes, regardless of the alignment of the type
+/// (e.g. 16 bytes for SSE state). `align` must be a power of two and at least the alignment of
+/// a free list node. Must be called before `init()`, because blocks allocated with a smaller
+/// alignment would be freed with a different size.
+pub fn set_min_align(align: usize) -> Result<(), AlignError> {
+    if !align.is_power_of_two() {
+        return Err(AlignError::NotPowerOfTwo);
+    }
+    if align < mem::align_of::<ListNode>() {
+        return Err(AlignError::TooSmall);
+    }
+    if INITIALIZED.load(Ordering::Relaxed) {
+        return Err(AlignError::AlreadyInitialized);
+    }
+    MIN_ALIGN.store(align, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Return the minimum alignment of every allocation.
+pub fn min_align() -> usize {
+    MIN_ALIGN.load(Ordering::Relaxed)
+}
+
+/// Number of requested bytes of all live allocations.
+static BYTES_IN_USE: AtomicUsize = AtomicUsize::new(0);
+
+/// Heap usage in percent at which a warning is printed (0 = disabled, see `set_usage_warning()`).
+static USAGE_WARNING_PERCENT: AtomicUsize = AtomicUsize::new(0);
+
+/// Set while the heap usage is at or above the warning threshold.
+static USAGE_WARNED: AtomicBool = AtomicBool::new(false);
+
+/// Return the number of requested bytes of all live allocations (without padding).
+pub fn bytes_in_use() -> usize {
+    BYTES_IN_USE.load(Ordering::Relaxed)
+}
+
+/// Print a warning on the debug console when the heap usage reaches `percent` of the heap size,
+/// giving early notice before allocations fail. The warning is printed once per crossing:
+/// when the usage drops below the threshold again, this is reported as well and the next
+/// crossing warns again. A `percent` of 0 disables the warning.
+pub fn set_usage_warning(percent: usize) {
+    USAGE_WARNING_PERCENT.store(percent, Ordering::Relaxed);
+    USAGE_WARNED.store(false, Ordering::Relaxed);
+    check_usage();
+}
+
+/// Return the heap usage in percent at which a warning is printed (0 if disabled).
+pub fn usage_warning() -> usize {
+    USAGE_WARNING_PERCENT.load(Ordering::Relaxed)
+}
+
+/// Report crossings of the heap usage warning threshold. Called while allocating and freeing,
+/// so the warnings are queued and printed later (see `report()`).
+fn check_usage() {
+    let percent = USAGE_WARNING_PERCENT.load(Ordering::Relaxed);
+    if percent == 0 {
+        return;
+    }
+
+    let (in_use, size) = (bytes_in_use(), heap_size());
+    if in_use >= size / 100 * percent {
+        if !USAGE_WARNED.swap(true, Ordering::Relaxed) {
+            report(HeapReport::UsageHigh { percent, in_use, size });
+        }
+    } else if USAGE_WARNED.swap(false, Ordering::Relaxed) {
+        report(HeapReport::UsageLow { percent, in_use, size });
+    }
+}
+
+/// Keep the free list of the list allocator sorted by address (see `set_address_ordered()`).
+static ADDRESS_ORDERED: AtomicBool = AtomicBool::new(false);
+
+/// Keep the free list of the list allocator sorted by address instead of inserting freed
+/// blocks at the front. As the first suitable block is used, allocations are then served
+/// from the lowest possible address (address-ordered first fit), so a fixed sequence of
+/// requests always yields the same addresses. Free blocks are inserted in O(n) instead of O(1).
+/// Should be enabled before `init()`, because blocks already in the list are not sorted.
+pub fn set_address_ordered(enabled: bool) {
+    ADDRESS_ORDERED.store(enabled, Ordering::Relaxed);
+}
+
+/// Check if the free list of the list allocator is sorted by address.
+pub fn address_ordered() -> bool {
+    ADDRESS_ORDERED.load(Ordering::Relaxed)
+}
+
+/// Size of a cache line in bytes.
+pub const CACHE_LINE_SIZE: usize = 64;
+
+/// Round size and alignment of every allocation up to a cache line (see `set_cache_align()`).
+static CACHE_ALIGN: AtomicBool = AtomicBool::new(false);
+
+/// Round size and alignment of every allocation of the list allocator up to `CACHE_LINE_SIZE`,
+/// so distinct allocations never share a cache line (e.g. for studying false sharing).
+/// This wastes memory, see `padding_bytes()`. Like the minimum alignment, this must be
+/// selected before `init()`, because blocks must be freed with the size they were allocated with.
+pub fn set_cache_align(enabled: bool) -> Result<(), AlignError> {
+    if INITIALIZED.load(Ordering::Relaxed) {
+        return Err(AlignError::AlreadyInitialized);
+    }
+    CACHE_ALIGN.store(enabled, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Check if allocations are aligned to cache lines.
+pub fn cache_align() -> bool {
+    CACHE_ALIGN.load(Ordering::Relaxed)
+}
+
+/// Return the number of bytes the list allocator added to the requested sizes of all
+/// live allocations (0 for the other backends).
+pub fn padding_bytes() -> usize {
+    match backend() {
+        Backend::List => LIST_ALLOCATOR.lock().padding_bytes(),
+        _ => 0,
+    }
+}
+
+/// Maximum number of free list nodes visited when searching a free block (see `set_max_search_nodes()`).
+/// By default, the number of nodes fitting into the memory, so only a cyclic list can exceed it.
+static MAX_SEARCH_NODES: AtomicUsize = AtomicUsize::new(memory::RAM_SIZE / mem::size_of::<ListNode>());
+
+/// Limit the number of free list nodes the list allocator visits for one allocation.
+/// If a search exceeds the limit, the list is reported as corrupted (see `HeapReport`)
+/// and the allocation fails.
+pub fn set_max_search_nodes(max: usize) {
+    MAX_SEARCH_NODES.store(max, Ordering::Relaxed);
+}
+
+/// Return the maximum number of free list nodes visited for one allocation.
+pub fn max_search_nodes() -> usize {
+    MAX_SEARCH_NODES.load(Ordering::Relaxed)
+}
+
+/// Zero the payload of every freed block if set (see `set_zero_on_free()`).
+static ZERO_ON_FREE: AtomicBool = AtomicBool::new(false);
+
+/// Enable or disable zeroing freed blocks, so sensitive data (e.g. a typed password)
+/// cannot be read by a later allocation of the same memory. Disabled by default.
+/// The payload is zeroed before the block is returned to the backend, which then writes its
+/// free list node (or tags) into the block. These only hold sizes and addresses, no payload data.
+pub fn set_zero_on_free(enabled: bool) {
+    ZERO_ON_FREE.store(enabled, Ordering::Relaxed);
+}
+
+/// Check if freed blocks are zeroed (see `set_zero_on_free()`).
+pub fn zero_on_free() -> bool {
+    ZERO_ON_FREE.load(Ordering::Relaxed)
+}
+
+/// Zero the whole heap region in `init()` if set (see `set_wipe_on_init()`).
+static WIPE_ON_INIT: AtomicBool = AtomicBool::new(false);
+
+/// Enable or disable zeroing the whole heap region, including the emergency pool, in `init()`
+/// before the free list is built. This way, no stale data of a previous run (e.g. after a
+/// warm reboot) can be read from fresh allocations. Disabled by default, since it takes time
+/// proportional to the heap size. Must be called before `init()`.
+pub fn set_wipe_on_init(enabled: bool) {
+    assert!(!INITIALIZED.load(Ordering::Relaxed), "heap wipe must be configured before init()");
+    WIPE_ON_INIT.store(enabled, Ordering::Relaxed);
+}
+
+/// Check if the heap region is zeroed in `init()` (see `set_wipe_on_init()`).
+pub fn wipe_on_init() -> bool {
+    WIPE_ON_INIT.load(Ordering::Relaxed)
+}
+
+/// Set `len` bytes at `dst` to `value`. The bulk is written in 64 bit words;
+/// only the unaligned bytes at the start and the end are written one by one.
+///
+/// # Safety
+/// `dst..dst + len` must be valid for writes.
+pub unsafe fn fast_set(dst: *mut u8, value: u8, len: usize) {
+    let word = u64::from_ne_bytes([value; 8]);
+    let head = dst.align_offset(mem::align_of::<u64>()).min(len);
+    let words = (len - head) / mem::size_of::<u64>();
+    let tail = len - head - words * mem::size_of::<u64>();
 
-/// Initialize the heap allocator.
-pub fn init() {
     unsafe {
-        ALLOCATOR.lock().init();
+        ptr::write_bytes(dst, value, head);
+        let aligned = dst.add(head) as *mut u64;
+        for i in 0..words {
+            aligned.add(i).write(word);
+        }
+        ptr::write_bytes(aligned.add(words) as *mut u8, value, tail);
     }
 }
 
+/// Number of allocations until an injected failure (0 = disabled, see `inject_failure_after()`).
+static FAIL_COUNTDOWN: AtomicUsize = AtomicUsize::new(0);
+
+/// Let the `n`th subsequent allocation fail (return null) regardless of the available memory,
+/// to exercise out-of-memory handling deterministically. The injection is one-shot:
+/// the allocations after the failed one succeed again until it is re-armed.
+/// A `n` of 0 disables a pending injection (same as `clear_failure_injection()`).
+pub fn inject_failure_after(n: usize) {
+    FAIL_COUNTDOWN.store(n, Ordering::Relaxed);
+}
+
+/// Disable a pending failure injection.
+pub fn clear_failure_injection() {
+    FAIL_COUNTDOWN.store(0, Ordering::Relaxed);
+}
+
+/// Count down the failure injection and check if the current allocation must fail.
+fn injected_failure() -> bool {
+    FAIL_COUNTDOWN
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+        .is_ok_and(|n| n == 1)
+}
+
+/// Default size of the emergency pool in bytes (see `set_emergency_pool()`).
+const DEFAULT_EMERGENCY_POOL: usize = 16 * 1024;
+
+/// Size of the emergency pool reserved by `init()`.
+static EMERGENCY_POOL_SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_EMERGENCY_POOL);
+
+/// Address range of the emergency pool (both 0 without a pool).
+static EMERGENCY_START: AtomicUsize = AtomicUsize::new(0);
+static EMERGENCY_END: AtomicUsize = AtomicUsize::new(0);
+
+/// Bytes currently allocated from the emergency pool.
+static EMERGENCY_BYTES_IN_USE: AtomicUsize = AtomicUsize::new(0);
+
+/// Test hook: the main heap fails every allocation if set (see `simulate_full_heap()`).
+static SIMULATE_FULL_HEAP: AtomicBool = AtomicBool::new(false);
+
+/// Reserve `bytes` at the end of the heap as emergency pool, which serves allocations only
+/// when the main heap is exhausted. This way, the out-of-memory diagnostics and the panic
+/// path can still allocate their messages. The pool is limited to half of the heap;
+/// 0 disables it. The default is `DEFAULT_EMERGENCY_POOL`.
+/// Must be called before `init()`, which carves out the pool.
+pub fn set_emergency_pool(bytes: usize) {
+    assert!(!INITIALIZED.load(Ordering::Relaxed), "emergency pool must be configured before init()");
+    EMERGENCY_POOL_SIZE.store(bytes, Ordering::Relaxed);
+}
+
+/// Return the size of the emergency pool in bytes (0 before `init()` or without a pool).
+pub fn emergency_pool_size() -> usize {
+    EMERGENCY_END.load(Ordering::Relaxed) - EMERGENCY_START.load(Ordering::Relaxed)
+}
+
+/// Return the number of bytes currently allocated from the emergency pool.
+pub fn emergency_bytes_in_use() -> usize {
+    EMERGENCY_BYTES_IN_USE.load(Ordering::Relaxed)
+}
+
+/// Check if `ptr` was allocated from the emergency pool.
+pub fn is_emergency(ptr: *const u8) -> bool {
+    let addr = ptr as usize;
+    addr >= EMERGENCY_START.load(Ordering::Relaxed) && addr < EMERGENCY_END.load(Ordering::Relaxed)
+}
+
+/// Test hook to check the emergency pool: while enabled, the main heap fails every
+/// allocation as if it were full. Never enable it for anything else than testing.
+pub fn simulate_full_heap(enabled: bool) {
+    SIMULATE_FULL_HEAP.store(enabled, Ordering::Relaxed);
+}
+
+/// Allocate from the emergency pool after the main heap failed. Returns null without a pool.
+fn emergency_alloc(layout: Layout) -> *mut u8 {
+    if EMERGENCY_END.load(Ordering::Relaxed) == 0 {
+        return ptr::null_mut();
+    }
+
+    let ptr = unsafe { EMERGENCY_ALLOCATOR.alloc(layout) };
+    if !ptr.is_null() {
+        EMERGENCY_BYTES_IN_USE.fetch_add(layout.size(), Ordering::Relaxed);
+        trace(format_args!("emergency: {} bytes at {:#x}\n", layout.size(), ptr as usize));
+    }
+    ptr
+}
+
+/// The per-allocation delay (see `set_alloc_delay()`) is only available in debug builds.
+pub const ALLOC_DELAY_AVAILABLE: bool = cfg!(debug_assertions);
+
+/// Number of TSC cycles each allocation spins while holding the backend lock (0 = disabled).
+static ALLOC_DELAY: AtomicU64 = AtomicU64::new(0);
+
+/// Let each allocation spin for `cycles` TSC cycles while it holds the lock of the backend.
+/// This widens the window in which an interrupt handler allocating meanwhile runs into
+/// the lock, so missing interrupt guards around allocations show up as deadlocks in tests.
+/// 0 disables the delay (the default). Has no effect unless `ALLOC_DELAY_AVAILABLE` is set.
+pub fn set_alloc_delay(cycles: u64) {
+    ALLOC_DELAY.store(cycles, Ordering::Relaxed);
+}
+
+/// Return the per-allocation delay in TSC cycles (see `set_alloc_delay()`).
+pub fn alloc_delay() -> u64 {
+    ALLOC_DELAY.load(Ordering::Relaxed)
+}
+
+/// Spin for the per-allocation delay. Called by the backends while holding their lock.
+fn spin_alloc_delay() {
+    if !ALLOC_DELAY_AVAILABLE {
+        return;
+    }
+    let cycles = ALLOC_DELAY.load(Ordering::Relaxed);
+    if cycles == 0 {
+        return;
+    }
+    let end = cpu::rdtsc() + cycles;
+    while cpu::rdtsc() < end {
+        core::hint::spin_loop();
+    }
+}
+
+/// Check the alignment of every returned pointer if set (see `audit_alignment()`).
+static AUDIT_ALIGNMENT: AtomicBool = AtomicBool::new(false);
+
+/// Number of allocations found by the audit to be aligned less than requested.
+static ALIGNMENT_VIOLATIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Test hook: the list allocator ignores the requested alignment if set (see `break_alignment()`).
+static BREAK_ALIGNMENT: AtomicBool = AtomicBool::new(false);
+
+/// Enable or disable the alignment audit. When enabled, the alignment of each returned
+/// pointer is compared with the requested one and a warning is reported (see `HeapReport`)
+/// if it is lower. Disabled by default.
+pub fn audit_alignment(enabled: bool) {
+    AUDIT_ALIGNMENT.store(enabled, Ordering::Relaxed);
+}
+
+/// Return the number of alignment violations found since boot (see `audit_alignment()`).
+pub fn alignment_violations() -> usize {
+    ALIGNMENT_VIOLATIONS.load(Ordering::Relaxed)
+}
+
+/// Return the largest power of two `addr` is aligned to.
+pub fn alignment_of(addr: usize) -> usize {
+    1 << addr.trailing_zeros().min(usize::BITS - 1)
+}
+
+/// Test hook to check the alignment audit: while enabled, the list allocator aligns
+/// its blocks only to the size of its list nodes, ignoring the requested alignment.
+/// Blocks allocated while it is enabled must be freed before disabling it again, since
+/// their size would be computed differently. Never enable it for anything else than testing.
+pub fn break_alignment(enabled: bool) {
+    BREAK_ALIGNMENT.store(enabled, Ordering::Relaxed);
+}
+
+/// Check if the alignment test hook is enabled (see `break_alignment()`).
+fn alignment_broken() -> bool {
+    BREAK_ALIGNMENT.load(Ordering::Relaxed)
+}
+
+/// Compare the alignment of `ptr` with the one requested by `layout` and report a violation.
+fn check_alignment(ptr: *mut u8, layout: Layout) {
+    if !AUDIT_ALIGNMENT.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let actual = alignment_of(ptr as usize);
+    if actual < layout.align() {
+        ALIGNMENT_VIOLATIONS.fetch_add(1, Ordering::Relaxed);
+        report(HeapReport::AlignmentViolation { addr: ptr as usize, requested: layout.align(), actual });
+    }
+}
+
+/// Print a line for each allocation and deallocation if set (see `set_trace()`).
+static TRACE: AtomicBool = AtomicBool::new(false);
+
+/// Enable or disable tracing of allocations and deallocations (see `set_trace_sink()`).
+/// Tracing is disabled by default, because it slows down every allocation.
+pub fn set_trace(enabled: bool) {
+    TRACE.store(enabled, Ordering::Relaxed);
+}
+
+/// Destination of the allocation trace.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Sink {
+    /// COM1 only, keeping the screen clean (e.g. for a full log in the QEMU serial output)
+    Serial = 0,
+    /// CGA screen only
+    Screen = 1,
+    /// COM1 and CGA screen
+    Both = 2,
+}
+
+/// Destination of the allocation trace (see `set_trace_sink()`).
+static TRACE_SINK: AtomicU8 = AtomicU8::new(Sink::Serial as u8);
+
+/// Select where the allocation trace is written to. The default is `Sink::Serial`.
+pub fn set_trace_sink(sink: Sink) {
+    TRACE_SINK.store(sink as u8, Ordering::Relaxed);
+}
+
+/// Return the destination of the allocation trace.
+pub fn trace_sink() -> Sink {
+    match TRACE_SINK.load(Ordering::Relaxed) {
+        0 => Sink::Serial,
+        1 => Sink::Screen,
+        _ => Sink::Both,
+    }
+}
+
+/// Check if tracing of allocations and deallocations is enabled.
+fn trace_enabled() -> bool {
+    TRACE.load(Ordering::Relaxed)
+}
+
+/// Write a trace message to the selected sink, if tracing is enabled.
+/// The devices are used directly (not the redirectable consoles), so the output
+/// ends up exactly where `set_trace_sink()` says.
+fn trace(args: fmt::Arguments) {
+    if !trace_enabled() {
+        return;
+    }
+    write_trace(trace_sink(), &serial::COM1, &cga_print::WRITER, args);
+}
+
+/// Write a trace message to `serial` and/or `screen`, as selected by `sink`.
+fn write_trace(sink: Sink, serial: &'static dyn Console, screen: &'static dyn Console, args: fmt::Arguments) {
+    if sink != Sink::Screen {
+        console::write_fmt(serial, args);
+    }
+    if sink != Sink::Serial {
+        console::write_fmt(screen, args);
+    }
+}
+
+/// Initialize the heap allocator. The heap is placed in the largest usable region
+/// of the memory map (see `memory::largest_usable_region()`). The emergency pool
+/// (see `set_emergency_pool()`) is taken from the end of the region.
+/// The region is zeroed first, if enabled with `set_wipe_on_init()`.
+/// Fails if the heap has already been initialized, since this would drop all allocated blocks.
+pub fn init() -> Result<(), StartupError> {
+    if INITIALIZED.swap(true, Ordering::Relaxed) {
+        return Err(StartupError::HeapAlreadyInitialized);
+    }
+
+    let region = memory::largest_usable_region().ok_or(StartupError::NoUsableMemory)?;
+    let pool_size = EMERGENCY_POOL_SIZE.load(Ordering::Relaxed);
+    let heap = place_heap(region, pool_size, wipe_on_init(), build_allocators)?;
+    HEAP_START.store(heap.start, Ordering::Relaxed);
+    HEAP_SIZE.store(heap.size, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Placement of the heap and the emergency pool in a memory region (see `place_heap()`).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct HeapPlacement {
+    /// Start address of the heap (aligned to `HEAP_ALIGN`)
+    start: usize,
+    /// Size of the heap in bytes (a multiple of `HEAP_ALIGN`)
+    size: usize,
+    /// End address of the emergency pool, which directly follows the heap (empty without a pool)
+    pool_end: usize,
+}
+
+/// Place the heap at the start of `region` and an emergency pool of `pool_size` bytes
+/// (at most half of the region) at its end. The free lists are written into the region,
+/// so it is zeroed first if `wipe` is set, and only then `build` is called to build the
+/// allocators. Used by `init()` for the real heap and by the self tests for a scratch region.
+fn place_heap(region: memory::Region, pool_size: usize, wipe: bool, build: impl FnOnce(&HeapPlacement))
+    -> Result<HeapPlacement, StartupError> {
+    let start = align_up(region.start, HEAP_ALIGN);
+    if start >= region.end {
+        return Err(StartupError::NoUsableMemory);
+    }
+    let total = region.end - start;
+    let pool = pool_size.min(total / 2);
+    let size = (total - pool) & !(HEAP_ALIGN - 1);
+    let heap = HeapPlacement { start, size, pool_end: region.end };
+
+    if wipe {
+        unsafe { fast_set(start as *mut u8, 0, total); }
+    }
+    build(&heap);
+    Ok(heap)
+}
+
+/// Build the emergency pool and the allocator of the active backend for the real heap.
+fn build_allocators(heap: &HeapPlacement) {
+    let pool_start = heap.start + heap.size;
+    if pool_start < heap.pool_end {
+        EMERGENCY_START.store(pool_start, Ordering::Relaxed);
+        EMERGENCY_END.store(heap.pool_end, Ordering::Relaxed);
+        unsafe {
+            let mut allocator = EMERGENCY_ALLOCATOR.lock();
+            *allocator = LinkedListAllocator::new(pool_start, heap.pool_end - pool_start);
+            allocator.init();
+        }
+    }
+
+    unsafe {
+        match backend() {
+            Backend::Bump => {
+                let mut allocator = BUMP_ALLOCATOR.lock();
+                *allocator = BumpAllocator::new(heap.start, heap.size);
+                allocator.init();
+            }
+            Backend::List => {
+                let mut allocator = LIST_ALLOCATOR.lock();
+                *allocator = LinkedListAllocator::new(heap.start, heap.size);
+                allocator.init();
+            }
+            Backend::BoundaryTag => {
+                let mut allocator = BOUNDARY_TAG_ALLOCATOR.lock();
+                *allocator = BoundaryTagAllocator::new(heap.start, heap.size);
+                allocator.init();
+            }
+        }
+    }
+}
+
+/// Return the start address of the heap (0 before `init()`).
+pub fn heap_start() -> usize {
+    HEAP_START.load(Ordering::Relaxed)
+}
+
+/// Return the size of the heap in bytes, without the emergency pool (0 before `init()`).
+pub fn heap_size() -> usize {
+    HEAP_SIZE.load(Ordering::Relaxed)
+}
+
 /// Allocates memory from the heap. Compiler generates code calling this function.
 pub fn alloc(layout: Layout) -> *mut u8 {
     unsafe {
-        ALLOCATOR.lock().alloc(layout)
+        ALLOCATOR.alloc(layout)
     }
 }
 
 /// Deallocates memory from the heap. Compiler generates code calling this function.
 pub fn dealloc(ptr: *mut u8, layout: Layout) {
     unsafe {
-        ALLOCATOR.lock().dealloc(ptr, layout)
+        ALLOCATOR.dealloc(ptr, layout)
     }
 }
 
 /// Dump heap free list. Must be called by own program.
 /// Can be used for debugging the heap allocator. 
 pub fn dump_free_list() {
-    ALLOCATOR.lock().dump_free_list();
+    match backend() {
+        Backend::Bump => BUMP_ALLOCATOR.lock().dump_free_list(),
+        Backend::List => LIST_ALLOCATOR.lock().dump_free_list(),
+        Backend::BoundaryTag => BOUNDARY_TAG_ALLOCATOR.lock().dump_free_list(),
+    }
+}
+
+/// Save the state of the bump allocator, so that everything allocated afterwards can be
+/// freed at once by `rollback()` (scratch allocations). Returns `None` for the other backends.
+pub fn checkpoint() -> Option<Checkpoint> {
+    match backend() {
+        Backend::Bump => Some(BUMP_ALLOCATOR.lock().checkpoint()),
+        _ => None,
+    }
+}
+
+/// Free everything allocated by the bump allocator since `cp` was taken in O(1)
+/// (see `BumpAllocator::rollback()`). Nested checkpoints can be rolled back from the newest
+/// to the oldest; a checkpoint taken after the one rolled back to is rejected afterwards.
+///
+/// # Safety
+/// All references into memory allocated after the checkpoint (e.g. `Box`es and `Vec`s
+/// created afterwards) become dangling and must neither be used nor dropped.
+pub unsafe fn rollback(cp: Checkpoint) -> Result<(), RollbackError> {
+    if backend() != Backend::Bump {
+        return Err(RollbackError::NotSupported);
+    }
+    unsafe { BUMP_ALLOCATOR.lock().rollback(cp)? };
+    BYTES_IN_USE.store(cp.bytes_in_use, Ordering::Relaxed);
+    check_usage();
+    Ok(())
+}
+
+/// Maximum number of free blocks listed by `visualize()`.
+const VISUALIZE_MAX_BLOCKS: usize = 16;
+
+/// Width of the bars drawn by `visualize()`.
+const VISUALIZE_BAR_WIDTH: usize = 30;
+
+/// Show the free list of the list allocator: one line per free block (at most `max_lines`,
+/// up to `VISUALIZE_MAX_BLOCKS`) with its address, size and a bar relative to the largest one,
+/// followed by the number of blocks not shown. Does nothing for the other backends.
+pub fn visualize(max_lines: usize) {
+    if backend() != Backend::List {
+        return;
+    }
+
+    // Copy the blocks first, so the allocator is not locked while printing.
+    let mut blocks: ArrayVec<(usize, usize), VISUALIZE_MAX_BLOCKS> = ArrayVec::new();
+    let mut count = 0;
+    let mut largest = 0;
+    for (start, size) in LIST_ALLOCATOR.lock().free_blocks() {
+        if count < max_lines {
+            blocks.push((start, size)).ok();
+        }
+        count += 1;
+        largest = largest.max(size);
+    }
+
+    for &(start, size) in blocks.iter() {
+        print!("{:#010x} ", start);
+        chart::print_bar(size, largest, VISUALIZE_BAR_WIDTH);
+    }
+    if count > blocks.len() {
+        println!("... {} more free blocks", count - blocks.len());
+    }
+}
+
+/// Result of `defragment()`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct DefragStats {
+    /// Number of free blocks merged into an adjacent one
+    pub merged: usize,
+    /// Size of the largest free block afterwards in bytes
+    pub largest_free: usize,
+}
+
+/// Sort the free list of the list allocator by address and merge all adjacent free blocks
+/// in one pass. Freed blocks are only inserted, not merged, so after heavy churn the heap
+/// consists of many small free blocks. Live allocations cannot be moved, so fragmentation
+/// caused by them remains. Returns `None` for the other backends, which have no free list to sort.
+pub fn defragment() -> Option<DefragStats> {
+    match backend() {
+        Backend::List => Some(LIST_ALLOCATOR.lock().defragment()),
+        _ => None,
+    }
+}
+
+/// Errors that can occur when warming up the heap.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WarmupError {
+    /// The active backend is not the list allocator.
+    NotSupported,
+}
+
+/// Touch every page of the free heap memory once, e.g. before a benchmark which should not
+/// include the cost of the first access. Live allocations are kept, so this works at any time
+/// after boot. Returns the number of pages written (see `LinkedListAllocator::warmup()`).
+pub fn warmup() -> Result<usize, WarmupError> {
+    match backend() {
+        Backend::List => Ok(LIST_ALLOCATOR.lock().warmup()),
+        _ => Err(WarmupError::NotSupported),
+    }
+}
+
+/// Maximum number of queued reports (see `report()`).
+const MAX_REPORTS: usize = 8;
+
+/// Delay before printing the queued reports is tried again while the console is busy (milliseconds).
+const REPORT_RETRY_MS: u64 = 10;
+
+/// A message of the allocator. The allocator must not print while it is locked (printing
+/// would deadlock if the interrupted code is printing itself), so it queues the message
+/// with `report()` and it is printed later by a soft interrupt.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HeapReport {
+    /// A search of the free list visited more than `max_nodes` nodes (see `set_max_search_nodes()`).
+    FreeListCorrupted { max_nodes: usize },
+    /// The heap usage reached the warning threshold of `percent` (see `set_usage_warning()`).
+    UsageHigh { percent: usize, in_use: usize, size: usize },
+    /// The heap usage dropped below the warning threshold of `percent` again.
+    UsageLow { percent: usize, in_use: usize, size: usize },
+    /// A block at `addr` was aligned to `actual` instead of `requested` (see `audit_alignment()`).
+    AlignmentViolation { addr: usize, requested: usize, actual: usize },
+}
+
+/// Reports not printed yet, oldest first.
+static REPORTS: IrqSafeMutex<ArrayVec<HeapReport, MAX_REPORTS>> = IrqSafeMutex::new(ArrayVec::new());
+
+/// Set while printing the queued reports is scheduled.
+static REPORTS_SCHEDULED: AtomicBool = AtomicBool::new(false);
+
+/// Queue `report` to be printed by a soft interrupt. If the queue is full, it is dropped.
+fn report(report: HeapReport) {
+    REPORTS.lock().push(report).ok();
+    if !REPORTS_SCHEDULED.swap(true, Ordering::Relaxed) && !softirq::raise(print_reports) {
+        REPORTS_SCHEDULED.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Print the queued reports. The soft interrupt runs on the stack of the interrupted code,
+/// so if that code is printing, the reports are printed by a later attempt.
+fn print_reports() {
+    if console::is_busy() {
+        if timer::set_alarm(REPORT_RETRY_MS, print_reports).is_err() {
+            REPORTS_SCHEDULED.store(false, Ordering::Relaxed);
+        }
+        return;
+    }
+
+    REPORTS_SCHEDULED.store(false, Ordering::Relaxed);
+    while let Some(report) = take_report() {
+        match report {
+            HeapReport::FreeListCorrupted { max_nodes } => {
+                kprintln!("list-alloc: free list longer than {} nodes, it is probably corrupted", max_nodes);
+            }
+            HeapReport::UsageHigh { percent, in_use, size } => {
+                kprintln!("heap {}% full ({} of {} bytes in use)", percent, in_use, size);
+            }
+            HeapReport::UsageLow { percent, in_use, size } => {
+                kprintln!("heap usage back below {}% ({} of {} bytes in use)", percent, in_use, size);
+            }
+            HeapReport::AlignmentViolation { addr, requested, actual } => {
+                kprintln!("allocator: alignment violation at {:#x}: requested {}, actual {}", addr, requested, actual);
+            }
+        }
+    }
+}
+
+/// Remove the oldest queued report and return it.
+fn take_report() -> Option<HeapReport> {
+    let mut reports = REPORTS.lock();
+    if reports.is_empty() { None } else { Some(reports.remove(0)) }
+}
+
+/// Interval between two checks of the tripwire in milliseconds.
+const TRIPWIRE_CHECK_MS: u64 = 10;
+
+/// The active tripwire (see `set_tripwire()`). It is checked by a soft interrupt.
+static TRIPWIRE: IrqSafeMutex<Option<Tripwire>> = IrqSafeMutex::new(None);
+
+/// Set while a check of the tripwire is scheduled as alarm.
+static TRIPWIRE_SCHEDULED: AtomicBool = AtomicBool::new(false);
+
+/// The last alert of the tripwire (see `tripwire_alert()`).
+static TRIPWIRE_ALERT: IrqSafeMutex<Option<TripwireAlert>> = IrqSafeMutex::new(None);
+
+/// Set while the last alert of the tripwire has not been printed yet.
+static TRIPWIRE_ALERT_PENDING: AtomicBool = AtomicBool::new(false);
+
+/// 8 bytes of memory watched for modification.
+#[derive(Copy, Clone)]
+struct Tripwire {
+    addr: usize,
+    expected: u64,
+}
+
+/// Modification of the memory watched by the tripwire.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TripwireAlert {
+    pub addr: usize,
+    pub expected: u64,
+    /// The value found instead of the expected one
+    pub value: u64,
+}
+
+/// Watch the 8 bytes at `addr`: an alert is printed as soon as they no longer equal `expected`.
+/// This is a poor man's watchpoint for debugging heap corruption. The memory is checked
+/// every `TRIPWIRE_CHECK_MS` milliseconds by a timer alarm (running as soft interrupt), so the
+/// timer must be plugged in. Only one tripwire can be active; it replaces a previous one.
+/// The tripwire is removed after it has fired.
+pub fn set_tripwire(addr: usize, expected: u64) {
+    *TRIPWIRE.lock() = Some(Tripwire { addr, expected });
+    *TRIPWIRE_ALERT.lock() = None;
+
+    if !TRIPWIRE_SCHEDULED.swap(true, Ordering::Relaxed) {
+        schedule_tripwire_check();
+    }
+}
+
+/// Remove the active tripwire.
+pub fn clear_tripwire() {
+    *TRIPWIRE.lock() = None;
+}
+
+/// Schedule the next check of the tripwire.
+fn schedule_tripwire_check() {
+    if timer::set_alarm(TRIPWIRE_CHECK_MS, check_tripwire).is_err() {
+        TRIPWIRE_SCHEDULED.store(false, Ordering::Relaxed);
+        kprintln!("tripwire: no free alarm, tripwire is not checked");
+    }
+}
+
+/// Return the last alert of the tripwire since `set_tripwire()` was called, if it has fired.
+pub fn tripwire_alert() -> Option<TripwireAlert> {
+    *TRIPWIRE_ALERT.lock()
+}
+
+/// Check the tripwire and schedule the next check, if it is still active
+/// or its alert has not been printed yet.
+fn check_tripwire() {
+    {
+        let mut tripwire = TRIPWIRE.lock();
+        if let Some(Tripwire { addr, expected }) = *tripwire {
+            // Volatile, because the memory is modified behind the back of the compiler.
+            // Read as byte array, so `addr` does not need to be aligned.
+            let value = u64::from_ne_bytes(unsafe { ptr::read_volatile(addr as *const [u8; 8]) });
+            if value != expected {
+                *tripwire = None;
+                *TRIPWIRE_ALERT.lock() = Some(TripwireAlert { addr, expected, value });
+                TRIPWIRE_ALERT_PENDING.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+
+    // The check runs as soft interrupt on the stack of the interrupted code. Printing would
+    // deadlock if that code is printing itself, so the alert is printed by a later check then.
+    if TRIPWIRE_ALERT_PENDING.load(Ordering::Relaxed) && !console::is_busy() {
+        TRIPWIRE_ALERT_PENDING.store(false, Ordering::Relaxed);
+        if let Some(alert) = tripwire_alert() {
+            kprintln!("tripwire: {:#x} changed from {:#x} to {:#x}", alert.addr, alert.expected, alert.value);
+            println!("Tripwire: {:#x} changed from {:#x} to {:#x}", alert.addr, alert.expected, alert.value);
+        }
+    }
+
+    if TRIPWIRE.lock().is_some() || TRIPWIRE_ALERT_PENDING.load(Ordering::Relaxed) {
+        schedule_tripwire_check();
+    } else {
+        TRIPWIRE_SCHEDULED.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Number of buckets of the size histogram: 0-15, 16-31, 32-63, ..., 16 KiB and more.
+pub const HISTOGRAM_BUCKETS: usize = 12;
+
+/// Width of the bars printed by `dump_size_histogram()`.
+const HISTOGRAM_BAR_WIDTH: usize = 40;
+
+/// Number of allocations per size bucket (see `size_bucket()`).
+static SIZE_HISTOGRAM: [AtomicUsize; HISTOGRAM_BUCKETS] = [const { AtomicUsize::new(0) }; HISTOGRAM_BUCKETS];
+
+/// Return the histogram bucket of an allocation of `size` bytes.
+/// Bucket 0 holds sizes 0-15, bucket `i` the sizes 2^(i+3) to 2^(i+4)-1, the last bucket all larger sizes.
+pub fn size_bucket(size: usize) -> usize {
+    if size < 16 {
+        return 0;
+    }
+    (size.ilog2() as usize - 3).min(HISTOGRAM_BUCKETS - 1)
+}
+
+/// Return the smallest size of histogram bucket `bucket`.
+pub fn bucket_start(bucket: usize) -> usize {
+    if bucket == 0 { 0 } else { 1 << (bucket + 3) }
+}
+
+/// Return the number of allocations per size bucket since boot (see `size_bucket()`).
+pub fn size_histogram() -> [usize; HISTOGRAM_BUCKETS] {
+    let mut histogram = [0; HISTOGRAM_BUCKETS];
+    for (count, bucket) in histogram.iter_mut().zip(SIZE_HISTOGRAM.iter()) {
+        *count = bucket.load(Ordering::Relaxed);
+    }
+    histogram
+}
+
+/// Print the size histogram as bar chart.
+pub fn dump_size_histogram() {
+    let histogram = size_histogram();
+    let max = histogram.iter().copied().max().unwrap_or(0);
+
+    println!("Allocation sizes:");
+    for (bucket, &count) in histogram.iter().enumerate() {
+        if bucket == HISTOGRAM_BUCKETS - 1 {
+            print!("{:>6}+      ", bucket_start(bucket));
+        } else {
+            print!("{:>6}-{:<6} ", bucket_start(bucket), bucket_start(bucket + 1) - 1);
+        }
+        chart::print_bar(count, max, HISTOGRAM_BAR_WIDTH);
+    }
+}
+
+/// The kernel allocator forwards all requests to the selected backend.
+struct KernelAllocator {}
+
+unsafe impl GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        SIZE_HISTOGRAM[size_bucket(layout.size())].fetch_add(1, Ordering::Relaxed);
+        if injected_failure() {
+            return ptr::null_mut();
+        }
+
+        let mut ptr = if SIMULATE_FULL_HEAP.load(Ordering::Relaxed) {
+            ptr::null_mut()
+        } else {
+            unsafe {
+                match backend() {
+                    Backend::Bump => BUMP_ALLOCATOR.alloc(layout),
+                    Backend::List => LIST_ALLOCATOR.alloc(layout),
+                    Backend::BoundaryTag => BOUNDARY_TAG_ALLOCATOR.alloc(layout),
+                }
+            }
+        };
+        if ptr.is_null() {
+            ptr = emergency_alloc(layout);
+        }
+        if !ptr.is_null() {
+            check_alignment(ptr, layout);
+            log::record(EventKind::Alloc, layout.size() as u64);
+            BYTES_IN_USE.fetch_add(layout.size(), Ordering::Relaxed);
+            check_usage();
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe {
+            if ZERO_ON_FREE.load(Ordering::Relaxed) {
+                fast_set(ptr, 0, layout.size());
+            }
+            if is_emergency(ptr) {
+                EMERGENCY_ALLOCATOR.dealloc(ptr, layout);
+                EMERGENCY_BYTES_IN_USE.fetch_sub(layout.size(), Ordering::Relaxed);
+            } else {
+                match backend() {
+                    Backend::Bump => BUMP_ALLOCATOR.dealloc(ptr, layout),
+                    Backend::List => LIST_ALLOCATOR.dealloc(ptr, layout),
+                    Backend::BoundaryTag => BOUNDARY_TAG_ALLOCATOR.dealloc(ptr, layout),
+                }
+            }
+        }
+        log::record(EventKind::Dealloc, layout.size() as u64);
+        BYTES_IN_USE.fetch_sub(layout.size(), Ordering::Relaxed);
+        check_usage();
+    }
 }
 
 /// A wrapper around `spin::Mutex` to allow for trait implementations.
@@ -86,3 +1048,464 @@ fn align_up(addr: usize, align: usize) -> usize {
         addr - remainder + align
     }
 }
+
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Self tests, run by the diagnostics (see `user::diag`).                  ║
+   ╚═════════════════════════════════════════════════════════════════════════╝ */
+
+pub mod selftest {
+    use alloc::alloc::Layout;
+    use alloc::vec::Vec;
+    use core::{mem, slice};
+    use crate::kernel::allocator;
+    use crate::kernel::allocator::list::{LinkedListAllocator, ListNode};
+    use crate::kernel::memory::{self, RegionKind};
+    use alloc::boxed::Box;
+    use crate::devices::console::{self, Capture};
+    use crate::devices::{cga, cga_print};
+    use crate::kernel::{cpu, timer};
+    use crate::library::selftest::{restore_on_exit, Test, TestResult};
+
+    /// The self tests of this module, run in this order.
+    pub const TESTS: &[Test] = &[
+        ("Alloc failure", test_alloc_failure),
+        ("Emergency pool", test_emergency_pool),
+        ("Alloc delay", test_alloc_delay),
+        ("Heap wipe", test_heap_wipe),
+        ("Heap init", test_heap_init),
+        ("Zero on free", test_zero_on_free),
+        ("Usage warning", test_usage_warning),
+        ("Alignment audit", test_alignment_audit),
+        ("Tripwire", test_tripwire),
+        ("Trace off", test_trace_off),
+        ("Trace sink", test_trace_sink),
+        ("Size histogram", test_size_histogram),
+    ];
+
+    /// Inject a failure into the 3rd allocation and check that exactly this one fails.
+    fn test_alloc_failure() -> TestResult {
+        const N: usize = 3;
+        let mut vecs: [Vec<u8>; N + 1] = [const { Vec::new() }; N + 1];
+
+        // Allocations of interrupt handlers would count as well, so interrupts are disabled
+        // until the injection is cleared.
+        let results = cpu::without_interrupts(|| {
+            allocator::inject_failure_after(N);
+            let results = vecs.each_mut().map(|v| v.try_reserve(16).is_ok());
+            allocator::clear_failure_injection();
+            results
+        });
+
+        match results {
+            [true, true, false, true] => Ok(()),
+            [true, true, true, _] => Err("allocation did not fail"),
+            [_, _, false, false] => Err("following allocation failed too"),
+            _ => Err("wrong allocation failed"),
+        }
+    }
+
+    /// Let the main heap fail (test hook) and check that an allocation is served from the
+    /// emergency pool and returned to it, and that the main heap is used again afterwards.
+    fn test_emergency_pool() -> TestResult {
+        const SIZE: usize = 64;
+
+        if allocator::emergency_pool_size() == 0 {
+            return Err("no emergency pool");
+        }
+        let before = allocator::emergency_bytes_in_use();
+
+        // Allocations of interrupt handlers would be served from the pool as well meanwhile.
+        let mut v: Vec<u8> = Vec::new();
+        let reserved = cpu::without_interrupts(|| {
+            allocator::simulate_full_heap(true);
+            let reserved = v.try_reserve_exact(SIZE).is_ok();
+            allocator::simulate_full_heap(false);
+            reserved
+        });
+        if !reserved {
+            return Err("allocation failed with full heap");
+        }
+        if !allocator::is_emergency(v.as_ptr()) || allocator::emergency_bytes_in_use() != before + SIZE {
+            return Err("not allocated from the pool");
+        }
+        drop(v);
+        if allocator::emergency_bytes_in_use() != before {
+            return Err("not returned to the pool");
+        }
+
+        let mut v: Vec<u8> = Vec::new();
+        v.try_reserve_exact(SIZE).map_err(|_| "allocation failed")?;
+        if allocator::is_emergency(v.as_ptr()) {
+            return Err("pool used with free heap");
+        }
+        Ok(())
+    }
+
+    /// Measure an allocation with the per-allocation delay set and check
+    /// that it takes at least the configured number of TSC cycles.
+    fn test_alloc_delay() -> TestResult {
+        const DELAY_CYCLES: u64 = 1_000_000;
+
+        if !allocator::ALLOC_DELAY_AVAILABLE {
+            return Err("not available in release builds");
+        }
+        let saved = allocator::alloc_delay();
+        let _restore = restore_on_exit(|| allocator::set_alloc_delay(saved));
+        allocator::set_alloc_delay(DELAY_CYCLES);
+        let mut v: Vec<u8> = Vec::new();
+        let start = cpu::rdtsc();
+        let reserved = v.try_reserve(16).is_ok();
+        let cycles = cpu::rdtsc() - start;
+
+        if !reserved {
+            return Err("allocation failed");
+        }
+        if cycles < DELAY_CYCLES {
+            return Err("delay not observed");
+        }
+        Ok(())
+    }
+
+    /// Wipe an unaligned part of a scratch buffer with `allocator::fast_set()`
+    /// and check that it reads as zero while the bytes around it are untouched.
+    fn test_heap_wipe() -> TestResult {
+        const LEN: usize = 1000;
+        const OFFSET: usize = 3;
+        const WIPED: usize = 989;
+
+        let mut buffer: Vec<u8> = Vec::new();
+        if buffer.try_reserve(LEN).is_err() {
+            return Err("allocation failed");
+        }
+        buffer.resize(LEN, 0xa5);
+        unsafe { allocator::fast_set(buffer.as_mut_ptr().add(OFFSET), 0, WIPED); }
+
+        if buffer[OFFSET..OFFSET + WIPED].iter().any(|&b| b != 0) {
+            return Err("wiped bytes not zero");
+        }
+        if buffer[..OFFSET].iter().chain(&buffer[OFFSET + WIPED..]).any(|&b| b != 0xa5) {
+            return Err("bytes outside the wiped range changed");
+        }
+        Ok(())
+    }
+
+    /// Place a heap with an emergency pool in an unaligned scratch region, as `init()` does for
+    /// the real heap (which can only be initialized once), with and without wiping it. The heap
+    /// must be aligned and followed by the pool, the region must be zeroed before a list
+    /// allocator is built in it, and the allocator must serve allocations from the heap.
+    fn test_heap_init() -> TestResult {
+        const PAGES: usize = 4;
+        const POOL_SIZE: usize = 1024;
+        const FILL: u8 = 0xa5;
+
+        let mut scratch: Vec<u8> = Vec::new();
+        if scratch.try_reserve(PAGES * allocator::HEAP_ALIGN + 1).is_err() {
+            return Err("allocation failed");
+        }
+        for wipe in [false, true] {
+            scratch.clear();
+            scratch.resize(PAGES * allocator::HEAP_ALIGN + 1, FILL);
+            let region_start = scratch.as_ptr() as usize + 1; // unaligned on purpose
+            let region_end = scratch.as_ptr() as usize + scratch.len();
+            let region = memory::Region { start: region_start, end: region_end, kind: RegionKind::Usable };
+
+            let mut wiped = false;
+            let mut list = None;
+            let heap = allocator::place_heap(region, POOL_SIZE, wipe, |heap| {
+                let heap_bytes = unsafe { slice::from_raw_parts(heap.start as *const u8, heap.size) };
+                wiped = heap_bytes.iter().all(|&b| b == 0);
+                let mut allocator = LinkedListAllocator::new(heap.start, heap.size);
+                unsafe { allocator.init() };
+                list = Some(allocator);
+            }).map_err(|_| "region rejected")?;
+
+            if heap.start % allocator::HEAP_ALIGN != 0 || heap.start < region_start
+                || heap.size % allocator::HEAP_ALIGN != 0 || heap.pool_end != region_end {
+                return Err("heap not placed in the region");
+            }
+            if heap.pool_end - (heap.start + heap.size) < POOL_SIZE {
+                return Err("emergency pool too small");
+            }
+            if wiped != wipe {
+                return Err("region not wiped as requested");
+            }
+
+            let mut list = list.ok_or("allocator not built")?;
+            let ptr = unsafe { list.alloc(Layout::from_size_align(64, 8).unwrap()) } as usize;
+            if ptr < heap.start || ptr + 64 > heap.start + heap.size {
+                return Err("allocation outside of the heap");
+            }
+        }
+        Ok(())
+    }
+
+    /// Fill a block with a pattern, free it with zeroing enabled and allocate it again: it must
+    /// read as zero, except for the free list node written into it, which holds no pattern data.
+    fn test_zero_on_free() -> TestResult {
+        const SIZE: usize = 256;
+        const PATTERN: u8 = 0xa5;
+
+        if allocator::backend() == allocator::Backend::Bump {
+            return Err("bump allocator does not reuse blocks");
+        }
+        let saved = allocator::zero_on_free();
+        let _restore = restore_on_exit(|| allocator::set_zero_on_free(saved));
+        allocator::set_zero_on_free(true);
+
+        // Interrupt handlers could get the freed block meanwhile.
+        let layout = Layout::from_size_align(SIZE, 8).unwrap();
+        let (first, again) = cpu::without_interrupts(|| unsafe {
+            let first = allocator::alloc(layout);
+            if !first.is_null() {
+                allocator::fast_set(first, PATTERN, SIZE);
+                allocator::dealloc(first, layout);
+            }
+            (first, allocator::alloc(layout))
+        });
+        if first.is_null() || again.is_null() {
+            return Err("allocation failed");
+        }
+        let _free = restore_on_exit(|| allocator::dealloc(again, layout));
+        if again != first {
+            return Err("freed block not reused");
+        }
+
+        let data = unsafe { slice::from_raw_parts(again, SIZE) };
+        let (node, payload) = data.split_at(mem::size_of::<ListNode>());
+        if payload.iter().any(|&b| b != 0) {
+            return Err("freed block not zeroed");
+        }
+        if node.chunks(8).any(|word| word.iter().all(|&b| b == PATTERN)) {
+            return Err("pattern left in the free list node");
+        }
+        Ok(())
+    }
+
+    /// Set the usage warning threshold right above the current usage, then allocate a block
+    /// reaching it and another one, and free both again: exactly one warning must be queued
+    /// for crossing the threshold and one for dropping below it.
+    fn test_usage_warning() -> TestResult {
+        let saved = allocator::usage_warning();
+        let _restore = restore_on_exit(|| allocator::set_usage_warning(saved));
+
+        // Interrupt handlers would allocate as well, and the reports must not be printed
+        // before they have been checked, so interrupts stay disabled.
+        cpu::without_interrupts(|| {
+            let size = allocator::heap_size();
+            let percent = allocator::bytes_in_use() * 100 / size + 1;
+            if percent >= 100 {
+                return Err("heap too full");
+            }
+            allocator::set_usage_warning(percent);
+            let before = allocator::REPORTS.lock().len();
+
+            let needed = (size / 100 * percent).saturating_sub(allocator::bytes_in_use()).max(1);
+            let large = Layout::from_size_align(needed, 8).unwrap();
+            let small = Layout::from_size_align(16, 8).unwrap();
+            let (a, b) = (allocator::alloc(large), allocator::alloc(small));
+            if a.is_null() || b.is_null() {
+                return Err("allocation failed");
+            }
+            allocator::dealloc(b, small);
+            allocator::dealloc(a, large);
+
+            let mut reports = allocator::REPORTS.lock();
+            let queued = matches!(reports.get(before..),
+                Some([allocator::HeapReport::UsageHigh { .. }, allocator::HeapReport::UsageLow { .. }]));
+            while reports.len() > before {
+                reports.pop();
+            }
+            if !queued {
+                return Err("not exactly one warning per crossing");
+            }
+            Ok(())
+        })
+    }
+
+    /// Allocate some blocks with a large alignment while the list allocator ignores it
+    /// (test hook) and check that the alignment audit counts and reports violations.
+    fn test_alignment_audit() -> TestResult {
+        const BLOCKS: usize = 4;
+
+        if allocator::backend() != allocator::Backend::List {
+            return Err("list allocator not active");
+        }
+        let layout = Layout::from_size_align(32, 256).unwrap();
+        let before = allocator::alignment_violations();
+
+        // Blocks allocated by interrupt handlers while the hook is enabled would be freed later
+        // with a different size, so interrupts are disabled until the hook is switched off.
+        // This also keeps the reports from being printed before they have been checked.
+        let reported = cpu::without_interrupts(|| {
+            let reports = allocator::REPORTS.lock().len();
+            allocator::audit_alignment(true);
+            allocator::break_alignment(true);
+            let blocks = [(); BLOCKS].map(|_| unsafe { alloc::alloc::alloc(layout) });
+            for block in blocks {
+                if !block.is_null() {
+                    unsafe { alloc::alloc::dealloc(block, layout) };
+                }
+            }
+            allocator::break_alignment(false);
+            allocator::audit_alignment(false);
+
+            let mut queued = allocator::REPORTS.lock();
+            let reported = queued.get(reports..).is_some_and(|new| {
+                new.iter().any(|report| matches!(report, allocator::HeapReport::AlignmentViolation { .. }))
+            });
+            while queued.len() > reports {
+                queued.pop();
+            }
+            reported
+        });
+
+        if allocator::alignment_violations() == before {
+            return Err("violation not detected");
+        }
+        if !reported {
+            return Err("violation not reported");
+        }
+        Ok(())
+    }
+
+    /// Console capturing the alert of the tripwire test.
+    static CAPTURE: Capture = Capture::new();
+
+    /// Watch a heap value with the tripwire and check that it does not fire while the value
+    /// is unchanged, but on the next check after it has been modified, printing the alert.
+    fn test_tripwire() -> TestResult {
+        const VALUE: u64 = 0x1234_5678_9abc_def0;
+        // Wait for two checks, as the first one may be scheduled right before the modification.
+        const WAIT_MS: u64 = 2 * allocator::TRIPWIRE_CHECK_MS + 2;
+
+        let wait = || {
+            let deadline = timer::uptime_ms() + WAIT_MS;
+            while timer::uptime_ms() < deadline {
+                cpu::wait_for_int();
+            }
+        };
+
+        let mut watched = Box::new(VALUE);
+        let addr = &*watched as *const u64 as usize;
+        let previous = console::current();
+        let _restore = restore_on_exit(|| {
+            allocator::clear_tripwire();
+            console::set(previous);
+        });
+        console::set(&CAPTURE);
+        CAPTURE.take();
+
+        allocator::set_tripwire(addr, VALUE);
+        wait();
+        if allocator::tripwire_alert().is_some() {
+            return Err("fired without modification");
+        }
+
+        unsafe { core::ptr::write_volatile(&mut *watched, !VALUE) };
+        wait();
+        if allocator::tripwire_alert() != Some(allocator::TripwireAlert { addr, expected: VALUE, value: !VALUE }) {
+            return Err("modification not detected");
+        }
+        if !CAPTURE.take().as_bytes().starts_with(b"Tripwire: ") {
+            return Err("alert not printed");
+        }
+        Ok(())
+    }
+
+    /// Allocate with the trace on the screen, once disabled and once enabled, and check
+    /// with the character statistics of the CGA writer that only the enabled trace prints.
+    fn test_trace_off() -> TestResult {
+        let screen = cga::CGA.lock().save_screen();
+        let state = cga_print::save_state();
+        let sink = allocator::trace_sink();
+        let _restore = restore_on_exit(|| {
+            allocator::set_trace(false);
+            allocator::set_trace_sink(sink);
+            cga::CGA.lock().restore_screen(&screen);
+            cga_print::restore_state(&state);
+        });
+
+        // Output of interrupt handlers would be counted as well.
+        let printed = |trace: bool| cpu::without_interrupts(|| {
+            allocator::set_trace_sink(allocator::Sink::Screen);
+            allocator::set_trace(trace);
+            let before: usize = cga_print::color_stats().iter().sum();
+            drop(core::hint::black_box(Box::new(0u64)));
+            allocator::set_trace(false);
+            cga_print::color_stats().iter().sum::<usize>() - before
+        });
+
+        if printed(false) != 0 {
+            return Err("output while tracing is off");
+        }
+        if printed(true) == 0 {
+            return Err("no output while tracing is on");
+        }
+        Ok(())
+    }
+
+    /// Allocate blocks of known sizes and check that each one is counted in the expected
+    /// bucket of the size histogram and in no other.
+    fn test_size_histogram() -> TestResult {
+        const CASES: [(usize, usize); 6] = [(8, 0), (16, 1), (24, 1), (100, 3), (1000, 6), (1 << 14, 11)];
+
+        for (size, bucket) in CASES {
+            if allocator::size_bucket(size) != bucket {
+                return Err("wrong bucket for size");
+            }
+
+            // Allocations of interrupt handlers would be counted as well.
+            let layout = Layout::from_size_align(size, 8).unwrap();
+            let (before, after) = cpu::without_interrupts(|| {
+                let before = allocator::size_histogram();
+                let ptr = unsafe { alloc::alloc::alloc(layout) };
+                let after = allocator::size_histogram();
+                if !ptr.is_null() {
+                    unsafe { alloc::alloc::dealloc(ptr, layout) };
+                }
+                (before, after)
+            });
+
+            for (i, (b, a)) in before.iter().zip(after.iter()).enumerate() {
+                if a - b != (i == bucket) as usize {
+                    kprintln!("size histogram: {} bytes not counted in bucket {}", size, bucket);
+                    return Err("allocation counted in the wrong bucket");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Consoles standing in for COM1 and the screen in the trace sink test.
+    static SERIAL_CAPTURE: Capture = Capture::new();
+    static SCREEN_CAPTURE: Capture = Capture::new();
+
+    /// Select each trace sink and write a trace message to two capture consoles standing in
+    /// for COM1 and the screen: only the selected ones may receive the message.
+    fn test_trace_sink() -> TestResult {
+        const MESSAGE: &[u8] = b"trace\n";
+        const CASES: [(allocator::Sink, bool, bool); 3] = [
+            (allocator::Sink::Serial, true, false),
+            (allocator::Sink::Screen, false, true),
+            (allocator::Sink::Both, true, true),
+        ];
+
+        let sink = allocator::trace_sink();
+        let _restore = restore_on_exit(|| allocator::set_trace_sink(sink));
+        for (selected, serial, screen) in CASES {
+            SERIAL_CAPTURE.take();
+            SCREEN_CAPTURE.take();
+            allocator::set_trace_sink(selected);
+            allocator::write_trace(allocator::trace_sink(), &SERIAL_CAPTURE, &SCREEN_CAPTURE, format_args!("trace\n"));
+
+            for (capture, wanted) in [(&SERIAL_CAPTURE, serial), (&SCREEN_CAPTURE, screen)] {
+                let output = capture.take();
+                if output.as_bytes() != if wanted { MESSAGE } else { b"" } {
+                    kprintln!("trace sink: wrong output with {:?}", selected);
+                    return Err("output not only on the selected sink");
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,357 @@
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+ *  ║ Module: boundary_tag                                                    ║
+ *  ╟─────────────────────────────────────────────────────────────────────────╢
+ *  ║ Descr.: Implementing a boundary tag heap allocator (Knuth). Each block  ║
+ *  ║         stores its size and a used bit in a header and in a footer. The ║
+ *  ║         footer of the physically previous block is located right before ║
+ *  ║         the header of a block, so both neighbors of a freed block can   ║
+ *  ║         be found and merged in O(1) without traversing the free list.   ║
+ *  ║                                                                         ║
+ *  ║         Block layout                                                    ║
+ *  ║            used: [header][payload ...........................][footer]  ║
+ *  ║            free: [header][prev][next][unused ................][footer]  ║
+ *  ╚═════════════════════════════════════════════════════════════════════════╝
+ */
+use super::{align_up, min_align, spin_alloc_delay, trace, Locked};
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::{mem, ptr};
+
+/// Size of a header, footer or free list link.
+const WORD: usize = mem::size_of::<usize>();
+
+/// Smallest possible block: header, two free list links and footer.
+const MIN_BLOCK_SIZE: usize = 4 * WORD;
+
+/// Bit in a tag marking the block as used. Block sizes are multiples of `WORD`,
+/// so the lowest bit of the size is always free.
+const USED_BIT: usize = 1;
+
+/// A boundary tag allocator using an explicit, doubly linked free list.
+pub struct BoundaryTagAllocator {
+    /// Address of the first free block (0 if there is none)
+    free_head: usize,
+    heap_start: usize,
+    heap_end: usize,
+}
+
+impl BoundaryTagAllocator {
+    /// Create a new empty boundary tag allocator.
+    pub const fn new(heap_start: usize, heap_size: usize) -> BoundaryTagAllocator {
+        BoundaryTagAllocator {
+            free_head: 0,
+            heap_start,
+            heap_end: heap_start + heap_size,
+        }
+    }
+
+    /// Initialize the allocator with the heap bounds given in the constructor.
+    /// The first and last word of the heap are used as sentinel tags (marked as used),
+    /// so that merging never looks beyond the heap. The rest is one big free block.
+    pub unsafe fn init(&mut self) {
+        assert_eq!(align_up(self.heap_start, WORD), self.heap_start);
+
+        let first = self.heap_start + WORD;
+        let last = (self.heap_end - WORD) & !(WORD - 1);
+        unsafe {
+            write_word(self.heap_start, USED_BIT); // prologue (footer of a used block)
+            write_word(last, USED_BIT);            // epilogue (header of a used block)
+            self.set_tags(first, last - first, false);
+            self.push_free(first);
+        }
+    }
+
+    /// Dump the free list for debugging purposes.
+    pub fn dump_free_list(&mut self) {
+        println!("--- Free List Dump (boundary tags) ---");
+        println!("Heap start: {:#x}, Heap end: {:#x}", self.heap_start, self.heap_end);
+
+        let mut block = self.free_head;
+        while block != 0 {
+            let size = unsafe { block_size(block) };
+            println!(
+                "Free block at {:#x} -> size: {:}B (from {:#x} to {:#x})",
+                block,
+                size,
+                block,
+                block + size
+            );
+            block = unsafe { next_free(block) };
+        }
+
+        println!("--- End of Free List ---");
+    }
+
+    pub unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        trace(format_args!("boundary-tag-alloc: size={}, align={}\n", layout.size(), layout.align()));
+
+        let align = layout.align().max(WORD).max(min_align());
+        let needed = (align_up(layout.size(), WORD) + 2 * WORD).max(MIN_BLOCK_SIZE);
+
+        // First fit search in the free list
+        let mut block = self.free_head;
+        while block != 0 {
+            let size = unsafe { block_size(block) };
+
+            // The payload must be aligned. If this leaves a gap in front of the block,
+            // the gap must be large enough to become a free block of its own.
+            let mut payload = align_up(block + WORD, align);
+            let mut lead = payload - WORD - block;
+            if lead > 0 && lead < MIN_BLOCK_SIZE {
+                payload = align_up(block + WORD + MIN_BLOCK_SIZE, align);
+                lead = payload - WORD - block;
+            }
+
+            if lead + needed <= size {
+                unsafe {
+                    self.remove_free(block);
+
+                    if lead > 0 {
+                        self.set_tags(block, lead, false);
+                        self.push_free(block);
+                    }
+
+                    // Split off the rest of the block, if it can hold a free block
+                    let used = payload - WORD;
+                    let rest = size - lead;
+                    if rest - needed >= MIN_BLOCK_SIZE {
+                        self.set_tags(used, needed, true);
+                        self.set_tags(used + needed, rest - needed, false);
+                        self.push_free(used + needed);
+                    } else {
+                        self.set_tags(used, rest, true);
+                    }
+                }
+                return payload as *mut u8;
+            }
+
+            block = unsafe { next_free(block) };
+        }
+
+        // no suitable block found
+        ptr::null_mut()
+    }
+
+    pub unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        trace(format_args!("boundary-tag-dealloc: size={}, align={}\n", layout.size(), layout.align()));
+
+        let mut block = ptr as usize - WORD;
+        unsafe {
+            let mut size = block_size(block);
+
+            // Merge with the physically next block, if it is free
+            let next = block + size;
+            if !is_used(read_word(next)) {
+                self.remove_free(next);
+                size += block_size(next);
+            }
+
+            // Merge with the physically previous block, if it is free (its footer is right before us)
+            let prev_footer = read_word(block - WORD);
+            if !is_used(prev_footer) {
+                let prev = block - tag_size(prev_footer);
+                self.remove_free(prev);
+                size += tag_size(prev_footer);
+                block = prev;
+            }
+
+            self.set_tags(block, size, false);
+            self.push_free(block);
+        }
+    }
+
+    /// Write the header and footer of the block at `block`.
+    unsafe fn set_tags(&mut self, block: usize, size: usize, used: bool) {
+        let tag = if used { size | USED_BIT } else { size };
+        unsafe {
+            write_word(block, tag);
+            write_word(block + size - WORD, tag);
+        }
+    }
+
+    /// Insert the free block `block` at the front of the free list.
+    unsafe fn push_free(&mut self, block: usize) {
+        unsafe {
+            write_word(block + WORD, 0);                  // prev
+            write_word(block + 2 * WORD, self.free_head); // next
+            if self.free_head != 0 {
+                write_word(self.free_head + WORD, block);
+            }
+        }
+        self.free_head = block;
+    }
+
+    /// Remove the free block `block` from the free list in O(1).
+    unsafe fn remove_free(&mut self, block: usize) {
+        unsafe {
+            let prev = read_word(block + WORD);
+            let next = next_free(block);
+
+            if prev == 0 {
+                self.free_head = next;
+            } else {
+                write_word(prev + 2 * WORD, next);
+            }
+            if next != 0 {
+                write_word(next + WORD, prev);
+            }
+        }
+    }
+}
+
+/// Read the word at `addr`.
+unsafe fn read_word(addr: usize) -> usize {
+    unsafe { (addr as *const usize).read() }
+}
+
+/// Write `value` to the word at `addr`.
+unsafe fn write_word(addr: usize, value: usize) {
+    unsafe { (addr as *mut usize).write(value) }
+}
+
+/// Return the size stored in a tag.
+fn tag_size(tag: usize) -> usize {
+    tag & !USED_BIT
+}
+
+/// Check if a tag marks its block as used.
+fn is_used(tag: usize) -> bool {
+    tag & USED_BIT != 0
+}
+
+/// Return the size of the block at `block`, read from its header.
+unsafe fn block_size(block: usize) -> usize {
+    unsafe { tag_size(read_word(block)) }
+}
+
+/// Return the next block in the free list after the free block `block` (0 if there is none).
+unsafe fn next_free(block: usize) -> usize {
+    unsafe { read_word(block + 2 * WORD) }
+}
+
+// Trait required by the Rust runtime for heap allocations
+unsafe impl GlobalAlloc for Locked<BoundaryTagAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut allocator = self.lock();
+        let ptr = unsafe { allocator.alloc(layout) };
+        spin_alloc_delay();
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe {
+            self.lock().dealloc(ptr, layout);
+        }
+    }
+}
+
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Self tests, run by the diagnostics (see `user::diag`).                  ║
+   ╚═════════════════════════════════════════════════════════════════════════╝ */
+
+pub mod selftest {
+    use alloc::alloc::Layout;
+    use alloc::vec::Vec;
+    use crate::kernel::allocator::boundary_tag::{block_size, next_free, BoundaryTagAllocator, WORD};
+    use crate::library::selftest::{Test, TestResult};
+
+    /// The self tests of this module, run in this order.
+    pub const TESTS: &[Test] = &[
+        ("Merge with next", test_merge_next),
+        ("Merge with previous", test_merge_prev),
+        ("Merge with both", test_merge_both),
+    ];
+
+    /// Size of the private arenas in words.
+    const ARENA_WORDS: usize = 64;
+
+    /// Run `test` with a boundary tag allocator on a private arena, in which four blocks
+    /// (passed as the addresses of their headers) have been allocated. The rest of the arena
+    /// stays one free block behind them.
+    fn with_blocks(test: impl FnOnce(&mut BoundaryTagAllocator, [usize; 4]) -> TestResult) -> TestResult {
+        let mut arena = [0usize; ARENA_WORDS];
+        let mut bt = BoundaryTagAllocator::new(arena.as_mut_ptr() as usize, ARENA_WORDS * WORD);
+        let layout = Layout::from_size_align(4 * WORD, WORD).unwrap();
+
+        let mut blocks = [0; 4];
+        unsafe {
+            bt.init();
+            for block in blocks.iter_mut() {
+                let ptr = bt.alloc(layout);
+                if ptr.is_null() {
+                    return Err("arena too small");
+                }
+                *block = ptr as usize - WORD;
+            }
+        }
+        if free_list(&bt).len() != 1 {
+            return Err("rest of the arena not one free block");
+        }
+        test(&mut bt, blocks)
+    }
+
+    /// Free the block with the header at `block`.
+    fn free(bt: &mut BoundaryTagAllocator, block: usize) {
+        let layout = Layout::from_size_align(4 * WORD, WORD).unwrap();
+        unsafe { bt.dealloc((block + WORD) as *mut u8, layout) }
+    }
+
+    /// Return the free blocks (address, size), sorted by address.
+    fn free_list(bt: &BoundaryTagAllocator) -> Vec<(usize, usize)> {
+        let mut blocks = Vec::new();
+        let mut block = bt.free_head;
+        while block != 0 {
+            blocks.push((block, unsafe { block_size(block) }));
+            block = unsafe { next_free(block) };
+        }
+        blocks.sort_unstable();
+        blocks
+    }
+
+    /// Free the third and then the second block: the second block must be merged with the
+    /// physically next (third) one.
+    fn test_merge_next() -> TestResult {
+        with_blocks(|bt, [_, b, c, d]| {
+            free(bt, c);
+            free(bt, b);
+            let tail = free_list(bt)[1];
+            if free_list(bt) != [(b, d - b), tail] {
+                return Err("not merged with the next block");
+            }
+            Ok(())
+        })
+    }
+
+    /// Free the second and then the third block: the third block must be merged with the
+    /// physically previous (second) one.
+    fn test_merge_prev() -> TestResult {
+        with_blocks(|bt, [_, b, c, d]| {
+            free(bt, b);
+            free(bt, c);
+            let tail = free_list(bt)[1];
+            if free_list(bt) != [(b, d - b), tail] {
+                return Err("not merged with the previous block");
+            }
+            Ok(())
+        })
+    }
+
+    /// Free the first and third block and then the second one in between: all three must be
+    /// merged into one block. Freeing the last block must then restore a single free block.
+    fn test_merge_both() -> TestResult {
+        with_blocks(|bt, [a, b, c, d]| {
+            let tail = free_list(bt)[0];
+            free(bt, a);
+            free(bt, c);
+            free(bt, b);
+            if free_list(bt) != [(a, d - a), tail] {
+                return Err("not merged with both neighbors");
+            }
+
+            free(bt, d);
+            if free_list(bt) != [(a, tail.0 + tail.1 - a)] {
+                return Err("arena not one free block again");
+            }
+            Ok(())
+        })
+    }
+}
@@ -8,9 +8,13 @@
  *   ║         https://os.phil-opp.com/allocator-designs/                      ║
  *   ╚═════════════════════════════════════════════════════════════════════════╝
  */
-use super::{align_up, Locked};
+use super::{align_up, bytes_in_use, min_align, spin_alloc_delay, trace, Locked};
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::ptr;
+use crate::library::arrayvec::ArrayVec;
+
+/// Number of rollbacks remembered to detect stale checkpoints (see `invalidated_by()`).
+const ROLLBACK_HISTORY: usize = 8;
 
 /// A simple bump allocator that allocates memory in a linear fashion.
 pub struct BumpAllocator {
@@ -18,6 +22,31 @@ pub struct BumpAllocator {
     heap_end: usize,
     next: usize,
     allocations: usize,
+    /// Number of rollbacks so far, saved in each checkpoint
+    epoch: u64,
+    /// Target and epoch of the rollbacks which invalidated the checkpoints above their target,
+    /// sorted by target (and thereby by epoch). A rollback hides all older ones to higher targets.
+    rollbacks: ArrayVec<(usize, u64), ROLLBACK_HISTORY>,
+}
+
+/// State of a bump allocator saved by `checkpoint()`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Checkpoint {
+    next: usize,
+    allocations: usize,
+    /// Number of rollbacks before the checkpoint was taken
+    epoch: u64,
+    /// Bytes in use by the kernel allocator (restored by `allocator::rollback()`)
+    pub(super) bytes_in_use: usize,
+}
+
+/// Errors that can occur when rolling back to a checkpoint.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RollbackError {
+    /// The active backend is not the bump allocator.
+    NotSupported,
+    /// The allocator has already been rolled back past the checkpoint (or it belongs to another heap).
+    Stale,
 }
 
 impl BumpAllocator {
@@ -28,6 +57,8 @@ impl BumpAllocator {
             heap_end: heap_start + heap_size,
             next: heap_start,
             allocations: 0,
+            epoch: 0,
+            rollbacks: ArrayVec::new(),
         }
     }
 
@@ -52,7 +83,9 @@ impl BumpAllocator {
 
     /// Allocate memory of the given size and alignment.
     pub unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
-        let alloc_start = align_up(self.next, layout.align());
+        trace(format_args!("bump-alloc: size={}, align={}\n", layout.size(), layout.align()));
+
+        let alloc_start = align_up(self.next, layout.align().max(min_align()));
         let alloc_end = match alloc_start.checked_add(layout.size()) {
             Some(end) => end,
             None => return ptr::null_mut(),
@@ -67,8 +100,50 @@ impl BumpAllocator {
         }
     }
 
+    /// Save the current state, so that all later allocations can be freed at once by `rollback()`.
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint { next: self.next, allocations: self.allocations, epoch: self.epoch, bytes_in_use: bytes_in_use() }
+    }
+
+    /// Free everything allocated since `cp` was taken in O(1) by resetting the next pointer.
+    /// Rolling back to an older checkpoint invalidates all newer ones, even after the next
+    /// pointer has moved past them again, so they are rejected, since rolling back to them
+    /// would hand out memory twice. The checkpoint itself stays valid.
+    ///
+    /// # Safety
+    /// All references into memory allocated after the checkpoint become dangling;
+    /// the caller must ensure that none of them is used afterwards.
+    pub unsafe fn rollback(&mut self, cp: Checkpoint) -> Result<(), RollbackError> {
+        if cp.next > self.next || cp.next < self.heap_start || cp.allocations > self.allocations
+            || cp.epoch < self.invalidated_by(cp.next) {
+            return Err(RollbackError::Stale);
+        }
+        trace(format_args!("bump-rollback: {} bytes, {} allocations\n", self.next - cp.next, self.allocations - cp.allocations));
+
+        self.next = cp.next;
+        self.allocations = cp.allocations;
+
+        self.epoch += 1;
+        self.rollbacks.retain(|&(target, _)| target < cp.next);
+        if self.rollbacks.push((cp.next, self.epoch)).is_err() {
+            // Forget the lowest rollback, counting the checkpoints it invalidated as invalidated
+            // by the next one, which is newer. This can only reject more checkpoints, never less.
+            self.rollbacks[1].0 = self.rollbacks[0].0;
+            self.rollbacks.remove(0);
+            self.rollbacks.push((cp.next, self.epoch)).ok();
+        }
+        Ok(())
+    }
+
+    /// Return the epoch of the last rollback to below `addr`, which invalidated all checkpoints
+    /// at `addr` taken before it (0 if there has been none).
+    fn invalidated_by(&self, addr: usize) -> u64 {
+        self.rollbacks.iter().rev().find(|&&(target, _)| target < addr).map_or(0, |&(_, epoch)| epoch)
+    }
+
     /// Deallocate memory (not supported by bump allocator).
     pub unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        trace(format_args!("bump-dealloc: size={}, align={}\n", layout.size(), layout.align()));
         println!("Bump Allocator does not support deallocation")
     }
 }
@@ -76,9 +151,10 @@ impl BumpAllocator {
 // Trait required by the Rust runtime for heap allocations
 unsafe impl GlobalAlloc for Locked<BumpAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        unsafe {
-            self.lock().alloc(layout)
-        }
+        let mut allocator = self.lock();
+        let ptr = unsafe { allocator.alloc(layout) };
+        spin_alloc_delay();
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
@@ -87,3 +163,75 @@ unsafe impl GlobalAlloc for Locked<BumpAllocator> {
         }
     }
 }
+
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Self tests, run by the diagnostics (see `user::diag`).                  ║
+   ╚═════════════════════════════════════════════════════════════════════════╝ */
+
+pub mod selftest {
+    use alloc::alloc::Layout;
+    use crate::kernel::allocator::bump::{BumpAllocator, RollbackError};
+    use crate::library::selftest::{Test, TestResult};
+
+    /// The self tests of this module, run in this order.
+    pub const TESTS: &[Test] = &[
+        ("Rollback", test_rollback),
+        ("Stale checkpoint", test_stale_checkpoint),
+    ];
+
+    /// Allocate from a private bump allocator after nested checkpoints and check that
+    /// rollbacks reclaim the allocations and that a rolled back checkpoint is rejected.
+    fn test_rollback() -> TestResult {
+        const ARENA_SIZE: usize = 256;
+
+        let mut arena = [0u8; ARENA_SIZE];
+        let mut bump = BumpAllocator::new(arena.as_mut_ptr() as usize, ARENA_SIZE);
+        let layout = Layout::from_size_align(32, 8).unwrap();
+
+        unsafe {
+            let outer = bump.checkpoint();
+            let first = bump.alloc(layout);
+            let inner = bump.checkpoint();
+            bump.alloc(layout);
+            bump.alloc(layout);
+
+            bump.rollback(inner).map_err(|_| "inner rollback rejected")?;
+            bump.rollback(outer).map_err(|_| "outer rollback rejected")?;
+            if bump.rollback(inner) != Err(RollbackError::Stale) {
+                return Err("stale checkpoint accepted");
+            }
+            if bump.alloc(layout) != first {
+                return Err("allocations not reclaimed");
+            }
+        }
+        Ok(())
+    }
+
+    /// Take two checkpoints, roll back to the first one and allocate past the second one
+    /// again. The second checkpoint must be rejected, as its memory is in use again,
+    /// while the first one stays valid.
+    fn test_stale_checkpoint() -> TestResult {
+        const ARENA_SIZE: usize = 256;
+
+        let mut arena = [0u8; ARENA_SIZE];
+        let mut bump = BumpAllocator::new(arena.as_mut_ptr() as usize, ARENA_SIZE);
+        let layout = Layout::from_size_align(32, 8).unwrap();
+
+        unsafe {
+            let first = bump.checkpoint();
+            bump.alloc(layout);
+            let second = bump.checkpoint();
+            bump.rollback(first).map_err(|_| "rollback rejected")?;
+            bump.alloc(layout);
+            if bump.alloc(layout).is_null() {
+                return Err("arena too small");
+            }
+
+            if bump.rollback(second) != Err(RollbackError::Stale) {
+                return Err("stale checkpoint accepted");
+            }
+            bump.rollback(first).map_err(|_| "valid checkpoint rejected")?;
+        }
+        Ok(())
+    }
+}
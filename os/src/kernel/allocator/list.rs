@@ -7,14 +7,14 @@
  *  ║         https://os.phil-opp.com/allocator-designs/                      ║
  *  ╚═════════════════════════════════════════════════════════════════════════╝
  */
-use super::{align_up, Locked};
+use super::{address_ordered, align_up, alignment_broken, cache_align, max_search_nodes, min_align, report, spin_alloc_delay, trace, DefragStats, HeapReport, Locked, CACHE_LINE_SIZE, PAGE_SIZE};
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::{mem, ptr};
 use crate::kernel::allocator::bump::BumpAllocator;
 use crate::kernel::cpu as cpu;
 
 /// Header of a free block in the list allocator.
-struct ListNode {
+pub(super) struct ListNode {
     /// Size of the memory block
     size: usize,
 
@@ -49,6 +49,8 @@ pub struct LinkedListAllocator {
     head: ListNode,
     heap_start: usize,
     heap_end: usize,
+    /// Bytes added to the requested sizes of all live allocations by `size_align()`
+    padding: usize,
 }
 
 impl LinkedListAllocator {
@@ -58,9 +60,16 @@ impl LinkedListAllocator {
             head: ListNode::new(heap_size),
             heap_start,
             heap_end: heap_start + heap_size,
+            padding: 0,
         }
     }
 
+    /// Return the number of bytes added to the requested sizes of all live allocations
+    /// (for storing a `ListNode` on free, the minimum alignment and cache line alignment).
+    pub fn padding_bytes(&self) -> usize {
+        self.padding
+    }
+
     /// Initialize the allocator with the heap bounds given in the constructor.
     pub unsafe fn init(&mut self) {
         unsafe { 
@@ -68,35 +77,53 @@ impl LinkedListAllocator {
         };
     }
 
-    /// Adds the given free memory block 'addr' to the front of the free list.
+    /// Adds the given free memory block 'addr' to the free list: to the front of the list,
+    /// or at its address position if the list is address ordered (see `set_address_ordered()`).
     unsafe fn add_free_block(&mut self, addr: usize, size: usize) {
          // ensure that the freed block is capable of holding ListNode
          assert_eq!(align_up(addr, mem::align_of::<ListNode>()), addr);
          assert!(size >= mem::size_of::<ListNode>());
+
+         // find the node after which the block is inserted
+         let mut current = &mut self.head;
+         if address_ordered() {
+             while current.next.as_ref().is_some_and(|next| next.start_addr() < addr) {
+                 current = current.next.as_mut().unwrap();
+             }
+         }
  
-         // create a new list node and append it at the start of the list
+         // create a new list node and insert it after the current node
          let mut node = ListNode::new(size);
-         node.next = self.head.next.take();
+         node.next = current.next.take();
          let node_ptr = addr as *mut ListNode;
          unsafe {
              node_ptr.write(node);
-             self.head.next = Some(&mut *node_ptr)
+             current.next = Some(&mut *node_ptr)
          }
     }
 
     /// Search a free block with the given size and alignment and remove it from the list.
-    fn find_free_block(&mut self, size: usize, align: usize) -> Option<&'static mut ListNode> {
+    /// Returns the block and the start address of the allocation within the block.
+    /// At most `max_search_nodes()` nodes are visited, so a corrupted (e.g. cyclic)
+    /// list is reported (printed later, see `HeapReport`) and the allocation fails instead of hanging.
+    fn find_free_block(&mut self, size: usize, align: usize) -> Option<(&'static mut ListNode, usize)> {
         // reference to current list node, updated for each iteration
         let mut current = &mut self.head;
+        let mut visited = 0;
         
         // look for a large enough memory block in linked list
         while let Some(ref mut block) = current.next {
+            visited += 1;
+            if visited > max_search_nodes() {
+                report(HeapReport::FreeListCorrupted { max_nodes: max_search_nodes() });
+                return None;
+            }
             if let Ok(alloc_start) = LinkedListAllocator::check_block_for_alloc(&block, size, align) {
                 // block suitable for allocation -> remove node from list
                 let next = block.next.take();
                 let ret = current.next.take();
                 current.next = next;
-                return ret;
+                return ret.map(|block| (block, alloc_start));
             } else {
                 // block not suitable -> continue with next block
                 current = current.next.as_mut().unwrap();
@@ -110,7 +137,12 @@ impl LinkedListAllocator {
     /// Check if the given block is large enough for an allocation with `size` and `align`.
     fn check_block_for_alloc(block: &ListNode, size: usize, align: usize) -> Result<usize, ()> {
 
-        let alloc_start = align_up(block.start_addr(), align);
+        let mut alloc_start = align_up(block.start_addr(), align);
+        if alloc_start > block.start_addr() && alloc_start - block.start_addr() < mem::size_of::<ListNode>() {
+            // gap in front of the allocation too small to hold a ListNode (it is
+            // returned to the free list) -> use the next aligned address
+            alloc_start = align_up(block.start_addr() + mem::size_of::<ListNode>(), align);
+        }
         let alloc_end = alloc_start.checked_add(size).ok_or(())?;
 
         if alloc_end > block.end_addr() {
@@ -130,10 +162,20 @@ impl LinkedListAllocator {
     }
 
     /// Adjust the given layout so that the resulting allocated memory
-    /// block is also capable of storing a `ListNode`.
+    /// block is also capable of storing a `ListNode` and respects the minimum alignment.
+    /// With cache line alignment, size and alignment are rounded up to `CACHE_LINE_SIZE`.
     fn size_align(layout: Layout) -> (usize, usize) {
+        let mut align = align_of::<ListNode>().max(min_align());
+        if cache_align() {
+            align = align.max(CACHE_LINE_SIZE);
+        }
+        if alignment_broken() {
+            // test hook: drop the requested alignment (see `allocator::break_alignment()`)
+            let size = align_up(layout.size(), align_of::<ListNode>()).max(size_of::<ListNode>());
+            return (size, align_of::<ListNode>());
+        }
         let layout = layout
-        .align_to(align_of::<ListNode>())
+        .align_to(align)
         .expect("adjusting alignment failed")
         .pad_to_align();
         let size = layout.size().max(size_of::<ListNode>());
@@ -141,6 +183,82 @@ impl LinkedListAllocator {
         (size, layout.align())
     }
 
+    /// Sort the free list by address and merge all adjacent free blocks.
+    /// Live allocations are not moved, only the free blocks are reorganized.
+    pub fn defragment(&mut self) -> DefragStats {
+        // sort the free list by reinserting each node at its address position
+        let mut unsorted = self.head.next.take();
+        while let Some(node) = unsorted {
+            unsorted = node.next.take();
+
+            let addr = node.start_addr();
+            let mut current = &mut self.head;
+            while current.next.as_ref().is_some_and(|next| next.start_addr() < addr) {
+                current = current.next.as_mut().unwrap();
+            }
+            node.next = current.next.take();
+            current.next = Some(node);
+        }
+
+        // merge each block with its successors as long as they are adjacent
+        let mut stats = DefragStats { merged: 0, largest_free: 0 };
+        let mut current = &mut self.head;
+        while let Some(ref mut block) = current.next {
+            loop {
+                let end = block.end_addr();
+                if !block.next.as_ref().is_some_and(|next| next.start_addr() == end) {
+                    break;
+                }
+                let next = block.next.take().unwrap();
+                block.size += next.size;
+                block.next = next.next.take();
+                stats.merged += 1;
+            }
+            stats.largest_free = stats.largest_free.max(block.size);
+            current = current.next.as_mut().unwrap();
+        }
+
+        stats
+    }
+
+    /// Write a byte to every page of the free blocks, so later allocations do not pay for the
+    /// first access. Only the memory behind the list nodes is written, so live allocations and
+    /// the free list are kept and this can be called at any time. Returns the number of writes
+    /// (one per page a free block touches).
+    pub fn warmup(&mut self) -> usize {
+        let mut pages = 0;
+        let mut current = &self.head;
+
+        while let Some(ref block) = current.next {
+            // The page holding the list node has been touched by walking the list.
+            let mut page = block.start_addr() + mem::size_of::<ListNode>();
+            while page < block.end_addr() {
+                unsafe { (page as *mut u8).write_volatile(0) };
+                pages += 1;
+                page = align_up(page + 1, PAGE_SIZE);
+            }
+            current = block;
+        }
+        pages
+    }
+
+    /// Return the total size of all free blocks in bytes.
+    pub fn free_bytes(&self) -> usize {
+        let mut free = 0;
+        let mut current = &self.head;
+        while let Some(ref block) = current.next {
+            free += block.size;
+            current = block;
+        }
+        free
+    }
+
+    /// Return an iterator over the free blocks as (start address, size) in list order.
+    pub fn free_blocks(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        core::iter::successors(self.head.next.as_deref(), |block| block.next.as_deref())
+            .map(|block| (block.start_addr(), block.size))
+    }
+
     /// Dump the free list for debugging purposes.
     pub fn dump_free_list(&mut self) {
 
@@ -167,20 +285,28 @@ impl LinkedListAllocator {
     }
 
     pub unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
-        // kprintln!("list-alloc: size={}, align={}", layout.size(), layout.align());
+        trace(format_args!("list-alloc: size={}, align={}\n", layout.size(), layout.align()));
 
         // perform layout adjustments
         let (size, align) = LinkedListAllocator::size_align(layout);
 
-        if let Some(block) = self.find_free_block(size, align) {
-            let alloc_start = block.start_addr();
+        if let Some((block, alloc_start)) = self.find_free_block(size, align) {
+            let block_start = block.start_addr();
+            let block_end = block.end_addr();
             let alloc_end = alloc_start.checked_add(size).expect("overflow");
-            let excess_size = block.end_addr() - alloc_end;
-            if excess_size > 0 {
+
+            // return the unused parts in front of and behind the allocation to the free list
+            if alloc_start > block_start {
+                unsafe {
+                    self.add_free_block(block_start, alloc_start - block_start);
+                }
+            }
+            if block_end > alloc_end {
                 unsafe {
-                    self.add_free_block(alloc_end, excess_size);
+                    self.add_free_block(alloc_end, block_end - alloc_end);
                 }
             }
+            self.padding += size - layout.size();
             alloc_start as *mut u8
         } else {
             ptr::null_mut()
@@ -188,9 +314,10 @@ impl LinkedListAllocator {
     }
 
     pub unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
-        // kprintln!("list-dealloc: size={}, align={}; ", layout.size(), layout.align());
+        trace(format_args!("list-dealloc: size={}, align={}\n", layout.size(), layout.align()));
 
         let (size, _) = LinkedListAllocator::size_align(layout);
+        self.padding -= size - layout.size();
 
         unsafe {
             self.add_free_block(ptr as usize, size)
@@ -202,9 +329,10 @@ impl LinkedListAllocator {
 // Trait required by the Rust runtime for heap allocations
 unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        unsafe {
-            self.lock().alloc(layout)
-        }
+        let mut allocator = self.lock();
+        let ptr = unsafe { allocator.alloc(layout) };
+        spin_alloc_delay();
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
@@ -213,3 +341,285 @@ unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
         }
     }
 }
+
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Self tests, run by the diagnostics (see `user::diag`).                  ║
+   ╚═════════════════════════════════════════════════════════════════════════╝ */
+
+pub mod selftest {
+    use alloc::alloc::Layout;
+    use core::mem;
+    use core::sync::atomic::Ordering;
+    use crate::kernel::allocator;
+    use crate::kernel::allocator::list::{LinkedListAllocator, ListNode};
+    use crate::kernel::allocator::HeapReport;
+    use crate::kernel::cpu;
+    use crate::library::selftest::{restore_on_exit, Test, TestResult};
+
+    /// The self tests of this module, run in this order.
+    pub const TESTS: &[Test] = &[
+        ("Defragment", test_defragment),
+        ("Heap warmup", test_warmup),
+        ("Min. alignment", test_min_align),
+        ("Search limit", test_search_limit),
+        ("Cache line alignment", test_cache_align),
+        ("Address order", test_address_ordered),
+    ];
+
+    /// Size of the arena of `with_list_allocator()`.
+    const ARENA_SIZE: usize = 4096;
+
+    /// Run `test` with a private list allocator on an arena of `ARENA_SIZE` bytes (aligned to
+    /// its size, its start is passed to `test`) and the given minimum alignment, which is
+    /// restored afterwards. The setting is global and the kernel's list allocator would compute
+    /// other block sizes with it, so interrupts are disabled and `test` must not use the heap.
+    fn with_list_allocator(min_align: usize, test: impl FnOnce(&mut LinkedListAllocator, usize) -> TestResult) -> TestResult {
+        #[repr(align(4096))]
+        struct Arena([u8; ARENA_SIZE]);
+        let mut arena = Arena([0; ARENA_SIZE]);
+        let start = arena.0.as_mut_ptr() as usize;
+
+        cpu::without_interrupts(|| {
+            let saved = allocator::min_align();
+            let _restore = restore_on_exit(|| allocator::MIN_ALIGN.store(saved, Ordering::Relaxed));
+            allocator::MIN_ALIGN.store(min_align, Ordering::Relaxed);
+
+            let mut list = LinkedListAllocator::new(start, ARENA_SIZE);
+            unsafe { list.init() };
+            test(&mut list, start)
+        })
+    }
+
+    /// Allocate 1-byte values from a private list allocator with a minimum alignment of 64 bytes
+    /// and check that each of them is aligned to it.
+    fn test_min_align() -> TestResult {
+        const MIN_ALIGN: usize = 64;
+
+        with_list_allocator(MIN_ALIGN, |list, _| {
+            let byte = Layout::new::<u8>();
+            for _ in 0..4 {
+                let ptr = unsafe { list.alloc(byte) } as usize;
+                if ptr == 0 {
+                    return Err("arena too small");
+                }
+                if !ptr.is_multiple_of(MIN_ALIGN) {
+                    return Err("minimum alignment not respected");
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Free adjacent blocks of a private list allocator in scrambled order
+    /// and check that `defragment()` merges them into one free block.
+    fn test_defragment() -> TestResult {
+        const BLOCKS: usize = 8;
+        const BLOCK_SIZE: usize = 64;
+        const FREE_ORDER: [usize; BLOCKS] = [5, 2, 7, 0, 3, 6, 1, 4];
+
+        #[repr(align(4096))]
+        struct Arena([u8; BLOCKS * BLOCK_SIZE]);
+        let mut arena = Arena([0; BLOCKS * BLOCK_SIZE]);
+
+        let start = arena.0.as_mut_ptr() as usize;
+        let mut list = LinkedListAllocator::new(start, BLOCKS * BLOCK_SIZE);
+        let layout = Layout::from_size_align(BLOCK_SIZE, 8).unwrap();
+        let mut blocks = [core::ptr::null_mut(); BLOCKS];
+
+        unsafe {
+            list.init();
+            for block in blocks.iter_mut() {
+                *block = list.alloc(layout);
+                if block.is_null() {
+                    return Err("arena too small");
+                }
+            }
+            for i in FREE_ORDER {
+                list.dealloc(blocks[i], layout);
+            }
+        }
+
+        let stats = list.defragment();
+        if stats.merged != BLOCKS - 1 || stats.largest_free != BLOCKS * BLOCK_SIZE {
+            return Err("free blocks not merged");
+        }
+        Ok(())
+    }
+
+    /// Warm up a private list allocator with live allocations and check that the free memory
+    /// has been written, while the live blocks and the free list are unchanged.
+    fn test_warmup() -> TestResult {
+        const PAGES: usize = 2;
+
+        #[repr(align(4096))]
+        struct Arena([u8; PAGES * allocator::PAGE_SIZE]);
+        let mut arena = Arena([0xff; PAGES * allocator::PAGE_SIZE]);
+
+        let start = arena.0.as_mut_ptr() as usize;
+        let mut list = LinkedListAllocator::new(start, PAGES * allocator::PAGE_SIZE);
+        let small = Layout::from_size_align(100, 8).unwrap();
+        let large = Layout::from_size_align(3000, 8).unwrap();
+
+        let (live, pages) = unsafe {
+            list.init();
+            let a = list.alloc(small);
+            let b = list.alloc(large);
+            let c = list.alloc(small);
+            if a.is_null() || b.is_null() || c.is_null() {
+                return Err("arena too small");
+            }
+            list.dealloc(b, large);
+            let free = (list.free_bytes(), list.free_blocks().count());
+
+            let pages = list.warmup();
+            let live = [a, c].iter().all(|&block| (0..small.size()).all(|i| *block.add(i) == 0xff));
+            if (list.free_bytes(), list.free_blocks().count()) != free {
+                return Err("free list changed");
+            }
+            (live, pages)
+        };
+
+        if !live {
+            return Err("live allocation overwritten");
+        }
+        if pages < PAGES || arena.0[allocator::PAGE_SIZE] != 0 {
+            return Err("pages not touched");
+        }
+        Ok(())
+    }
+
+    /// Search a cyclic free list and a valid one longer than the search limit of a private list
+    /// allocator: both searches must stop at the limit, fail and queue a report (which is
+    /// removed again, it is no real corruption). A higher limit must find the block in the long list.
+    fn test_search_limit() -> TestResult {
+        const LIMIT: usize = 4;
+        const BLOCKS: usize = 12;
+
+        let small = Layout::from_size_align(32, 8).unwrap();
+        let large = Layout::from_size_align(64, 8).unwrap();
+
+        // Interrupts stay disabled, so the kernel heap is not searched with the lowered limit
+        // and the reports are not printed before they have been checked.
+        cpu::without_interrupts(|| {
+            let saved = allocator::max_search_nodes();
+            let _restore = restore_on_exit(|| allocator::set_max_search_nodes(saved));
+
+            with_list_allocator(allocator::min_align(), |list, _| unsafe {
+                let (a, b) = (list.alloc(small), list.alloc(small));
+                list.alloc(small); // keeps the freed blocks apart from the rest of the arena
+                list.dealloc(a, small);
+                list.dealloc(b, small);
+                // The list is b -> a -> rest, link a back to b.
+                (*(a as *mut ListNode)).next = Some(&mut *(b as *mut ListNode));
+
+                allocator::set_max_search_nodes(LIMIT);
+                if !list.alloc(large).is_null() {
+                    return Err("allocation from a cyclic list succeeded");
+                }
+                Ok(())
+            })?;
+
+            with_list_allocator(allocator::min_align(), |list, _| unsafe {
+                let mut blocks = [core::ptr::null_mut(); BLOCKS];
+                for block in blocks.iter_mut() {
+                    *block = list.alloc(small);
+                }
+                for block in blocks.iter().step_by(2) {
+                    list.dealloc(*block, small);
+                }
+
+                allocator::set_max_search_nodes(LIMIT);
+                let limited = list.alloc(large).is_null();
+                allocator::set_max_search_nodes(BLOCKS);
+                if !limited || list.alloc(large).is_null() {
+                    return Err("search limit not applied to a long list");
+                }
+                Ok(())
+            })?;
+
+            let corrupted = HeapReport::FreeListCorrupted { max_nodes: LIMIT };
+            let mut reports = allocator::REPORTS.lock();
+            let found = reports.iter().filter(|&&report| report == corrupted).count();
+            reports.retain(|&report| report != corrupted);
+            if found != 2 {
+                return Err("corruption not reported");
+            }
+            Ok(())
+        })
+    }
+
+    /// Allocate two single bytes from a private list allocator with cache line alignment and
+    /// check that they are at least a cache line apart and that the padding is accounted for.
+    fn test_cache_align() -> TestResult {
+        const LINE: usize = allocator::CACHE_LINE_SIZE;
+
+        with_list_allocator(allocator::min_align(), |list, _| {
+            let saved = allocator::cache_align();
+            let _restore = restore_on_exit(|| allocator::CACHE_ALIGN.store(saved, Ordering::Relaxed));
+            allocator::CACHE_ALIGN.store(true, Ordering::Relaxed);
+
+            let byte = Layout::new::<u8>();
+            let (a, b) = unsafe { (list.alloc(byte) as usize, list.alloc(byte) as usize) };
+            if a == 0 || b == 0 {
+                return Err("arena too small");
+            }
+            if a.abs_diff(b) < LINE || !a.is_multiple_of(LINE) || !b.is_multiple_of(LINE) {
+                return Err("allocations share a cache line");
+            }
+            if list.padding_bytes() != 2 * (LINE - 1) {
+                return Err("padding not accounted for");
+            }
+            Ok(())
+        })
+    }
+
+    /// Run a fixed sequence of allocations and frees on a private list allocator with an address
+    /// ordered free list and check that it yields the expected addresses: each allocation must
+    /// come from the lowest suitable free block.
+    fn test_address_ordered() -> TestResult {
+        /// A step of the request sequence: allocate a block of a size or free the nth allocated block.
+        enum Step {
+            Alloc(usize),
+            Free(usize),
+        }
+        use Step::{Alloc, Free};
+
+        const STEPS: [Step; 10] = [
+            Alloc(32), Alloc(64), Alloc(16), Alloc(128), Free(1), Free(0),
+            Alloc(24), Alloc(32), Alloc(40), Alloc(8),
+        ];
+        // Offsets from the start of the arena (a free list node takes 16 bytes)
+        const EXPECTED: [usize; 8] = [0, 32, 96, 112, 32, 0, 56, 240];
+
+        with_list_allocator(mem::align_of::<ListNode>(), |list, start| {
+            let saved = (allocator::address_ordered(), allocator::cache_align());
+            let _restore = restore_on_exit(|| {
+                allocator::set_address_ordered(saved.0);
+                allocator::CACHE_ALIGN.store(saved.1, Ordering::Relaxed);
+            });
+            allocator::set_address_ordered(true);
+            allocator::CACHE_ALIGN.store(false, Ordering::Relaxed);
+
+            let mut blocks = [(0, 0); EXPECTED.len()];
+            let mut allocated = 0;
+            for step in STEPS {
+                match step {
+                    Alloc(size) => {
+                        let layout = Layout::from_size_align(size, 8).unwrap();
+                        let ptr = unsafe { list.alloc(layout) } as usize;
+                        if ptr.wrapping_sub(start) != EXPECTED[allocated] {
+                            return Err("unexpected address");
+                        }
+                        blocks[allocated] = (ptr, size);
+                        allocated += 1;
+                    }
+                    Free(n) => {
+                        let (ptr, size) = blocks[n];
+                        unsafe { list.dealloc(ptr as *mut u8, Layout::from_size_align(size, 8).unwrap()) };
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,116 @@
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Module: boot                                                            ║
+   ╟─────────────────────────────────────────────────────────────────────────╢
+   ║ Descr.: Reporting of the init steps run by 'startup'. In verbose mode   ║
+   ║         each step prints a line on the screen, in quiet mode only a     ║
+   ║         single 'Booting' line with a dot per step. The messages always  ║
+   ║         go to the debug console (serial).                               ║
+   ╚═════════════════════════════════════════════════════════════════════════╝
+*/
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::devices::console;
+
+/// Boot mode used unless `set_verbose()` is called.
+pub const VERBOSE_BOOT: bool = true;
+
+/// Print a line per init step on the screen if set (see `set_verbose()`).
+static VERBOSE: AtomicBool = AtomicBool::new(VERBOSE_BOOT);
+
+/// Select verbose boot (a line per init step on the screen) or quiet boot
+/// (a single "Booting" line with a dot per step). The messages of the steps
+/// are written to the debug console in both modes.
+pub fn set_verbose(enabled: bool) {
+    VERBOSE.store(enabled, Ordering::Relaxed);
+}
+
+/// Check if the boot is verbose.
+pub fn is_verbose() -> bool {
+    VERBOSE.load(Ordering::Relaxed)
+}
+
+/// Output of the print macros as `fmt::Write`.
+struct Screen;
+
+impl Write for Screen {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        print!("{}", s);
+        Ok(())
+    }
+}
+
+/// Output of the kprint macros as `fmt::Write`.
+struct Serial;
+
+impl Write for Serial {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        kprint!("{}", s);
+        Ok(())
+    }
+}
+
+/// Start reporting the init steps.
+pub fn begin() {
+    if !is_verbose() {
+        print!("Booting");
+        console::flush();
+    }
+}
+
+/// Report the successful init step with `message`.
+pub fn step_done(message: &str) {
+    report_step(&mut Screen, &mut Serial, message, is_verbose()).ok();
+    console::flush();
+}
+
+/// Finish reporting the init steps.
+pub fn end() {
+    if !is_verbose() {
+        println!(" done");
+    }
+}
+
+/// Write the `message` of an init step to `serial` and, if `verbose`, to `screen`.
+/// In quiet mode, only a progress dot is written to `screen`.
+pub fn report_step(screen: &mut impl Write, serial: &mut impl Write, message: &str, verbose: bool) -> fmt::Result {
+    writeln!(serial, "{}", message)?;
+    if verbose {
+        writeln!(screen, "{}", message)
+    } else {
+        screen.write_char('.')
+    }
+}
+
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Self tests, run by the diagnostics (see `user::diag`).                  ║
+   ╚═════════════════════════════════════════════════════════════════════════╝ */
+
+pub mod selftest {
+    use alloc::string::String;
+    use crate::kernel::boot;
+    use crate::library::selftest::{Test, TestResult};
+
+    /// The self tests of this module, run in this order.
+    pub const TESTS: &[Test] = &[
+        ("Boot messages", test_boot_messages),
+    ];
+
+    /// Report an init step in quiet and verbose mode and check that the message
+    /// only reaches the screen in verbose mode, but always reaches serial.
+    fn test_boot_messages() -> TestResult {
+        const MESSAGE: &str = "Step done.";
+
+        let (mut screen, mut serial) = (String::new(), String::new());
+        boot::report_step(&mut screen, &mut serial, MESSAGE, false).map_err(|_| "report failed")?;
+        if screen != "." || serial != "Step done.\n" {
+            return Err("quiet mode printed the message");
+        }
+
+        let (mut screen, mut serial) = (String::new(), String::new());
+        boot::report_step(&mut screen, &mut serial, MESSAGE, true).map_err(|_| "report failed")?;
+        if screen != "Step done.\n" || serial != "Step done.\n" {
+            return Err("verbose mode did not print the message");
+        }
+        Ok(())
+    }
+}
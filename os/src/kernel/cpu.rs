@@ -7,7 +7,12 @@
    ╚═════════════════════════════════════════════════════════════════════════╝
 */
 
+use alloc::boxed::Box;
 use core::arch::asm;
+use core::sync::atomic::{compiler_fence, AtomicBool, Ordering};
+use spin::Mutex;
+use crate::kernel::interrupts::intdispatcher::{InterruptVector, INT_VECTORS};
+use crate::kernel::interrupts::isr::ISR;
 
 pub struct IoPort {
     port: u16
@@ -32,6 +37,18 @@ impl IoPort {
         }
     }
 
+    /// Write a word to a port
+    #[inline]
+    pub unsafe fn outw(&mut self, data: u16) {
+        unsafe {
+            asm!(
+            "out dx, ax",
+            in("dx") self.port,
+            in("ax") data,
+            );
+        }
+    }
+
     /// Read a single byte from a port
     #[inline]
     pub unsafe fn inb(&mut self) -> u8 {
@@ -100,6 +117,16 @@ pub fn halt () {
     }
 }
 
+/// Stop the CPU until the next interrupt occurs.
+/// If interrupts are disabled, this function returns immediately,
+/// because `hlt` would never be woken up.
+#[inline]
+pub fn wait_for_int() {
+    if is_int_enabled() {
+        unsafe { asm!("hlt"); }
+    }
+}
+
 /// Get the current value of RFLAGS
 #[inline]
 pub fn get_flags() -> u64 {
@@ -127,9 +154,155 @@ where F: FnOnce() -> R{
     ret
 }
 
+/// Read the time stamp counter, which counts processor cycles since reset.
+#[inline]
+pub fn rdtsc() -> u64 {
+    let low: u32;
+    let high: u32;
+    unsafe {
+        asm!("rdtsc", out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags));
+    }
+    (high as u64) << 32 | low as u64
+}
+
+/// Wait for about 1 µs by writing to port 0x80 (the unused POST code port), which takes
+/// one bus cycle. Slow devices like the PIC need this between two consecutive accesses.
 #[inline]
 pub fn io_wait() {
     unsafe {
-        asm!("rep insw", in("dx") 0x80, in("cx") 0);
+        asm!("out 0x80, al", in("al") 0u8, options(nostack, preserves_flags));
+    }
+}
+
+/// Memory fence: all loads and stores before it are completed before any load or store after it.
+#[inline]
+pub fn mfence() {
+    unsafe {
+        asm!("mfence", options(nostack, preserves_flags));
+    }
+}
+
+/// Compiler barrier: keeps the compiler from moving memory accesses across it.
+/// Unlike `mfence()`, no instruction is emitted.
+#[inline]
+pub fn barrier() {
+    compiler_fence(Ordering::SeqCst);
+}
+
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Hardware watchpoints using the debug registers.                         ║
+   ╚═════════════════════════════════════════════════════════════════════════╝ */
+
+// Bits in DR7 for breakpoint 0
+const DR7_L0: u64 = 1 << 0;       // local enable
+const DR7_LE: u64 = 1 << 8;       // exact breakpoint detection
+const DR7_RW0_SHIFT: u64 = 16;
+const DR7_LEN0_SHIFT: u64 = 18;
+const DR7_BP0_MASK: u64 = DR7_L0 | 0b1111 << DR7_RW0_SHIFT; // enable, RW0 and LEN0
+
+// Bits in DR6
+const DR6_B0: u64 = 1 << 0;       // breakpoint 0 was hit
+const DR6_BS: u64 = 1 << 14;      // single step
+
+/// Kind of access a watchpoint traps on.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Access {
+    Execute = 0b00,
+    Write = 0b01,
+    ReadWrite = 0b11,
+}
+
+/// Errors that can occur when setting a watchpoint.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WatchpointError {
+    /// `len` is not 1, 2, 4 or 8 (or not 1 for `Access::Execute`).
+    BadLength,
+    /// `addr` is not aligned to `len`.
+    Unaligned,
+}
+
+/// Called with the watched address when the watchpoint is hit.
+static WATCHPOINT_HANDLER: Mutex<Option<fn(usize)>> = Mutex::new(None);
+
+/// Set when the debug exception ISR has been registered.
+static WATCHPOINT_PLUGGED_IN: AtomicBool = AtomicBool::new(false);
+
+/// Set the function called (in the debug exception handler) when the watchpoint is hit.
+pub fn set_watchpoint_handler(handler: fn(usize)) {
+    without_interrupts(|| *WATCHPOINT_HANDLER.lock() = Some(handler));
+}
+
+/// Trap on each `access` to the `len` bytes at `addr`, using debug register DR0.
+/// A previous watchpoint is replaced. Data watchpoints trap after the access has completed.
+/// Must not be called from an ISR, since the debug exception handler is registered on first use.
+pub fn set_watchpoint(addr: usize, len: usize, access: Access) -> Result<(), WatchpointError> {
+    let len_bits: u64 = match len {
+        1 => 0b00,
+        2 => 0b01,
+        4 => 0b11,
+        8 => 0b10,
+        _ => return Err(WatchpointError::BadLength),
+    };
+    if access == Access::Execute && len != 1 {
+        return Err(WatchpointError::BadLength);
+    }
+    if !addr.is_multiple_of(len) {
+        return Err(WatchpointError::Unaligned);
+    }
+
+    if !WATCHPOINT_PLUGGED_IN.swap(true, Ordering::Relaxed) {
+        INT_VECTORS.lock().register(InterruptVector::Debug, Box::new(DebugISR {}));
+    }
+
+    let mut dr7 = read_dr7() & !DR7_BP0_MASK;
+    dr7 |= DR7_L0 | DR7_LE | (access as u64) << DR7_RW0_SHIFT | len_bits << DR7_LEN0_SHIFT;
+
+    unsafe {
+        asm!("mov dr0, {}", in(reg) addr as u64, options(nomem, nostack));
+        write_dr7(dr7);
+    }
+    Ok(())
+}
+
+/// Remove the watchpoint set by `set_watchpoint()`.
+pub fn clear_watchpoint() {
+    unsafe { write_dr7(read_dr7() & !DR7_BP0_MASK); }
+}
+
+fn read_dr7() -> u64 {
+    let dr7: u64;
+    unsafe { asm!("mov {}, dr7", out(reg) dr7, options(nomem, nostack)); }
+    dr7
+}
+
+unsafe fn write_dr7(dr7: u64) {
+    unsafe { asm!("mov dr7, {}", in(reg) dr7, options(nomem, nostack)); }
+}
+
+/// The debug exception (vector 1) service routine.
+struct DebugISR {}
+
+impl ISR for DebugISR {
+    fn trigger(&self) {
+        let dr6: u64;
+        let dr0: u64;
+        unsafe {
+            asm!("mov {}, dr6", out(reg) dr6, options(nomem, nostack));
+            asm!("mov {}, dr0", out(reg) dr0, options(nomem, nostack));
+            // DR6 is never cleared by the CPU
+            asm!("mov dr6, {}", in(reg) 0u64, options(nomem, nostack));
+        }
+
+        if dr6 & DR6_B0 != 0 {
+            let handler = *WATCHPOINT_HANDLER.lock();
+            match handler {
+                Some(handler) => handler(dr0 as usize),
+                None => kprintln!("watchpoint hit at {:#x}", dr0),
+            }
+        } else if dr6 & DR6_BS != 0 {
+            kprintln!("debug exception: single step");
+        } else {
+            kprintln!("debug exception: dr6 = {:#x}", dr6);
+        }
     }
 }
@@ -0,0 +1,111 @@
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Module: debug                                                           ║
+   ╟─────────────────────────────────────────────────────────────────────────╢
+   ║ Descr.: A simple inline debugger. 'breakpoint' dumps the register state ║
+   ║         and waits for single-key commands from the keyboard before      ║
+   ║         returning.                                                      ║
+   ╚═════════════════════════════════════════════════════════════════════════╝
+*/
+use core::arch::asm;
+use crate::devices::cga_print;
+use crate::devices::keyboard;
+use crate::kernel::allocator;
+use crate::kernel::cpu;
+use crate::kernel::log;
+use crate::kernel::timer;
+use crate::library::input;
+
+/// Snapshot of the registers at the time of `Registers::capture()`.
+#[derive(Copy, Clone, Debug)]
+pub struct Registers {
+    pub rip: u64,
+    pub rsp: u64,
+    pub rbp: u64,
+    pub rflags: u64,
+    pub cr0: u64,
+    pub cr2: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+}
+
+impl Registers {
+    /// Read the current register state.
+    /// `rip` is the address of the instruction following the capture.
+    #[inline(always)]
+    pub fn capture() -> Registers {
+        let (rip, rsp, rbp, cr0, cr2, cr3, cr4): (u64, u64, u64, u64, u64, u64, u64);
+
+        unsafe {
+            asm!(
+            "lea {rip}, [rip]",
+            "mov {rsp}, rsp",
+            "mov {rbp}, rbp",
+            "mov {cr0}, cr0",
+            "mov {cr2}, cr2",
+            "mov {cr3}, cr3",
+            "mov {cr4}, cr4",
+            rip = out(reg) rip,
+            rsp = out(reg) rsp,
+            rbp = out(reg) rbp,
+            cr0 = out(reg) cr0,
+            cr2 = out(reg) cr2,
+            cr3 = out(reg) cr3,
+            cr4 = out(reg) cr4,
+            options(nomem, nostack, preserves_flags)
+            );
+        }
+
+        Registers { rip, rsp, rbp, rflags: cpu::get_flags(), cr0, cr2, cr3, cr4 }
+    }
+
+    /// Print the registers on the screen.
+    pub fn dump(&self) {
+        println!("rip={:#018x} rsp={:#018x} rbp={:#018x}", self.rip, self.rsp, self.rbp);
+        println!("rflags={:#018x} (IF={})", self.rflags, (self.rflags >> 9) & 1);
+        println!("cr0={:#018x} cr2={:#018x}", self.cr0, self.cr2);
+        println!("cr3={:#018x} cr4={:#018x}", self.cr3, self.cr4);
+    }
+}
+
+/// Stop at a breakpoint: print the register state and a `(dbg)>` prompt and execute
+/// single-key commands until `c` is pressed:
+///   c = continue, r = registers, h = heap dump, s = statistics, k = keyboard state (on serial)
+///
+/// Keys are read from the keyboard interrupt, so this must not be called from an ISR,
+/// with interrupts disabled or while holding the lock of the CGA or the keyboard.
+pub fn breakpoint() {
+    let regs = Registers::capture();
+
+    if !cpu::is_int_enabled() {
+        kprintln!("breakpoint at {:#x} ignored: interrupts are disabled", regs.rip);
+        return;
+    }
+
+    println!("Breakpoint at {:#x}", regs.rip);
+    regs.dump();
+
+    loop {
+        print!("(dbg)> ");
+        let cmd = input::getch();
+        println!("{}", cmd);
+
+        match cmd {
+            'c' => break,
+            'r' => regs.dump(),
+            'h' => allocator::dump_free_list(),
+            's' => print_stats(),
+            'k' => keyboard::dump_state(),
+            'e' => log::export_events(),
+            _ => println!("c=continue, r=registers, h=heap dump, s=stats, k=keyboard state, e=export events"),
+        }
+    }
+}
+
+/// Print some statistics about the running system.
+fn print_stats() {
+    let printed: usize = cga_print::color_stats().iter().sum();
+
+    println!("uptime: {} ms ({} ticks)", timer::uptime_ms(), timer::get_ticks());
+    println!("allocator: {:?}, padding: {} bytes", allocator::backend(), allocator::padding_bytes());
+    println!("characters printed: {}", printed);
+}
@@ -0,0 +1,81 @@
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Module: init                                                            ║
+   ╟─────────────────────────────────────────────────────────────────────────╢
+   ║ Descr.: Errors of the init steps run by 'startup'. Each step returns a  ║
+   ║         'Result', so a failing step can be reported instead of          ║
+   ║         panicking somewhere deep in the kernel.                         ║
+   ╚═════════════════════════════════════════════════════════════════════════╝
+*/
+use core::fmt;
+
+/// Reasons why an init step of the kernel failed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StartupError {
+    /// `allocator::init()` has already been called.
+    HeapAlreadyInitialized,
+    /// The memory map has no usable region for the heap.
+    NoUsableMemory,
+    /// The interrupt masks read back from the PIC do not match the written ones.
+    PicNotResponding,
+    /// `IntVectors::init()` has already been called.
+    IntVectorsAlreadyInitialized,
+    /// An ISR was plugged in before `IntVectors::init()` was called.
+    IntVectorsNotInitialized,
+    /// The kernel stack is already used beyond the place of the stack canary.
+    StackTooSmall,
+}
+
+impl fmt::Display for StartupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match self {
+            StartupError::HeapAlreadyInitialized => "heap is already initialized",
+            StartupError::NoUsableMemory => "no usable memory for the heap",
+            StartupError::PicNotResponding => "PIC does not respond",
+            StartupError::IntVectorsAlreadyInitialized => "interrupt vector map is already initialized",
+            StartupError::IntVectorsNotInitialized => "interrupt vector map is not initialized",
+            StartupError::StackTooSmall => "kernel stack too small for the stack guard",
+        };
+        f.write_str(msg)
+    }
+}
+
+/// Message reported on the screen when an init step fails, e.g.
+/// `println!("{}", Failure(err))` (see `startup_failed()` in `startup`).
+pub struct Failure(pub StartupError);
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Startup failed: {}\nSystem halted.", self.0)
+    }
+}
+
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Self tests, run by the diagnostics (see `user::diag`).                  ║
+   ╚═════════════════════════════════════════════════════════════════════════╝ */
+
+pub mod selftest {
+    use crate::devices::console::{self, Capture};
+    use crate::kernel::init::{Failure, StartupError};
+    use crate::library::selftest::{Test, TestResult};
+
+    /// The self tests of this module, run in this order.
+    pub const TESTS: &[Test] = &[
+        ("Failure message", test_failure_message),
+    ];
+
+    /// Console capturing the output of the tests.
+    static CAPTURE: Capture = Capture::new();
+
+    /// Report a simulated failure of the heap init step and check the message
+    /// written before the system is halted.
+    fn test_failure_message() -> TestResult {
+        const EXPECTED: &[u8] = b"Startup failed: no usable memory for the heap\nSystem halted.\n";
+
+        CAPTURE.take();
+        console::write_fmt(&CAPTURE, format_args!("{}\n", Failure(StartupError::NoUsableMemory)));
+        if CAPTURE.take().as_bytes() != EXPECTED {
+            return Err("wrong failure message");
+        }
+        Ok(())
+    }
+}
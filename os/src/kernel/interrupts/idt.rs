@@ -36,6 +36,20 @@ pub struct IdtEntry {
     reserved: u32,
 }
 
+/// Type of the gate of an IDT entry (bits 8-11 of the options).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum GateType {
+    /// Interrupts are disabled (IF cleared) when the handler is entered (the default).
+    Interrupt = 0xe,
+    /// The interrupt flag is preserved, so the handler can be interrupted (e.g. for system calls).
+    Trap = 0xf,
+}
+
+/// Position and mask of the gate type bits in the options of an IDT entry.
+const GATE_TYPE_SHIFT: u16 = 8;
+const GATE_TYPE_MASK: u16 = 0xf << GATE_TYPE_SHIFT;
+
 #[repr(C, packed)]
 /// The IDT itself is just a packed array of 256 IDT entries.
 pub struct Idt {
@@ -64,7 +78,8 @@ impl IdtEntry {
     /// Create a new IDT entry for an interrupt handler at the given offset.
     /// Each entry has the same selector and options:
     /// The selector is the second entry in the GDT (kernel code segment) -> 2 * 8 = 16.
-    /// The options are always 'Present', 'DPL=0' and '64-bit interrupt gate'.
+    /// The options are always 'Present', 'DPL=0' and '64-bit interrupt gate'
+    /// (see `with_gate_type()` for a trap gate).
     const fn new(offset: u64) -> IdtEntry {
         IdtEntry {
             offset_low: (offset & 0xffff) as u16,
@@ -90,6 +105,26 @@ impl IdtEntry {
     pub fn with_error_code(handler: extern "x86-interrupt" fn(InterruptStackFrame, error_code: u64)) -> IdtEntry {
         IdtEntry::new(handler as u64)
     }
+
+    /// Return this entry with the gate type `gate`; all other options are kept.
+    pub const fn with_gate_type(mut self, gate: GateType) -> IdtEntry {
+        self.options = (self.options & !GATE_TYPE_MASK) | (gate as u16) << GATE_TYPE_SHIFT;
+        self
+    }
+
+    /// Return the gate type of this entry, or `None` for other types (e.g. call gates).
+    pub fn gate_type(&self) -> Option<GateType> {
+        match (self.options & GATE_TYPE_MASK) >> GATE_TYPE_SHIFT {
+            0xe => Some(GateType::Interrupt),
+            0xf => Some(GateType::Trap),
+            _ => None,
+        }
+    }
+
+    /// Return the raw options (present bit, DPL, gate type and IST index) of this entry.
+    pub fn options(&self) -> u16 {
+        self.options
+    }
 }
 
 #[macro_export]
@@ -393,6 +428,18 @@ impl Idt {
         self.entries[index] = entry;
     }
 
+    /// Install `entry` as handler for interrupt `index` with gate type `gate`.
+    /// Exceptions and device interrupts should use `GateType::Interrupt`, so the
+    /// handler is not interrupted before it has saved the state it needs.
+    pub fn set_handler(&mut self, index: usize, entry: IdtEntry, gate: GateType) {
+        self.set_entry(index, entry.with_gate_type(gate));
+    }
+
+    /// Return a copy of entry `index` of the IDT.
+    pub fn entry(&self, index: usize) -> IdtEntry {
+        self.entries[index]
+    }
+
     /// Load the IDT into the CPU.
     pub fn load(&self) {
         let idt_descriptor = IdtDescriptor::new(self);
@@ -405,3 +452,41 @@ impl Idt {
         }
     }
 }
+
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Self tests, run by the diagnostics (see `user::diag`).                  ║
+   ╚═════════════════════════════════════════════════════════════════════════╝ */
+
+pub mod selftest {
+    use crate::kernel::interrupts::idt::{self, GateType};
+    use crate::library::selftest::{Test, TestResult};
+
+    /// The self tests of this module, run in this order.
+    pub const TESTS: &[Test] = &[
+        ("Gate types", test_gate_types),
+    ];
+
+    /// Check the type bits of the timer's IDT entry (interrupt gate by default)
+    /// and of a copy turned into a trap gate and back.
+    fn test_gate_types() -> TestResult {
+        const TIMER_VECTOR: usize = 0x20;
+        const PRESENT: u16 = 0x8000;
+
+        let entry = idt::get_idt().entry(TIMER_VECTOR);
+        let cases = [
+            (entry, GateType::Interrupt, 0xe),
+            (entry.with_gate_type(GateType::Trap), GateType::Trap, 0xf),
+            (entry.with_gate_type(GateType::Trap).with_gate_type(GateType::Interrupt), GateType::Interrupt, 0xe),
+        ];
+
+        for (entry, gate, bits) in cases {
+            if entry.gate_type() != Some(gate) || (entry.options() >> 8) & 0xf != bits {
+                return Err("wrong gate type bits");
+            }
+            if entry.options() & PRESENT == 0 {
+                return Err("present bit lost");
+            }
+        }
+        Ok(())
+    }
+}
@@ -14,7 +14,12 @@
 extern crate spin;
 
 use crate::kernel::cpu;
+use crate::kernel::init::StartupError;
+use crate::kernel::profiler;
+use crate::kernel::softirq;
+use crate::kernel::timer;
 use crate::kernel::interrupts::InterruptStackFrame;
+use crate::kernel::interrupts::pic::{Irq, PIC};
 use alloc::{boxed::Box, vec, vec::Vec};
 use spin::Mutex;
 use crate::kernel::interrupts::idt::IDT_SIZE;
@@ -75,8 +80,14 @@ pub static INT_VECTORS: Mutex<IntVectors> = Mutex::new(IntVectors::new());
 /// Every interrupt is routed here, if not specified otherwise in the IDT.
 pub fn int_disp(vector: u8, stack_frame: InterruptStackFrame, error_code: Option<u64>) {
     /* Hier muss Code eingefuegt werden */
-    kprintln!("Interrupt: vector = {}", vector as u8);
+    if vector == InterruptVector::Pit as u8 {
+        profiler::sample(stack_frame.instruction_pointer);
+    }
+    check_storm(vector);
+
     if INT_VECTORS.lock().report(vector) == true {
+        // Run the work deferred by the ISR (the lock on INT_VECTORS is already released)
+        softirq::run_pending();
         return;
     }
 
@@ -89,6 +100,84 @@ pub fn int_disp(vector: u8, stack_frame: InterruptStackFrame, error_code: Option
     cpu::halt();
 }
 
+/// Vector of IRQ 0 and number of IRQ lines of the PICs.
+const IRQ_BASE: u8 = InterruptVector::Pit as u8;
+const IRQ_LINES: usize = 16;
+
+/// Default maximum number of interrupts per second of an IRQ line (see `set_storm_threshold()`).
+pub const DEFAULT_STORM_THRESHOLD: usize = 10_000;
+
+/// Length of the window in which the interrupts of each line are counted (milliseconds).
+const STORM_WINDOW_MS: u64 = 1000;
+
+/// Interrupt rates of the IRQ lines.
+static STORMS: Mutex<StormDetector> = Mutex::new(StormDetector::new(DEFAULT_STORM_THRESHOLD));
+
+/// Counts the interrupts of each IRQ line in windows of one second to detect interrupt storms,
+/// e.g. caused by a misbehaving device or a missing EOI, which would starve the system.
+pub struct StormDetector {
+    /// Maximum number of interrupts per second and line (0 = unlimited)
+    threshold: usize,
+    /// Uptime in milliseconds when the current window of each line started
+    window_start_ms: [u64; IRQ_LINES],
+    /// Interrupts of each line in the current window
+    counts: [usize; IRQ_LINES],
+}
+
+impl StormDetector {
+    pub const fn new(threshold: usize) -> StormDetector {
+        StormDetector { threshold, window_start_ms: [0; IRQ_LINES], counts: [0; IRQ_LINES] }
+    }
+
+    /// Count an interrupt of IRQ `line` at uptime `now_ms`.
+    /// Returns true if the line has exceeded the threshold within the current window.
+    pub fn count(&mut self, line: usize, now_ms: u64) -> bool {
+        if self.threshold == 0 || line >= IRQ_LINES {
+            return false;
+        }
+
+        if now_ms.saturating_sub(self.window_start_ms[line]) >= STORM_WINDOW_MS {
+            self.window_start_ms[line] = now_ms;
+            self.counts[line] = 0;
+        }
+        self.counts[line] += 1;
+        self.counts[line] > self.threshold
+    }
+}
+
+/// Set the maximum number of interrupts per second of each IRQ line (0 = unlimited).
+/// A line exceeding it is masked in the PIC and a warning is written to COM1.
+/// The default is `DEFAULT_STORM_THRESHOLD`.
+pub fn set_storm_threshold(rate: usize) {
+    cpu::without_interrupts(|| *STORMS.lock() = StormDetector::new(rate));
+}
+
+/// Count an interrupt of `vector` and mask its IRQ line if it exceeds the storm threshold
+/// (see `set_storm_threshold()`). Returns true if the line has been masked.
+/// Called by `int_disp()` for every interrupt; CPU exceptions are ignored, and the timer
+/// (the clock of the detector) and the cascade line are never masked.
+pub fn check_storm(vector: u8) -> bool {
+    let Some(line) = vector.checked_sub(IRQ_BASE).map(usize::from).filter(|&line| line < IRQ_LINES) else {
+        return false;
+    };
+    let irq = Irq::ALL[line];
+    if irq == Irq::Timer || irq == Irq::Cascade {
+        return false;
+    }
+
+    if !cpu::without_interrupts(|| STORMS.lock().count(line, timer::uptime_ms())) {
+        return false;
+    }
+
+    // If the interrupted code is using the PIC, the line is masked on its next interrupt.
+    let Some(mut pic) = PIC.try_lock() else {
+        return false;
+    };
+    pic.forbid(irq);
+    kprintln!("Interrupt storm on IRQ {} ({:?}), line masked", line, irq);
+    true
+}
+
 /// The Interrupt vector map. Each ISR is registered in this map.
 pub struct IntVectors {
     // Each ISR is wrapped in a Box, because the size of the ISRs is not known at compile time.
@@ -108,14 +197,20 @@ impl IntVectors {
 
     /// Fill the ISR map with IDT_SIZE empty Options.
     /// Specific ISRs can be overwritten by calling `register()`.
-    pub fn init(&mut self) {
-        if !self.map.is_empty() {
-            panic!("ISR map is already initialized!");
+    pub fn init(&mut self) -> Result<(), StartupError> {
+        if self.is_initialized() {
+            return Err(StartupError::IntVectorsAlreadyInitialized);
         }
 
         for _ in 0..IDT_SIZE {
             self.map.push(None);
         }
+        Ok(())
+    }
+
+    /// Check if `init()` has been called, i.e. ISRs can be registered.
+    pub fn is_initialized(&self) -> bool {
+        !self.map.is_empty()
     }
 
     /// Register an ISR.
@@ -138,3 +233,47 @@ impl IntVectors {
     }
     }
 }
+
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Self tests, run by the diagnostics (see `user::diag`).                  ║
+   ╚═════════════════════════════════════════════════════════════════════════╝ */
+
+pub mod selftest {
+    use crate::kernel::interrupts::intdispatcher::{self, InterruptVector};
+    use crate::kernel::interrupts::pic::{Irq, PIC};
+    use crate::library::selftest::{restore_on_exit, Test, TestResult};
+
+    /// The self tests of this module, run in this order.
+    pub const TESTS: &[Test] = &[
+        ("Interrupt storm", test_interrupt_storm),
+    ];
+
+    /// Simulate a burst of interrupts on the unused IRQ 11 with a low storm threshold and check
+    /// that the line is masked after crossing it. The mask and the threshold are restored.
+    fn test_interrupt_storm() -> TestResult {
+        const THRESHOLD: usize = 100;
+
+        let was_masked = PIC.lock().is_masked(Irq::Free3);
+        let _restore = restore_on_exit(|| {
+            intdispatcher::set_storm_threshold(intdispatcher::DEFAULT_STORM_THRESHOLD);
+            if was_masked {
+                PIC.lock().forbid(Irq::Free3);
+            } else {
+                PIC.lock().allow(Irq::Free3);
+            }
+        });
+        PIC.lock().allow(Irq::Free3);
+        intdispatcher::set_storm_threshold(THRESHOLD);
+
+        // A new window may start during the burst, so up to two windows are filled.
+        let crossed = (1..=2 * THRESHOLD + 2).find(|_| intdispatcher::check_storm(InterruptVector::Free3 as u8));
+        let masked = PIC.lock().is_masked(Irq::Free3);
+
+        match crossed {
+            None => Err("storm not detected"),
+            Some(n) if n <= THRESHOLD => Err("masked below the threshold"),
+            Some(_) if !masked => Err("line not masked"),
+            Some(_) => Ok(()),
+        }
+    }
+}
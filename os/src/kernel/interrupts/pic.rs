@@ -15,6 +15,7 @@
 use spin::Mutex;
 use crate::kernel::cpu as cpu;
 use crate::kernel::cpu::IoPort;
+use crate::kernel::init::StartupError;
 
 /// Global PIC instance, used for interrupt handling in the whole kernel.
 pub static PIC: Mutex<Pic> = Mutex::new(Pic::new());
@@ -27,6 +28,7 @@ const PIC_DATA_2: u16 = 0xa1; // Data register of PIC 2 (Slave)
 const PIC_COMMAND_INITIALIZE: u8 = 0x11; // Initialization command for PIC
 
 #[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 /// Enumeration of all IRQs (Interrupt Request Lines).
 pub enum Irq {
     Timer = 0x00,
@@ -47,6 +49,16 @@ pub enum Irq {
     SecondaryAta = 0x0f,
 }
 
+impl Irq {
+    /// All IRQs, indexed by their line number.
+    pub const ALL: [Irq; 16] = [
+        Irq::Timer, Irq::Keyboard, Irq::Cascade, Irq::Com2,
+        Irq::Com1, Irq::Lpt2, Irq::Floppy, Irq::Lpt1,
+        Irq::Rtc, Irq::Free1, Irq::Free2, Irq::Free3,
+        Irq::Mouse, Irq::Fpu, Irq::PrimaryAta, Irq::SecondaryAta,
+    ];
+}
+
 /// Representation of the Programmable Interrupt Controller (PIC).
 /// The PIC is responsible for handling hardware interrupts and forwarding them to the CPU.
 /// It actually consists of two chips (PIC 1 and PIC 2),
@@ -71,7 +83,10 @@ impl Pic {
 
     /// Initialize the PIC.
     /// See the OSDev wiki for details: https://wiki.osdev.org/8259_PIC
-    pub fn init(&mut self) {
+    /// Fails if the interrupt masks cannot be read back afterwards.
+    pub fn init(&mut self) -> Result<(), StartupError> {
+        // The 8259 needs some time to process each ICW. On older hardware it misses
+        // the next one if it follows too quickly, so `io_wait()` follows each write.
         unsafe {
             // Start initialization sequence on both PICs (ICW1)
             self.command1.outb(PIC_COMMAND_INITIALIZE);
@@ -102,7 +117,12 @@ impl Pic {
             cpu::io_wait();
             self.data2.outb(0xff); // Disable all interrupts
             cpu::io_wait();
+
+            if self.data1.inb() != 0xfb || self.data2.inb() != 0xff {
+                return Err(StartupError::PicNotResponding);
+            }
         }
+        Ok(())
     }
 
     /// Enable an IRQ to be forwarded to the processor by the PIC.
@@ -136,6 +156,18 @@ impl Pic {
 
     }
 
+    /// Check if `irq` is masked, i.e. not forwarded to the processor.
+    pub fn is_masked(&mut self, irq: Irq) -> bool {
+        let irq_int = irq as usize;
+        unsafe {
+            if irq_int < 8 {
+                self.data1.inb() & (1 << irq_int) != 0
+            } else {
+                self.data2.inb() & (1 << (irq_int - 8)) != 0
+            }
+        }
+    }
+
     /// Get the state (enabled/disabled) of an IRQ in the PIC.
     pub fn status (&mut self, irq: Irq) -> bool {
         let irq_int = irq as usize;
@@ -0,0 +1,336 @@
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Module: loader                                                          ║
+   ╟─────────────────────────────────────────────────────────────────────────╢
+   ║ Descr.: Minimal loader for static, position-dependent ELF64             ║
+   ║         executables. The loadable segments of the image are copied to   ║
+   ║         their link addresses, which must lie in the program area        ║
+   ║         reserved in the memory map (see 'memory::PROGRAM_AREA'), and    ║
+   ║         the program is started by jumping to its entry point (in ring   ║
+   ║         0). Relocations and dynamic linking are not supported. Only one ║
+   ║         program can be loaded at a time.                                ║
+   ╚═════════════════════════════════════════════════════════════════════════╝
+*/
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::{mem, ptr};
+use crate::kernel::memory;
+use crate::library::slice;
+
+// ELF header constants
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELF_CLASS_64: u8 = 2;
+const ELF_DATA_LITTLE_ENDIAN: u8 = 1;
+const ELF_TYPE_EXEC: u16 = 2;
+const ELF_MACHINE_X86_64: u16 = 0x3e;
+const ELF_HEADER_SIZE: usize = 64;
+
+// Program header constants
+const PH_TYPE_LOAD: u32 = 1;
+const PH_ENTRY_SIZE: usize = 56;
+
+/// Set while a program is loaded into the program area.
+static LOADED: AtomicBool = AtomicBool::new(false);
+
+/// Reasons why an ELF image cannot be loaded.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LoaderError {
+    /// The image ends before a header or segment that should be contained in it.
+    Truncated,
+    /// The image does not start with the ELF magic number.
+    BadMagic,
+    /// The image is not a 64-bit ELF file.
+    NotElf64,
+    /// The image is not encoded in little endian.
+    NotLittleEndian,
+    /// The image is not a static executable (e.g. a shared object or relocatable file).
+    UnsupportedType,
+    /// The image is not built for x86_64.
+    UnsupportedMachine,
+    /// The size of the program header entries is not the one of ELF64.
+    BadProgramHeader,
+    /// The image does not contain any loadable segment.
+    NoLoadableSegment,
+    /// A loadable segment is not linked to an address within the program area.
+    OutsideProgramArea,
+    /// The entry point is not inside one of the loadable segments.
+    BadEntryPoint,
+    /// Another program is loaded into the program area.
+    AreaInUse,
+}
+
+/// A program that has been loaded into the program area and is ready to run.
+/// The program area is released when the program is dropped.
+pub struct Program {
+    entry: usize,
+}
+
+impl Program {
+    /// Return the address of the entry point in memory.
+    pub fn entry(&self) -> usize {
+        self.entry
+    }
+
+    /// Jump to the entry point of the program (in ring 0).
+    /// Returns the value returned by the entry function of the program.
+    ///
+    /// Unsafe because the program runs with full kernel privileges
+    /// and we cannot check what it does.
+    pub unsafe fn run(&self) -> i32 {
+        unsafe {
+            let entry: extern "C" fn() -> i32 = mem::transmute(self.entry);
+            entry()
+        }
+    }
+}
+
+impl Drop for Program {
+    fn drop(&mut self) {
+        LOADED.store(false, Ordering::Release);
+    }
+}
+
+/// Load the static ELF64 executable `image` (e.g. a file from a ramdisk).
+///
+/// The loadable segments are copied to their link addresses (`p_vaddr`), as no relocations
+/// are applied. The image is rejected if any of them lies outside `memory::PROGRAM_AREA`,
+/// before anything has been written.
+pub fn load(image: &[u8]) -> Result<Program, LoaderError> {
+    if image.len() < ELF_HEADER_SIZE {
+        return Err(LoaderError::Truncated);
+    }
+    if image[0..4] != ELF_MAGIC {
+        return Err(LoaderError::BadMagic);
+    }
+    if image[4] != ELF_CLASS_64 {
+        return Err(LoaderError::NotElf64);
+    }
+    if image[5] != ELF_DATA_LITTLE_ENDIAN {
+        return Err(LoaderError::NotLittleEndian);
+    }
+    if read_u16(image, 16)? != ELF_TYPE_EXEC {
+        return Err(LoaderError::UnsupportedType);
+    }
+    if read_u16(image, 18)? != ELF_MACHINE_X86_64 {
+        return Err(LoaderError::UnsupportedMachine);
+    }
+
+    let entry = read_u64(image, 24)? as usize;
+    let ph_offset = read_u64(image, 32)? as usize;
+    let ph_count = read_u16(image, 56)? as usize;
+    if ph_count > 0 && read_u16(image, 54)? as usize != PH_ENTRY_SIZE {
+        return Err(LoaderError::BadProgramHeader);
+    }
+
+    // Check that all loadable segments are complete and inside the program area
+    let (area_start, area_end) = memory::PROGRAM_AREA;
+    let mut segments = 0;
+    let mut entry_found = false;
+    for i in 0..ph_count {
+        let Some(segment) = read_segment(image, ph_offset, i)? else {
+            continue;
+        };
+        let end = segment.vaddr.checked_add(segment.mem_size).ok_or(LoaderError::OutsideProgramArea)?;
+        if segment.vaddr < area_start || end > area_end {
+            return Err(LoaderError::OutsideProgramArea);
+        }
+        slice::get_bytes(image, segment.file_offset..segment.file_offset.saturating_add(segment.file_size))
+            .ok_or(LoaderError::Truncated)?;
+        segments += 1;
+        entry_found |= (segment.vaddr..end).contains(&entry);
+    }
+
+    if segments == 0 {
+        return Err(LoaderError::NoLoadableSegment);
+    }
+    if !entry_found {
+        return Err(LoaderError::BadEntryPoint);
+    }
+    if LOADED.swap(true, Ordering::Acquire) {
+        return Err(LoaderError::AreaInUse);
+    }
+
+    // Copy the segments; the part of a segment not contained in the file (bss) is zeroed
+    for i in 0..ph_count {
+        let Some(segment) = read_segment(image, ph_offset, i)? else {
+            continue;
+        };
+        let data = &image[segment.file_offset..segment.file_offset + segment.file_size];
+        let file_size = segment.file_size.min(segment.mem_size);
+
+        // Unsafe because we write to raw memory.
+        // The destination is inside the program area, which is reserved for programs
+        // and identity mapped, as checked above.
+        unsafe {
+            let dest = segment.vaddr as *mut u8;
+            ptr::copy_nonoverlapping(data.as_ptr(), dest, file_size);
+            ptr::write_bytes(dest.add(file_size), 0, segment.mem_size - file_size);
+        }
+    }
+
+    Ok(Program { entry })
+}
+
+/// A loadable segment described by a program header.
+struct Segment {
+    file_offset: usize,
+    vaddr: usize,
+    file_size: usize,
+    mem_size: usize,
+}
+
+/// Read program header `index` of `image`. Returns `None` if it does not describe a loadable segment.
+fn read_segment(image: &[u8], ph_offset: usize, index: usize) -> Result<Option<Segment>, LoaderError> {
+    let ph = index.checked_mul(PH_ENTRY_SIZE).and_then(|offset| offset.checked_add(ph_offset))
+        .filter(|ph| ph.checked_add(PH_ENTRY_SIZE).is_some())
+        .ok_or(LoaderError::Truncated)?;
+    if read_u32(image, ph)? != PH_TYPE_LOAD {
+        return Ok(None);
+    }
+    Ok(Some(Segment {
+        file_offset: read_u64(image, ph + 8)? as usize,
+        vaddr: read_u64(image, ph + 16)? as usize,
+        file_size: read_u64(image, ph + 32)? as usize,
+        mem_size: read_u64(image, ph + 40)? as usize,
+    }))
+}
+
+/// Read a little endian u16 at `offset` in `image`.
+fn read_u16(image: &[u8], offset: usize) -> Result<u16, LoaderError> {
+    slice::get_u16_le(image, offset).ok_or(LoaderError::Truncated)
+}
+
+/// Read a little endian u32 at `offset` in `image`.
+fn read_u32(image: &[u8], offset: usize) -> Result<u32, LoaderError> {
+    slice::get_u32_le(image, offset).ok_or(LoaderError::Truncated)
+}
+
+/// Read a little endian u64 at `offset` in `image`.
+fn read_u64(image: &[u8], offset: usize) -> Result<u64, LoaderError> {
+    slice::get_u64_le(image, offset).ok_or(LoaderError::Truncated)
+}
+
+/// Value returned by `SAMPLE_PROGRAM`.
+pub const SAMPLE_EXIT_CODE: i32 = 42;
+
+/// Machine code of `SAMPLE_PROGRAM`: mov eax, SAMPLE_EXIT_CODE; ret
+const SAMPLE_CODE: [u8; 6] = [0xb8, SAMPLE_EXIT_CODE as u8, 0, 0, 0, 0xc3];
+
+/// Size of `SAMPLE_PROGRAM`: ELF header, one program header and the code.
+const SAMPLE_SIZE: usize = ELF_HEADER_SIZE + PH_ENTRY_SIZE + SAMPLE_CODE.len();
+
+/// Size of the zeroed data (bss) following the code of `SAMPLE_PROGRAM` in memory.
+const SAMPLE_BSS_SIZE: usize = 256;
+
+/// A tiny static executable linked to the start of the program area, returning
+/// `SAMPLE_EXIT_CODE`. There is no ramdisk yet, so it is built into the kernel.
+pub const SAMPLE_PROGRAM: [u8; SAMPLE_SIZE] = sample_program();
+
+/// Build `SAMPLE_PROGRAM`: one loadable segment containing the whole file, with the code
+/// right after the headers, followed by `SAMPLE_BSS_SIZE` bytes of bss.
+const fn sample_program() -> [u8; SAMPLE_SIZE] {
+    const READ_EXECUTE: u32 = 5;
+    let vaddr = memory::PROGRAM_AREA.0 as u64;
+    let code_offset = ELF_HEADER_SIZE + PH_ENTRY_SIZE;
+    let ph = ELF_HEADER_SIZE;
+
+    let mut image = [0; SAMPLE_SIZE];
+    put(&mut image, 0, &ELF_MAGIC);
+    put(&mut image, 4, &[ELF_CLASS_64, ELF_DATA_LITTLE_ENDIAN, 1]);
+    put(&mut image, 16, &ELF_TYPE_EXEC.to_le_bytes());
+    put(&mut image, 18, &ELF_MACHINE_X86_64.to_le_bytes());
+    put(&mut image, 20, &1u32.to_le_bytes());
+    put(&mut image, 24, &(vaddr + code_offset as u64).to_le_bytes());
+    put(&mut image, 32, &(ph as u64).to_le_bytes());
+    put(&mut image, 52, &(ELF_HEADER_SIZE as u16).to_le_bytes());
+    put(&mut image, 54, &(PH_ENTRY_SIZE as u16).to_le_bytes());
+    put(&mut image, 56, &1u16.to_le_bytes());
+
+    put(&mut image, ph, &PH_TYPE_LOAD.to_le_bytes());
+    put(&mut image, ph + 4, &READ_EXECUTE.to_le_bytes());
+    put(&mut image, ph + 16, &vaddr.to_le_bytes());
+    put(&mut image, ph + 24, &vaddr.to_le_bytes());
+    put(&mut image, ph + 32, &(SAMPLE_SIZE as u64).to_le_bytes());
+    put(&mut image, ph + 40, &((SAMPLE_SIZE + SAMPLE_BSS_SIZE) as u64).to_le_bytes());
+    put(&mut image, ph + 48, &0x1000u64.to_le_bytes());
+
+    put(&mut image, code_offset, &SAMPLE_CODE);
+    image
+}
+
+/// Copy `bytes` into `image` at `offset` (usable in constants).
+const fn put<const N: usize>(image: &mut [u8; N], offset: usize, bytes: &[u8]) {
+    let mut i = 0;
+    while i < bytes.len() {
+        image[offset + i] = bytes[i];
+        i += 1;
+    }
+}
+
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Self tests, run by the diagnostics (see `user::diag`).                  ║
+   ╚═════════════════════════════════════════════════════════════════════════╝ */
+
+pub mod selftest {
+    use crate::kernel::loader::{self, LoaderError, SAMPLE_PROGRAM};
+    use crate::kernel::memory;
+    use crate::library::selftest::{Test, TestResult};
+
+    /// The self tests of this module, run in this order.
+    pub const TESTS: &[Test] = &[
+        ("Sample program", test_sample_program),
+        ("Invalid images", test_invalid_images),
+    ];
+
+    /// Load the sample program over garbage in the program area, check that the code is at
+    /// its link address and the bss is zeroed, then run it and check its exit code.
+    /// While it is loaded, no other program can be loaded.
+    fn test_sample_program() -> TestResult {
+        let (start, _) = memory::PROGRAM_AREA;
+        // Unsafe because we write to raw memory, which is reserved for programs.
+        unsafe { core::ptr::write_bytes(start as *mut u8, 0xff, SAMPLE_PROGRAM.len() + loader::SAMPLE_BSS_SIZE) };
+
+        let program = loader::load(&SAMPLE_PROGRAM).map_err(|_| "sample program rejected")?;
+        let loaded = unsafe { core::slice::from_raw_parts(start as *const u8, SAMPLE_PROGRAM.len() + loader::SAMPLE_BSS_SIZE) };
+        let (code, bss) = loaded.split_at(SAMPLE_PROGRAM.len());
+        if code != SAMPLE_PROGRAM || program.entry() != start + SAMPLE_PROGRAM.len() - loader::SAMPLE_CODE.len() {
+            return Err("not loaded to the link address");
+        }
+        if bss.iter().any(|&b| b != 0) {
+            return Err("bss not zeroed");
+        }
+        if loader::load(&SAMPLE_PROGRAM).err() != Some(LoaderError::AreaInUse) {
+            return Err("loaded twice");
+        }
+        if unsafe { program.run() } != loader::SAMPLE_EXIT_CODE {
+            return Err("wrong exit code");
+        }
+        drop(program);
+        loader::load(&SAMPLE_PROGRAM).map(drop).map_err(|_| "program area not released")
+    }
+
+    /// Modify single fields of the sample program and check that each is rejected
+    /// with the expected error.
+    fn test_invalid_images() -> TestResult {
+        const CASES: [(usize, &[u8], LoaderError); 6] = [
+            (0, b"\x7fELG", LoaderError::BadMagic),
+            (4, &[1], LoaderError::NotElf64),
+            (16, &[3, 0], LoaderError::UnsupportedType),
+            (54, &[32, 0], LoaderError::BadProgramHeader),
+            // segment linked to 1 MiB, outside the program area
+            (64 + 16, &[0, 0, 0x10, 0], LoaderError::OutsideProgramArea),
+            // entry point behind the segment
+            (24 + 2, &[0x7f], LoaderError::BadEntryPoint),
+        ];
+
+        for (offset, bytes, error) in CASES {
+            let mut image = SAMPLE_PROGRAM;
+            image[offset..offset + bytes.len()].copy_from_slice(bytes);
+            if loader::load(&image).err() != Some(error) {
+                return Err("invalid image not rejected");
+            }
+        }
+        if loader::load(&SAMPLE_PROGRAM[..100]).err() != Some(LoaderError::Truncated) {
+            return Err("truncated image not rejected");
+        }
+        Ok(())
+    }
+}
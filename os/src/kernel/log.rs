@@ -0,0 +1,300 @@
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Module: log                                                             ║
+   ╟─────────────────────────────────────────────────────────────────────────╢
+   ║ Descr.: Timestamped event log. Subsystems record events (key presses,   ║
+   ║         alarms, allocations) in a ring buffer, 'export_events' writes   ║
+   ║         them to COM1 as CSV lines, giving a single timeline for         ║
+   ║         debugging interactions between subsystems. A rate limit keeps   ║
+   ║         the screen readable under heavy logging.                        ║
+   ╚═════════════════════════════════════════════════════════════════════════╝
+*/
+use core::fmt;
+use core::sync::atomic::{AtomicU8, Ordering};
+use spin::Mutex;
+use crate::devices::serial;
+use crate::kernel::cpu;
+use crate::kernel::timer;
+
+/// Number of events kept in the log; older events are overwritten.
+const LOG_SIZE: usize = 256;
+
+/// The global event log.
+static EVENTS: Mutex<EventLog<LOG_SIZE>> = Mutex::new(EventLog::new());
+
+/// Bit mask of the event kinds being recorded (bit n = kind n, see `set_recording()`).
+static RECORDING: AtomicU8 = AtomicU8::new(0);
+
+/// Kinds of events, each of which can be recorded separately.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum EventKind {
+    /// A key was pressed (data: ASCII code and scancode)
+    Key = 0,
+    /// A timer alarm expired (data: address of the callback)
+    Alarm = 1,
+    /// Memory was allocated (data: size in bytes)
+    Alloc = 2,
+    /// Memory was freed (data: size in bytes)
+    Dealloc = 3,
+}
+
+impl EventKind {
+    /// Return the name of the kind used in the export.
+    pub const fn name(self) -> &'static str {
+        match self {
+            EventKind::Key => "key",
+            EventKind::Alarm => "alarm",
+            EventKind::Alloc => "alloc",
+            EventKind::Dealloc => "dealloc",
+        }
+    }
+}
+
+/// An event with the time it was recorded.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Event {
+    /// Milliseconds since the timer was plugged in
+    pub timestamp_ms: u64,
+    pub kind: EventKind,
+    /// Details of the event, depending on its kind
+    pub data: u64,
+}
+
+/// A ring buffer of the last `N` events.
+pub struct EventLog<const N: usize> {
+    events: [Option<Event>; N],
+    /// Index of the oldest event
+    head: usize,
+    len: usize,
+    /// Number of events overwritten because the log was full
+    overwritten: usize,
+}
+
+impl<const N: usize> EventLog<N> {
+    /// Create a new empty log.
+    pub const fn new() -> Self {
+        EventLog { events: [None; N], head: 0, len: 0, overwritten: 0 }
+    }
+
+    /// Append `event`, overwriting the oldest event if the log is full.
+    pub fn push(&mut self, event: Event) {
+        if self.len == N {
+            self.head = (self.head + 1) % N;
+            self.len -= 1;
+            self.overwritten += 1;
+        }
+        self.events[(self.head + self.len) % N] = Some(event);
+        self.len += 1;
+    }
+
+    /// Return the events in the order they were recorded.
+    pub fn iter(&self) -> impl Iterator<Item = Event> + '_ {
+        (0..self.len).filter_map(move |i| self.events[(self.head + i) % N])
+    }
+
+    /// Remove all events.
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+        self.overwritten = 0;
+    }
+
+    /// Write the events in chronological order to `out`, one line `timestamp_ms,kind,data` per event,
+    /// after a header line. A comment line reports events lost by overwriting.
+    pub fn export(&self, out: &mut impl fmt::Write) -> fmt::Result {
+        writeln!(out, "timestamp_ms,event,data")?;
+        if self.overwritten > 0 {
+            writeln!(out, "# {} older events overwritten", self.overwritten)?;
+        }
+        for event in self.iter() {
+            writeln!(out, "{},{},{:#x}", event.timestamp_ms, event.kind.name(), event.data)?;
+        }
+        Ok(())
+    }
+}
+
+/// Enable or disable recording events of `kind`. All kinds are disabled by default.
+pub fn set_recording(kind: EventKind, enabled: bool) {
+    let bit = 1 << kind as u8;
+    if enabled {
+        RECORDING.fetch_or(bit, Ordering::Relaxed);
+    } else {
+        RECORDING.fetch_and(!bit, Ordering::Relaxed);
+    }
+}
+
+/// Check if events of `kind` are recorded.
+pub fn is_recording(kind: EventKind) -> bool {
+    RECORDING.load(Ordering::Relaxed) & (1 << kind as u8) != 0
+}
+
+/// Record an event of `kind` with `data` at the current time, if the kind is being recorded.
+/// Can be called from interrupt handlers.
+pub fn record(kind: EventKind, data: u64) {
+    if !is_recording(kind) {
+        return;
+    }
+
+    let event = Event { timestamp_ms: timer::uptime_ms(), kind, data };
+    // Interrupt handlers record events, so they must not interrupt us while the log is locked.
+    cpu::without_interrupts(|| EVENTS.lock().push(event));
+}
+
+/// Remove all recorded events.
+pub fn clear_events() {
+    cpu::without_interrupts(|| EVENTS.lock().clear());
+}
+
+/// Write all recorded events to COM1 as CSV lines (see `EventLog::export()`).
+/// The log is copied first, so events can be recorded while exporting.
+pub fn export_events() {
+    let mut log = EventLog::<LOG_SIZE>::new();
+    cpu::without_interrupts(|| {
+        let events = EVENTS.lock();
+        for event in events.iter() {
+            log.push(event);
+        }
+        log.overwritten = events.overwritten;
+    });
+
+    log.export(&mut *serial::COM1.lock()).ok();
+}
+
+/// Rate limit of the lines written by `print!` (see `set_rate_limit()`).
+static RATE_LIMITER: Mutex<RateLimiter> = Mutex::new(RateLimiter::new(0));
+
+/// A token bucket limiting the number of lines per second. Each admitted line takes a token;
+/// the bucket holds up to one second's worth of tokens and is refilled from the tick counter,
+/// so bursts up to the limit pass and everything beyond is dropped until tokens are refilled.
+pub struct RateLimiter {
+    /// Maximum number of lines per second (0 = unlimited)
+    max_per_second: usize,
+    tokens: usize,
+    /// Uptime in milliseconds up to which tokens have been refilled
+    refilled_ms: u64,
+    /// Number of lines dropped since the last admitted line
+    suppressed: usize,
+}
+
+impl RateLimiter {
+    /// Create a limiter for `max_per_second` lines per second (0 = unlimited) with a full bucket.
+    pub const fn new(max_per_second: usize) -> RateLimiter {
+        RateLimiter { max_per_second, tokens: max_per_second, refilled_ms: 0, suppressed: 0 }
+    }
+
+    /// Check if a line starting at uptime `now_ms` may be written. If so, returns the number
+    /// of lines dropped before it (to be summarized), otherwise `None` and the line is counted.
+    pub fn admit(&mut self, now_ms: u64) -> Option<usize> {
+        if self.max_per_second == 0 {
+            return Some(0);
+        }
+
+        self.refill(now_ms);
+        if self.tokens == 0 {
+            self.suppressed += 1;
+            return None;
+        }
+        self.tokens -= 1;
+        Some(core::mem::take(&mut self.suppressed))
+    }
+
+    /// Add the tokens earned since the last refill. Only the time of whole tokens
+    /// is consumed, so slow rates do not lose the fractions between two calls.
+    fn refill(&mut self, now_ms: u64) {
+        let rate = self.max_per_second as u64;
+        let earned = now_ms.saturating_sub(self.refilled_ms) * rate / 1000;
+        if earned == 0 {
+            return;
+        }
+
+        self.tokens = (self.tokens + earned as usize).min(self.max_per_second);
+        if self.tokens == self.max_per_second {
+            self.refilled_ms = now_ms;
+        } else {
+            self.refilled_ms += earned * 1000 / rate;
+        }
+    }
+}
+
+/// Limit the output of `print!` to `max_lines_per_second` lines (0 = unlimited, the default).
+/// Lines beyond the limit are dropped; the next admitted line is preceded by
+/// a summary of the number of lines suppressed.
+pub fn set_rate_limit(max_lines_per_second: usize) {
+    let mut limiter = RateLimiter::new(max_lines_per_second);
+    limiter.refilled_ms = timer::uptime_ms();
+    cpu::without_interrupts(|| *RATE_LIMITER.lock() = limiter);
+}
+
+/// Return the rate limit of `print!` in lines per second (0 = unlimited).
+pub fn rate_limit() -> usize {
+    cpu::without_interrupts(|| RATE_LIMITER.lock().max_per_second)
+}
+
+/// Check if a new line of `print!` may be written now (see `RateLimiter::admit()`).
+/// Called by the console at the beginning of each line.
+pub fn admit_line() -> Option<usize> {
+    // Interrupt handlers may print, so they must not interrupt us while the limiter is locked.
+    cpu::without_interrupts(|| RATE_LIMITER.lock().admit(timer::uptime_ms()))
+}
+
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Self tests, run by the diagnostics (see `user::diag`).                  ║
+   ╚═════════════════════════════════════════════════════════════════════════╝ */
+
+pub mod selftest {
+    use alloc::string::String;
+    use crate::kernel::log::{Event, EventKind, EventLog, RateLimiter};
+    use crate::library::selftest::{Test, TestResult};
+
+    /// The self tests of this module, run in this order.
+    pub const TESTS: &[Test] = &[
+        ("Event log", test_event_log),
+        ("Rate limit", test_rate_limit),
+    ];
+
+    /// Record more events than fit into a small log and check
+    /// that the newest ones are exported in chronological order.
+    fn test_event_log() -> TestResult {
+        const EXPECTED: &str = "timestamp_ms,event,data\n\
+                                # 1 older events overwritten\n\
+                                20,alarm,0x2\n\
+                                30,alloc,0x3\n\
+                                40,key,0x4\n";
+
+        let mut log: EventLog<3> = EventLog::new();
+        let kinds = [EventKind::Key, EventKind::Alarm, EventKind::Alloc, EventKind::Key];
+        for (i, kind) in kinds.into_iter().enumerate() {
+            log.push(Event { timestamp_ms: (i as u64 + 1) * 10, kind, data: i as u64 + 1 });
+        }
+
+        let mut csv = String::new();
+        log.export(&mut csv).map_err(|_| "export failed")?;
+        if csv != EXPECTED {
+            return Err("wrong export");
+        }
+        Ok(())
+    }
+
+    /// Send a burst of lines through a rate limiter of 5 lines per second and check that
+    /// the lines beyond the limit are dropped and summarized once tokens have been refilled.
+    fn test_rate_limit() -> TestResult {
+        const LIMIT: usize = 5;
+        const BURST: usize = 8;
+
+        let mut limiter = RateLimiter::new(LIMIT);
+        let admitted = (0..BURST).filter(|_| limiter.admit(0).is_some()).count();
+        if admitted != LIMIT {
+            return Err("burst not limited");
+        }
+        if limiter.admit(100).is_some() {
+            return Err("admitted before a token was refilled");
+        }
+        if limiter.admit(1000) != Some(BURST - LIMIT + 1) {
+            return Err("dropped lines not summarized");
+        }
+        if limiter.admit(1200) != Some(0) {
+            return Err("token not refilled");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,209 @@
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Module: memory                                                          ║
+   ╟─────────────────────────────────────────────────────────────────────────╢
+   ║ Descr.: Map of the physical memory, describing which regions are usable ║
+   ║         and which are reserved (low memory with the BIOS data and video ║
+   ║         memory, kernel image, page tables, program area). Used to place ║
+   ║         the heap.                                                       ║
+   ╚═════════════════════════════════════════════════════════════════════════╝
+*/
+use core::ptr;
+use crate::library::arrayvec::ArrayVec;
+
+/// Assumed size of the physical memory. The memory information of the boot loader
+/// is not evaluated, so this is the minimum RAM required by the kernel.
+pub const RAM_SIZE: usize = 8 * 1024 * 1024;
+
+/// End of the low memory area (real mode IVT, BIOS data, video memory and ROMs).
+const LOW_MEMORY_END: usize = 0x100000;
+
+/// Video memory of the CGA (within the low memory area).
+const VIDEO_MEMORY: (usize, usize) = (0xa0000, 0xc0000);
+
+/// Memory at the top of the RAM which user programs are loaded into (see `loader`).
+/// Programs must be linked to addresses within it.
+pub const PROGRAM_AREA: (usize, usize) = (RAM_SIZE - 0x100000, RAM_SIZE);
+
+/// Number of page directories set up by `boot.asm` (`MAX_MEM`).
+const PAGE_DIRECTORIES: usize = 254;
+
+/// Maximum number of regions of a memory map.
+const MAX_REGIONS: usize = 16;
+
+// Symbols defined in `linker.ld` and `boot.asm`
+#[allow(non_upper_case_globals)]
+unsafe extern "C" {
+    static ___KERNEL_DATA_START__: u8;
+    static ___KERNEL_DATA_END__: u8;
+    static pml4: u8;
+    static pdp: u8;
+}
+
+/// Kind of a memory region.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RegionKind {
+    Usable,
+    Reserved,
+}
+
+/// A memory region from `start` (inclusive) to `end` (exclusive).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Region {
+    pub start: usize,
+    pub end: usize,
+    pub kind: RegionKind,
+}
+
+impl Region {
+    /// Return the size of the region in bytes.
+    pub fn size(&self) -> usize {
+        self.end - self.start
+    }
+}
+
+/// Errors that can occur when building a memory map.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MemoryMapError {
+    /// More than `MAX_REGIONS` regions would be needed.
+    TooManyRegions,
+}
+
+/// A map of the physical memory as sorted list of regions without gaps, starting at address 0.
+pub struct MemoryMap {
+    regions: ArrayVec<Region, MAX_REGIONS>,
+}
+
+impl MemoryMap {
+    /// Create a map of `size` bytes of usable memory.
+    pub fn new(size: usize) -> MemoryMap {
+        let mut regions = ArrayVec::new();
+        regions.push(Region { start: 0, end: size, kind: RegionKind::Usable }).ok();
+        MemoryMap { regions }
+    }
+
+    /// Mark the memory from `start` to `end` as reserved. Parts outside the map are ignored.
+    pub fn reserve(&mut self, start: usize, end: usize) -> Result<(), MemoryMapError> {
+        let mut regions: ArrayVec<Region, MAX_REGIONS> = ArrayVec::new();
+
+        for &region in self.regions.iter() {
+            let (overlap_start, overlap_end) = (region.start.max(start), region.end.min(end));
+            if region.kind == RegionKind::Reserved || overlap_start >= overlap_end {
+                push_merged(&mut regions, region)?;
+                continue;
+            }
+
+            // split the usable region into the usable parts around the reserved one
+            let parts = [
+                Region { start: region.start, end: overlap_start, kind: RegionKind::Usable },
+                Region { start: overlap_start, end: overlap_end, kind: RegionKind::Reserved },
+                Region { start: overlap_end, end: region.end, kind: RegionKind::Usable },
+            ];
+            for part in parts {
+                if part.start < part.end {
+                    push_merged(&mut regions, part)?;
+                }
+            }
+        }
+
+        self.regions = regions;
+        Ok(())
+    }
+
+    /// Return the regions sorted by address.
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+
+    /// Return the largest usable region (the first one, if several have the same size).
+    pub fn largest_usable_region(&self) -> Option<Region> {
+        let mut largest: Option<Region> = None;
+        for &region in self.regions.iter().filter(|region| region.kind == RegionKind::Usable) {
+            if largest.is_none_or(|largest| region.size() > largest.size()) {
+                largest = Some(region);
+            }
+        }
+        largest
+    }
+
+    /// Print the memory map.
+    pub fn dump(&self) {
+        println!("--- Memory Map ---");
+        for region in self.regions.iter() {
+            println!("{:#010x} - {:#010x}: {:?}", region.start, region.end, region.kind);
+        }
+        println!("--- End of Memory Map ---");
+    }
+}
+
+/// Append `region` to `regions`, merging it with the last region if it has the same kind.
+fn push_merged(regions: &mut ArrayVec<Region, MAX_REGIONS>, region: Region) -> Result<(), MemoryMapError> {
+    if let Some(last) = regions.last_mut() && last.kind == region.kind && last.end == region.start {
+        last.end = region.end;
+        return Ok(());
+    }
+    regions.push(region).map_err(|_| MemoryMapError::TooManyRegions)
+}
+
+/// Return the start (inclusive) and end (exclusive) address of the kernel image.
+pub fn kernel_image() -> (usize, usize) {
+    // The symbols are only used for their addresses.
+    (ptr::addr_of!(___KERNEL_DATA_START__) as usize, ptr::addr_of!(___KERNEL_DATA_END__) as usize)
+}
+
+/// Build the memory map of the kernel from the known fixed regions.
+pub fn kernel_map() -> Result<MemoryMap, MemoryMapError> {
+    let (kernel_start, kernel_end) = kernel_image();
+    // The symbols are only used for their addresses.
+    let (tables_start, tables_end) = (ptr::addr_of!(pml4) as usize, ptr::addr_of!(pdp) as usize + PAGE_DIRECTORIES * 8);
+
+    let mut map = MemoryMap::new(RAM_SIZE);
+    map.reserve(0, LOW_MEMORY_END)?;
+    map.reserve(VIDEO_MEMORY.0, VIDEO_MEMORY.1)?;
+    map.reserve(kernel_start, kernel_end)?;
+    // The page tables are not part of a section listed in `linker.ld`.
+    map.reserve(tables_start, tables_end)?;
+    map.reserve(PROGRAM_AREA.0, PROGRAM_AREA.1)?;
+    Ok(map)
+}
+
+/// Return the largest usable region of the kernel memory map.
+pub fn largest_usable_region() -> Option<Region> {
+    kernel_map().ok()?.largest_usable_region()
+}
+
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Self tests, run by the diagnostics (see `user::diag`).                  ║
+   ╚═════════════════════════════════════════════════════════════════════════╝ */
+
+pub mod selftest {
+    use crate::kernel::memory::{MemoryMap, Region, RegionKind};
+    use crate::library::selftest::{Test, TestResult};
+
+    /// The self tests of this module, run in this order.
+    pub const TESTS: &[Test] = &[
+        ("Largest usable region", test_largest_usable_region),
+    ];
+
+    /// Reserve three ranges of a small map and check the resulting regions and that the
+    /// largest usable one is returned, then check that a fully reserved map has none.
+    fn test_largest_usable_region() -> TestResult {
+        const SIZE: usize = 0x1000;
+
+        let mut map = MemoryMap::new(SIZE);
+        for (start, end) in [(0x100, 0x200), (0x400, 0x480), (0xc00, SIZE)] {
+            map.reserve(start, end).map_err(|_| "too many regions")?;
+        }
+
+        if map.regions().len() != 6 {
+            return Err("wrong number of regions");
+        }
+        if map.largest_usable_region() != Some(Region { start: 0x480, end: 0xc00, kind: RegionKind::Usable }) {
+            return Err("wrong largest region");
+        }
+        map.reserve(0, SIZE).map_err(|_| "too many regions")?;
+        if map.largest_usable_region().is_some() {
+            return Err("usable region in reserved map");
+        }
+        Ok(())
+    }
+}
@@ -1,5 +1,20 @@
 pub mod cpu;
+pub mod debug;
 pub mod allocator;
+pub mod boot;
+pub mod init;
 pub mod interrupts;
+pub mod pit;
+pub mod profiler;
+pub mod softirq;
+pub mod stack_guard;
+pub mod timer;
 pub mod threads;
 pub mod coroutines;
+pub mod loader;
+pub mod log;
+pub mod memory;
+pub mod panic_screen;
+pub mod shutdown;
+
+pub use shutdown::shutdown;
@@ -0,0 +1,113 @@
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Module: panic_screen                                                    ║
+   ╟─────────────────────────────────────────────────────────────────────────╢
+   ║ Descr.: Crash screen shown by the panic handler: red background, a      ║
+   ║         'KERNEL PANIC' banner, the panic message, its location and a    ║
+   ║         register summary in a fixed layout. Locks are only tried (never ║
+   ║         waited for) and nothing is allocated. If the CGA is not         ║
+   ║         available, only the serial output is written.                   ║
+   ╚═════════════════════════════════════════════════════════════════════════╝
+*/
+use core::fmt;
+use core::fmt::Write;
+use core::panic::PanicInfo;
+use crate::devices::cga::{self, Color, CGA};
+use crate::devices::serial;
+use crate::kernel::debug::Registers;
+
+// Attributes of the panic screen
+const PANIC_ATTR: u8 = (Color::Red as u8) << 4 | (Color::White as u8);
+const PANIC_BANNER_ATTR: u8 = (Color::White as u8) << 4 | (Color::Red as u8);
+
+// Layout of the panic screen (rows)
+const MESSAGE_ROW: usize = 2;
+const MESSAGE_LAST_ROW: usize = 10;
+const LOCATION_ROW: usize = 12;
+const REGISTERS_ROW: usize = 15;
+const FOOTER_ROW: usize = 24;
+
+// Indentation of the contents below a heading
+const INDENT: usize = 2;
+const COLUMNS: usize = 80;
+
+/// Show the panic screen for `info`.
+/// The panic is always written to COM1 (if it is not locked), the screen only if the CGA can be locked.
+pub fn show(info: &PanicInfo) {
+    let regs = Registers::capture();
+
+    // Write to COM1 directly, the debug console could be redirected to the screen.
+    if let Some(mut com) = serial::COM1.try_lock() {
+        writeln!(com, "Panic: {}", info).ok();
+        writeln!(com, "rip={:#x} rsp={:#x} rbp={:#x} rflags={:#x}", regs.rip, regs.rsp, regs.rbp, regs.rflags).ok();
+    }
+
+    let Some(mut cga) = CGA.try_lock() else {
+        if let Some(mut com) = serial::COM1.try_lock() {
+            writeln!(com, "CGA is locked, panic screen not shown").ok();
+        }
+        return;
+    };
+
+    cga.clear_with(b' ', PANIC_ATTR);
+    cga.draw_banner("KERNEL PANIC", PANIC_BANNER_ATTR);
+
+    let mut w = ScreenWriter { cga: &mut cga, x: 0, y: MESSAGE_ROW, left: 0, last_row: MESSAGE_LAST_ROW };
+    write!(w, "Message:").ok();
+    w.newline_at(INDENT);
+    write!(w, "{}", info.message()).ok();
+
+    let mut w = ScreenWriter { cga: w.cga, x: 0, y: LOCATION_ROW, left: 0, last_row: REGISTERS_ROW - 1 };
+    write!(w, "Location:").ok();
+    w.newline_at(INDENT);
+    match info.location() {
+        Some(location) => write!(w, "{}:{}:{}", location.file(), location.line(), location.column()).ok(),
+        None => write!(w, "unknown").ok(),
+    };
+
+    let mut w = ScreenWriter { cga: w.cga, x: 0, y: REGISTERS_ROW, left: 0, last_row: FOOTER_ROW - 1 };
+    write!(w, "Registers:").ok();
+    w.newline_at(INDENT);
+    write!(w, "rip={:#018x}  rsp={:#018x}  rbp={:#018x}", regs.rip, regs.rsp, regs.rbp).ok();
+    w.newline_at(INDENT);
+    write!(w, "rflags={:#018x}  cr2={:#018x}  cr3={:#018x}", regs.rflags, regs.cr2, regs.cr3).ok();
+
+    let mut w = ScreenWriter { cga: w.cga, x: 0, y: FOOTER_ROW, left: 0, last_row: FOOTER_ROW };
+    write!(w, "System halted.").ok();
+}
+
+/// Writes text to a region of the locked screen with `PANIC_ATTR`, starting at `x`,`y`.
+/// Lines are wrapped at column `left`, text beyond `last_row` is dropped.
+struct ScreenWriter<'a> {
+    cga: &'a mut cga::CGA,
+    x: usize,
+    y: usize,
+    left: usize,
+    last_row: usize,
+}
+
+impl ScreenWriter<'_> {
+    /// Continue in the next row at column `left` (which is also used for wrapped lines).
+    fn newline_at(&mut self, left: usize) {
+        self.left = left;
+        self.x = left;
+        self.y += 1;
+    }
+}
+
+impl fmt::Write for ScreenWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for b in s.bytes() {
+            if b == b'\n' || self.x >= COLUMNS {
+                self.newline_at(self.left);
+            }
+            if self.y > self.last_row {
+                break;
+            }
+            if b != b'\n' {
+                self.cga.show(self.x, self.y, b as char, PANIC_ATTR);
+                self.x += 1;
+            }
+        }
+        Ok(())
+    }
+}
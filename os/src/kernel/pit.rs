@@ -0,0 +1,115 @@
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Module: pit                                                             ║
+   ╟─────────────────────────────────────────────────────────────────────────╢
+   ║ Descr.: Arbitration of the counters of the PIT (programmable interval   ║
+   ║         timer). Counter 0 is owned by the system timer and counter 2    ║
+   ║         generates the tone of the pc speaker. All counters are          ║
+   ║         programmed through the global PIT instance, which remembers the ║
+   ║         configuration of counter 0. Code that borrows counter 0 (e.g.   ║
+   ║         the busy-waiting delay of the pc speaker) must restore the      ║
+   ║         saved configuration afterwards, otherwise the system timer runs ║
+   ║         with the wrong interval.                                        ║
+   ╚═════════════════════════════════════════════════════════════════════════╝
+*/
+use spin::Mutex;
+use crate::kernel::cpu::IoPort;
+
+/// Global PIT instance, used by the system timer and the pc speaker.
+pub static PIT: Mutex<Pit> = Mutex::new(Pit::new());
+
+// Ports
+const PORT_CTRL: u16 = 0x43;
+const PORT_DATA0: u16 = 0x40;
+const PORT_DATA2: u16 = 0x42;
+
+/// Base frequency of the PIT in Hz.
+pub const PIT_BASE_FREQUENCY: usize = 1193182;
+
+/// Operating mode and reload value of a PIT counter.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ChannelConfig {
+    /// Operating mode (0-5), e.g. 2 = rate generator, 3 = square wave generator
+    pub mode: u8,
+    /// Reload value; 0 is interpreted as 65536 by the PIT
+    pub reload: u16,
+}
+
+impl ChannelConfig {
+    /// Create a new configuration with the given `mode` and `reload` value.
+    pub const fn new(mode: u8, reload: u16) -> ChannelConfig {
+        ChannelConfig { mode, reload }
+    }
+}
+
+/// Representation of the PIT with its counters 0 and 2.
+/// Counter 1 is not used (historically it refreshed the DRAM).
+pub struct Pit {
+    ctrl_port: IoPort,
+    data0_port: IoPort,
+    data2_port: IoPort,
+    /// Current configuration of counter 0
+    channel0: ChannelConfig,
+    /// Last configuration of counter 2, `None` until it has been programmed
+    channel2: Option<ChannelConfig>,
+}
+
+impl Pit {
+    /// Create a new PIT instance.
+    /// The configuration of counter 0 is initialized with the BIOS default (mode 3, reload 65536).
+    pub const fn new() -> Pit {
+        Pit {
+            ctrl_port: IoPort::new(PORT_CTRL),
+            data0_port: IoPort::new(PORT_DATA0),
+            data2_port: IoPort::new(PORT_DATA2),
+            channel0: ChannelConfig::new(3, 0),
+            channel2: None,
+        }
+    }
+
+    /// Return the current configuration of counter 0.
+    /// It can be passed to `set_channel0()` later to restore counter 0.
+    pub fn channel0(&self) -> ChannelConfig {
+        self.channel0
+    }
+
+    /// Program counter 0 with `config` and remember the configuration.
+    pub fn set_channel0(&mut self, config: ChannelConfig) {
+        self.channel0 = config;
+        unsafe {
+            // Channel 0, access mode: lobyte/hibyte, binary
+            self.ctrl_port.outb(0b0011_0000 | (config.mode & 0x7) << 1);
+            self.data0_port.outb((config.reload & 0xff) as u8); // low byte
+            self.data0_port.outb((config.reload >> 8) as u8);   // high byte
+        }
+    }
+
+    /// Return the configuration counter 2 has last been programmed with, if any.
+    pub fn channel2(&self) -> Option<ChannelConfig> {
+        self.channel2
+    }
+
+    /// Program counter 2 with `config`. Counter 2 is connected to the pc speaker.
+    pub fn set_channel2(&mut self, config: ChannelConfig) {
+        self.channel2 = Some(config);
+        unsafe {
+            // Channel 2, access mode: lobyte/hibyte, binary
+            self.ctrl_port.outb(0b1011_0000 | (config.mode & 0x7) << 1);
+            self.data2_port.outb((config.reload & 0xff) as u8); // low byte
+            self.data2_port.outb((config.reload >> 8) as u8);   // high byte
+        }
+    }
+
+    /// Return the current value of counter 0 (16-bit).
+    pub fn read_channel0(&mut self) -> u16 {
+        let mut counter: u16 = 0;
+
+        unsafe {
+            // Latch the value of counter 0, so that both bytes belong together
+            self.ctrl_port.outb(0b0000_0000);
+            counter |= self.data0_port.inb() as u16;
+            counter |= (self.data0_port.inb() as u16) << 8;
+        }
+
+        counter
+    }
+}
@@ -0,0 +1,188 @@
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Module: profiler                                                        ║
+   ╟─────────────────────────────────────────────────────────────────────────╢
+   ║ Descr.: Sampling profiler. The timer interrupt records the interrupted  ║
+   ║         instruction pointer in a fixed-size hash table, 'report' prints ║
+   ║         the addresses with the most samples. Hot loops show up as the   ║
+   ║         addresses sampled most often.                                   ║
+   ╚═════════════════════════════════════════════════════════════════════════╝
+*/
+use core::cmp::Reverse;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use crate::kernel::cpu;
+use crate::kernel::memory;
+use crate::library::arrayvec::ArrayVec;
+
+/// Number of distinct addresses the sample table can hold.
+const PROFILE_SLOTS: usize = 256;
+
+/// Number of addresses printed by `report()`.
+pub const REPORT_TOP: usize = 10;
+
+/// Samples recorded since the last `start()`.
+static PROFILE: Mutex<Profile> = Mutex::new(Profile::new(0, 0));
+
+/// Set while the timer interrupt records samples.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Number of samples of one address (a count of 0 marks an empty slot).
+#[derive(Copy, Clone)]
+struct Slot {
+    rip: u64,
+    count: usize,
+}
+
+/// A table of instruction pointer samples, hashed by address with linear probing.
+#[derive(Clone)]
+pub struct Profile {
+    slots: [Slot; PROFILE_SLOTS],
+    /// Start (inclusive) and end (exclusive) of the addresses recorded in the table
+    range: (u64, u64),
+    /// Number of samples recorded
+    samples: usize,
+    /// Number of samples outside `range`, e.g. in code loaded at runtime
+    outside: usize,
+    /// Number of samples of new addresses lost because all slots were in use
+    dropped: usize,
+}
+
+impl Profile {
+    /// Create an empty profile for addresses from `start` (inclusive) to `end` (exclusive).
+    pub const fn new(start: u64, end: u64) -> Profile {
+        Profile {
+            slots: [Slot { rip: 0, count: 0 }; PROFILE_SLOTS],
+            range: (start, end),
+            samples: 0,
+            outside: 0,
+            dropped: 0,
+        }
+    }
+
+    /// Record a sample of the instruction pointer `rip`.
+    pub fn record(&mut self, rip: u64) {
+        self.samples += 1;
+        if rip < self.range.0 || rip >= self.range.1 {
+            self.outside += 1;
+            return;
+        }
+
+        // Fibonacci hashing spreads neighbouring addresses over the table.
+        let hash = (rip.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 32) as usize;
+        for i in 0..PROFILE_SLOTS {
+            let slot = &mut self.slots[(hash + i) % PROFILE_SLOTS];
+            if slot.count == 0 {
+                *slot = Slot { rip, count: 1 };
+                return;
+            }
+            if slot.rip == rip {
+                slot.count += 1;
+                return;
+            }
+        }
+        self.dropped += 1;
+    }
+
+    /// Return the `N` addresses with the most samples and their sample counts,
+    /// sorted by count (descending). Of addresses with equal counts, those found first in the table are kept.
+    pub fn top<const N: usize>(&self) -> ArrayVec<(u64, usize), N> {
+        let mut top: ArrayVec<(u64, usize), N> = ArrayVec::new();
+        for slot in self.slots.iter().filter(|slot| slot.count > 0) {
+            if top.is_full() {
+                if slot.count <= top[top.len() - 1].1 {
+                    continue;
+                }
+                top.pop();
+            }
+            top.push((slot.rip, slot.count)).ok();
+            top.sort_unstable_by_key(|&(_, count)| Reverse(count));
+        }
+        top
+    }
+
+    /// Return the number of samples recorded.
+    pub fn samples(&self) -> usize {
+        self.samples
+    }
+
+    /// Return the number of samples outside the address range of the profile.
+    pub fn outside(&self) -> usize {
+        self.outside
+    }
+
+    /// Return the number of samples lost because the table was full.
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+}
+
+/// Clear the samples and start recording the instruction pointer on each timer interrupt.
+/// Only addresses within the kernel image are recorded in the table.
+/// The timer must be plugged in.
+pub fn start() {
+    let (start, end) = memory::kernel_image();
+    cpu::without_interrupts(|| *PROFILE.lock() = Profile::new(start as u64, end as u64));
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Stop recording samples. The samples are kept for `report()`.
+pub fn stop() {
+    ENABLED.store(false, Ordering::Relaxed);
+}
+
+/// Record the instruction pointer `rip` interrupted by the timer, if the profiler is running.
+/// Called by `int_disp`. The sample is skipped if the table is currently locked.
+pub fn sample(rip: u64) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    if let Some(mut profile) = PROFILE.try_lock() {
+        profile.record(rip);
+    }
+}
+
+/// Print the `REPORT_TOP` addresses with the most samples.
+/// The addresses can be mapped to functions with `addr2line` or the disassembly of the kernel.
+pub fn report() {
+    // Copy the profile, so the table is not locked while printing.
+    let profile = cpu::without_interrupts(|| PROFILE.lock().clone());
+
+    println!("Profile: {} samples ({} outside the kernel, {} dropped)", profile.samples(), profile.outside(), profile.dropped());
+    for &(rip, count) in profile.top::<REPORT_TOP>().iter() {
+        println!("  {:#010x}  {:>6}  {:>3}%", rip, count, count * 100 / profile.samples());
+    }
+}
+
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Self tests, run by the diagnostics (see `user::diag`).                  ║
+   ╚═════════════════════════════════════════════════════════════════════════╝ */
+
+pub mod selftest {
+    use crate::kernel::profiler::Profile;
+    use crate::library::selftest::{Test, TestResult};
+
+    /// The self tests of this module, run in this order.
+    pub const TESTS: &[Test] = &[
+        ("Profile", test_profiler),
+    ];
+
+    /// Feed synthetic instruction pointer samples into a profile and check the top addresses.
+    fn test_profiler() -> TestResult {
+        const SAMPLES: [(u64, usize); 4] = [(0x1300, 1), (0x1100, 5), (0x0500, 2), (0x1200, 3)];
+
+        let mut profile = Profile::new(0x1000, 0x2000);
+        for (rip, count) in SAMPLES {
+            for _ in 0..count {
+                profile.record(rip);
+            }
+        }
+
+        if profile.samples() != 11 || profile.outside() != 2 {
+            return Err("samples not counted");
+        }
+        if profile.top::<2>().as_slice() != [(0x1100, 5), (0x1200, 3)] {
+            return Err("wrong top addresses");
+        }
+        Ok(())
+    }
+}
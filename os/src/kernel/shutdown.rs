@@ -0,0 +1,161 @@
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Module: shutdown                                                        ║
+   ╟─────────────────────────────────────────────────────────────────────────╢
+   ║ Descr.: Orderly shutdown of the kernel: the devices are quieted,        ║
+   ║         pending output is written, a final message is shown and the     ║
+   ║         machine is powered off via ACPI (QEMU, Bochs) or halted. Locks  ║
+   ║         are only tried, so a shutdown requested from a soft interrupt   ║
+   ║         cannot deadlock.                                                ║
+   ╚═════════════════════════════════════════════════════════════════════════╝
+*/
+use core::fmt::Write;
+use crate::devices::cga::{self, CGA};
+use crate::devices::{console, pcspk, serial};
+use crate::kernel::cpu;
+use crate::kernel::cpu::IoPort;
+use crate::kernel::interrupts::pic::{Irq, PIC};
+
+/// ACPI PM1a control ports of QEMU (since 2.0) and of older QEMU versions and Bochs.
+const ACPI_PM1A_CONTROL_PORTS: [u16; 2] = [0x604, 0xb004];
+
+/// Value of the PM1a control register entering sleep state S5 (soft off) on these machines.
+const ACPI_SLEEP_S5: u16 = 0x2000;
+
+/// Message shown when the kernel has been shut down.
+const FINAL_MESSAGE: &str = "System halted. It is now safe to turn off the computer.";
+
+/// Row of the final message.
+const FINAL_MESSAGE_ROW: usize = 12;
+
+/// A teardown step: a name reported before it runs and the function performing it.
+pub type Step = (&'static str, fn());
+
+/// The teardown steps of `shutdown()` in the order they are run. The interrupts are masked
+/// first, so no more output is produced while the consoles are flushed. The final message
+/// is written last, directly to the devices, after everything buffered before.
+pub const TEARDOWN_STEPS: [Step; 5] = [
+    ("Turning off the speaker", speaker_off),
+    ("Masking interrupts", mask_interrupts),
+    ("Flushing consoles", console::flush_all),
+    ("Disabling the cursor", disable_cursor),
+    ("Printing the final message", final_message),
+];
+
+/// Shut down the kernel: run the `TEARDOWN_STEPS`, then power off the machine.
+/// If powering off is not supported, the CPU is halted with interrupts disabled.
+pub fn shutdown() -> ! {
+    run_steps(&TEARDOWN_STEPS, |name| {
+        if let Some(mut com) = serial::COM1.try_lock() {
+            writeln!(com, "Shutdown: {}", name).ok();
+        }
+    });
+    power_off();
+
+    cpu::disable_int();
+    loop {
+        cpu::halt();
+    }
+}
+
+/// Run `steps` in order, calling `report` with the name of each step before it runs.
+pub fn run_steps(steps: &[Step], mut report: impl FnMut(&'static str)) {
+    for (name, step) in steps {
+        report(name);
+        step();
+    }
+}
+
+/// Request soft off (S5) from the ACPI controllers of the emulators.
+/// Returns if none of them powered off the machine.
+fn power_off() {
+    for port in ACPI_PM1A_CONTROL_PORTS {
+        unsafe { IoPort::new(port).outw(ACPI_SLEEP_S5); }
+    }
+}
+
+fn speaker_off() {
+    if let Some(mut speaker) = pcspk::SPEAKER.try_lock() {
+        speaker.off();
+    }
+}
+
+/// Disable the interrupts and mask all IRQs, so they stay off even if interrupts get enabled again.
+fn mask_interrupts() {
+    cpu::disable_int();
+    if let Some(mut pic) = PIC.try_lock() {
+        for irq in Irq::ALL {
+            pic.forbid(irq);
+        }
+    }
+}
+
+fn disable_cursor() {
+    if let Some(mut cga) = CGA.try_lock() {
+        cga.disable_cursor();
+    }
+}
+
+/// Show `FINAL_MESSAGE` centered on the cleared screen and write it to COM1.
+fn final_message() {
+    if let Some(mut cga) = CGA.try_lock() {
+        cga.clear();
+        let x = cga::banner_column(FINAL_MESSAGE.len());
+        for (i, ch) in FINAL_MESSAGE.chars().enumerate() {
+            cga.show(x + i, FINAL_MESSAGE_ROW, ch, cga::CGA_STD_ATTR);
+        }
+    }
+    if let Some(mut com) = serial::COM1.try_lock() {
+        writeln!(com, "{}", FINAL_MESSAGE).ok();
+        com.drain();
+    }
+}
+
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Self tests, run by the diagnostics (see `user::diag`).                  ║
+   ╚═════════════════════════════════════════════════════════════════════════╝ */
+
+pub mod selftest {
+    use spin::Mutex;
+    use crate::kernel::shutdown::{self, Step};
+    use crate::library::arrayvec::ArrayVec;
+    use crate::library::selftest::{Test, TestResult};
+
+    /// The self tests of this module, run in this order.
+    pub const TESTS: &[Test] = &[
+        ("Steps", test_shutdown_steps),
+    ];
+
+    /// Steps invoked by the mock teardown of the shutdown test, in invocation order.
+    static INVOKED_STEPS: Mutex<ArrayVec<usize, 3>> = Mutex::new(ArrayVec::new());
+
+    /// Run mock teardown steps through the shutdown sequence and check that each is reported
+    /// and invoked once in order. Also check the order of the real teardown steps: interrupts
+    /// are masked before the consoles are flushed, and the final message comes last.
+    fn test_shutdown_steps() -> TestResult {
+        const MOCK_STEPS: [Step; 3] = [
+            ("first", || { INVOKED_STEPS.lock().push(0).ok(); }),
+            ("second", || { INVOKED_STEPS.lock().push(1).ok(); }),
+            ("third", || { INVOKED_STEPS.lock().push(2).ok(); }),
+        ];
+
+        INVOKED_STEPS.lock().clear();
+        let mut reported: ArrayVec<&str, 3> = ArrayVec::new();
+        shutdown::run_steps(&MOCK_STEPS, |name| { reported.push(name).ok(); });
+        if INVOKED_STEPS.lock().as_slice() != [0, 1, 2] {
+            return Err("steps not invoked in order");
+        }
+        if reported.as_slice() != ["first", "second", "third"] {
+            return Err("steps not reported in order");
+        }
+
+        let position = |name| shutdown::TEARDOWN_STEPS.iter().position(|(step, _)| *step == name);
+        let (Some(mask), Some(flush), Some(message)) =
+            (position("Masking interrupts"), position("Flushing consoles"), position("Printing the final message")) else {
+            return Err("teardown step missing");
+        };
+        if mask > flush || message != shutdown::TEARDOWN_STEPS.len() - 1 {
+            return Err("teardown steps in wrong order");
+        }
+        Ok(())
+    }
+}
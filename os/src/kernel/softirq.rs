@@ -0,0 +1,81 @@
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Module: softirq                                                         ║
+   ╟─────────────────────────────────────────────────────────────────────────╢
+   ║ Descr.: Deferred execution of work raised by interrupt service routines ║
+   ║         (soft interrupts). An ISR only queues a handler via 'raise',    ║
+   ║         which is then run by 'int_disp' after the ISR has returned,     ║
+   ║         with interrupts enabled. This keeps the time spent with         ║
+   ║         interrupts disabled short.                                      ║
+   ╚═════════════════════════════════════════════════════════════════════════╝
+*/
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use crate::kernel::cpu;
+
+/// Maximum number of pending soft interrupts.
+const MAX_PENDING: usize = 32;
+
+/// Queue of pending soft interrupt handlers.
+static PENDING: Mutex<SoftIrqQueue> = Mutex::new(SoftIrqQueue::new());
+
+/// Set while `run_pending()` is executing handlers, to avoid running them nested.
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// A ring buffer of soft interrupt handlers.
+struct SoftIrqQueue {
+    handlers: [Option<fn()>; MAX_PENDING],
+    head: usize,
+    len: usize,
+}
+
+impl SoftIrqQueue {
+    const fn new() -> SoftIrqQueue {
+        SoftIrqQueue { handlers: [None; MAX_PENDING], head: 0, len: 0 }
+    }
+
+    fn push(&mut self, handler: fn()) -> bool {
+        if self.len == MAX_PENDING {
+            return false;
+        }
+        self.handlers[(self.head + self.len) % MAX_PENDING] = Some(handler);
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<fn()> {
+        if self.len == 0 {
+            return None;
+        }
+        let handler = self.handlers[self.head].take();
+        self.head = (self.head + 1) % MAX_PENDING;
+        self.len -= 1;
+        handler
+    }
+}
+
+/// Queue `handler` to be run after the current interrupt has been handled.
+/// Can be called from an ISR. Returns false if the queue is full and the handler was dropped.
+pub fn raise(handler: fn()) -> bool {
+    cpu::without_interrupts(|| PENDING.lock().push(handler))
+}
+
+/// Run all pending soft interrupt handlers (with interrupts enabled).
+/// Called by `int_disp` after an ISR has been triggered.
+/// The interrupt state of the caller is restored before returning.
+pub fn run_pending() {
+    if RUNNING.swap(true, Ordering::Acquire) {
+        // Already running further up in the call stack (nested interrupt)
+        return;
+    }
+
+    while let Some(handler) = cpu::without_interrupts(|| PENDING.lock().pop()) {
+        let ie = cpu::is_int_enabled();
+        cpu::enable_int();
+        handler();
+        if !ie {
+            cpu::disable_int();
+        }
+    }
+
+    RUNNING.store(false, Ordering::Release);
+}
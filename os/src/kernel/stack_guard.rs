@@ -0,0 +1,154 @@
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Module: stack_guard                                                     ║
+   ╟─────────────────────────────────────────────────────────────────────────╢
+   ║ Descr.: Stack canary for the kernel stack. A known value is placed at a ║
+   ║         low-water mark near the bottom of the stack and checked         ║
+   ║         periodically by a timer alarm. If it has been overwritten, the  ║
+   ║         stack has grown too deep (e.g. by unbounded recursion) and an   ║
+   ║         alert is printed.                                               ║
+   ╚═════════════════════════════════════════════════════════════════════════╝
+*/
+use core::arch::asm;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use crate::kernel::init::StartupError;
+use crate::kernel::timer;
+
+/// Value of the canary, unlikely to be written by accident.
+pub const STACK_CANARY: u64 = 0x5354_4143_4b47_5244;
+
+/// Distance of the canary from the bottom (lowest address) of the stack in bytes.
+/// Code using the last `STACK_GUARD_MARGIN` bytes of the stack overwrites the canary
+/// before it leaves the stack, so the overflow is detected before other data is corrupted.
+const STACK_GUARD_MARGIN: usize = 4096;
+
+/// Interval between two checks of the canary in milliseconds.
+const STACK_CHECK_MS: u64 = 10;
+
+// Symbols defined in `boot.asm`
+#[allow(non_upper_case_globals)]
+unsafe extern "C" {
+    static init_stack: u8;
+    static init_stack_end: u8;
+}
+
+/// The canary of the kernel stack (see `init()`).
+static KERNEL_GUARD: Mutex<Option<StackGuard>> = Mutex::new(None);
+
+/// Set while a check of the canary is scheduled as alarm.
+static CHECK_SCHEDULED: AtomicBool = AtomicBool::new(false);
+
+/// A canary placed in a stack.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct StackGuard {
+    addr: usize,
+}
+
+impl StackGuard {
+    /// Place the canary `STACK_GUARD_MARGIN` bytes above `bottom` in the stack from `bottom`
+    /// to `top` which is currently used up to the stack pointer `sp`.
+    /// Returns `None` if the stack is too small or already used beyond the canary.
+    /// Unsafe because the memory at the canary is overwritten.
+    pub unsafe fn place(bottom: usize, top: usize, sp: usize) -> Option<StackGuard> {
+        let addr = (bottom + STACK_GUARD_MARGIN) & !(align_of::<u64>() - 1);
+        if addr + size_of::<u64>() > top || sp <= addr + size_of::<u64>() {
+            return None;
+        }
+
+        unsafe { ptr::write_volatile(addr as *mut u64, STACK_CANARY) };
+        Some(StackGuard { addr })
+    }
+
+    /// Return the address of the canary.
+    pub fn addr(&self) -> usize {
+        self.addr
+    }
+
+    /// Check if the canary still holds `STACK_CANARY`.
+    pub fn intact(&self) -> bool {
+        // Volatile, because the stack is modified behind the back of the compiler.
+        unsafe { ptr::read_volatile(self.addr as *const u64) == STACK_CANARY }
+    }
+}
+
+/// Return the bottom (inclusive) and top (exclusive) address of the kernel stack set up by `boot.asm`.
+pub fn kernel_stack() -> (usize, usize) {
+    // The symbols are only used for their addresses.
+    (ptr::addr_of!(init_stack) as usize, ptr::addr_of!(init_stack_end) as usize)
+}
+
+/// Place the canary in the kernel stack and check it every `STACK_CHECK_MS` milliseconds
+/// by a timer alarm (running as soft interrupt), so the timer must be plugged in.
+/// Threads and coroutines have their own stacks, which are not guarded.
+pub fn init() -> Result<(), StartupError> {
+    let sp: usize;
+    unsafe { asm!("mov {}, rsp", out(reg) sp, options(nomem, nostack, preserves_flags)) };
+
+    let (bottom, top) = kernel_stack();
+    let guard = unsafe { StackGuard::place(bottom, top, sp) }.ok_or(StartupError::StackTooSmall)?;
+    *KERNEL_GUARD.lock() = Some(guard);
+
+    if !CHECK_SCHEDULED.swap(true, Ordering::Relaxed) {
+        schedule_check();
+    }
+    Ok(())
+}
+
+/// Schedule the next check of the canary.
+fn schedule_check() {
+    if timer::set_alarm(STACK_CHECK_MS, check).is_err() {
+        CHECK_SCHEDULED.store(false, Ordering::Relaxed);
+        kprintln!("stack guard: no free alarm, the canary is not checked");
+    }
+}
+
+/// Check the canary and schedule the next check, unless it has been overwritten.
+/// The alert is printed only once.
+fn check() {
+    let Some(guard) = *KERNEL_GUARD.lock() else {
+        CHECK_SCHEDULED.store(false, Ordering::Relaxed);
+        return;
+    };
+    if guard.intact() {
+        schedule_check();
+        return;
+    }
+
+    *KERNEL_GUARD.lock() = None;
+    CHECK_SCHEDULED.store(false, Ordering::Relaxed);
+    kprintln!("stack guard: canary at {:#x} overwritten, the kernel stack overflowed", guard.addr());
+    println!("Stack guard: canary at {:#x} overwritten, the kernel stack overflowed", guard.addr());
+}
+
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Self tests, run by the diagnostics (see `user::diag`).                  ║
+   ╚═════════════════════════════════════════════════════════════════════════╝ */
+
+pub mod selftest {
+    use crate::kernel::stack_guard::StackGuard;
+    use crate::library::selftest::{Test, TestResult};
+
+    /// The self tests of this module, run in this order.
+    pub const TESTS: &[Test] = &[
+        ("Canary", test_stack_guard),
+    ];
+
+    /// Place a canary in a synthetic stack, overwrite it as an overflowing stack would
+    /// and check that this is detected.
+    fn test_stack_guard() -> TestResult {
+        let mut stack = [0u64; 1024];
+        let bottom = stack.as_mut_ptr() as usize;
+        let top = bottom + size_of_val(&stack);
+
+        let guard = unsafe { StackGuard::place(bottom, top, top) }.ok_or("canary not placed")?;
+        if !guard.intact() {
+            return Err("fresh canary not intact");
+        }
+        unsafe { (guard.addr() as *mut u64).write_volatile(0) };
+        if guard.intact() {
+            return Err("overwritten canary not detected");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,475 @@
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Module: timer                                                           ║
+   ╟─────────────────────────────────────────────────────────────────────────╢
+   ║ Descr.: System timer using counter 0 of the PIT. It triggers an         ║
+   ║         interrupt every millisecond and counts the ticks since the      ║
+   ║         timer was plugged in. The tick counter is the time base for     ║
+   ║         timeouts and delays in the kernel. Busy-wait delays use either  ║
+   ║         the PIT or the calibrated time stamp counter (see               ║
+   ║         `delay_ms()`).                                                  ║
+   ╚═════════════════════════════════════════════════════════════════════════╝
+*/
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use spin::Mutex;
+use crate::devices::keyboard;
+use crate::devices::rtc;
+use crate::kernel::cpu;
+use crate::kernel::init::StartupError;
+use crate::kernel::log::{self, EventKind};
+use crate::kernel::softirq;
+use crate::kernel::pit::{self, ChannelConfig};
+use crate::kernel::interrupts::intdispatcher::{self, InterruptVector};
+use crate::kernel::interrupts::isr::ISR;
+use crate::kernel::interrupts::pic::{Irq, PIC};
+use crate::library::arrayvec::ArrayVec;
+use crate::library::fixed;
+
+/// Number of PIT counter decrements per millisecond (base frequency 1.193182 MHz).
+const PIT_TICKS_PER_MS: u16 = 1193;
+
+/// Interval between two timer interrupts in milliseconds.
+pub const TIMER_INTERVAL_MS: u64 = 1;
+
+/// Number of timer interrupts since `plugin()` was called.
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Number of slots of the alarm timer wheel. An alarm is stored in the slot of its expiry
+/// tick, so each tick only the alarms of one slot are examined instead of all pending alarms.
+const WHEEL_SLOTS: usize = 64;
+
+/// Number of timer ticks covered by one slot of the wheel. The wheel advances by one slot
+/// every `WHEEL_GRANULARITY` ticks, so alarms fire with this granularity.
+const WHEEL_GRANULARITY: u64 = 1;
+
+/// Maximum number of pending alarms per slot of the wheel.
+const SLOT_CAPACITY: usize = 8;
+
+/// Timer wheel of pending alarms, advanced by the timer interrupt.
+static ALARMS: Mutex<TimerWheel> = Mutex::new(TimerWheel::new(WHEEL_GRANULARITY));
+
+/// Timer wheel of one-shot alarms. Alarms more than one revolution
+/// (`WHEEL_SLOTS * granularity` ticks) ahead stay in their slot
+/// and are skipped until the wheel reaches it in the revolution of their expiry tick.
+struct TimerWheel {
+    slots: [ArrayVec<Alarm, SLOT_CAPACITY>; WHEEL_SLOTS],
+    /// Number of timer ticks covered by one slot
+    granularity: u64,
+}
+
+/// A one-shot alarm calling `callback` once the tick counter has reached `expiry`.
+#[derive(Copy, Clone)]
+struct Alarm {
+    expiry: u64,
+    callback: fn(),
+}
+
+/// Errors that can occur when setting an alarm.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AlarmError {
+    /// All `SLOT_CAPACITY` entries of the wheel slot of the expiry tick are in use.
+    TableFull,
+}
+
+/// Number of RTC seconds measured by `calibrate()`.
+const CALIBRATION_SECONDS: u64 = 2;
+
+/// Number of 1 ms delay loops (see `pit_delay()`) which actually take 1 ms,
+/// as fixed-point value. Measured by `calibrate()`.
+static DELAY_CORRECTION: AtomicU64 = AtomicU64::new(fixed::ONE);
+
+/// Number of timer ticks measured by `calibrate_tsc()`.
+const TSC_CALIBRATION_TICKS: u64 = 50;
+
+/// Time stamp counter increments per millisecond, measured by `calibrate_tsc()` (0 = not calibrated).
+static TSC_PER_MS: AtomicU64 = AtomicU64::new(0);
+
+/// Clock used by `delay_ms()` (see `set_delay_source()`).
+static DELAY_SOURCE: AtomicU8 = AtomicU8::new(DelaySource::Pit as u8);
+
+/// Clock used by `delay_ms()` to busy-wait.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum DelaySource {
+    /// Count 1 ms periods of counter 0 of the PIT (see `pit_delay()`)
+    Pit = 0,
+    /// Spin on the time stamp counter of the processor (see `calibrate_tsc()`)
+    Tsc = 1,
+}
+
+/// Result of `calibrate()`.
+#[derive(Copy, Clone, Debug)]
+pub struct Calibration {
+    /// Number of 1 ms delay loops executed
+    pub iterations: u64,
+    /// Time these loops actually took according to the RTC
+    pub measured_ms: u64,
+    /// Deviation of the uncorrected delay in percent (negative if the delay is too long)
+    pub error_percent: i64,
+}
+
+/// Program counter 0 of the PIT and register the timer interrupt handler.
+/// `INT_VECTORS` must have been initialized.
+pub fn plugin() -> Result<(), StartupError> {
+    if !intdispatcher::INT_VECTORS.lock().is_initialized() {
+        return Err(StartupError::IntVectorsNotInitialized);
+    }
+
+    let reload_value = PIT_TICKS_PER_MS * TIMER_INTERVAL_MS as u16;
+
+    // Mode 2 (rate generator)
+    pit::PIT.lock().set_channel0(ChannelConfig::new(2, reload_value));
+
+    intdispatcher::INT_VECTORS.lock().register(InterruptVector::Pit, Box::new(TimerISR {}));
+    PIC.lock().allow(Irq::Timer);
+    Ok(())
+}
+
+/// Return the number of timer interrupts since the timer was plugged in.
+pub fn get_ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Return the time in milliseconds since the timer was plugged in.
+pub fn uptime_ms() -> u64 {
+    get_ticks() * TIMER_INTERVAL_MS
+}
+
+/// Select the clock used by `delay_ms()`. The default is `DelaySource::Pit`.
+/// `DelaySource::Tsc` only takes effect once `calibrate_tsc()` has succeeded;
+/// until then `delay_ms()` falls back to the PIT.
+pub fn set_delay_source(source: DelaySource) {
+    DELAY_SOURCE.store(source as u8, Ordering::Relaxed);
+}
+
+/// Return the clock selected for `delay_ms()`.
+pub fn delay_source() -> DelaySource {
+    match DELAY_SOURCE.load(Ordering::Relaxed) {
+        1 => DelaySource::Tsc,
+        _ => DelaySource::Pit,
+    }
+}
+
+/// Busy-wait for `ms` milliseconds using the clock selected by `set_delay_source()`.
+pub fn delay_ms(ms: usize) {
+    let tsc_per_ms = TSC_PER_MS.load(Ordering::Relaxed);
+    if delay_source() == DelaySource::Tsc && tsc_per_ms != 0 {
+        let end = cpu::rdtsc() + ms as u64 * tsc_per_ms;
+        while cpu::rdtsc() < end {
+            core::hint::spin_loop();
+        }
+    } else {
+        pit_delay(ms);
+    }
+}
+
+/// Wait for `ms` milliseconds using counter 0 of the PIT.
+/// Mode 2 (rate generator) with a reload value of 1193 (0x04a9) is used.
+/// This means that the counter will count down from 1193 to 0 and then reload itself.
+/// Counting from 1193 to 0 takes 1ms.
+/// Counter 0 is owned by the system timer, so its configuration is restored afterwards.
+/// The number of loops is adjusted by the correction factor measured by `calibrate()`.
+pub fn pit_delay(ms: usize) {
+    let mut pit = pit::PIT.lock();
+    let saved = pit.channel0();
+
+    for _ in 0..delay_loops(ms) {
+        // Set channel 0 to mode 2 (rate generator)
+        pit.set_channel0(ChannelConfig::new(2, PIT_TICKS_PER_MS));
+
+        // Wait for counter to wrap around (when it reaches 0 and reloads)
+        let mut prev = pit.read_channel0();
+        loop {
+            let curr = pit.read_channel0();
+            if curr > prev {
+                break; // PIT counter reloaded (wrapped around)
+            }
+            prev = curr;
+        }
+    }
+
+    pit.set_channel0(saved);
+}
+
+/// Measure the increments of the time stamp counter per millisecond over
+/// `TSC_CALIBRATION_TICKS` timer ticks, enabling `DelaySource::Tsc`.
+/// Returns the measured rate, or `None` if interrupts are disabled and the ticks do not advance.
+pub fn calibrate_tsc() -> Option<u64> {
+    if !cpu::is_int_enabled() {
+        return None;
+    }
+
+    // Start measuring at the beginning of a tick
+    let start = get_ticks();
+    while get_ticks() == start {
+        core::hint::spin_loop();
+    }
+    let first = get_ticks();
+    let tsc_start = cpu::rdtsc();
+    while get_ticks() < first + TSC_CALIBRATION_TICKS {
+        core::hint::spin_loop();
+    }
+    let elapsed = cpu::rdtsc() - tsc_start;
+
+    let per_ms = elapsed / (TSC_CALIBRATION_TICKS * TIMER_INTERVAL_MS);
+    TSC_PER_MS.store(per_ms, Ordering::Relaxed);
+    kprintln!("TSC calibrated: {} increments per ms", per_ms);
+    Some(per_ms)
+}
+
+/// Return the increments of the time stamp counter per millisecond, or `None` if
+/// `calibrate_tsc()` has not succeeded yet.
+pub fn tsc_per_ms() -> Option<u64> {
+    match TSC_PER_MS.load(Ordering::Relaxed) {
+        0 => None,
+        per_ms => Some(per_ms),
+    }
+}
+
+/// Measure the accuracy of the 1 ms PIT delay loop (see `pit_delay()`) against the RTC.
+/// Delays of 1 ms are executed for `CALIBRATION_SECONDS` RTC seconds and the resulting
+/// correction factor is applied to all future delays (see `delay_loops()`).
+/// Takes up to `CALIBRATION_SECONDS` + 1 seconds.
+pub fn calibrate() -> Calibration {
+    DELAY_CORRECTION.store(fixed::ONE, Ordering::Relaxed);
+
+    let mut rtc = rtc::RTC.lock();
+
+    // Start measuring at the beginning of an RTC second
+    let start = rtc.read_seconds();
+    while rtc.read_seconds() == start {}
+
+    let mut last = rtc.read_seconds();
+    let mut seconds = 0;
+    let mut iterations = 0;
+    while seconds < CALIBRATION_SECONDS {
+        pit_delay(1);
+        iterations += 1;
+
+        let now = rtc.read_seconds();
+        if now != last {
+            seconds += 1;
+            last = now;
+        }
+    }
+
+    let measured_ms = seconds * 1000;
+    DELAY_CORRECTION.store(fixed::from_int(iterations) / measured_ms, Ordering::Relaxed);
+
+    let error_percent = (iterations as i64 - measured_ms as i64) * 100 / measured_ms as i64;
+    kprintln!("Delay calibrated: {} loops in {} ms, error {}%", iterations, measured_ms, error_percent);
+
+    Calibration { iterations, measured_ms, error_percent }
+}
+
+/// Return the number of 1 ms delay loops needed to wait `ms` milliseconds.
+pub fn delay_loops(ms: usize) -> usize {
+    fixed::round(fixed::mul(fixed::from_int(ms as u64), DELAY_CORRECTION.load(Ordering::Relaxed))) as usize
+}
+
+/// Set a one-shot alarm calling `callback` after `ms_from_now` milliseconds.
+/// The callback is not called directly by the timer interrupt, but as a soft interrupt
+/// (see `softirq`) and is never called before the time has elapsed.
+pub fn set_alarm(ms_from_now: u64, callback: fn()) -> Result<(), AlarmError> {
+    let ticks = ms_from_now.div_ceil(TIMER_INTERVAL_MS);
+
+    // The timer ISR also locks the alarm table, so interrupts must be disabled.
+    cpu::without_interrupts(|| {
+        // The tick counter may increase right after reading it, so the
+        // alarm expires one tick after the requested number of ticks.
+        let expiry = get_ticks() + ticks + 1;
+        ALARMS.lock().insert(Alarm { expiry, callback })
+    })
+}
+
+/// Advance the timer wheel and raise a soft interrupt for each expired alarm.
+fn check_alarms(now: u64) {
+    ALARMS.lock().advance(now, |alarm| {
+        log::record(EventKind::Alarm, alarm.callback as usize as u64);
+        softirq::raise(alarm.callback);
+    });
+}
+
+impl TimerWheel {
+    /// Create an empty wheel advancing by one slot every `granularity` ticks (at least 1).
+    const fn new(granularity: u64) -> TimerWheel {
+        TimerWheel {
+            slots: [const { ArrayVec::new() }; WHEEL_SLOTS],
+            granularity: if granularity == 0 { 1 } else { granularity },
+        }
+    }
+
+    /// Store `alarm` in the slot examined on its expiry tick (rounded up to the granularity).
+    fn insert(&mut self, alarm: Alarm) -> Result<(), AlarmError> {
+        let slot = self.slot(alarm.expiry);
+        self.slots[slot].push(alarm).map_err(|_| AlarmError::TableFull)
+    }
+
+    /// Return the slot of the wheel examined on tick `expiry` (rounded up to the granularity).
+    fn slot(&self, expiry: u64) -> usize {
+        (expiry.div_ceil(self.granularity) % WHEEL_SLOTS as u64) as usize
+    }
+
+    /// Advance the wheel to tick `now`: call `fire` for each alarm of the current slot whose
+    /// expiry tick has been reached and remove it from the slot. Must be called every tick;
+    /// only every `granularity`-th tick examines a slot.
+    fn advance(&mut self, now: u64, mut fire: impl FnMut(Alarm)) {
+        if !now.is_multiple_of(self.granularity) {
+            return;
+        }
+
+        let slot = self.slot(now);
+        self.slots[slot].retain(|alarm| {
+            if now >= alarm.expiry {
+                fire(*alarm);
+                return false;
+            }
+            true
+        });
+    }
+}
+
+/// The timer interrupt service routine.
+pub struct TimerISR {}
+
+impl ISR for TimerISR {
+    fn trigger(&self) {
+        let now = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+        check_alarms(now);
+        keyboard::repeat_tick(now * TIMER_INTERVAL_MS);
+    }
+}
+
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Self tests, run by the diagnostics (see `user::diag`).                  ║
+   ╚═════════════════════════════════════════════════════════════════════════╝ */
+
+pub mod selftest {
+    use core::sync::atomic::{AtomicU64, Ordering};
+    use crate::devices::rtc;
+    use crate::kernel::cpu;
+    use crate::kernel::timer::{self, Alarm, TimerWheel, WHEEL_SLOTS};
+    use crate::library::arrayvec::ArrayVec;
+    use crate::library::selftest::{restore_on_exit, Test, TestResult};
+
+    /// The self tests of this module, run in this order.
+    pub const TESTS: &[Test] = &[
+        ("Timer wheel", test_timer_wheel),
+        ("Delay sources", test_delay_sources),
+        ("Alarm deadline", test_alarm_deadline),
+        ("Delay calibration", test_calibration),
+    ];
+
+    /// Set alarms at various deltas, up to several revolutions ahead, on private wheels with
+    /// a granularity of 1 and 4 ticks, advance them tick by tick and check that each alarm
+    /// fires exactly once, on its expiry tick rounded up to the granularity.
+    fn test_timer_wheel() -> TestResult {
+        const START: u64 = 1001;
+        const DELTAS: [u64; 9] = [1, 3, 5, 63, 64, 65, 200, 257, 700];
+
+        for granularity in [1, 4] {
+            let revolution = WHEEL_SLOTS as u64 * granularity;
+            let mut wheel = TimerWheel::new(granularity);
+            for delta in DELTAS {
+                wheel.insert(Alarm { expiry: START + delta, callback: || {} }).map_err(|_| "slot full")?;
+            }
+
+            let mut fired: ArrayVec<(u64, u64), 9> = ArrayVec::new();
+            for now in START + 1..=START + DELTAS[DELTAS.len() - 1] + revolution {
+                wheel.advance(now, |alarm| { fired.push((alarm.expiry, now)).ok(); });
+            }
+
+            if fired.as_slice().len() != DELTAS.len() {
+                return Err("alarm lost or fired twice");
+            }
+            for (delta, &(expiry, now)) in DELTAS.iter().zip(fired.as_slice()) {
+                if expiry != START + delta || now != expiry.div_ceil(granularity) * granularity {
+                    return Err("alarm fired on the wrong tick");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Duration of the delays compared by the delay source test and the allowed deviation
+    /// from it (milliseconds).
+    const DELAY_TEST_MS: usize = 100;
+    const DELAY_TOLERANCE_MS: u64 = 20;
+
+    /// Measure `timer::delay_ms()` with the PIT and the TSC as source against the uptime
+    /// and check that both delays last the requested time within the tolerance.
+    fn test_delay_sources() -> TestResult {
+        if timer::tsc_per_ms().is_none() && timer::calibrate_tsc().is_none() {
+            return Err("TSC calibration failed");
+        }
+
+        let saved = timer::delay_source();
+        let _restore = restore_on_exit(|| timer::set_delay_source(saved));
+        let mut measured = [0; 2];
+        for (i, source) in [timer::DelaySource::Pit, timer::DelaySource::Tsc].into_iter().enumerate() {
+            timer::set_delay_source(source);
+            let start = timer::uptime_ms();
+            timer::delay_ms(DELAY_TEST_MS);
+            measured[i] = timer::uptime_ms() - start;
+        }
+
+        if measured.iter().any(|&ms| ms.abs_diff(DELAY_TEST_MS as u64) > DELAY_TOLERANCE_MS) {
+            return Err("delay out of tolerance");
+        }
+        if measured[0].abs_diff(measured[1]) > DELAY_TOLERANCE_MS {
+            return Err("delays differ");
+        }
+        Ok(())
+    }
+
+    /// Tick on which the alarm of the alarm deadline test fired, 0 if it has not fired yet.
+    static ALARM_TICK: AtomicU64 = AtomicU64::new(0);
+
+    /// Set a real alarm a few milliseconds ahead, wait for it and check
+    /// that it fires, but not before its deadline.
+    fn test_alarm_deadline() -> TestResult {
+        const ALARM_MS: u64 = 5;
+        const TIMEOUT_MS: u64 = 100;
+
+        ALARM_TICK.store(0, Ordering::Relaxed);
+        let start = timer::get_ticks();
+        timer::set_alarm(ALARM_MS, || ALARM_TICK.store(timer::get_ticks(), Ordering::Relaxed))
+            .map_err(|_| "alarm table full")?;
+
+        let deadline = start + ALARM_MS / timer::TIMER_INTERVAL_MS;
+        while ALARM_TICK.load(Ordering::Relaxed) == 0 && timer::get_ticks() < deadline + TIMEOUT_MS / timer::TIMER_INTERVAL_MS {
+            cpu::wait_for_int();
+        }
+
+        match ALARM_TICK.load(Ordering::Relaxed) {
+            0 => Err("alarm not fired"),
+            tick if tick < deadline => Err("alarm fired before its deadline"),
+            _ => Ok(()),
+        }
+    }
+
+    /// Calibrate the PIT delay, then count corrected delays during one RTC second and check
+    /// that they add up to one second within a few percent. Takes about 4 seconds.
+    fn test_calibration() -> TestResult {
+        const DELAY_MS: usize = 10;
+        const TOLERANCE_PERCENT: u64 = 3;
+
+        timer::calibrate();
+
+        let mut rtc = rtc::RTC.lock();
+        let start = rtc.read_seconds();
+        while rtc.read_seconds() == start {}
+        let second = rtc.read_seconds();
+        let mut delays = 0;
+        while rtc.read_seconds() == second {
+            timer::pit_delay(DELAY_MS);
+            delays += 1;
+        }
+
+        let measured_ms = delays * DELAY_MS as u64;
+        if measured_ms.abs_diff(1000) > 1000 * TOLERANCE_PERCENT / 100 {
+            return Err("delay error too large after calibration");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,245 @@
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Module: arrayvec                                                        ║
+   ╟─────────────────────────────────────────────────────────────────────────╢
+   ║ Descr.: A vector with a fixed capacity stored inline (without the       ║
+   ║         heap). Usable in statics and before the allocator is            ║
+   ║         initialized, e.g. for tables accessed by interrupt handlers.    ║
+   ╚═════════════════════════════════════════════════════════════════════════╝
+*/
+use core::mem::MaybeUninit;
+use core::ops::{Deref, DerefMut};
+use core::{ptr, slice};
+
+/// A vector holding up to `N` elements of type `T`.
+/// Indexing and iteration are provided by dereferencing to a slice.
+pub struct ArrayVec<T, const N: usize> {
+    items: [MaybeUninit<T>; N],
+    len: usize,
+}
+
+impl<T, const N: usize> ArrayVec<T, N> {
+    /// Create a new empty vector.
+    pub const fn new() -> Self {
+        ArrayVec { items: [const { MaybeUninit::uninit() }; N], len: 0 }
+    }
+
+    /// Return the number of elements.
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Check if the vector is empty.
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Check if the vector holds `N` elements.
+    pub const fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    /// Return the maximum number of elements.
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Append `item`. If the vector is full, `item` is returned as error.
+    pub fn push(&mut self, item: T) -> Result<(), T> {
+        if self.is_full() {
+            return Err(item);
+        }
+        self.items[self.len].write(item);
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Remove and return the last element.
+    pub fn pop(&mut self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+        self.len -= 1;
+        // Safe, because the element was initialized and is no longer part of the vector.
+        Some(unsafe { self.items[self.len].assume_init_read() })
+    }
+
+    /// Remove and return the element at `index`, shifting all elements after it to the left.
+    /// Panics if `index` is out of bounds.
+    pub fn remove(&mut self, index: usize) -> T {
+        assert!(index < self.len, "ArrayVec::remove: index out of bounds");
+        unsafe {
+            let item = self.items[index].assume_init_read();
+            let base = self.items.as_mut_ptr();
+            ptr::copy(base.add(index + 1), base.add(index), self.len - index - 1);
+            self.len -= 1;
+            item
+        }
+    }
+
+    /// Remove and return the element at `index`, replacing it by the last element.
+    /// Faster than `remove()`, but does not keep the order. Panics if `index` is out of bounds.
+    pub fn swap_remove(&mut self, index: usize) -> T {
+        assert!(index < self.len, "ArrayVec::swap_remove: index out of bounds");
+        let last = self.len - 1;
+        self.swap(index, last);
+        self.pop().unwrap()
+    }
+
+    /// Keep only the elements for which `keep` returns true (in their order).
+    pub fn retain<F: FnMut(&T) -> bool>(&mut self, mut keep: F) {
+        let mut i = 0;
+        while i < self.len {
+            if keep(&self[i]) {
+                i += 1;
+            } else {
+                self.remove(i);
+            }
+        }
+    }
+
+    /// Remove all elements.
+    pub fn clear(&mut self) {
+        while self.pop().is_some() {}
+    }
+
+    /// Return the elements as slice.
+    pub fn as_slice(&self) -> &[T] {
+        // Safe, because the first `len` elements are initialized.
+        unsafe { slice::from_raw_parts(self.items.as_ptr() as *const T, self.len) }
+    }
+
+    /// Return the elements as mutable slice.
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        // Safe, because the first `len` elements are initialized.
+        unsafe { slice::from_raw_parts_mut(self.items.as_mut_ptr() as *mut T, self.len) }
+    }
+}
+
+impl<T, const N: usize> Deref for ArrayVec<T, N> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        self.as_slice()
+    }
+}
+
+impl<T, const N: usize> DerefMut for ArrayVec<T, N> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        self.as_mut_slice()
+    }
+}
+
+impl<'a, T, const N: usize> IntoIterator for &'a ArrayVec<T, N> {
+    type Item = &'a T;
+    type IntoIter = slice::Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<'a, T, const N: usize> IntoIterator for &'a mut ArrayVec<T, N> {
+    type Item = &'a mut T;
+    type IntoIter = slice::IterMut<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter_mut()
+    }
+}
+
+impl<T, const N: usize> Drop for ArrayVec<T, N> {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Self tests, run by the diagnostics (see `user::diag`).                  ║
+   ╚═════════════════════════════════════════════════════════════════════════╝ */
+
+pub mod selftest {
+    use crate::library::arrayvec::ArrayVec;
+    use crate::library::selftest::{Test, TestResult};
+
+    /// The self tests of this module, run in this order.
+    pub const TESTS: &[Test] = &[
+        ("Push to full", test_push_full),
+        ("Pop", test_pop),
+        ("Iteration order", test_iteration),
+    ];
+
+    /// Capacity of the vectors of the tests.
+    const N: usize = 4;
+
+    /// Fill a vector up to its capacity and check that the next push is refused,
+    /// returning the item and leaving the vector unchanged.
+    fn test_push_full() -> TestResult {
+        let mut vec: ArrayVec<usize, N> = ArrayVec::new();
+        for i in 0..N {
+            vec.push(i).map_err(|_| "push refused before full")?;
+        }
+
+        if !vec.is_full() || vec.len() != N {
+            return Err("not full after N pushes");
+        }
+        if vec.push(N) != Err(N) {
+            return Err("push accepted when full");
+        }
+        if vec.len() != N || vec[N - 1] != N - 1 {
+            return Err("modified by refused push");
+        }
+        Ok(())
+    }
+
+    /// Pop all elements of a vector and check that they come in reverse order
+    /// of pushing, then check that popping an empty vector returns `None`.
+    fn test_pop() -> TestResult {
+        let mut vec: ArrayVec<usize, N> = ArrayVec::new();
+        if vec.pop().is_some() {
+            return Err("popped from new vector");
+        }
+        for i in 0..N {
+            vec.push(i).ok();
+        }
+
+        for i in (0..N).rev() {
+            if vec.pop() != Some(i) {
+                return Err("wrong element popped");
+            }
+        }
+        if vec.pop().is_some() || !vec.is_empty() {
+            return Err("popped from empty vector");
+        }
+        vec.push(N).ok();
+        if vec.as_slice() != [N] {
+            return Err("push after pop failed");
+        }
+        Ok(())
+    }
+
+    /// Push, pop and remove elements and check that iteration and indexing
+    /// visit the remaining elements in insertion order.
+    fn test_iteration() -> TestResult {
+        let mut vec: ArrayVec<usize, N> = ArrayVec::new();
+        for i in [10, 20, 30, 40] {
+            vec.push(i).ok();
+        }
+        vec.pop();
+        vec.remove(0);
+        vec.push(50).ok();
+
+        if !vec.iter().eq([20, 30, 50].iter()) {
+            return Err("wrong iteration order");
+        }
+        if (&vec).into_iter().copied().ne((0..vec.len()).map(|i| vec[i])) {
+            return Err("iteration differs from indexing");
+        }
+        for item in &mut vec {
+            *item += 1;
+        }
+        if vec.as_slice() != [21, 31, 51] {
+            return Err("mutable iteration order");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,145 @@
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Module: binheap                                                         ║
+   ╟─────────────────────────────────────────────────────────────────────────╢
+   ║ Descr.: A priority queue with a fixed capacity stored inline (without   ║
+   ║         the heap), implemented as binary min-heap in an array. Usable   ║
+   ║         for ordering deadlines, e.g. for earliest-deadline scheduling   ║
+   ║         and alarms.                                                     ║
+   ╚═════════════════════════════════════════════════════════════════════════╝
+*/
+use crate::library::arrayvec::ArrayVec;
+
+/// A min-heap holding up to `N` elements of type `T`. The smallest element is at index 0
+/// and the children of the element at index `i` are at `2 * i + 1` and `2 * i + 2`.
+/// Elements comparing equal are returned in no particular order.
+pub struct BinaryHeap<T: Ord, const N: usize> {
+    items: ArrayVec<T, N>,
+}
+
+impl<T: Ord, const N: usize> BinaryHeap<T, N> {
+    /// Create a new empty heap.
+    pub const fn new() -> Self {
+        BinaryHeap { items: ArrayVec::new() }
+    }
+
+    /// Return the number of elements.
+    pub const fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    /// Check if the heap is empty.
+    pub const fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Check if the heap holds `N` elements.
+    pub const fn is_full(&self) -> bool {
+        self.items.is_full()
+    }
+
+    /// Insert `item` in O(log n). If the heap is full, `item` is returned as error.
+    pub fn push(&mut self, item: T) -> Result<(), T> {
+        self.items.push(item)?;
+        self.sift_up(self.items.len() - 1);
+        Ok(())
+    }
+
+    /// Return the smallest element without removing it.
+    pub fn peek(&self) -> Option<&T> {
+        self.items.first()
+    }
+
+    /// Remove and return the smallest element in O(log n).
+    pub fn pop_min(&mut self) -> Option<T> {
+        if self.items.is_empty() {
+            return None;
+        }
+        let min = self.items.swap_remove(0);
+        self.sift_down(0);
+        Some(min)
+    }
+
+    /// Remove all elements.
+    pub fn clear(&mut self) {
+        self.items.clear();
+    }
+
+    /// Move the element at `index` up until its parent is not greater.
+    fn sift_up(&mut self, mut index: usize) {
+        while index > 0 {
+            let parent = (index - 1) / 2;
+            if self.items[parent] <= self.items[index] {
+                break;
+            }
+            self.items.swap(parent, index);
+            index = parent;
+        }
+    }
+
+    /// Move the element at `index` down until none of its children is smaller.
+    fn sift_down(&mut self, mut index: usize) {
+        let len = self.items.len();
+        loop {
+            let left = 2 * index + 1;
+            let right = left + 1;
+            let mut smallest = index;
+            if left < len && self.items[left] < self.items[smallest] {
+                smallest = left;
+            }
+            if right < len && self.items[right] < self.items[smallest] {
+                smallest = right;
+            }
+            if smallest == index {
+                break;
+            }
+            self.items.swap(index, smallest);
+            index = smallest;
+        }
+    }
+}
+
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Self tests, run by the diagnostics (see `user::diag`).                  ║
+   ╚═════════════════════════════════════════════════════════════════════════╝ */
+
+pub mod selftest {
+    use crate::library::binheap::BinaryHeap;
+    use crate::library::selftest::{Test, TestResult};
+
+    /// The self tests of this module, run in this order.
+    pub const TESTS: &[Test] = &[
+        ("Order", test_binheap),
+    ];
+
+    /// Push unordered deadlines into a binary heap until it is full, then check that they
+    /// are popped in ascending order and that an empty heap returns nothing.
+    fn test_binheap() -> TestResult {
+        const DEADLINES: [u64; 8] = [42, 7, 19, 7, 100, 3, 56, 21];
+
+        let mut heap: BinaryHeap<u64, 8> = BinaryHeap::new();
+        if heap.peek().is_some() || heap.pop_min().is_some() {
+            return Err("empty heap returned an element");
+        }
+        for deadline in DEADLINES {
+            heap.push(deadline).map_err(|_| "push failed before the heap was full")?;
+        }
+        if !heap.is_full() || heap.push(1) != Err(1) {
+            return Err("push accepted beyond the capacity");
+        }
+        if heap.peek() != Some(&3) {
+            return Err("peek did not return the minimum");
+        }
+
+        let mut sorted = DEADLINES;
+        sorted.sort_unstable();
+        for expected in sorted {
+            if heap.pop_min() != Some(expected) {
+                return Err("elements not popped in ascending order");
+            }
+        }
+        if !heap.is_empty() || heap.pop_min().is_some() {
+            return Err("heap not empty after popping all elements");
+        }
+        Ok(())
+    }
+}
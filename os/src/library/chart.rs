@@ -0,0 +1,26 @@
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Module: chart                                                           ║
+   ╟─────────────────────────────────────────────────────────────────────────╢
+   ║ Descr.: Simple text mode charts for statistics output.                  ║
+   ╚═════════════════════════════════════════════════════════════════════════╝
+*/
+
+/// Character used for the bars.
+const BAR_CHAR: char = '#';
+
+/// Print a horizontal bar for `value`, scaled so that `max` fills `width` characters,
+/// followed by the value and a newline. Non-zero values get a bar of at least one character.
+pub fn print_bar(value: usize, max: usize, width: usize) {
+    let mut len = (value.min(max) * width).checked_div(max).unwrap_or(0);
+    if value > 0 && len == 0 {
+        len = 1;
+    }
+
+    for _ in 0..len {
+        print!("{}", BAR_CHAR);
+    }
+    for _ in len..width {
+        print!(" ");
+    }
+    println!(" {}", value);
+}
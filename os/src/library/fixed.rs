@@ -0,0 +1,48 @@
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Module: fixed                                                           ║
+   ╟─────────────────────────────────────────────────────────────────────────╢
+   ║ Descr.: Fixed-point arithmetic for computations that would normally     ║
+   ║         need floating point, which our OS does not support. Values are  ║
+   ║         stored as u64 in Q16.16 format (16 integer bits and 16 fraction ║
+   ║         bits).                                                          ║
+   ╚═════════════════════════════════════════════════════════════════════════╝
+*/
+
+/// Number of fraction bits of a fixed-point value.
+pub const FRAC_BITS: u32 = 16;
+
+/// The value 1.0 as fixed-point value.
+pub const ONE: u64 = 1 << FRAC_BITS;
+
+/// 2^(k/12) for k = 0..11, rounded to the nearest fixed-point value.
+/// The relative error of each entry is below 2^-17 (less than 0.001%).
+const POW2_TWELFTH_TAB: [u64; 12] = [
+    65536, 69433, 73562, 77936, 82570, 87480,
+    92682, 98193, 104032, 110218, 116772, 123715,
+];
+
+/// Convert the integer `value` to a fixed-point value.
+pub const fn from_int(value: u64) -> u64 {
+    value << FRAC_BITS
+}
+
+/// Multiply the fixed-point values `a` and `b`.
+pub const fn mul(a: u64, b: u64) -> u64 {
+    (a * b) >> FRAC_BITS
+}
+
+/// Round the fixed-point value `value` to the nearest integer.
+pub const fn round(value: u64) -> u64 {
+    (value + ONE / 2) >> FRAC_BITS
+}
+
+/// Return the ratio 2^(semitones/12) as fixed-point value.
+/// This is the frequency ratio between two notes `semitones` apart.
+///
+/// The fraction of an octave is looked up in a table and whole octaves are
+/// applied as shifts, so the result has the precision of the table
+/// (relative error below 2^-17). `semitones` must be below 12 * 47,
+/// otherwise the result does not fit into 64 bits.
+pub const fn pow2_twelfth(semitones: usize) -> u64 {
+    POW2_TWELFTH_TAB[semitones % 12] << (semitones / 12)
+}
@@ -1,7 +1,11 @@
+use crate::devices::console;
 use crate::devices::keyboard;
 
 /// Wait for a key press and return the character if it is a valid ASCII character.
 pub fn getch() -> char {
+   // Show a prompt that is still in the line buffer
+   console::flush();
+
    loop {
       let mut key = keyboard::get_key_buffer().wait_for_key();
       if key.valid() && key.get_ascii() != 0 {
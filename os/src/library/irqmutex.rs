@@ -0,0 +1,132 @@
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Module: irqmutex                                                        ║
+   ╟─────────────────────────────────────────────────────────────────────────╢
+   ║ Descr.: A spin lock which disables interrupts while it is held, so an   ║
+   ║         interrupt handler can never spin on a lock held by the code it  ║
+   ║         has interrupted (e.g. CGA or KEYBOARD). Debug builds check that ║
+   ║         interrupts stay disabled inside the critical section.           ║
+   ╚═════════════════════════════════════════════════════════════════════════╝
+*/
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use spin::{Mutex, MutexGuard};
+use crate::kernel::cpu;
+
+/// A `spin::Mutex` for data shared with interrupt handlers. Locking disables interrupts
+/// (like `cpu::without_interrupts()`) and unlocking restores the previous interrupt state,
+/// so nested locks and locks taken with interrupts already disabled work as expected.
+pub struct IrqSafeMutex<T> {
+    inner: Mutex<T>,
+}
+
+/// Guard of a locked `IrqSafeMutex`. The lock is released before interrupts are enabled again.
+pub struct IrqSafeMutexGuard<'a, T> {
+    guard: ManuallyDrop<MutexGuard<'a, T>>,
+    /// Interrupts were enabled before locking.
+    was_enabled: bool,
+}
+
+impl<T> IrqSafeMutex<T> {
+    /// Create a new unlocked mutex holding `data`.
+    pub const fn new(data: T) -> Self {
+        IrqSafeMutex { inner: Mutex::new(data) }
+    }
+
+    /// Disable interrupts and lock the mutex, spinning until it is available.
+    pub fn lock(&self) -> IrqSafeMutexGuard<'_, T> {
+        let was_enabled = cpu::disable_int_nested();
+        IrqSafeMutexGuard { guard: ManuallyDrop::new(self.inner.lock()), was_enabled }
+    }
+
+    /// Try to lock the mutex without spinning. If it is locked already,
+    /// the interrupt state is restored and `None` is returned.
+    pub fn try_lock(&self) -> Option<IrqSafeMutexGuard<'_, T>> {
+        let was_enabled = cpu::disable_int_nested();
+        match self.inner.try_lock() {
+            Some(guard) => Some(IrqSafeMutexGuard { guard: ManuallyDrop::new(guard), was_enabled }),
+            None => {
+                cpu::enable_int_nested(was_enabled);
+                None
+            }
+        }
+    }
+
+    /// Check if the mutex is locked (only a hint, it may change right after).
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+}
+
+impl<T> Deref for IrqSafeMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for IrqSafeMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for IrqSafeMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // Enabling interrupts inside the critical section (e.g. with `cpu::enable_int()`)
+        // would let an interrupt handler deadlock on this lock.
+        debug_assert!(!cpu::is_int_enabled(), "interrupts enabled while holding an IrqSafeMutex");
+
+        // The lock must be released before interrupts are enabled again.
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        cpu::enable_int_nested(self.was_enabled);
+    }
+}
+
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Self tests, run by the diagnostics (see `user::diag`).                  ║
+   ╚═════════════════════════════════════════════════════════════════════════╝ */
+
+pub mod selftest {
+    use crate::kernel::cpu;
+    use crate::library::IrqSafeMutex;
+    use crate::library::selftest::{Test, TestResult};
+
+    /// The self tests of this module, run in this order.
+    pub const TESTS: &[Test] = &[
+        ("Interrupt state", test_irq_safe_mutex),
+    ];
+
+    /// Lock an `IrqSafeMutex` with interrupts enabled and disabled and check
+    /// that the guard disables interrupts and restores the previous state.
+    fn test_irq_safe_mutex() -> TestResult {
+        let mutex = IrqSafeMutex::new(0);
+        let was_enabled = cpu::is_int_enabled();
+
+        cpu::enable_int();
+        let guard = mutex.lock();
+        let disabled = !cpu::is_int_enabled();
+        let contended = mutex.try_lock().is_none();
+        drop(guard);
+        let restored = cpu::is_int_enabled();
+
+        cpu::disable_int();
+        drop(mutex.lock());
+        let kept_disabled = !cpu::is_int_enabled();
+        cpu::enable_int_nested(was_enabled);
+
+        if !disabled {
+            return Err("interrupts enabled while locked");
+        }
+        if !contended {
+            return Err("locked twice");
+        }
+        if !restored {
+            return Err("interrupts not enabled again");
+        }
+        if !kept_disabled {
+            return Err("interrupts enabled by unlocking");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,150 @@
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Module: lazy                                                            ║
+   ╟─────────────────────────────────────────────────────────────────────────╢
+   ║ Descr.: A global value initialized on first access by a given function. ║
+   ║         Used for globals which cannot be created by a 'const fn', e.g.  ║
+   ║         'static DEV: Lazy<Mutex<Dev>> = Lazy::new(||                    ║
+   ║         Mutex::new(Dev::new()))'.                                       ║
+   ╚═════════════════════════════════════════════════════════════════════════╝
+*/
+use core::cell::UnsafeCell;
+use core::hint;
+use core::mem::MaybeUninit;
+use core::ops::Deref;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+// States of a Lazy value
+const UNINIT: u8 = 0;
+const RUNNING: u8 = 1;
+const READY: u8 = 2;
+
+/// A value of type `T` created by `init` on first access.
+/// If several cores or threads race for the first access, `init` runs exactly once
+/// and the others spin until the value is ready.
+pub struct Lazy<T> {
+    state: AtomicU8,
+    init: fn() -> T,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+// The value is only written once (guarded by `state`) and shared immutably afterwards.
+unsafe impl<T: Send + Sync> Sync for Lazy<T> {}
+
+impl<T> Lazy<T> {
+    /// Create a new uninitialized value which is created by `init` on first access.
+    pub const fn new(init: fn() -> T) -> Lazy<T> {
+        Lazy { state: AtomicU8::new(UNINIT), init, value: UnsafeCell::new(MaybeUninit::uninit()) }
+    }
+
+    /// Return a reference to the value, initializing it if necessary.
+    pub fn get(&self) -> &T {
+        if self.state.load(Ordering::Acquire) != READY {
+            self.initialize();
+        }
+
+        // Safe, because the state is READY, so the value has been written.
+        unsafe { (*self.value.get()).assume_init_ref() }
+    }
+
+    /// Check if the value has been initialized.
+    pub fn is_initialized(&self) -> bool {
+        self.state.load(Ordering::Acquire) == READY
+    }
+
+    /// Run `init` if nobody else has, otherwise wait until the value is ready.
+    fn initialize(&self) {
+        match self.state.compare_exchange(UNINIT, RUNNING, Ordering::Acquire, Ordering::Acquire) {
+            Ok(_) => {
+                let value = (self.init)();
+                // Safe, because only the winner of the compare_exchange gets here.
+                unsafe { (*self.value.get()).write(value); }
+                self.state.store(READY, Ordering::Release);
+            }
+            Err(_) => {
+                while self.state.load(Ordering::Acquire) != READY {
+                    hint::spin_loop();
+                }
+            }
+        }
+    }
+}
+
+impl<T> Deref for Lazy<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.get()
+    }
+}
+
+impl<T> Drop for Lazy<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == READY {
+            unsafe { self.value.get_mut().assume_init_drop(); }
+        }
+    }
+}
+
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Self tests, run by the diagnostics (see `user::diag`).                  ║
+   ╚═════════════════════════════════════════════════════════════════════════╝ */
+
+pub mod selftest {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use crate::kernel::{cpu, timer};
+    use crate::library::lazy::Lazy;
+    use crate::library::selftest::{Test, TestResult};
+
+    /// The self tests of this module, run in this order.
+    pub const TESTS: &[Test] = &[
+        ("Init once", test_init_once),
+    ];
+
+    /// Value created by the init function of `LAZY`.
+    const VALUE: usize = 42;
+
+    /// Number of calls of the init function of `LAZY`.
+    static INIT_RUNS: AtomicUsize = AtomicUsize::new(0);
+
+    /// Number of accesses to `LAZY` by alarms which returned `VALUE`.
+    static READS: AtomicUsize = AtomicUsize::new(0);
+
+    /// Lazy value accessed by the init once test, counting the runs of its init function.
+    static LAZY: Lazy<usize> = Lazy::new(|| {
+        INIT_RUNS.fetch_add(1, Ordering::Relaxed);
+        VALUE
+    });
+
+    /// Access `LAZY` from an alarm (see `timer::set_alarm()`).
+    fn read_lazy() {
+        if *LAZY == VALUE {
+            READS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Let several alarms expiring on the same tick race for the first access to a lazy value,
+    /// access it again afterwards and check that the init function has run exactly once.
+    /// The value is never reset, so the check holds when the diagnostics are run again.
+    fn test_init_once() -> TestResult {
+        const ALARMS: usize = 4;
+        const ALARM_MS: u64 = 2;
+        const TIMEOUT_MS: u64 = 100;
+
+        let reads = READS.load(Ordering::Relaxed);
+        for _ in 0..ALARMS {
+            timer::set_alarm(ALARM_MS, read_lazy).map_err(|_| "alarm table full")?;
+        }
+        let timeout = timer::uptime_ms() + TIMEOUT_MS;
+        while READS.load(Ordering::Relaxed) < reads + ALARMS && timer::uptime_ms() < timeout {
+            cpu::wait_for_int();
+        }
+
+        if READS.load(Ordering::Relaxed) != reads + ALARMS || *LAZY != VALUE {
+            return Err("wrong value read");
+        }
+        if !LAZY.is_initialized() || INIT_RUNS.load(Ordering::Relaxed) != 1 {
+            return Err("not initialized exactly once");
+        }
+        Ok(())
+    }
+}
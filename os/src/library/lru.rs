@@ -0,0 +1,133 @@
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Module: lru                                                             ║
+   ╟─────────────────────────────────────────────────────────────────────────╢
+   ║ Descr.: A cache of up to N key/value pairs with least-recently-used     ║
+   ║         eviction, stored inline (without the heap), e.g. for recently   ║
+   ║         read disk blocks.                                               ║
+   ╚═════════════════════════════════════════════════════════════════════════╝
+*/
+use crate::library::arrayvec::ArrayVec;
+
+/// A cache holding up to `N` entries. When it is full, inserting a new key evicts the entry
+/// used least recently. Entries are kept ordered by their last use, the most recent last,
+/// so lookups and updates take O(N), which is fine for the small `N` of a block cache.
+pub struct LruCache<K, V, const N: usize> {
+    entries: ArrayVec<(K, V), N>,
+    hits: usize,
+    misses: usize,
+}
+
+impl<K: PartialEq, V, const N: usize> LruCache<K, V, N> {
+    /// Create a new empty cache.
+    pub const fn new() -> Self {
+        LruCache { entries: ArrayVec::new(), hits: 0, misses: 0 }
+    }
+
+    /// Return the value of `key` and mark it as most recently used, or `None` if it is not cached.
+    /// Counts a hit or a miss.
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        match self.position(key) {
+            Some(index) => {
+                self.hits += 1;
+                let entry = self.entries.remove(index);
+                self.entries.push(entry).ok();
+                self.entries.last().map(|(_, value)| value)
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Return the value of `key` without marking it as used or counting a hit or miss.
+    pub fn peek(&self, key: &K) -> Option<&V> {
+        self.position(key).map(|index| &self.entries[index].1)
+    }
+
+    /// Insert `value` for `key` as most recently used entry, replacing a cached value of `key`.
+    /// If the cache is full, the least recently used entry is evicted and returned.
+    pub fn put(&mut self, key: K, value: V) -> Option<(K, V)> {
+        let mut evicted = None;
+        if let Some(index) = self.position(&key) {
+            self.entries.remove(index);
+        } else if self.entries.is_full() {
+            evicted = Some(self.entries.remove(0));
+        }
+        // There is room, because an entry has been removed if the cache was full.
+        self.entries.push((key, value)).ok();
+        evicted
+    }
+
+    /// Remove `key` from the cache and return its value.
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        self.position(key).map(|index| self.entries.remove(index).1)
+    }
+
+    /// Remove all entries. The hit and miss counters are kept.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Return the number of cached entries.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Check if the cache is empty.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Return the number of `get()` calls which found the key.
+    pub fn hits(&self) -> usize {
+        self.hits
+    }
+
+    /// Return the number of `get()` calls which did not find the key.
+    pub fn misses(&self) -> usize {
+        self.misses
+    }
+
+    /// Return the index of `key` in the entries.
+    fn position(&self, key: &K) -> Option<usize> {
+        self.entries.iter().position(|(k, _)| k == key)
+    }
+}
+
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Self tests, run by the diagnostics (see `user::diag`).                  ║
+   ╚═════════════════════════════════════════════════════════════════════════╝ */
+
+pub mod selftest {
+    use crate::library::lru::LruCache;
+    use crate::library::selftest::{Test, TestResult};
+
+    /// The self tests of this module, run in this order.
+    pub const TESTS: &[Test] = &[
+        ("Eviction", test_lru),
+    ];
+
+    /// Fill an LRU cache beyond its capacity and check eviction order and hit/miss counters.
+    fn test_lru() -> TestResult {
+        let mut cache: LruCache<u32, u32, 3> = LruCache::new();
+        cache.put(1, 10);
+        cache.put(2, 20);
+        cache.put(3, 30);
+
+        // 1 becomes the most recently used entry, so 2 is evicted first
+        if cache.get(&1) != Some(&10) {
+            return Err("cached value not found");
+        }
+        if cache.put(4, 40) != Some((2, 20)) || cache.put(5, 50) != Some((3, 30)) {
+            return Err("wrong entry evicted");
+        }
+        if cache.get(&2).is_some() || cache.get(&5) != Some(&50) {
+            return Err("evicted entry still cached");
+        }
+        if cache.hits() != 2 || cache.misses() != 1 {
+            return Err("hits and misses not counted");
+        }
+        Ok(())
+    }
+}
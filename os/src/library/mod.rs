@@ -1,2 +1,14 @@
+pub mod arrayvec;
+pub mod binheap;
+pub mod chart;
+pub mod fixed;
 pub mod input;
+pub mod irqmutex;
+pub mod lazy;
+pub mod lru;
 pub mod queue;
+pub mod rng;
+pub mod selftest;
+pub mod slice;
+
+pub use irqmutex::IrqSafeMutex;
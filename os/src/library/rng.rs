@@ -0,0 +1,40 @@
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Module: rng                                                             ║
+   ╟─────────────────────────────────────────────────────────────────────────╢
+   ║ Descr.: A small pseudo random number generator (xorshift64). The        ║
+   ║         sequence only depends on the seed, so runs using it (e.g. the   ║
+   ║         heap stress test) can be replayed exactly. Not suitable for     ║
+   ║         cryptography.                                                   ║
+   ╚═════════════════════════════════════════════════════════════════════════╝
+*/
+
+/// Seed used instead of 0, which would make xorshift return 0 forever.
+const ZERO_SEED_REPLACEMENT: u64 = 0x9e37_79b9_7f4a_7c15;
+
+/// A xorshift64 generator. Equal seeds yield equal sequences.
+#[derive(Copy, Clone, Debug)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    /// Create a generator with the given `seed` (any value, including 0).
+    pub const fn new(seed: u64) -> Rng {
+        Rng { state: if seed == 0 { ZERO_SEED_REPLACEMENT } else { seed } }
+    }
+
+    /// Return the next pseudo random number.
+    pub fn next_u64(&mut self) -> u64 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.state = x;
+        x
+    }
+
+    /// Return a pseudo random number in `0..bound` (`bound` must not be 0).
+    pub fn below(&mut self, bound: usize) -> usize {
+        (self.next_u64() % bound as u64) as usize
+    }
+}
@@ -0,0 +1,35 @@
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Module: selftest                                                        ║
+   ╟─────────────────────────────────────────────────────────────────────────╢
+   ║ Descr.: Support for the self tests of the kernel modules. Each module   ║
+   ║         with tests has a `selftest` submodule listing them in `TESTS`;  ║
+   ║         they are run by the diagnostics (see `user::diag`). The tests   ║
+   ║         run in the kernel itself, on the real hardware, so a test       ║
+   ║         changing global state must restore it on every path out of it   ║
+   ║         (see `Restore`).                                                ║
+   ╚═════════════════════════════════════════════════════════════════════════╝
+*/
+
+/// Result of a self test: an error message on failure.
+pub type TestResult = Result<(), &'static str>;
+
+/// A self test: its name and the function running it.
+pub type Test = (&'static str, fn() -> TestResult);
+
+/// Runs a closure when dropped, restoring global state changed by a test
+/// also when the test returns early with an error (e.g. with `?`).
+pub struct Restore<F: FnMut()> {
+    restore: F,
+}
+
+impl<F: FnMut()> Drop for Restore<F> {
+    fn drop(&mut self) {
+        (self.restore)();
+    }
+}
+
+/// Return a guard which calls `restore` when it goes out of scope.
+/// Usage: let _restore = restore_on_exit(|| allocator::set_trace(false));
+pub fn restore_on_exit<F: FnMut()>(restore: F) -> Restore<F> {
+    Restore { restore }
+}
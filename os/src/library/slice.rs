@@ -0,0 +1,66 @@
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Module: slice                                                           ║
+   ╟─────────────────────────────────────────────────────────────────────────╢
+   ║ Descr.: Bounds-safe access to byte buffers for parsers of binary        ║
+   ║         formats (e.g. ELF images). All functions return 'None' instead  ║
+   ║         of panicking if the requested bytes are out of range.           ║
+   ╚═════════════════════════════════════════════════════════════════════════╝
+*/
+use core::ops::Range;
+
+/// Return the bytes in `range` of `data`, or `None` if the range is out of bounds.
+pub fn get_bytes(data: &[u8], range: Range<usize>) -> Option<&[u8]> {
+    data.get(range)
+}
+
+/// Return the `N` bytes at `offset` in `data` as array.
+pub fn get_array<const N: usize>(data: &[u8], offset: usize) -> Option<[u8; N]> {
+    let end = offset.checked_add(N)?;
+    get_bytes(data, offset..end)?.try_into().ok()
+}
+
+/// Read a little endian u16 at `offset` in `data`.
+pub fn get_u16_le(data: &[u8], offset: usize) -> Option<u16> {
+    get_array(data, offset).map(u16::from_le_bytes)
+}
+
+/// Read a little endian u32 at `offset` in `data`.
+pub fn get_u32_le(data: &[u8], offset: usize) -> Option<u32> {
+    get_array(data, offset).map(u32::from_le_bytes)
+}
+
+/// Read a little endian u64 at `offset` in `data`.
+pub fn get_u64_le(data: &[u8], offset: usize) -> Option<u64> {
+    get_array(data, offset).map(u64::from_le_bytes)
+}
+
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Self tests, run by the diagnostics (see `user::diag`).                  ║
+   ╚═════════════════════════════════════════════════════════════════════════╝ */
+
+pub mod selftest {
+    use crate::library::selftest::{Test, TestResult};
+    use crate::library::slice;
+
+    /// The self tests of this module, run in this order.
+    pub const TESTS: &[Test] = &[
+        ("Little endian u32", test_u32_le),
+    ];
+
+    /// Read a little endian u32 at valid offsets, including the last possible one, and check
+    /// that offsets running past the end (or overflowing) return `None`.
+    fn test_u32_le() -> TestResult {
+        const DATA: [u8; 6] = [0xff, 0x78, 0x56, 0x34, 0x12, 0xab];
+
+        if slice::get_u32_le(&DATA, 1) != Some(0x1234_5678) || slice::get_u32_le(&DATA, 2) != Some(0xab12_3456) {
+            return Err("wrong value read");
+        }
+        if slice::get_u32_le(&DATA, 3).is_some() || slice::get_u32_le(&DATA, DATA.len()).is_some() {
+            return Err("read past the end");
+        }
+        if slice::get_u32_le(&DATA, usize::MAX - 1).is_some() {
+            return Err("offset overflow not detected");
+        }
+        Ok(())
+    }
+}
@@ -33,8 +33,16 @@ use core::panic::PanicInfo;
 use devices::cga; // shortcut for cga
 use devices::cga_print; // used to import code needed by println! 
 use devices::keyboard; // shortcut for keyboard
+use devices::mouse;
+use devices::cga::Color;
+use devices::pcspk;
+use devices::console;
 
+use kernel::boot;
 use kernel::cpu;
+use kernel::init::{Failure, StartupError};
+use kernel::timer;
+use kernel::stack_guard;
 
 use kernel::interrupts::idt;
 use kernel::interrupts::intdispatcher;
@@ -45,6 +53,10 @@ use user::aufgabe1::keyboard_demo;
 use kernel::allocator;
 use user::aufgabe2::heap_demo;
 use user::aufgabe2::sound_demo;
+use user::debug_demo;
+use user::watchpoint_demo;
+use user::menu;
+use user::splash;
 
 fn aufgabe1() {
     text_demo::run();
@@ -58,27 +70,59 @@ fn aufgabe2() {
     sound_demo::run();
 }
 
+/// An init step: the message printed on success and the function performing it.
+type InitStep = (&'static str, fn() -> Result<(), StartupError>);
+
+/// Init steps run by `startup()` in this order, with the message printed on success.
+const INIT_STEPS: [InitStep; 8] = [
+    ("Heap Allocator initialized.", allocator::init),
+    ("Programmable Interrupt Controller initialized.", || PIC.lock().init()),
+    ("Interrupt Descriptor Table loaded.", || { idt::get_idt().load(); Ok(()) }),
+    ("Interrupt Dispatcher INT_VECTORS initialized.", || intdispatcher::INT_VECTORS.lock().init()),
+    ("Keyboard plugged in.", keyboard::plugin),
+    ("Mouse plugged in.", mouse::plugin),
+    ("Timer plugged in.", timer::plugin),
+    ("Stack guard placed.", stack_guard::init),
+];
+
+/// Frequency and duration of the beep on a failed init step.
+const ERROR_BEEP_FREQ: usize = 880;
+const ERROR_BEEP_MS: usize = 300;
+
+/// Report a failed init step in red, beep and halt.
+fn startup_failed(err: StartupError) -> ! {
+    kprintln!("{}", Failure(err));
+    unsafe { cga_print::FG_COLOR = Color::LightRed; }
+    println!("{}", Failure(err));
+
+    pcspk::SPEAKER.lock().play(ERROR_BEEP_FREQ, ERROR_BEEP_MS);
+
+    console::flush_all();
+    cpu::disable_int();
+    loop {
+        cpu::halt();
+    }
+}
+
 #[unsafe(no_mangle)]
 pub extern "C" fn startup() {
-    allocator::init();
-    kprintln!("Heap Allocator initialized.");
-
-    PIC.lock().init();
-    kprintln!("Programmable Interrupt Controller initialized.");
-
-    idt::get_idt().load();
-    kprintln!("Interrupt Descriptor Table loaded.");
-
-    intdispatcher::INT_VECTORS.lock().init();
-    kprintln!("Interrupt Dispatcher INT_VECTORS initialized.");
-
+    // The CGA needs no initialization, so the init steps can be reported on the screen.
     cga::CGA.lock().clear();
     cga::CGA.lock().enable_cursor();
+    cga::banner("hhuTOS");
     kprintln!("CGA cleared and ready.");
 
-    keyboard::plugin();
-    kprintln!("Keyboard plugged in.");
-    
+    boot::begin();
+    for (message, step) in INIT_STEPS {
+        if let Err(err) = step() {
+            startup_failed(err);
+        }
+        unsafe { cga_print::FG_COLOR = Color::LightGreen; }
+        boot::step_done(message);
+    }
+    unsafe { cga_print::FG_COLOR = Color::White; }
+    boot::end();
+
     cpu::enable_int();
     kprintln!("Interrupts enabled.");
     
@@ -93,12 +137,20 @@ pub extern "C" fn startup() {
 
     // aufgabe2();
 
+    // debug_demo::run();
+    // watchpoint_demo::run();
+
+    // splash::run();
+    // menu::run();
+
     loop{}
 }
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    kprintln!("Panic: {}", info);
+    cpu::disable_int();
+    console::flush_all();
+    kernel::panic_screen::show(info);
     //	kprintln!("{:?}", Backtrace::new());
     loop {}
 }
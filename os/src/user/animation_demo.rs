@@ -0,0 +1,53 @@
+use crate::devices::cga::{self, Animation, Color, Frame};
+use crate::devices::keyboard;
+
+const SPINNER_ATTR: u8 = (Color::Black as u8) << 4 | (Color::Yellow as u8);
+const BALL_ATTR: u8 = (Color::Black as u8) << 4 | (Color::LightCyan as u8);
+
+/// A spinner in the middle of the screen.
+static SPINNER: [Frame; 4] = [
+    Frame { x: 40, y: 10, glyph: b'|', attrib: SPINNER_ATTR },
+    Frame { x: 40, y: 10, glyph: b'/', attrib: SPINNER_ATTR },
+    Frame { x: 40, y: 10, glyph: b'-', attrib: SPINNER_ATTR },
+    Frame { x: 40, y: 10, glyph: b'\\', attrib: SPINNER_ATTR },
+];
+
+/// Width of the way of the bouncing ball.
+const BOUNCE_WIDTH: usize = 20;
+
+/// A ball bouncing between column 30 and 30 + `BOUNCE_WIDTH`.
+static BALL: [Frame; 2 * BOUNCE_WIDTH] = ball_frames();
+
+const fn ball_frames() -> [Frame; 2 * BOUNCE_WIDTH] {
+    let mut frames = [Frame { x: 0, y: 0, glyph: b'o', attrib: BALL_ATTR }; 2 * BOUNCE_WIDTH];
+    let mut i = 0;
+    while i < 2 * BOUNCE_WIDTH {
+        let offset = if i < BOUNCE_WIDTH { i } else { 2 * BOUNCE_WIDTH - i };
+        frames[i].x = 30 + offset;
+        frames[i].y = 13;
+        i += 1;
+    }
+    frames
+}
+
+pub fn run() {
+    cga::CGA.lock().clear();
+    println!("Animation demo: a spinner and a bouncing ball");
+    println!("===========================");
+    println!("");
+    println!("Press any key to stop the animations");
+
+    let animations = [
+        Animation { frames: &SPINNER, interval_ms: 100 },
+        Animation { frames: &BALL, interval_ms: 50 },
+    ];
+    for anim in animations {
+        if let Err(e) = cga::animate(anim) {
+            println!("Starting the animation failed: {:?}", e);
+        }
+    }
+
+    // The animations stop by themselves on the key press.
+    keyboard::key_hit();
+    cga::stop_animations();
+}
@@ -7,12 +7,10 @@ use crate::cga::Color;
 
 pub fn run() {
 
-    let mut keyboard = keyboard::KEYBOARD.lock();
-
-    keyboard.set_repeat_rate(2, 2);
+    keyboard::KEYBOARD.lock().set_repeat_rate(2, 2).ok();
     // 'key_hit' aufrufen und Zeichen ausgeben
     loop {
-        let mut c = keyboard.key_hit();
+        let mut c = keyboard::key_hit();
         let mut ascii = c.get_ascii();
 
         if ascii >= 0x20 && ascii <= 0x7e || ascii == 13 { // 13 == return
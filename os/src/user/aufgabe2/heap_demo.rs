@@ -1,8 +1,9 @@
 use alloc::boxed::Box;
 use alloc::vec;
 
-use crate::devices::cga::{self, Color};
-use crate::devices::cga_print::{self, print};
+use crate::devices::cga;
+use crate::devices::cga_print::print;
+use crate::devices::theme::{self, Style};
 use crate::devices::keyboard;
 use crate::kernel::allocator;
 
@@ -20,21 +21,20 @@ pub fn run () {
 
     allocator::dump_free_list();
 
-    unsafe {cga_print::FG_COLOR = Color::LightGreen;}
+    theme::apply(Style::Highlight);
     let s1 = Box::new(S { a: 1, b: 2 });
     println!("s1.a={}, s1.b={}", s1.a, s1.b);
-    unsafe {cga_print::FG_COLOR = Color::White;}
+    theme::apply(Style::Body);
     allocator::dump_free_list();
     
-    unsafe {cga_print::FG_COLOR = Color::LightGreen;}
+    theme::apply(Style::Highlight);
     let s2 = Box::new(S { a: 3, b: 4 });
     println!("s2.a={}, s2.b={}", s2.a, s2.b);
-    unsafe {cga_print::FG_COLOR = Color::White;}
+    theme::apply(Style::Body);
     allocator::dump_free_list();
 
     println!("");
-    println!("Press <Return> to continue");
-    while 13 != keyboard::KEYBOARD.lock().key_hit().get_ascii(){}
+    keyboard::press_any_key(Some("Press any key to continue"), None);
     
     
     cga::CGA.lock().clear();
@@ -42,19 +42,18 @@ pub fn run () {
     println!("===========================");
     println!("");
 
-    unsafe {cga_print::FG_COLOR = Color::LightRed;}
+    theme::apply(Style::Error);
     drop(s1);
-    unsafe {cga_print::FG_COLOR = Color::White;}
+    theme::apply(Style::Body);
     allocator::dump_free_list();
 
-    unsafe {cga_print::FG_COLOR = Color::LightRed;}
+    theme::apply(Style::Error);
     drop(s2);
-    unsafe {cga_print::FG_COLOR = Color::White;}
+    theme::apply(Style::Body);
     allocator::dump_free_list();
     
     println!("");
-    println!("Press <Return> to continue");
-    while 13 != keyboard::KEYBOARD.lock().key_hit().get_ascii(){}
+    keyboard::press_any_key(Some("Press any key to continue"), None);
     
     
     cga::CGA.lock().clear();
@@ -62,14 +61,13 @@ pub fn run () {
     println!("===========================");
     println!("");
 
-    unsafe {cga_print::FG_COLOR = Color::LightGreen;}
+    theme::apply(Style::Highlight);
     let s1 = vec![S { a: 1, b: 2 }, S { a: 3, b: 4 }, S { a: 5, b: 6 }];
-    unsafe {cga_print::FG_COLOR = Color::White;}
+    theme::apply(Style::Body);
     allocator::dump_free_list();
 
     println!("");
-    println!("Press <Return> to continue");
-    while 13 != keyboard::KEYBOARD.lock().key_hit().get_ascii(){}
+    keyboard::press_any_key(Some("Press any key to continue"), None);
     
     
     cga::CGA.lock().clear();
@@ -77,13 +75,15 @@ pub fn run () {
     println!("===========================");
     println!("");
 
-    unsafe {cga_print::FG_COLOR = Color::LightRed;}
+    theme::apply(Style::Error);
     drop(s1);
-    unsafe {cga_print::FG_COLOR = Color::White;}
+    theme::apply(Style::Body);
     allocator::dump_free_list();
     
     println!("");
-    println!("Press <Return> to continue");
-    while 13 != keyboard::KEYBOARD.lock().key_hit().get_ascii(){}
+    allocator::dump_size_histogram();
+
+    println!("");
+    keyboard::press_any_key(Some("Press any key to continue"), None);
     cga::CGA.lock().clear();
 }
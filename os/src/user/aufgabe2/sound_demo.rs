@@ -1,9 +1,52 @@
+use crate::devices::cga;
+use crate::devices::keyboard;
 use crate::devices::pcspk;
+use crate::devices::pcspk::Note;
+use crate::library::input;
 
+/// The songs selectable in the sound demo, with the key selecting them.
+const SONGS: [(char, &str, fn()); 5] = [
+   ('1', "Zelda", pcspk::zelda),
+   ('2', "Tetris", pcspk::tetris),
+   ('3', "Aerodynamic", pcspk::aerodynamic),
+   ('4', "Chromatic scale from C1 to C2", chromatic_scale),
+   ('5', "Panned tones: left, center, right", panned_tones),
+];
+
+/// Let the user select songs and play them, until the demo is left with 'q'.
+/// A song is stopped by pressing any key.
 pub fn run() {
- 
-   println!("Sound Demo");
+   loop {
+      cga::CGA.lock().clear();
+      println!("Sound Demo");
+      println!("");
+      for (key, name, _) in SONGS {
+         println!("  {}  {}", key, name);
+      }
+      println!("");
+      println!("  q  Back to the menu");
+
+      let choice = input::getch();
+      if choice == 'q' {
+         return;
+      }
+      if let Some((_, name, song)) = SONGS.iter().find(|(key, _, _)| *key == choice) {
+         println!("");
+         println!("Playing {}, press any key to stop", name);
+         if pcspk::play_interruptible(*song) {
+            // consume the key which stopped the song
+            keyboard::get_key_buffer().get_last_key();
+         }
+      }
+   }
+}
+
+fn chromatic_scale() {
+   pcspk::chromatic_scale(Note::C.semitone(1), 13);
+}
 
-   pcspk::zelda();
- 
+fn panned_tones() {
+   pcspk::play_panned(pcspk::A1, 1000, 0);
+   pcspk::play_panned(pcspk::A1, 1000, pcspk::PAN_CENTER);
+   pcspk::play_panned(pcspk::A1, 1000, 255);
 }
@@ -0,0 +1,21 @@
+use alloc::vec::Vec;
+
+use crate::devices::cga;
+use crate::kernel::debug;
+
+pub fn run() {
+    cga::CGA.lock().clear();
+    println!("Debug demo: breakpoint");
+    println!("===========================");
+    println!("");
+
+    let squares: Vec<u64> = (1..=8).map(|i| i * i).collect();
+    println!("squares = {:?}", squares);
+    println!("Stopping at a breakpoint, press 'c' to continue.");
+    println!("");
+
+    debug::breakpoint();
+
+    println!("");
+    println!("Continued after the breakpoint.");
+}
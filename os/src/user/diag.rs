@@ -0,0 +1,275 @@
+use alloc::vec::Vec;
+
+use crate::devices::cga;
+use crate::devices::cga_print;
+use crate::devices::console;
+use crate::devices::key::Key;
+use crate::devices::keyboard;
+use crate::devices::mouse;
+use crate::devices::pcspk;
+use crate::devices::rtc;
+use crate::devices::serial;
+use crate::devices::theme::{self, Style};
+use crate::devices::vga;
+use crate::kernel::allocator::{self, boundary_tag, bump, list};
+use crate::kernel::boot;
+use crate::kernel::cpu;
+use crate::kernel::init;
+use crate::kernel::interrupts::{idt, intdispatcher};
+use crate::kernel::loader;
+use crate::kernel::log;
+use crate::kernel::memory;
+use crate::kernel::profiler;
+use crate::kernel::shutdown;
+use crate::kernel::stack_guard;
+use crate::kernel::timer;
+use crate::library::selftest::{Test, TestResult};
+use crate::library::{arrayvec, binheap, irqmutex, lazy, lru, slice};
+use crate::user::{heap_stress, menu};
+
+/// The self tests of a module: its name and its tests.
+type Suite = (&'static str, &'static [Test]);
+
+/// The self tests of the modules (see `library::selftest`), run first with one line
+/// per module.
+const MODULE_TESTS: [Suite; 32] = [
+    ("cga", cga::selftest::TESTS),
+    ("cga_print", cga_print::selftest::TESTS),
+    ("console", console::selftest::TESTS),
+    ("vga", vga::selftest::TESTS),
+    ("theme", theme::selftest::TESTS),
+    ("pcspk", pcspk::selftest::TESTS),
+    ("keyboard", keyboard::selftest::TESTS),
+    ("mouse", mouse::selftest::TESTS),
+    ("serial", serial::selftest::TESTS),
+    ("timer", timer::selftest::TESTS),
+    ("allocator", allocator::selftest::TESTS),
+    ("allocator::list", list::selftest::TESTS),
+    ("allocator::bump", bump::selftest::TESTS),
+    ("allocator::boundary_tag", boundary_tag::selftest::TESTS),
+    ("init", init::selftest::TESTS),
+    ("idt", idt::selftest::TESTS),
+    ("intdispatcher", intdispatcher::selftest::TESTS),
+    ("loader", loader::selftest::TESTS),
+    ("log", log::selftest::TESTS),
+    ("memory", memory::selftest::TESTS),
+    ("profiler", profiler::selftest::TESTS),
+    ("stack_guard", stack_guard::selftest::TESTS),
+    ("boot", boot::selftest::TESTS),
+    ("shutdown", shutdown::selftest::TESTS),
+    ("irqmutex", irqmutex::selftest::TESTS),
+    ("arrayvec", arrayvec::selftest::TESTS),
+    ("lazy", lazy::selftest::TESTS),
+    ("lru", lru::selftest::TESTS),
+    ("slice", slice::selftest::TESTS),
+    ("binheap", binheap::selftest::TESTS),
+    ("menu", menu::selftest::TESTS),
+    ("heap_stress", heap_stress::selftest::TESTS),
+];
+
+/// Smoke tests of the subsystems, run after the module tests in this order with one
+/// line each. The keyboard test waits for the user, so it comes last.
+const SYSTEM_TESTS: [Test; 6] = [
+    ("CGA color grid", test_cga),
+    ("PC speaker", test_speaker),
+    ("RTC", test_rtc),
+    ("Timer", test_timer),
+    ("Allocator", test_allocator),
+    ("Keyboard", test_keyboard),
+];
+
+/// Frequency and duration of the test tone.
+const TONE_FREQ: usize = 440;
+const TONE_MS: u64 = 100;
+
+/// Time to wait for the timer to advance and for a key press (milliseconds).
+const TIMER_WAIT_MS: u64 = 50;
+const KEY_TIMEOUT_MS: u32 = 10_000;
+
+/// Timeout of the key checks which do not wait for the user (milliseconds).
+const SHORT_KEY_TIMEOUT_MS: u32 = 20;
+
+/// First row of the color grid (one row per background color) and width of each cell.
+const GRID_ROW: usize = 3;
+const GRID_ROWS: usize = 8;
+const GRID_CELL_WIDTH: usize = 4;
+
+/// Run the self tests of all modules and the smoke tests of all subsystems and print
+/// pass or fail for each module and each smoke test, and a summary.
+/// A failing test does not stop the following ones.
+/// Needs the timer and the keyboard interrupt.
+pub fn run() {
+    cga::CGA.lock().clear();
+    theme::apply(Style::Header);
+    println!("Diagnostics: testing all subsystems");
+    println!("===========================");
+    theme::apply(Style::Body);
+    // leave room for the color grid
+    cga::CGA.lock().setpos(0, GRID_ROW + GRID_ROWS + 1);
+
+    let mut total = 0;
+    let mut failed = 0;
+    for (module, tests) in MODULE_TESTS {
+        print!("{:<16} ", module);
+        console::flush();
+        total += tests.len();
+        failed += run_suite(tests);
+    }
+    for (name, test) in SYSTEM_TESTS {
+        print!("{:<16} ", name);
+        console::flush();
+        total += 1;
+        match test() {
+            Ok(()) => report_pass(),
+            Err(msg) => {
+                report_failure(None, msg);
+                failed += 1;
+            }
+        }
+    }
+
+    println!("");
+    theme::apply(if failed == 0 { Style::Highlight } else { Style::Error });
+    println!("{} of {} tests passed", total - failed, total);
+    theme::apply(Style::Body);
+}
+
+/// Run the tests of a module and print one line if all pass, otherwise one line
+/// per failed test. Returns the number of failed tests.
+fn run_suite(tests: &[Test]) -> usize {
+    let mut failed = 0;
+    for (name, test) in tests {
+        if let Err(msg) = test() {
+            if failed > 0 {
+                print!("{:<16} ", "");
+            }
+            report_failure(Some(name), msg);
+            failed += 1;
+        }
+    }
+    if failed == 0 {
+        report_pass();
+    }
+    failed
+}
+
+fn report_pass() {
+    theme::apply(Style::Highlight);
+    println!("pass");
+    theme::apply(Style::Body);
+}
+
+/// Print the failure `msg`, preceded by the name of the failed test if given.
+fn report_failure(name: Option<&str>, msg: &str) {
+    theme::apply(Style::Error);
+    match name {
+        Some(name) => println!("FAIL: {}: {}", name, msg),
+        None => println!("FAIL: {}", msg),
+    }
+    theme::apply(Style::Body);
+}
+
+/// Draw a grid of all 8 background colors with all 16 foreground colors
+/// and read the attributes back from video memory.
+fn test_cga() -> TestResult {
+    let mut cga = cga::CGA.lock();
+    for bg in 0..GRID_ROWS as u8 {
+        for fg in 0..16u8 {
+            let attrib = bg << 4 | fg;
+            let x = fg as usize * GRID_CELL_WIDTH;
+            let y = GRID_ROW + bg as usize;
+            for i in 0..GRID_CELL_WIDTH {
+                cga.show(x + i, y, '#', attrib);
+            }
+            let (_, read) = cga.read_cell(x, y).ok_or("grid outside the screen")?;
+            if read != attrib {
+                return Err("attribute read back differs");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Play a short tone and check the speaker is turned on and off.
+fn test_speaker() -> TestResult {
+    let mut speaker = pcspk::SPEAKER.lock();
+    speaker.tone_on(TONE_FREQ);
+    let on = speaker.is_on();
+    wait_ms(TONE_MS);
+    speaker.off();
+    match (on, speaker.is_on()) {
+        (true, false) => Ok(()),
+        (false, _) => Err("speaker did not turn on"),
+        (true, true) => Err("speaker did not turn off"),
+    }
+}
+
+/// Read and print the time of the RTC.
+fn test_rtc() -> TestResult {
+    let time = rtc::RTC.lock().read_time();
+    print!("{:02}:{:02}:{:02} ", time.hour, time.minute, time.second);
+    if time.hour > 23 || time.minute > 59 || time.second > 59 {
+        return Err("invalid time");
+    }
+    Ok(())
+}
+
+/// Print the uptime and check that the timer is running.
+fn test_timer() -> TestResult {
+    if !cpu::is_int_enabled() {
+        return Err("interrupts are disabled");
+    }
+    let start = timer::uptime_ms();
+    print!("uptime {} ms ", start);
+    wait_ms(TIMER_WAIT_MS);
+    if timer::uptime_ms() <= start {
+        return Err("timer does not advance");
+    }
+    Ok(())
+}
+
+/// Allocate and free memory and check the allocator statistics.
+fn test_allocator() -> TestResult {
+    let before = allocator::bytes_in_use();
+    let mut v: Vec<u64> = Vec::new();
+    v.try_reserve(1024).map_err(|_| "allocation failed")?;
+    v.extend(0..1024);
+    if allocator::bytes_in_use() < before + 1024 * 8 {
+        return Err("allocation not counted");
+    }
+    if v.iter().sum::<u64>() != 1023 * 1024 / 2 {
+        return Err("allocated memory is corrupted");
+    }
+    drop(v);
+    if allocator::bytes_in_use() != before {
+        return Err("free not counted");
+    }
+    Ok(())
+}
+
+/// Check both results of `press_any_key()` with a key put into the key buffer and
+/// without a key, then wait for a real key press.
+fn test_keyboard() -> TestResult {
+    const KEY: Key = Key::new(b'x', 0x2d, 0);
+
+    keyboard::get_key_buffer().push_key(KEY);
+    if keyboard::press_any_key(None, Some(SHORT_KEY_TIMEOUT_MS)).map(|mut key| key.get_ascii()) != Some(b'x') {
+        return Err("buffered key not returned");
+    }
+    if keyboard::press_any_key(None, Some(SHORT_KEY_TIMEOUT_MS)).is_some() {
+        return Err("key returned after the timeout");
+    }
+
+    match keyboard::press_any_key(Some("press a key ... "), Some(KEY_TIMEOUT_MS)) {
+        Some(_) => Ok(()),
+        None => Err("no key pressed"),
+    }
+}
+
+/// Wait `ms` milliseconds using the timer.
+fn wait_ms(ms: u64) {
+    let deadline = timer::uptime_ms() + ms;
+    while timer::uptime_ms() < deadline {
+        cpu::wait_for_int();
+    }
+}
@@ -0,0 +1,227 @@
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Module: heap_stress                                                     ║
+   ╟─────────────────────────────────────────────────────────────────────────╢
+   ║ Descr.: Heap stress test: random allocations and frees of blocks with   ║
+   ║         random size and alignment, whose contents are checked for       ║
+   ║         corruption. A run only depends on its seed, so a failing run    ║
+   ║         can be replayed.                                                ║
+   ╚═════════════════════════════════════════════════════════════════════════╝
+*/
+use alloc::alloc::{alloc, dealloc, Layout};
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::devices::cga;
+use crate::kernel::allocator;
+use crate::kernel::cpu;
+use crate::library::input;
+use crate::library::rng::Rng;
+
+/// Seed used for every run if set, e.g. to replay a failing run from a log without the prompt.
+const FIXED_SEED: Option<u64> = None;
+
+/// Number of allocations and frees of a run.
+const OPERATIONS: usize = 20_000;
+
+/// Maximum number of blocks allocated at the same time.
+const SLOTS: usize = 64;
+
+/// Largest block size.
+const MAX_SIZE: usize = 4096;
+
+/// Seed of the last run, offered for replaying it (0 = no run yet).
+static LAST_SEED: AtomicU64 = AtomicU64::new(0);
+
+/// An operation of the stress test, chosen by the random generator.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Op {
+    /// Allocate a block for the empty `slot`.
+    Alloc { slot: usize, size: usize, align: usize },
+    /// Check the contents of the block in `slot` and free it (nothing, if its allocation failed).
+    Free { slot: usize },
+}
+
+/// The contents of a block were overwritten while it was allocated.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Corruption {
+    /// Index of the operation freeing the block
+    pub op: usize,
+    pub slot: usize,
+}
+
+/// A block allocated by the stress test, filled with the pattern of its slot.
+struct Block {
+    ptr: NonNull<u8>,
+    layout: Layout,
+}
+
+/// Ask for the seed (a new random one or the one of the last run), print it and run the
+/// stress test. A failing run can be replayed exactly with its seed, because the sequence
+/// of allocations and frees only depends on it (see `stress()`).
+pub fn run() {
+    cga::CGA.lock().clear();
+    cga::banner("Heap stress test");
+
+    let seed = match FIXED_SEED {
+        Some(seed) => seed,
+        None => choose_seed(),
+    };
+    LAST_SEED.store(seed, Ordering::Relaxed);
+    println!("Seed: {:#x}", seed);
+    kprintln!("heap stress: seed {:#x}", seed);
+
+    let used = allocator::bytes_in_use();
+    match stress(seed, OPERATIONS, |_| {}) {
+        Ok(()) => println!("{} operations done, no corruption found.", OPERATIONS),
+        Err(e) => {
+            println!("Block in slot {} corrupted at operation {}!", e.slot, e.op);
+            kprintln!("heap stress: seed {:#x} failed: {:?}", seed, e);
+        }
+    }
+    println!("Heap in use: {} bytes before, {} bytes after", used, allocator::bytes_in_use());
+    println!("");
+    println!("Press any key to return.");
+    input::getch();
+}
+
+/// Let the user choose between a new random seed and the seed of the last run.
+fn choose_seed() -> u64 {
+    let last = LAST_SEED.load(Ordering::Relaxed);
+    if last == 0 {
+        return cpu::rdtsc();
+    }
+    println!("r: replay the last seed ({:#x}), other keys: new random seed", last);
+    if input::getch() == 'r' { last } else { cpu::rdtsc() }
+}
+
+/// Run `operations` random allocations and frees of blocks with random size and alignment,
+/// reporting each operation to `log` before it is executed. Each block is filled with
+/// a pattern, which is checked when the block is freed. All blocks are freed at the end.
+/// The operations only depend on `seed`: a slot counts as occupied after its allocation
+/// even if the allocation failed because the heap is full, so failures do not change the
+/// sequence (the free of such a slot just frees nothing).
+pub fn stress(seed: u64, operations: usize, mut log: impl FnMut(Op)) -> Result<(), Corruption> {
+    let mut rng = Rng::new(seed);
+    let mut occupied = [false; SLOTS];
+    let mut blocks: [Option<Block>; SLOTS] = [const { None }; SLOTS];
+    let mut result = Ok(());
+
+    for op in 0..operations {
+        let slot = rng.below(SLOTS);
+        if occupied[slot] {
+            log(Op::Free { slot });
+            occupied[slot] = false;
+            if let Some(block) = blocks[slot].take() && !free_block(block, slot) {
+                result = Err(Corruption { op, slot });
+                break;
+            }
+        } else {
+            let size = 1 + rng.below(MAX_SIZE);
+            let align = 1 << rng.below(7);
+            log(Op::Alloc { slot, size, align });
+            occupied[slot] = true;
+            blocks[slot] = alloc_block(size, align, slot);
+        }
+    }
+
+    for (slot, block) in blocks.iter_mut().enumerate() {
+        if let Some(block) = block.take() {
+            free_block(block, slot);
+        }
+    }
+    result
+}
+
+/// Byte the block in `slot` is filled with.
+fn pattern(slot: usize) -> u8 {
+    0x80 | slot as u8
+}
+
+/// Allocate a block and fill it with the pattern of `slot`. Returns `None` if the heap is full.
+fn alloc_block(size: usize, align: usize, slot: usize) -> Option<Block> {
+    let layout = Layout::from_size_align(size, align).ok()?;
+    let ptr = NonNull::new(unsafe { alloc(layout) })?;
+    unsafe { allocator::fast_set(ptr.as_ptr(), pattern(slot), size); }
+    Some(Block { ptr, layout })
+}
+
+/// Free a block. Returns false if it does not hold the pattern of `slot` anymore.
+fn free_block(block: Block, slot: usize) -> bool {
+    let data = unsafe { core::slice::from_raw_parts(block.ptr.as_ptr(), block.layout.size()) };
+    let intact = data.iter().all(|&b| b == pattern(slot));
+    unsafe { dealloc(block.ptr.as_ptr(), block.layout) };
+    intact
+}
+
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Self tests, run by the diagnostics (see `user::diag`).                  ║
+   ╚═════════════════════════════════════════════════════════════════════════╝ */
+
+pub mod selftest {
+    use alloc::vec::Vec;
+    use crate::kernel::{allocator, cpu};
+    use crate::user::heap_stress::{self, Op};
+    use crate::library::selftest::{Test, TestResult};
+
+    /// The self tests of this module, run in this order.
+    pub const TESTS: &[Test] = &[
+        ("Stress replay", test_stress_replay),
+        ("Failed allocation", test_stress_failure),
+    ];
+
+    /// Run the heap stress test twice with the same seed and once with another seed
+    /// and check that only equal seeds produce the same operation log.
+    fn test_stress_replay() -> TestResult {
+        const SEED: u64 = 0x1234_5678;
+        const OPERATIONS: usize = 200;
+
+        let run = |seed| -> Result<Vec<Op>, &'static str> {
+            let mut log = Vec::new();
+            heap_stress::stress(seed, OPERATIONS, |op| log.push(op)).map_err(|_| "block corrupted")?;
+            Ok(log)
+        };
+        let first = run(SEED)?;
+        let replay = run(SEED)?;
+        let other = run(SEED + 1)?;
+
+        if first.len() != OPERATIONS {
+            return Err("operations missing in the log");
+        }
+        if first != replay {
+            return Err("replay differs");
+        }
+        if first == other {
+            return Err("seed has no effect");
+        }
+        Ok(())
+    }
+
+    /// Run the heap stress test with an injected allocation failure and check that the
+    /// operation log equals the one of a run without failures.
+    fn test_stress_failure() -> TestResult {
+        const SEED: u64 = 0x1234_5678;
+        const OPERATIONS: usize = 200;
+        const FAILING_ALLOC: usize = 3;
+
+        let mut expected = Vec::new();
+        heap_stress::stress(SEED, OPERATIONS, |op| expected.push(op)).map_err(|_| "block corrupted")?;
+
+        // The log is reserved up front, so only the allocations of the blocks count.
+        // Allocations of interrupt handlers would count as well, so interrupts are disabled.
+        let mut log = Vec::new();
+        if log.try_reserve(OPERATIONS).is_err() {
+            return Err("allocation failed");
+        }
+        cpu::without_interrupts(|| {
+            allocator::inject_failure_after(FAILING_ALLOC);
+            let result = heap_stress::stress(SEED, OPERATIONS, |op| log.push(op));
+            allocator::clear_failure_injection();
+            result
+        }).map_err(|_| "block corrupted")?;
+
+        if log != expected {
+            return Err("failed allocation changed the sequence");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,121 @@
+use alloc::alloc::{alloc, dealloc, Layout};
+use core::ptr::NonNull;
+
+use crate::devices::cga::{self, Color};
+use crate::devices::theme::{self, Style};
+use crate::kernel::allocator;
+use crate::kernel::cpu;
+use crate::kernel::timer;
+use crate::library::arrayvec::ArrayVec;
+use crate::library::input;
+
+/// Block sizes selectable with the keys '1' to '4'; other sizes are entered after 's'.
+const SIZES: [usize; 4] = [16, 256, 4096, MAX_SIZE];
+
+/// Largest block size that can be entered.
+const MAX_SIZE: usize = 1 << 20;
+
+/// Maximum number of live blocks allocated by the inspector.
+const MAX_BLOCKS: usize = 32;
+
+/// Maximum number of free blocks shown.
+const FREE_LIST_LINES: usize = 16;
+
+/// Duration of the red border flash when an allocation fails (milliseconds).
+const FLASH_MS: u64 = 200;
+
+/// A block allocated by the inspector, freed with the layout it was allocated with.
+struct Block {
+    ptr: NonNull<u8>,
+    layout: Layout,
+}
+
+/// Show the free list of the heap and let the user allocate and free blocks with the keyboard,
+/// so the effect on the free list can be watched live. The blocks are freed most recent first;
+/// all blocks still allocated are freed when the inspector is left.
+pub fn run() {
+    let mut blocks: ArrayVec<Block, MAX_BLOCKS> = ArrayVec::new();
+    let mut size = SIZES[0];
+
+    loop {
+        draw(&blocks, size);
+
+        match input::getch() {
+            'q' => break,
+            c @ '1'..='4' => size = SIZES[c as usize - '1' as usize],
+            's' => size = cga::input_number("Block size (Up/Down, Enter): ", 1, MAX_SIZE, size),
+            'a' => {
+                if !allocate(&mut blocks, size) {
+                    flash_border();
+                }
+            }
+            'f' => {
+                if let Some(block) = blocks.pop() {
+                    unsafe { dealloc(block.ptr.as_ptr(), block.layout) };
+                }
+            }
+            'd' => {
+                allocator::defragment();
+            }
+            _ => {}
+        }
+    }
+
+    while let Some(block) = blocks.pop() {
+        unsafe { dealloc(block.ptr.as_ptr(), block.layout) };
+    }
+}
+
+/// Allocate a block of `size` bytes and track it in `blocks`.
+/// Returns false if the heap is out of memory or no more blocks can be tracked.
+fn allocate(blocks: &mut ArrayVec<Block, MAX_BLOCKS>, size: usize) -> bool {
+    if blocks.len() == MAX_BLOCKS {
+        return false;
+    }
+    let layout = Layout::from_size_align(size, 8).unwrap();
+    let Some(ptr) = NonNull::new(unsafe { alloc(layout) }) else {
+        return false;
+    };
+    blocks.push(Block { ptr, layout }).ok();
+    true
+}
+
+/// Draw the help, the free list and the status line.
+fn draw(blocks: &ArrayVec<Block, MAX_BLOCKS>, size: usize) {
+    cga::CGA.lock().clear();
+    cga::banner("Heap inspector");
+    println!("1-4/s: block size   a: allocate   f: free last   d: defragment   q: quit");
+    println!("");
+
+    theme::apply(Style::Header);
+    println!("Free list:");
+    theme::apply(Style::Body);
+    allocator::visualize(FREE_LIST_LINES);
+
+    let live: usize = blocks.iter().map(|block| block.layout.size()).sum();
+    let (columns, rows) = cga::screen_size();
+    {
+        let mut cga = cga::CGA.lock();
+        cga.clear_region(0, rows - 1, columns, 1, theme::attribute(Style::Status));
+        cga.setpos(0, rows - 1);
+    }
+    theme::apply(Style::Status);
+    print!(
+        " size: {}  live: {} blocks, {} bytes  heap in use: {} of {} bytes",
+        size, blocks.len(), live, allocator::bytes_in_use(), allocator::heap_size()
+    );
+    theme::apply(Style::Body);
+
+    // Prompts (see `cga::input_number()`) appear above the status line.
+    cga::CGA.lock().setpos(0, rows - 2);
+}
+
+/// Flash the screen border red to signal a failed allocation.
+fn flash_border() {
+    cga::CGA.lock().set_border_color(Color::Red);
+    let end = timer::uptime_ms() + FLASH_MS;
+    while timer::uptime_ms() < end {
+        cpu::wait_for_int();
+    }
+    cga::CGA.lock().set_border_color(theme::get().border);
+}
@@ -0,0 +1,26 @@
+use crate::devices::cga;
+use crate::kernel::loader;
+use crate::kernel::memory;
+
+pub fn run() {
+    cga::CGA.lock().clear();
+    println!("Loader demo: static ELF64 program");
+    println!("===========================");
+    println!("");
+
+    let (start, end) = memory::PROGRAM_AREA;
+    println!("Program area: {:#x} - {:#x}", start, end);
+    println!("Loading the sample program ({} bytes) ...", loader::SAMPLE_PROGRAM.len());
+    let program = match loader::load(&loader::SAMPLE_PROGRAM) {
+        Ok(program) => program,
+        Err(e) => {
+            println!("Loading failed: {:?}", e);
+            return;
+        }
+    };
+    println!("Entry point at {:#x}", program.entry());
+
+    // Unsafe because the program runs in ring 0; the sample program only returns a value.
+    let exit_code = unsafe { program.run() };
+    println!("Program returned {}", exit_code);
+}
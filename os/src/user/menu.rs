@@ -0,0 +1,177 @@
+use crate::devices::cga;
+use crate::devices::console;
+use crate::devices::key::Key;
+use crate::devices::keyboard;
+use crate::devices::theme::{self, Style};
+use crate::kernel;
+use crate::library::input;
+use crate::user::aufgabe1::{keyboard_demo, text_demo};
+use crate::user::aufgabe2::{heap_demo, sound_demo};
+use crate::user::{animation_demo, debug_demo, diag, heap_stress, heapinspect, loader_demo, piano, vga_demo, watchpoint_demo};
+
+/// The demos selectable in the menu, with the key selecting them.
+const DEMOS: [(char, &str, fn()); 13] = [
+    ('1', "Text demo", text_demo::run),
+    ('2', "Keyboard demo", keyboard_demo::run),
+    ('3', "Heap demo", heap_demo::run),
+    ('4', "Sound demo", sound_demo::run),
+    ('5', "Debugger breakpoint demo", debug_demo::run),
+    ('6', "Watchpoint demo", watchpoint_demo::run),
+    ('7', "Piano", piano::run),
+    ('8', "Animation demo", animation_demo::run),
+    ('9', "Diagnostics", diag::run),
+    ('h', "Heap inspector", heapinspect::run),
+    ('l', "ELF loader demo", loader_demo::run),
+    ('s', "Heap stress test", heap_stress::run),
+    ('v', "VGA graphics demo", vga_demo::run),
+];
+
+/// Key of the demo started when no key is pressed within `BOOT_TIMEOUT_S` seconds
+/// after the menu is shown for the first time.
+const DEFAULT_DEMO: char = '3';
+
+/// Seconds until the default demo is started (0 = always wait for a choice).
+const BOOT_TIMEOUT_S: u64 = 5;
+
+/// Result of `countdown()`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Countdown {
+    /// A key was pressed after the given number of seconds
+    Cancelled(u64),
+    /// No key was pressed until the end of the countdown
+    Expired,
+}
+
+/// Count down `seconds` seconds. At the start of each second, `show` is called with the
+/// remaining seconds and `wait_key` waits at most the given milliseconds for a key.
+/// The countdown is cancelled as soon as `wait_key` returns a key.
+pub fn countdown(seconds: u64, mut show: impl FnMut(u64), mut wait_key: impl FnMut(u64) -> Option<Key>) -> Countdown {
+    for elapsed in 0..seconds {
+        show(seconds - elapsed);
+        if wait_key(1000).is_some() {
+            return Countdown::Cancelled(elapsed);
+        }
+    }
+    Countdown::Expired
+}
+
+/// Show the countdown to the default demo below the menu.
+/// Returns the key of the default demo if the countdown expired, or `None` if it was cancelled.
+fn boot_countdown() -> Option<char> {
+    let (_, name, _) = DEMOS.iter().find(|(key, _, _)| *key == DEFAULT_DEMO)?;
+
+    println!("");
+    print!("Starting {} in ", name);
+    let result = countdown(
+        BOOT_TIMEOUT_S,
+        |remaining| {
+            print!("{}... ", remaining);
+            console::flush();
+        },
+        keyboard::read_with_timeout,
+    );
+
+    match result {
+        Countdown::Expired => Some(DEFAULT_DEMO),
+        Countdown::Cancelled(_) => {
+            println!("cancelled");
+            None
+        }
+    }
+}
+
+/// The themes selectable in the menu, switched in this order.
+const THEMES: [(&str, theme::Theme); 3] = [
+    ("Classic", theme::CLASSIC),
+    ("Amber", theme::AMBER),
+    ("Matrix", theme::MATRIX),
+];
+
+/// Show the demo menu and run the selected demos, until the menu is left with 'q'.
+/// When the menu is shown for the first time, the default demo is started after
+/// `BOOT_TIMEOUT_S` seconds unless a key is pressed.
+/// Needs interrupts to be enabled (the keyboard is read via the key buffer).
+pub fn run() {
+    let mut current_theme = 0;
+    let mut first = BOOT_TIMEOUT_S > 0;
+    loop {
+        cga::CGA.lock().clear();
+        theme::apply(Style::Header);
+        println!("Demo menu");
+        cga::print_hline(27);
+        theme::apply(Style::Body);
+        println!("");
+        for (key, name, _) in DEMOS {
+            println!("  {}  {}", key, name);
+        }
+        println!("");
+        println!("  t  Switch theme (current: {})", THEMES[current_theme].0);
+        println!("  x  Shut down");
+        println!("  q  Quit");
+
+        let auto_choice = if first { boot_countdown() } else { None };
+        first = false;
+        let choice = auto_choice.unwrap_or_else(input::getch);
+        if choice == 'q' {
+            return;
+        }
+        if choice == 'x' {
+            kernel::shutdown();
+        }
+        if choice == 't' {
+            current_theme = (current_theme + 1) % THEMES.len();
+            theme::set(&THEMES[current_theme].1);
+            continue;
+        }
+        if let Some((_, _, demo)) = DEMOS.iter().find(|(key, _, _)| *key == choice) {
+            demo();
+            console::flush();
+            println!("");
+            println!("Press <Return> to continue");
+            input::wait_for_return();
+        }
+    }
+}
+
+/* ╔═════════════════════════════════════════════════════════════════════════╗
+   ║ Self tests, run by the diagnostics (see `user::diag`).                  ║
+   ╚═════════════════════════════════════════════════════════════════════════╝ */
+
+pub mod selftest {
+    use crate::devices::key::Key;
+    use crate::library::arrayvec::ArrayVec;
+    use crate::user::menu::{self, Countdown};
+    use crate::library::selftest::{Test, TestResult};
+
+    /// The self tests of this module, run in this order.
+    pub const TESTS: &[Test] = &[
+        ("Boot countdown", test_boot_countdown),
+    ];
+
+    /// Run the boot countdown of the menu with a simulated clock, once until it expires
+    /// and once cancelled by a key injected in the second second.
+    fn test_boot_countdown() -> TestResult {
+        const KEY: Key = Key::new(b'x', 0x2d, 0);
+
+        let mut clock_ms = 0;
+        let mut shown: ArrayVec<u64, 3> = ArrayVec::new();
+        let result = menu::countdown(3, |s| { shown.push(s).ok(); }, |ms| {
+            clock_ms += ms;
+            None
+        });
+        if result != Countdown::Expired || clock_ms != 3000 || shown.as_slice() != [3, 2, 1] {
+            return Err("countdown did not expire after 3 s");
+        }
+
+        let mut shown: ArrayVec<u64, 3> = ArrayVec::new();
+        let mut calls = 0;
+        let result = menu::countdown(3, |s| { shown.push(s).ok(); }, |_| {
+            calls += 1;
+            if calls == 2 { Some(KEY) } else { None }
+        });
+        if result != Countdown::Cancelled(1) || shown.as_slice() != [3, 2] {
+            return Err("key did not cancel the countdown");
+        }
+        Ok(())
+    }
+}
@@ -1,3 +1,14 @@
 pub mod aufgabe1;
 pub mod aufgabe2;
-pub mod aufgabe4;
\ No newline at end of file
+pub mod aufgabe4;
+pub mod animation_demo;
+pub mod debug_demo;
+pub mod diag;
+pub mod heapinspect;
+pub mod heap_stress;
+pub mod loader_demo;
+pub mod watchpoint_demo;
+pub mod menu;
+pub mod piano;
+pub mod splash;
+pub mod vga_demo;
@@ -0,0 +1,92 @@
+use crate::devices::cga;
+use crate::devices::keyboard;
+use crate::devices::pcspk::{self, Note};
+use crate::kernel::cpu;
+
+/// Octave of the lowest note of the piano (octave 1 starts at middle C).
+const PIANO_OCTAVE: usize = 1;
+
+/// Scancode of the Esc key, which ends the piano.
+const SCAN_ESC: u8 = 1;
+
+/// Maximum number of simultaneously held keys that are tracked.
+const MAX_HELD: usize = 8;
+
+/// Mapping of scancodes to notes: the home row is the C major scale,
+/// the row above holds the sharps (like the black keys of a piano).
+const PIANO_KEYS: [(u8, Note, usize); 13] = [
+    (30, Note::C, 0),  // a
+    (17, Note::Cx, 0), // w
+    (31, Note::D, 0),  // s
+    (18, Note::Dx, 0), // e
+    (32, Note::E, 0),  // d
+    (33, Note::F, 0),  // f
+    (20, Note::Fx, 0), // t
+    (34, Note::G, 0),  // g
+    (21, Note::Gx, 0), // z (y on a US keyboard)
+    (35, Note::A, 0),  // h
+    (22, Note::Ax, 0), // u
+    (36, Note::B, 0),  // j
+    (37, Note::C, 1),  // k
+];
+
+/// Return the frequency of the note played by the key with `code`, if any.
+fn key_freq(code: u8) -> Option<usize> {
+    let (_, note, octave) = PIANO_KEYS.iter().find(|(scancode, _, _)| *scancode == code)?;
+    pcspk::note_freq(note.semitone(PIANO_OCTAVE + octave))
+}
+
+/// Play the speaker like a piano: a tone sounds while its key is held.
+/// If several keys are held, the last pressed one is played. Esc ends the piano.
+pub fn run() {
+    cga::CGA.lock().clear();
+    println!("Piano");
+    println!("===========================");
+    println!("");
+    println!("  w e   t z u");
+    println!(" a s d f g h j k");
+    println!("");
+    println!("Press <Esc> to quit");
+
+    // Keys held down in the order they were pressed
+    let mut held = [0u8; MAX_HELD];
+    let mut count = 0;
+
+    keyboard::enable_key_events(true);
+    loop {
+        // The decoded keys are not needed, so the key buffer is just drained.
+        while keyboard::get_key_buffer().get_last_key().is_some() {}
+
+        let Some(event) = keyboard::next_event() else {
+            cpu::wait_for_int();
+            continue;
+        };
+        if event.prefix != 0 {
+            continue;
+        }
+        if event.code == SCAN_ESC {
+            break;
+        }
+        if key_freq(event.code).is_none() {
+            continue;
+        }
+
+        // Remove the key (a held key is repeated by the typematic) and append it if pressed
+        if let Some(pos) = held[..count].iter().position(|&code| code == event.code) {
+            held.copy_within(pos + 1..count, pos);
+            count -= 1;
+        }
+        if event.pressed && count < MAX_HELD {
+            held[count] = event.code;
+            count += 1;
+        }
+
+        let mut speaker = pcspk::SPEAKER.lock();
+        match held[..count].last() {
+            Some(&code) => speaker.tone_on(key_freq(code).unwrap_or(0)),
+            None => speaker.off(),
+        }
+    }
+    keyboard::enable_key_events(false);
+    pcspk::SPEAKER.lock().off();
+}
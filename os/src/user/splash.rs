@@ -0,0 +1,79 @@
+use crate::devices::cga;
+use crate::devices::console;
+use crate::devices::keyboard;
+use crate::devices::pcspk::{self, Note};
+use crate::devices::theme::{self, Style};
+use crate::kernel::timer;
+
+/// Total duration of the splash screen (milliseconds), unless it is skipped.
+const SPLASH_DURATION_MS: u64 = 3000;
+
+/// Delay between two characters of the typed title (milliseconds).
+const TYPE_DELAY_MS: u64 = 120;
+
+/// Duration of each note of the chime (milliseconds).
+const CHIME_NOTE_MS: u64 = 90;
+
+/// The title typed on the splash screen.
+const TITLE: &str = "hhuTOS";
+
+/// Row of the title (the middle of the screen).
+const TITLE_ROW: usize = 12;
+
+/// Ascending power-up chime: a major arpeggio.
+const CHIME: [(Note, usize); 5] = [(Note::C, 3), (Note::E, 3), (Note::G, 3), (Note::C, 4), (Note::E, 4)];
+
+/// Show the boot splash: the title is typed out in the header color of the theme,
+/// followed by a chime. Any key skips the splash immediately.
+/// Needs the timer and the keyboard interrupt.
+pub fn run() {
+    let deadline = timer::uptime_ms() + SPLASH_DURATION_MS;
+
+    cga::CGA.lock().clear();
+    cga::CGA.lock().setpos(cga::banner_column(TITLE.len()), TITLE_ROW);
+    theme::apply(Style::Header);
+    let skipped = type_title(deadline) || play_chime(deadline) || wait(SPLASH_DURATION_MS, deadline);
+    theme::apply(Style::Body);
+
+    pcspk::SPEAKER.lock().off();
+    if skipped {
+        kprintln!("splash skipped");
+    }
+    cga::CGA.lock().clear();
+}
+
+/// Print the title character by character. Returns true if a key was pressed.
+fn type_title(deadline: u64) -> bool {
+    for c in TITLE.chars() {
+        print!("{}", c);
+        // Show the character right away, also with a line buffered console.
+        console::flush();
+        if wait(TYPE_DELAY_MS, deadline) {
+            return true;
+        }
+    }
+    false
+}
+
+/// Play the chime. Returns true if a key was pressed.
+fn play_chime(deadline: u64) -> bool {
+    for (note, octave) in CHIME {
+        if let Some(freq) = pcspk::note_freq(note.semitone(octave)) {
+            pcspk::SPEAKER.lock().tone_on(freq);
+        }
+        let skipped = wait(CHIME_NOTE_MS, deadline);
+        pcspk::SPEAKER.lock().off();
+        if skipped {
+            return true;
+        }
+    }
+    false
+}
+
+/// Wait `ms` milliseconds, but not beyond `deadline` (uptime in milliseconds).
+/// Returns true if a key was pressed during the wait.
+fn wait(ms: u64, deadline: u64) -> bool {
+    let now = timer::uptime_ms();
+    let ms = ms.min(deadline.saturating_sub(now));
+    keyboard::read_with_timeout(ms).is_some()
+}
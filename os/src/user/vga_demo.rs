@@ -0,0 +1,48 @@
+use crate::devices::cga;
+use crate::devices::vga::{self, HEIGHT, VGA, WIDTH};
+use crate::library::input;
+
+/// Palette index of the frame around the gradient.
+const FRAME_COLOR: u8 = 255;
+
+/// Width of the frame around the gradient (pixels).
+const FRAME_WIDTH: usize = 8;
+
+/// Switch to graphics mode 13h, draw a horizontal gradient from blue to red in a frame
+/// and switch back to text mode when a key is pressed.
+pub fn run() {
+    cga::CGA.lock().clear();
+    println!("VGA demo: mode 13h (320x200, 256 colors)");
+    println!("===========================");
+    println!("");
+
+    if let Err(e) = vga::enter_mode13h() {
+        println!("Switching to graphics mode failed: {:?}", e);
+        return;
+    }
+
+    {
+        let mut vga = VGA.lock();
+
+        // Palette entries 0-254 fade from blue to red, 255 is white
+        for i in 0..FRAME_COLOR {
+            vga.set_palette(i, i >> 2, 0, 63 - (i >> 2));
+        }
+        vga.set_palette(FRAME_COLOR, 63, 63, 63);
+
+        vga.clear(FRAME_COLOR);
+        let inner = WIDTH - 2 * FRAME_WIDTH;
+        for x in 0..inner {
+            let color = (x * FRAME_COLOR as usize / inner) as u8;
+            vga.draw_rect(FRAME_WIDTH + x, FRAME_WIDTH, 1, HEIGHT - 2 * FRAME_WIDTH, color);
+        }
+    }
+
+    input::getch();
+
+    if let Err(e) = vga::leave_mode13h() {
+        println!("Switching back to text mode failed: {:?}", e);
+        return;
+    }
+    println!("Back in text mode.");
+}
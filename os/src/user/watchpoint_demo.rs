@@ -0,0 +1,36 @@
+use core::ptr;
+
+use crate::devices::cga;
+use crate::kernel::cpu::{self, Access};
+
+/// The variable watched by the demo.
+static mut WATCHED: u64 = 0;
+
+/// Called by the debug exception handler when `WATCHED` is written.
+fn on_write(addr: usize) {
+    println!("Watchpoint hit: {:#x} was written", addr);
+}
+
+pub fn run() {
+    cga::CGA.lock().clear();
+    println!("Watchpoint demo: debug registers");
+    println!("===========================");
+    println!("");
+
+    let addr = ptr::addr_of!(WATCHED) as usize;
+    cpu::set_watchpoint_handler(on_write);
+    if let Err(e) = cpu::set_watchpoint(addr, 8, Access::Write) {
+        println!("Setting the watchpoint failed: {:?}", e);
+        return;
+    }
+    println!("Watching {:#x}", addr);
+
+    println!("Writing 42 ...");
+    unsafe { ptr::write_volatile(ptr::addr_of_mut!(WATCHED), 42); }
+
+    cpu::clear_watchpoint();
+    println!("Watchpoint cleared, writing 43 ...");
+    unsafe { ptr::write_volatile(ptr::addr_of_mut!(WATCHED), 43); }
+
+    println!("WATCHED = {}", unsafe { ptr::read_volatile(ptr::addr_of!(WATCHED)) });
+}